@@ -0,0 +1,181 @@
+//! A cell that holds an `Arc`, for read-mostly shared state (such as
+//! configuration) that is hot-swapped by writers while readers clone it.
+//!
+//! Cloning the `Arc` out of a shared cell is the hard part: a reader has to
+//! bump the strong count of the target, but a writer can swap the pointer
+//! out and drop the cell's reference between the reader's load and its
+//! increment.  `AtomicArc` uses split (or "differential") reference
+//! counting to close that window.  The cell's word holds the pointer and a
+//! local count of readers that are in the middle of cloning it.  A reader
+//! increments the local count and reads the pointer in a single CAS, which
+//! borrows one of the writer's references, then bumps the strong count, and
+//! finally gives the borrowed reference back by decrementing the local
+//! count.
+//!
+//! A writer that swaps the pointer out takes the local count along with
+//! it, and adds that many references to the old value's strong count before
+//! releasing the cell's own reference.  Readers whose second CAS finds that
+//! the pointer changed know their borrowed reference was converted into a
+//! real one, and release it with an ordinary decrement.
+//!
+//! The same `Arc` may be stored, swapped out and stored again while a
+//! reader is mid-clone, so the reader can't tell which store its borrowed
+//! reference came from.  That is fine, because references to the same
+//! allocation are interchangeable: a reader only decrements the local count
+//! if it is non-zero, and otherwise treats its reference as converted.
+//!
+//! Loads are two CASes on a word that every reader shares, so heavily read
+//! cells see some contention, though readers never wait for writers (or
+//! each other) beyond retrying a CAS.  The word is 128 bits wide, so on
+//! stable rust, `AtomicCell` implements it with a lock, like every other
+//! `Atom<_, u128>`.
+
+use std::{marker::PhantomData, sync::Arc};
+
+use crate::{atomic_try_update, Atom};
+
+struct ArcState<T> {
+    ptr: *const T,
+    /// The number of references borrowed by readers that are mid-clone.
+    local: u64,
+}
+
+/// An atomically replaceable `Arc<T>`.  See the module documentation.
+pub struct AtomicArc<T> {
+    state: Atom<ArcState<T>, u128>,
+    /// Atom is unconditionally Send and Sync, and the cell owns an `Arc<T>`,
+    /// so this gives the cell the same auto traits as the `Arc`.
+    _val: PhantomData<Arc<T>>,
+}
+
+impl<T> AtomicArc<T> {
+    pub fn new(val: Arc<T>) -> Self {
+        let this = Self {
+            state: Atom::default(),
+            _val: PhantomData,
+        };
+        let ptr = Arc::into_raw(val);
+        unsafe {
+            atomic_try_update(&this.state, |s| {
+                s.ptr = ptr;
+                (true, ())
+            })
+        }
+        this
+    }
+
+    /// Returns a clone of the current value.
+    pub fn load(&self) -> Arc<T> {
+        let ptr = unsafe {
+            atomic_try_update(&self.state, |s| {
+                s.local += 1;
+                (true, s.ptr)
+            })
+        };
+        // The reference we borrowed keeps ptr alive, whether or not it is
+        // still in the cell.
+        unsafe { Arc::increment_strong_count(ptr) };
+        let returned = unsafe {
+            atomic_try_update(&self.state, |s| {
+                if s.ptr == ptr && s.local > 0 {
+                    s.local -= 1;
+                    (true, true)
+                } else {
+                    (false, false)
+                }
+            })
+        };
+        if !returned {
+            // A writer converted the borrowed reference into a real one.
+            unsafe { Arc::decrement_strong_count(ptr) };
+        }
+        unsafe { Arc::from_raw(ptr) }
+    }
+
+    /// Replaces the value.  The old value is dropped once the last reader
+    /// that cloned it drops its clone.
+    pub fn store(&self, val: Arc<T>) {
+        drop(self.swap(val));
+    }
+
+    /// Replaces the value, and returns the old one.
+    pub fn swap(&self, val: Arc<T>) -> Arc<T> {
+        let new = Arc::into_raw(val);
+        let (old, local) = unsafe {
+            atomic_try_update(&self.state, |s| {
+                let old = (s.ptr, s.local);
+                s.ptr = new;
+                s.local = 0;
+                (true, old)
+            })
+        };
+        unsafe { settle(old, local) }
+    }
+
+    /// Replaces the value with new if the cell still holds current (that is,
+    /// an `Arc` that points to the same allocation), and returns the old
+    /// value.  Otherwise, leaves the cell unchanged, and hands new back.
+    ///
+    /// This is the building block for read-copy-update: load the value,
+    /// build a modified copy, and retry from the load if another writer got
+    /// there first.
+    pub fn compare_and_swap(&self, current: &Arc<T>, new: Arc<T>) -> Result<Arc<T>, Arc<T>> {
+        let current = Arc::as_ptr(current);
+        let new = Arc::into_raw(new);
+        // current is kept alive by the caller, so if the pointers match, the
+        // cell holds the caller's allocation, and not a reused address.
+        let swapped = unsafe {
+            atomic_try_update(&self.state, |s| {
+                if s.ptr != current {
+                    return (false, None);
+                }
+                let old = (s.ptr, s.local);
+                s.ptr = new;
+                s.local = 0;
+                (true, Some(old))
+            })
+        };
+        match swapped {
+            Some((old, local)) => Ok(unsafe { settle(old, local) }),
+            None => Err(unsafe { Arc::from_raw(new) }),
+        }
+    }
+
+    /// Consumes the cell, and returns its value.
+    pub fn into_inner(self) -> Arc<T> {
+        let this = std::mem::ManuallyDrop::new(self);
+        let (ptr, local) = unsafe { atomic_try_update(&this.state, |s| (false, (s.ptr, s.local))) };
+        unsafe { settle(ptr, local) }
+    }
+}
+
+impl<T> Default for AtomicArc<T>
+where
+    T: Default,
+{
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl<T> From<Arc<T>> for AtomicArc<T> {
+    fn from(val: Arc<T>) -> Self {
+        Self::new(val)
+    }
+}
+
+impl<T> Drop for AtomicArc<T> {
+    fn drop(&mut self) {
+        let (ptr, local) = unsafe { atomic_try_update(&self.state, |s| (false, (s.ptr, s.local))) };
+        drop(unsafe { settle(ptr, local) });
+    }
+}
+
+/// Takes ownership of the reference the cell held to ptr, after converting
+/// the local references that readers borrowed from it into real ones.
+unsafe fn settle<T>(ptr: *const T, local: u64) -> Arc<T> {
+    for _ in 0..local {
+        Arc::increment_strong_count(ptr);
+    }
+    Arc::from_raw(ptr)
+}
@@ -1,10 +1,71 @@
 //! User-friendly barriers that use `atomic_try_update` to handle startup and teardown race conditions.
-use std::{error::Error, fmt::Display};
+use std::{
+    any::Any,
+    cell::UnsafeCell,
+    error::Error,
+    fmt::Display,
+    future::Future,
+    marker::PhantomData,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe, RefUnwindSafe},
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicPtr, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::{atomic_try_update, bits::FlagU64, Atom};
+use crate::{
+    atomic_try_update, claim::Claim, epoch::Collector, waker_list::WakerList, Atom, Node,
+    NodeIterator,
+};
 
-pub struct ShutdownBarrierWaitResult {
+/// Emits a `tracing` event at the given level if the `tracing` feature is
+/// enabled, and otherwise compiles to nothing.
+macro_rules! trace_barrier {
+    ($level:ident, $message:literal $(, $name:ident = $value:expr)* $(,)?) => {{
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($name = $value,)* $message);
+        #[cfg(not(feature = "tracing"))]
+        let _ = ($(&$value,)*);
+    }};
+}
+
+pub struct ShutdownBarrierWaitResult<R = (), A = ()> {
     cancelled: bool,
+    reason: Option<Arc<R>>,
+    aggregate: Option<Arc<A>>,
+    stats: Option<ShutdownBarrierStats>,
+    first_observer: bool,
+}
+
+/// Statistics about a generation of a `ShutdownBarrier`, as of the moment it
+/// shut down or was cancelled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShutdownBarrierStats {
+    total_workers: u32,
+    elapsed: Option<Duration>,
+    cancelled_mid_drain: bool,
+}
+
+impl ShutdownBarrierStats {
+    /// The number of workers registered over the generation, including the
+    /// parent.
+    pub fn total_workers(&self) -> u32 {
+        self.total_workers
+    }
+
+    /// The wall-clock time from the first `spawn()` to shutdown (or
+    /// cancellation).  None if no workers were spawned.
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.elapsed
+    }
+
+    /// True if the generation was cancelled after some of its workers had
+    /// already called `done()`.
+    pub fn cancelled_mid_drain(&self) -> bool {
+        self.cancelled_mid_drain
+    }
 }
 
 pub struct ShutdownBarrierDoneResult {
@@ -12,12 +73,56 @@ pub struct ShutdownBarrierDoneResult {
     shutdown_leader: bool,
 }
 
-impl ShutdownBarrierWaitResult {
+impl<R, A> ShutdownBarrierWaitResult<R, A> {
     /// This will return true for all waiters if at least one
     /// waiter called cancel() before shutdown.
     pub fn is_cancelled(&self) -> bool {
         self.cancelled
     }
+
+    /// Returns the reason passed to `cancel_with()`, or None if the barrier
+    /// was not cancelled, or was cancelled by `cancel()`.
+    pub fn reason(&self) -> Option<&R> {
+        self.reason.as_deref()
+    }
+
+    /// Like `reason()`, but returns the reason itself, which is shared with
+    /// the other waiters.
+    pub fn into_reason(self) -> Option<Arc<R>> {
+        self.reason
+    }
+
+    /// Returns the values passed to `done_with()` during the generation,
+    /// folded together by the reducer passed to `reduce_with()`.  None if no
+    /// worker submitted a value, if the reducer panicked, or if the
+    /// generation was cancelled (waiters return before the remaining workers
+    /// are done, so there is no final aggregate to report).
+    pub fn aggregate(&self) -> Option<&A> {
+        self.aggregate.as_deref()
+    }
+
+    /// Like `aggregate()`, but returns the aggregate itself, which is shared
+    /// with the other waiters.
+    pub fn into_aggregate(self) -> Option<Arc<A>> {
+        self.aggregate
+    }
+
+    /// Returns statistics about the generation the wait was for.  Only the
+    /// most recently ended generation's statistics are kept, so this is None
+    /// if the barrier was re-armed and a later generation ended before the
+    /// waiter noticed.
+    pub fn stats(&self) -> Option<&ShutdownBarrierStats> {
+        self.stats.as_ref()
+    }
+
+    /// True for exactly one waiter per generation: the first to observe that
+    /// it ended.  Unlike `ShutdownBarrierDoneResult::is_leader()`, this is
+    /// decided among waiters, so it suits post-shutdown work such as flushing
+    /// metrics.  (If every waiter falls more than a generation behind, as
+    /// described in `stats()`, no waiter is first.)
+    pub fn is_first_observer(&self) -> bool {
+        self.first_observer
+    }
 }
 
 impl ShutdownBarrierDoneResult {
@@ -44,36 +149,459 @@ impl ShutdownBarrierDoneResult {
 ///    complete.
 ///
 /// You can also invoke `cancel()`, which causes the wait result's
-/// `is_cancelled()` method to return true for all waiters.
-pub struct ShutdownBarrier {
-    state: Atom<FlagU64, u64>,
-    /// We send false for normal shutdown; true for cancellation
-    broadcast: tokio::sync::broadcast::Sender<bool>,
+/// `is_cancelled()` method to return true for all waiters.  If R is not `()`,
+/// `cancel_with()` also hands every waiter a reason, so they can tell (for
+/// instance) a requested stop from a failed worker.
+///
+/// Similarly, a barrier built with `reduce_with()` lets workers report a
+/// value of type A with `done_with()`.  The values are folded together, and
+/// waiters receive the result (for instance, the total number of rows the
+/// workers processed).
+///
+/// Once the worker count reaches zero the barrier is normally finished for
+/// good, but `reset()` re-arms it for another round of work, and a barrier
+/// created with `with_auto_rearm()` does so on its own.  Each round is a new
+/// generation; waits complete when the generation they started in ends.
+///
+/// `wait()` returns a plain future that is woken through a lock-free list of
+/// wakers, so it works with any async runtime (not just tokio).
+pub struct ShutdownBarrier<R = (), A = ()> {
+    state: Atom<BarrierState, u64>,
+    auto_rearm: bool,
+    /// The reason each cancelled generation was cancelled.
+    reasons: GenerationLog<Option<Arc<R>>>,
+    /// The number of `cancel()` calls that may not have published their
+    /// reason yet.  See `poll_shutdown()`.
+    cancels_in_flight: AtomicU64,
+    /// Tasks blocked in `wait()`.  Woken on shutdown and on cancellation.
+    waiters: WakerList,
+    /// Subscribers blocked in `ShutdownBarrierProgress::changed()`.  Woken
+    /// whenever the worker count changes.
+    progress: WakerList,
+    on_shutdown: Option<OnShutdown>,
+    /// Worker counts for the generation being run.  This is updated by
+    /// every spawn, so it fits in a u64 (and is lock free).
+    live: Atom<LiveStats, u64>,
+    /// Microseconds from `created` to the first `spawn()` of the generation
+    /// in `live`.  Stored before the spawn that resets `live`.
+    first_spawn: AtomicU64,
+    /// Published by whichever call ends a generation, for its waiters.
+    /// This is only touched once per generation and once per waiter, so it
+    /// is 128 bits wide, and on stable rust, `AtomicCell` implements it with
+    /// a lock, like every other `Atom<_, u128>`.
+    summary: Atom<GenerationSummary, u128>,
+    /// The base for the timestamps in `first_spawn`.
+    created: Instant,
+    aggregator: Aggregator<A>,
+    _marker: PhantomData<(Arc<R>, Arc<A>)>,
 }
 
-enum WaitResult {
-    StillRunning,
-    Shutdown,
-    Cancelled,
+// RefUnwindSafe keeps `&ShutdownBarrier` usable across `catch_unwind`.
+type OnShutdown = Box<dyn Fn(bool) + Send + Sync + RefUnwindSafe>;
+type Reducer<A> = Box<dyn Fn(A, A) -> A + Send + Sync + RefUnwindSafe>;
+
+/// The packed barrier state.  From the bottom bit up: the cancelled flag, a
+/// spare bit, 32 bits of worker count, and a (wrapping) 30 bit generation
+/// number.  Whether earlier generations were cancelled is recorded in
+/// `ShutdownBarrier::reasons`.
+#[derive(Clone, Copy, Default)]
+struct BarrierState {
+    val: u64,
+}
+
+impl BarrierState {
+    const CANCELLED: u64 = 0b01;
+    const COUNT_SHIFT: u32 = 2;
+    const COUNT_MASK: u64 = (u32::MAX as u64) << Self::COUNT_SHIFT;
+    const GENERATION_SHIFT: u32 = 34;
+
+    fn cancelled(&self) -> bool {
+        self.val & Self::CANCELLED != 0
+    }
+    fn set_cancelled(&mut self, cancelled: bool) {
+        self.val = (self.val & !Self::CANCELLED) | u64::from(cancelled);
+    }
+    fn count(&self) -> u32 {
+        ((self.val & Self::COUNT_MASK) >> Self::COUNT_SHIFT) as u32
+    }
+    fn set_count(&mut self, count: u32) {
+        self.val = (self.val & !Self::COUNT_MASK) | (u64::from(count) << Self::COUNT_SHIFT);
+    }
+    fn generation(&self) -> u32 {
+        (self.val >> Self::GENERATION_SHIFT) as u32
+    }
+    /// Starts the next generation with a single worker.
+    fn rearm(&mut self) {
+        let generation = self.generation().wrapping_add(1) as u64;
+        self.val = (generation << Self::GENERATION_SHIFT) | (1 << Self::COUNT_SHIFT);
+    }
+}
+
+/// How many generations a `GenerationLog` remembers.  Waiters that fall
+/// further behind than this get `ShutdownBarrierError::Expired`.
+const GENERATION_LOG_WINDOW: u32 = 64;
+
+/// Generations are 30 bits wide, and wrap.
+const GENERATION_MASK: u32 = (1 << 30) - 1;
+
+/// Returns how many generations ago generation started, as of current.
+fn generation_age(current: u32, generation: u32) -> u32 {
+    current.wrapping_sub(generation) & GENERATION_MASK
+}
+
+/// Returns true if generation is at least `GENERATION_LOG_WINDOW` older than
+/// current.  Ages past half the generation space are treated as generations
+/// that started after current, since the caller's idea of the current
+/// generation may be out of date.
+fn generation_expired(current: u32, generation: u32) -> bool {
+    let age = generation_age(current, generation);
+    (GENERATION_LOG_WINDOW..=GENERATION_MASK / 2).contains(&age)
+}
+
+/// A value per generation, newest first.  Entries more than
+/// `GENERATION_LOG_WINDOW` generations old are pruned on each push and each
+/// re-arm, which bounds the log, and keeps an entry from matching a
+/// generation number that has since wrapped around.
+///
+/// Readers pin the log's epoch collector while they walk it, and pruned
+/// entries are retired to the collector.  Pruning is serialized by a claim,
+/// and the links are atomic, since the pruner rewrites them while readers
+/// follow them.
+struct GenerationLog<T> {
+    head: Atom<GenerationLogHead<T>, u64>,
+    pruning: Claim,
+    readers: Collector,
+}
+
+struct GenerationLogHead<T> {
+    head: *mut LogEntry<T>,
+}
+
+struct LogEntry<T> {
+    generation: u32,
+    val: T,
+    next: AtomicPtr<LogEntry<T>>,
+}
+
+impl<T> Default for GenerationLog<T> {
+    fn default() -> Self {
+        Self {
+            head: Default::default(),
+            pruning: Default::default(),
+            readers: Default::default(),
+        }
+    }
+}
+
+impl<T: Clone> GenerationLog<T> {
+    fn push(&self, generation: u32, val: T) {
+        let node = Box::into_raw(Box::new(LogEntry {
+            generation,
+            val,
+            next: AtomicPtr::new(null_mut()),
+        }));
+        unsafe {
+            atomic_try_update(&self.head, |s| {
+                (*node).next.store(s.head, Ordering::Relaxed);
+                s.head = node;
+                (true, ())
+            })
+        }
+        self.prune(generation);
+    }
+
+    /// Returns the value for generation, or None if it has not been
+    /// published yet (or has been pruned).
+    fn find(&self, generation: u32) -> Option<T> {
+        let _guard = self.readers.pin();
+        let mut node = unsafe { atomic_try_update(&self.head, |s| (false, s.head)) };
+        while !node.is_null() {
+            let entry = unsafe { &*node };
+            if entry.generation == generation {
+                return Some(entry.val.clone());
+            }
+            node = entry.next.load(Ordering::Acquire);
+        }
+        None
+    }
+
+    /// Unlinks and retires the entries that are at least
+    /// `GENERATION_LOG_WINDOW` generations older than current.  Pushes can
+    /// finish out of order, so this checks every entry.  If another thread
+    /// is already pruning, this leaves the work to it.
+    fn prune(&self, current: u32) {
+        let Some(_pruning) = self.pruning.try_guard() else {
+            return;
+        };
+        let expired =
+            |node: *mut LogEntry<T>| unsafe { generation_expired(current, (*node).generation) };
+        'restart: loop {
+            let mut prev: *mut LogEntry<T> = null_mut();
+            let mut node = unsafe { atomic_try_update(&self.head, |s| (false, s.head)) };
+            while !node.is_null() {
+                let next = unsafe { (*node).next.load(Ordering::Acquire) };
+                if !expired(node) {
+                    prev = node;
+                    node = next;
+                    continue;
+                }
+                if prev.is_null() {
+                    let unlinked = unsafe {
+                        atomic_try_update(&self.head, |s| {
+                            let unlinked = s.head == node;
+                            if unlinked {
+                                s.head = next;
+                            }
+                            (unlinked, unlinked)
+                        })
+                    };
+                    if !unlinked {
+                        // A push got in front of node; find its new
+                        // predecessor.
+                        continue 'restart;
+                    }
+                } else {
+                    // Only the pruner changes links after an entry is
+                    // pushed, so nothing else can race with this store.
+                    unsafe { (*prev).next.store(next, Ordering::Release) };
+                }
+                // Readers that already reached node can still follow its
+                // link, which is left intact.
+                unsafe { self.readers.retire(node) };
+                node = next;
+            }
+            return;
+        }
+    }
+}
+
+impl<T> Drop for GenerationLog<T> {
+    fn drop(&mut self) {
+        let mut node = unsafe { atomic_try_update(&self.head, |s| (false, s.head)) };
+        while !node.is_null() {
+            let entry = unsafe { Box::from_raw(node) };
+            node = entry.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+/// Folds the values passed to `done_with()`, using the claim pattern: each
+/// worker queues its value, and whichever one gets the claim runs the reducer
+/// over everything queued so far.  The call that ends a generation queues an
+/// end marker, and whoever processes it publishes the generation's
+/// aggregate.
+///
+/// Values are tagged with their generation, since an auto-rearming barrier's
+/// next generation can start submitting before the marker for the last one
+/// has been queued.  They are processed in the order they were queued, and a
+/// worker queues its value before it calls `done()`, so each generation's
+/// values are folded before its end marker is processed.
+struct Aggregator<A> {
+    reducer: Option<Reducer<A>>,
+    queued: Atom<SubmissionsHead<A>, u64>,
+    claim: Claim,
+    /// The aggregates of running generations.  None if the reducer panicked.
+    /// Only accessed by the claim holder.
+    partial: UnsafeCell<Vec<(u32, Option<A>)>>,
+    finished: GenerationLog<Option<Arc<A>>>,
+}
+
+// Safety: partial is only accessed by the claim holder.
+unsafe impl<A: Send> Sync for Aggregator<A> {}
+
+// Reducer panics are caught before they can leave partial inconsistent.
+impl<A> RefUnwindSafe for Aggregator<A> {}
+
+struct SubmissionsHead<A> {
+    head: *mut Node<Submission<A>>,
+}
+
+enum Submission<A> {
+    Value(u32, A),
+    /// The generation ended.  Its aggregate is only published if it was not
+    /// cancelled.
+    End(u32, bool),
+}
+
+impl<A> Aggregator<A> {
+    fn new(reducer: Option<Reducer<A>>) -> Self {
+        Self {
+            reducer,
+            queued: Default::default(),
+            claim: Default::default(),
+            partial: Default::default(),
+            finished: Default::default(),
+        }
+    }
+
+    /// Queues submission, and then processes the queue unless another thread
+    /// has the claim.  Returns whether this published an aggregate (so
+    /// waiters need to be woken), and the first panic raised by the reducer.
+    fn submit(&self, submission: Submission<A>) -> (bool, Option<Box<dyn Any + Send>>) {
+        let node = Box::into_raw(Box::new(Node {
+            val: submission,
+            next: null_mut(),
+        }));
+        unsafe {
+            atomic_try_update(&self.queued, |s| {
+                (*node).next = s.head;
+                s.head = node;
+                (true, ())
+            })
+        }
+        let (mut published, mut panic) = (false, None);
+        let Some(mut claim) = self.claim.try_guard() else {
+            return (published, panic);
+        };
+        loop {
+            let head = unsafe {
+                atomic_try_update(&self.queued, |s| {
+                    let head = s.head;
+                    s.head = null_mut();
+                    (true, head)
+                })
+            };
+            // Newest first, so process them in reverse.
+            let batch: Vec<_> = NodeIterator::new(head).collect();
+            let partial = unsafe { &mut *self.partial.get() };
+            for submission in batch.into_iter().rev() {
+                match submission {
+                    Submission::Value(generation, value) => {
+                        let Some((_, slot)) = partial.iter_mut().find(|(g, _)| *g == generation)
+                        else {
+                            partial.push((generation, Some(value)));
+                            continue;
+                        };
+                        let (Some(reducer), Some(aggregate)) = (&self.reducer, slot.take()) else {
+                            continue;
+                        };
+                        match catch_unwind(AssertUnwindSafe(|| reducer(aggregate, value))) {
+                            Ok(aggregate) => *slot = Some(aggregate),
+                            Err(payload) => {
+                                panic.get_or_insert(payload);
+                            }
+                        }
+                    }
+                    Submission::End(generation, publish) => {
+                        let aggregate = partial
+                            .iter()
+                            .position(|(g, _)| *g == generation)
+                            .and_then(|i| partial.swap_remove(i).1);
+                        if publish {
+                            self.finished.push(generation, aggregate.map(Arc::new));
+                            published = true;
+                        }
+                    }
+                }
+            }
+            match claim.release() {
+                Some(still_held) => claim = still_held,
+                None => return (published, panic),
+            }
+        }
+    }
+}
+
+impl<A> Drop for Aggregator<A> {
+    fn drop(&mut self) {
+        let head = unsafe {
+            atomic_try_update(&self.queued, |s| {
+                let head = s.head;
+                s.head = null_mut();
+                (true, head)
+            })
+        };
+        drop(NodeIterator::new(head));
+    }
+}
+
+/// Generation numbers in `LiveStats` and `GenerationSummary` are tagged with
+/// this bit, so zeroed stats don't look like they belong to generation zero.
+const STATS_VALID: u32 = 1 << 31;
+
+/// Statistics for a generation that is still running.  Reset by the first
+/// `spawn()` of each generation.
+#[derive(Clone, Copy)]
+struct LiveStats {
+    generation: u32,
+    total_workers: u32,
+}
+
+#[derive(Clone, Copy)]
+struct GenerationSummary {
+    /// The generation, tagged with `STATS_VALID` and `MID_DRAIN`.
+    generation: u32,
+    total_workers: u32,
+    /// Elapsed microseconds, or `NO_ELAPSED` if there were no spawns.  The
+    /// top bit is set once a waiter has observed the summary.
+    elapsed: u64,
+}
+
+impl GenerationSummary {
+    const MID_DRAIN: u32 = 1 << 30;
+    const OBSERVED: u64 = 1 << 63;
+    const NO_ELAPSED: u64 = u64::MAX >> 1;
+
+    fn is_for(&self, generation: u32) -> bool {
+        self.generation & !Self::MID_DRAIN == generation | STATS_VALID
+    }
+
+    /// True if this summarizes a generation after the given one.  (Generation
+    /// numbers wrap, but only recent generations are ever compared.)
+    fn is_after(&self, generation: u32) -> bool {
+        let distance = (self.generation & !(Self::MID_DRAIN | STATS_VALID))
+            .wrapping_sub(generation)
+            & !(Self::MID_DRAIN | STATS_VALID);
+        self.generation & STATS_VALID != 0 && distance != 0 && distance < Self::MID_DRAIN / 2
+    }
+
+    fn stats(&self) -> ShutdownBarrierStats {
+        ShutdownBarrierStats {
+            total_workers: self.total_workers,
+            elapsed: match self.elapsed & !Self::OBSERVED {
+                Self::NO_ELAPSED => None,
+                elapsed => Some(Duration::from_micros(elapsed)),
+            },
+            cancelled_mid_drain: self.generation & Self::MID_DRAIN != 0,
+        }
+    }
 }
 
 #[derive(Debug)]
 enum DoneResult {
     Cancelled,
     AlreadyDone,
+    TooMany,
     ShutdownLeader,
     Running,
 }
 
-impl Default for ShutdownBarrier {
+impl<R, A> Default for ShutdownBarrier<R, A> {
     fn default() -> Self {
+        Self::with_one_worker(false)
+    }
+}
+
+impl<R, A> ShutdownBarrier<R, A> {
+    fn with_one_worker(auto_rearm: bool) -> Self {
         let this = Self {
             state: Default::default(),
-            broadcast: tokio::sync::broadcast::channel(1).0,
+            auto_rearm,
+            reasons: Default::default(),
+            cancels_in_flight: AtomicU64::new(0),
+            waiters: Default::default(),
+            progress: Default::default(),
+            on_shutdown: None,
+            live: Default::default(),
+            first_spawn: AtomicU64::new(0),
+            summary: Default::default(),
+            created: Instant::now(),
+            aggregator: Aggregator::new(None),
+            _marker: PhantomData,
         };
         unsafe {
             atomic_try_update(&this.state, |s| {
-                s.set_val(1);
+                s.set_count(1);
                 (true, ())
             });
         }
@@ -81,9 +609,25 @@ impl Default for ShutdownBarrier {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum ShutdownBarrierError {
     AlreadyShutdown,
+    /// Returned by `reset()` if workers are still registered with the
+    /// current generation.
+    StillRunning,
+    /// Returned by `done_many()` if it would complete more workers than are
+    /// registered.
+    TooManyDone,
+    /// Returned by the `wait` variants that take a timeout or deadline if the
+    /// barrier is still running when it passes.
+    Timeout,
+    /// Returned by the `wait` variants if the barrier moved on more than 64
+    /// generations before the waiter saw its generation end.  Outcomes are
+    /// only kept for that many generations.
+    Expired,
+    /// Returned by `spawn_n()` if it would register more than `u32::MAX`
+    /// workers at once.
+    TooManyWorkers,
 }
 
 impl Error for ShutdownBarrierError {}
@@ -94,7 +638,7 @@ impl Display for ShutdownBarrierError {
     }
 }
 
-impl ShutdownBarrier {
+impl<R, A> ShutdownBarrier<R, A> {
     /// Register another worker with the barrier.
     ///
     /// Returns Error if the barrier has already been completed.  The barrier
@@ -102,46 +646,125 @@ impl ShutdownBarrier {
     ///         so this will never happen if you are careful not to invoke `spawn()`
     ///         after the parent task invokes `done()`
     pub fn spawn(&self) -> Result<(), ShutdownBarrierError> {
-        let already_shutdown = unsafe {
+        self.spawn_n(1)
+    }
+
+    /// Like `spawn()`, but returns a guard that calls `done()` when it is
+    /// dropped, so early returns can't leave the barrier waiting forever.  If
+    /// the guard is dropped while its thread is panicking, it calls `cancel()`
+    /// first.
+    pub fn spawn_guard(&self) -> Result<WorkerGuard<'_, R, A>, ShutdownBarrierError> {
+        self.spawn()?;
+        Ok(WorkerGuard { barrier: self })
+    }
+
+    /// Registers n workers at once.  Either all n are registered, or (if the
+    /// barrier has already been completed) none are.
+    pub fn spawn_n(&self, n: u32) -> Result<(), ShutdownBarrierError> {
+        let spawned = unsafe {
             atomic_try_update(&self.state, |s| {
-                let count = s.get_val();
-                if s.get_flag() || count == 0 {
-                    (false, true) // already shutdown
-                } else {
-                    s.set_val(count + 1);
-                    (true, false)
+                let count = s.count();
+                if s.cancelled() || count == 0 {
+                    return (false, Err(ShutdownBarrierError::AlreadyShutdown));
                 }
+                let Some(count) = count.checked_add(n) else {
+                    return (false, Err(ShutdownBarrierError::TooManyWorkers));
+                };
+                s.set_count(count);
+                (true, Ok((s.generation(), count)))
             })
         };
-        if already_shutdown {
-            Err(ShutdownBarrierError::AlreadyShutdown)
-        } else {
-            Ok(())
+        let (generation, remaining) = match spawned {
+            Ok(spawned) => spawned,
+            Err(err) => {
+                match err {
+                    ShutdownBarrierError::TooManyWorkers => {
+                        trace_barrier!(debug, "spawn would overflow", workers = n)
+                    }
+                    _ => trace_barrier!(debug, "spawn after shutdown", workers = n),
+                }
+                return Err(err);
+            }
+        };
+        trace_barrier!(
+            trace,
+            "spawn",
+            generation = generation,
+            workers = n,
+            remaining = remaining
+        );
+        // This runs after the state CAS, so a done() that ends the
+        // generation in between publishes a summary without these workers
+        // (and, if this is the generation's first spawn, without an elapsed
+        // time).  The stats are approximate in that case, but the barrier
+        // itself is unaffected.
+        let tag = generation | STATS_VALID;
+        if unsafe { atomic_try_update(&self.live, |l| (false, l.generation != tag)) } {
+            // Probably the first spawn of this generation.  Publish the time
+            // before the reset below, so publish_summary never pairs a fresh
+            // count with a stale time.
+            self.first_spawn.store(self.now(), Ordering::Relaxed);
+        }
+        unsafe {
+            atomic_try_update(&self.live, |l| {
+                if l.generation != tag {
+                    *l = LiveStats {
+                        generation: tag,
+                        total_workers: 1,
+                    };
+                }
+                l.total_workers = l.total_workers.saturating_add(n);
+                (true, ())
+            })
         }
+        self.progress.wake_all();
+        Ok(())
     }
 
     /// Inform the barrier that whatever work all the workers are performing
     /// has been cancelled.  This call causes `wait()` to return immediately
     /// with `cancelled = true`.
     pub fn cancel(&self) -> Result<(), ShutdownBarrierError> {
-        let already_shutdown = unsafe {
+        self.cancel_inner(None)
+    }
+
+    /// Like `cancel()`, but every waiter also receives reason.  Only the first
+    /// cancellation of a generation succeeds, so waiters all see the same
+    /// reason.
+    pub fn cancel_with(&self, reason: R) -> Result<(), ShutdownBarrierError> {
+        self.cancel_inner(Some(Arc::new(reason)))
+    }
+
+    fn cancel_inner(&self, reason: Option<Arc<R>>) -> Result<(), ShutdownBarrierError> {
+        self.cancels_in_flight.fetch_add(1, Ordering::SeqCst);
+        let cancelled = unsafe {
             atomic_try_update(&self.state, |s| {
-                let count = s.get_val();
-                if s.get_flag() || count == 0 {
-                    (false, true)
+                let count = s.count();
+                if s.cancelled() || count == 0 {
+                    (false, None)
                 } else {
-                    s.set_flag(true);
-                    (true, false)
+                    s.set_cancelled(true);
+                    (true, Some((s.generation(), count)))
                 }
             })
         };
-        if already_shutdown {
-            Err(ShutdownBarrierError::AlreadyShutdown)
-        } else {
-            // send true for cancellation; false on success
-            _ = self.broadcast.send(true);
-            Ok(())
-        }
+        let Some((generation, remaining)) = cancelled else {
+            self.cancels_in_flight.fetch_sub(1, Ordering::SeqCst);
+            return Err(ShutdownBarrierError::AlreadyShutdown);
+        };
+        trace_barrier!(
+            debug,
+            "cancel",
+            generation = generation,
+            remaining = remaining
+        );
+        // Waiters that see the flag before these are published keep waiting
+        // until the wake below.
+        self.reasons.push(generation, reason);
+        self.cancels_in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.publish_summary(generation, Some(remaining));
+        self.waiters.wake_all();
+        Ok(())
     }
 
     /// Inform the barrier that a single worker has completed.
@@ -151,77 +774,1260 @@ impl ShutdownBarrier {
     /// `shutdown_leader = true` if this call to `done()` was the one that completed
     /// the pool of work.  Workers can check for `shutdown_leader = true` to
     /// perform clean up logic outside the thread of control that invokes `done()`.
+    ///
+    /// If the barrier was created with `with_auto_rearm()`, the call that
+    /// brings the count to zero also starts the next generation.  That call
+    /// also runs the closure passed to `on_shutdown()`, if any, even if the
+    /// generation was cancelled.
     pub fn done(&self) -> Result<ShutdownBarrierDoneResult, ShutdownBarrierError> {
-        let done_result = unsafe {
+        self.done_many(1)
+    }
+
+    /// Like `done()`, for a worker that failed.  This cancels the generation
+    /// with error as the reason, so waiters return right away and receive the
+    /// first error reported (see `cancel_with()`).  Errors reported after the
+    /// generation was cancelled are dropped.
+    pub fn done_with_error(
+        &self,
+        error: R,
+    ) -> Result<ShutdownBarrierDoneResult, ShutdownBarrierError> {
+        // Fails if the generation was already cancelled.  done() reports the
+        // other failures.
+        let _ = self.cancel_with(error);
+        self.done()
+    }
+
+    /// Like `done()`, but also reports value, which is folded into the
+    /// generation's aggregate (see `reduce_with()`).  On a barrier without a
+    /// reducer, value is dropped.
+    ///
+    /// If the reducer panics, the panic is resumed once this call has
+    /// completed the worker, and the generation's aggregate is None.
+    pub fn done_with(&self, value: A) -> Result<ShutdownBarrierDoneResult, ShutdownBarrierError> {
+        let s = self.load();
+        let mut panic = None;
+        if self.aggregator.reducer.is_some() && s.count() != 0 {
+            // We are one of the generation's workers, so it can't end before
+            // the done() below.
+            let (published, payload) = self
+                .aggregator
+                .submit(Submission::Value(s.generation(), value));
+            if published {
+                self.waiters.wake_all();
+            }
+            panic = payload;
+        }
+        let result = self.done();
+        if let Some(payload) = panic {
+            resume_unwind(payload);
+        }
+        result
+    }
+
+    /// Like `done()`, but completes n workers at once, such as a worker that
+    /// was registered with `spawn_n()`.  Returns `TooManyDone` (and completes
+    /// none of them) if fewer than n workers are registered.
+    pub fn done_many(&self, n: u32) -> Result<ShutdownBarrierDoneResult, ShutdownBarrierError> {
+        let (done_result, last, generation, remaining) = unsafe {
             atomic_try_update(&self.state, |s| {
-                let count = s.get_val();
-                s.set_val(count - 1);
-                if s.get_flag() {
-                    (true, DoneResult::Cancelled)
-                } else if count == 0 {
-                    (false, DoneResult::AlreadyDone)
-                } else if count == 1 {
-                    (true, DoneResult::ShutdownLeader)
+                let count = s.count();
+                let generation = s.generation();
+                if count == 0 {
+                    return (false, (DoneResult::AlreadyDone, false, generation, count));
+                }
+                if n > count {
+                    return (false, (DoneResult::TooMany, false, generation, count));
+                }
+                let result = if s.cancelled() {
+                    DoneResult::Cancelled
+                } else if count == n {
+                    DoneResult::ShutdownLeader
                 } else {
-                    (true, DoneResult::Running)
+                    DoneResult::Running
+                };
+                if n == 0 {
+                    return (false, (result, false, generation, count));
                 }
+                if count == n && self.auto_rearm {
+                    s.rearm();
+                } else {
+                    s.set_count(count - n);
+                }
+                (true, (result, count == n, generation, count - n))
             })
         };
-        match done_result {
-            DoneResult::Cancelled => Ok(ShutdownBarrierDoneResult {
-                cancelled: true,
-                shutdown_leader: false,
-            }),
-            DoneResult::ShutdownLeader => {
-                _ = self.broadcast.send(false);
-                Ok(ShutdownBarrierDoneResult {
-                    cancelled: false,
-                    shutdown_leader: true,
-                })
+        if last && self.auto_rearm {
+            self.prune_logs(generation.wrapping_add(1) & GENERATION_MASK);
+        }
+        let cancelled = matches!(done_result, DoneResult::Cancelled);
+        let mut panic = None;
+        trace_barrier!(
+            trace,
+            "done",
+            generation = generation,
+            workers = n,
+            remaining = remaining,
+            leader = matches!(done_result, DoneResult::ShutdownLeader),
+            cancelled = cancelled,
+        );
+        if !matches!(done_result, DoneResult::AlreadyDone | DoneResult::TooMany) {
+            self.progress.wake_all();
+        }
+        if last {
+            trace_barrier!(
+                debug,
+                "shutdown",
+                generation = generation,
+                cancelled = cancelled
+            );
+            if !cancelled {
+                // Cancelled generations were summarized by `cancel()`.
+                self.publish_summary(generation, None);
             }
-            DoneResult::Running => Ok(ShutdownBarrierDoneResult {
-                cancelled: false,
-                shutdown_leader: false,
-            }),
+            if self.aggregator.reducer.is_some() {
+                // If another thread has the claim, it publishes the aggregate
+                // (and wakes the waiters) instead.
+                panic = self
+                    .aggregator
+                    .submit(Submission::End(generation, !cancelled))
+                    .1;
+            }
+            // Waiters of a cancelled generation were already woken, but if
+            // the barrier re-armed, the ones that started waiting after the
+            // cancellation were not.
+            self.waiters.wake_all();
+            if let Some(on_shutdown) = &self.on_shutdown {
+                on_shutdown(cancelled);
+            }
+        }
+        if let Some(payload) = panic {
+            resume_unwind(payload);
+        }
+        match done_result {
             DoneResult::AlreadyDone => Err(ShutdownBarrierError::AlreadyShutdown),
+            DoneResult::TooMany => Err(ShutdownBarrierError::TooManyDone),
+            _ => Ok(ShutdownBarrierDoneResult {
+                cancelled,
+                shutdown_leader: matches!(done_result, DoneResult::ShutdownLeader),
+            }),
         }
     }
 
     /// Waits until the number of workers reaches zero.  This can be called at any time
     /// and can be called multiple times.
-    pub async fn wait(&self) -> Result<ShutdownBarrierWaitResult, ShutdownBarrierError> {
-        // We have to subscribe before we check state.  Otherwise, some some thread
-        // could send the shutdown message after our subscription begins!
-        let mut rx = self.broadcast.subscribe();
-        let wait_result = unsafe {
+    ///
+    /// The wait is for the generation that is current when `wait()` is called,
+    /// not when the returned future is first polled.
+    pub fn wait(
+        &self,
+    ) -> impl Future<Output = Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError>> + '_
+    {
+        let generation = self.generation();
+        async move {
+            self.waiters
+                .wait_for(|| self.poll_shutdown(generation))
+                .await
+        }
+    }
+
+    /// Waits for a quiet point: the next time the worker count reaches zero.
+    /// Unlike `wait()`, this ignores cancellation, and with a barrier created
+    /// by `with_auto_rearm()`, it completes at the end of every generation
+    /// even though work continues in the next one.  Returns the generation
+    /// that drained.
+    ///
+    /// Like `wait()`, this waits on the generation that is current when it
+    /// is called.
+    pub fn wait_for_idle(&self) -> impl Future<Output = u32> + '_ {
+        let generation = self.generation();
+        self.waiters.wait_for(move || self.poll_idle(generation))
+    }
+
+    /// Like `wait_for_idle()`, but blocks the calling thread.
+    pub fn wait_for_idle_blocking(&self) -> u32 {
+        let generation = self.generation();
+        self.waiters
+            .wait_blocking_for(|| self.poll_idle(generation), None)
+            .expect("waits without a deadline can't time out")
+    }
+
+    fn poll_idle(&self, generation: u32) -> Option<u32> {
+        let s = self.load();
+        (s.generation() != generation || s.count() == 0).then_some(generation)
+    }
+
+    /// Like `wait()`, but blocks the calling thread.  Blocking and async
+    /// waiters can wait on the same barrier.
+    pub fn wait_blocking(&self) -> Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError> {
+        let generation = self.generation();
+        self.waiters
+            .wait_blocking_for(|| self.poll_shutdown(generation), None)
+            .expect("waits without a deadline can't time out")
+    }
+
+    /// Like `wait()`, but gives up and returns `Timeout` if the barrier has
+    /// not shut down within timeout.  This uses tokio's timer, so it requires
+    /// the `tokio-rt` feature, and must be called from within a tokio
+    /// runtime.  `wait_blocking_timeout()` works without either.
+    #[cfg(feature = "tokio-rt")]
+    pub async fn wait_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError> {
+        tokio::time::timeout(timeout, self.wait())
+            .await
+            .map_err(|_| ShutdownBarrierError::Timeout)?
+    }
+
+    /// Like `wait_timeout()`, but takes a deadline.
+    #[cfg(feature = "tokio-rt")]
+    pub async fn wait_until(
+        &self,
+        deadline: Instant,
+    ) -> Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError> {
+        tokio::time::timeout_at(deadline.into(), self.wait())
+            .await
+            .map_err(|_| ShutdownBarrierError::Timeout)?
+    }
+
+    /// Like `wait_blocking()`, but gives up and returns `Timeout` if the
+    /// barrier has not shut down within timeout.
+    pub fn wait_blocking_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError> {
+        self.wait_blocking_until(Instant::now() + timeout)
+    }
+
+    /// Like `wait_blocking_timeout()`, but takes a deadline.
+    pub fn wait_blocking_until(
+        &self,
+        deadline: Instant,
+    ) -> Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError> {
+        let generation = self.generation();
+        self.waiters
+            .wait_blocking_for(|| self.poll_shutdown(generation), Some(deadline))
+            .ok_or(ShutdownBarrierError::Timeout)?
+    }
+
+    /// Re-arms a barrier whose worker count has reached zero, starting a new
+    /// generation with a single worker (the caller), as though it had just
+    /// been created with `new()`.
+    ///
+    /// Returns `StillRunning` if workers are still registered, even if the
+    /// barrier was cancelled; they would otherwise count against the new
+    /// generation when they call `done()`.
+    pub fn reset(&self) -> Result<(), ShutdownBarrierError> {
+        let still_running = unsafe {
             atomic_try_update(&self.state, |s| {
-                let count = s.get_val();
-                if s.get_flag() {
-                    (false, WaitResult::Cancelled)
-                } else if count == 0 {
-                    (true, WaitResult::Shutdown)
+                if s.count() != 0 {
+                    (false, true)
                 } else {
-                    (false, WaitResult::StillRunning)
+                    s.rearm();
+                    (true, false)
                 }
             })
         };
-        match wait_result {
-            WaitResult::StillRunning => {
-                let cancelled = rx
-                    .recv()
-                    .await
-                    .map_err(|_| ShutdownBarrierError::AlreadyShutdown)?;
-                Ok(ShutdownBarrierWaitResult { cancelled })
+        if still_running {
+            Err(ShutdownBarrierError::StillRunning)
+        } else {
+            self.prune_logs(self.generation());
+            self.progress.wake_all();
+            Ok(())
+        }
+    }
+
+    /// Returns a subscription to the worker count, for reporting shutdown
+    /// progress ("draining: 37 workers left") without polling
+    /// `remaining_workers()`.
+    pub fn subscribe(&self) -> ShutdownBarrierProgress<'_, R, A> {
+        let s = self.load();
+        ShutdownBarrierProgress {
+            barrier: self,
+            seen: (s.generation(), s.count()),
+        }
+    }
+
+    /// Returns the current generation.  This starts at zero, and is
+    /// incremented (wrapping at 2^30) each time the barrier is re-armed.
+    pub fn generation(&self) -> u32 {
+        self.load().generation()
+    }
+
+    /// Returns the number of workers that have not called `done()` yet,
+    /// including the parent.  Zero once the barrier has shut down.
+    pub fn remaining_workers(&self) -> u32 {
+        self.load().count()
+    }
+
+    /// Returns true if the current generation has been cancelled.  Workers
+    /// may still be running.
+    pub fn is_cancelled(&self) -> bool {
+        self.load().cancelled()
+    }
+
+    /// Returns true if the worker count of the current generation has reached
+    /// zero.  A barrier created with `with_auto_rearm()` starts its next
+    /// generation at that point instead, so it never reports shutdown.
+    pub fn is_shutdown(&self) -> bool {
+        self.load().count() == 0
+    }
+
+    fn load(&self) -> BarrierState {
+        unsafe { atomic_try_update(&self.state, |s| (false, *s)) }
+    }
+
+    /// Returns the result of waiting for the given generation, or None if it
+    /// is still running.
+    ///
+    /// The current generation's outcome is in the state word, and whether
+    /// each earlier one was cancelled (and why) is in `reasons`, which only
+    /// remembers the last `GENERATION_LOG_WINDOW` generations.  Waiters that
+    /// fall further behind get `Expired`.
+    fn poll_shutdown(
+        &self,
+        generation: u32,
+    ) -> Option<Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError>> {
+        let s = self.load();
+        if generation_age(s.generation(), generation) >= GENERATION_LOG_WINDOW {
+            return Some(Err(ShutdownBarrierError::Expired));
+        }
+        let reason = if s.generation() != generation {
+            // Re-armed since the wait began.  A cancel() of our generation
+            // updated the state before it ended, so if one is still in flight
+            // now, it may not have published its reason yet; it wakes us when
+            // it has.
+            let in_flight = self.cancels_in_flight.load(Ordering::SeqCst) != 0;
+            match self.reasons.find(generation) {
+                Some(reason) => Some(reason),
+                None if in_flight => return None,
+                None => None,
             }
-            WaitResult::Shutdown => Ok(ShutdownBarrierWaitResult { cancelled: false }),
-            WaitResult::Cancelled => Ok(ShutdownBarrierWaitResult { cancelled: true }),
+        } else if s.cancelled() {
+            // The canceller publishes the reason after updating the state,
+            // and then wakes us.
+            Some(self.reasons.find(generation)?)
+        } else if s.count() == 0 {
+            None
+        } else {
+            return None;
+        };
+        let cancelled = reason.is_some();
+        let reason = reason.flatten();
+        let aggregate = if cancelled || self.aggregator.reducer.is_none() {
+            None
+        } else {
+            // Published by whoever processes the generation's end marker,
+            // which then wakes us.
+            self.aggregator.finished.find(generation)?
+        };
+        // Claiming the summary is the last step, so a waiter that claims it
+        // always completes.
+        let (stats, first_observer) = unsafe {
+            atomic_try_update(&self.summary, |summary| {
+                if summary.is_for(generation) {
+                    let first = summary.elapsed & GenerationSummary::OBSERVED == 0;
+                    summary.elapsed |= GenerationSummary::OBSERVED;
+                    (first, Some((Some(summary.stats()), first)))
+                } else if summary.is_after(generation) {
+                    // Only the latest summary is kept.
+                    (false, Some((None, false)))
+                } else {
+                    // Not published yet; whoever ended the generation wakes
+                    // us when it is.
+                    (false, None)
+                }
+            })
+        }?;
+        Some(Ok(ShutdownBarrierWaitResult {
+            cancelled,
+            reason,
+            aggregate,
+            stats,
+            first_observer,
+        }))
+    }
+
+    /// Drops log entries that fell out of the window when generation
+    /// started.
+    fn prune_logs(&self, generation: u32) {
+        self.reasons.prune(generation);
+        self.aggregator.finished.prune(generation);
+    }
+
+    /// Summarizes a generation that has just ended.  remaining is the worker
+    /// count when it was cancelled, or None if it shut down.
+    fn publish_summary(&self, generation: u32, remaining: Option<u32>) {
+        let now = self.now();
+        let live = unsafe { atomic_try_update(&self.live, |l| (false, *l)) };
+        let (total_workers, elapsed) = if live.generation == generation | STATS_VALID {
+            let first_spawn = self.first_spawn.load(Ordering::Relaxed);
+            (live.total_workers, now.saturating_sub(first_spawn))
+        } else {
+            (1, GenerationSummary::NO_ELAPSED)
+        };
+        let mid_drain = remaining.is_some_and(|remaining| remaining < total_workers);
+        unsafe {
+            atomic_try_update(&self.summary, |summary| {
+                if summary.is_after(generation) {
+                    // A cancellation that raced with the next generation ending.
+                    return (false, ());
+                }
+                *summary = GenerationSummary {
+                    generation: generation
+                        | STATS_VALID
+                        | if mid_drain {
+                            GenerationSummary::MID_DRAIN
+                        } else {
+                            0
+                        },
+                    total_workers,
+                    elapsed,
+                };
+                (true, ())
+            })
         }
     }
-    /// Returns a new shutdown barrier with a single worker.  The caller
-    /// should spawn() all the work that needs to be done, then invoke
-    /// done().  This makes sure the worker count doesn't spuriously
-    /// reach zero while work is being spawned.
-    pub fn new() -> Self {
-        Default::default()
+
+    /// Microseconds since the barrier was created.
+    fn now(&self) -> u64 {
+        self.created.elapsed().as_micros() as u64
+    }
+
+    /// Splits the barrier into a handle that can only register and complete
+    /// workers, and one that can only wait and cancel.  Both can be cloned,
+    /// so the spawning side can be handed to worker factories without also
+    /// letting them cancel everyone.
+    pub fn split(self) -> (ShutdownBarrierSpawner<R, A>, ShutdownBarrierWaiter<R, A>) {
+        let barrier = Arc::new(self);
+        (
+            ShutdownBarrierSpawner {
+                barrier: barrier.clone(),
+            },
+            ShutdownBarrierWaiter { barrier },
+        )
+    }
+
+    /// Registers a closure that runs when the worker count reaches zero (once
+    /// per generation), in the thread whose `done()` call got it there.  It
+    /// is passed whether the generation was cancelled.  Waiters are woken
+    /// before it runs, so they shouldn't assume it has finished.
+    pub fn on_shutdown<F>(mut self, on_shutdown: F) -> Self
+    where
+        F: Fn(bool) + Send + Sync + RefUnwindSafe + 'static,
+    {
+        self.on_shutdown = Some(Box::new(on_shutdown));
+        self
+    }
+}
+
+impl<R> ShutdownBarrier<R> {
+    /// Like `new()`, but for a barrier whose cancellations can carry a reason
+    /// of type R.
+    pub fn with_reasons() -> Self {
+        Default::default()
+    }
+
+    /// Like `with_auto_rearm()`, but for a barrier whose cancellations can
+    /// carry a reason of type R.
+    pub fn with_reasons_and_auto_rearm() -> Self {
+        Self::with_one_worker(true)
+    }
+
+    /// Lets workers report a value of type A with `done_with()`.  The values
+    /// reported during a generation are folded together with reducer, and
+    /// handed to its waiters by `ShutdownBarrierWaitResult::aggregate()`.
+    ///
+    /// The order in which values are folded is unspecified, so reducer
+    /// should be associative and commutative (a sum, or a maximum).  It runs
+    /// in the thread of whichever `done_with()` call (or `done()` call that
+    /// ends a generation) finds it has work to do, one call at a time.
+    pub fn reduce_with<A, F>(self, reducer: F) -> ShutdownBarrier<R, A>
+    where
+        F: Fn(A, A) -> A + Send + Sync + RefUnwindSafe + 'static,
+    {
+        let ShutdownBarrier {
+            state,
+            auto_rearm,
+            reasons,
+            cancels_in_flight,
+            waiters,
+            progress,
+            on_shutdown,
+            live,
+            first_spawn,
+            summary,
+            created,
+            aggregator: _,
+            _marker: _,
+        } = self;
+        ShutdownBarrier {
+            state,
+            auto_rearm,
+            reasons,
+            cancels_in_flight,
+            waiters,
+            progress,
+            on_shutdown,
+            live,
+            first_spawn,
+            summary,
+            created,
+            aggregator: Aggregator::new(Some(Box::new(reducer))),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl ShutdownBarrier {
+    /// Returns a new shutdown barrier with a single worker.  The caller
+    /// should spawn() all the work that needs to be done, then invoke
+    /// done().  This makes sure the worker count doesn't spuriously
+    /// reach zero while work is being spawned.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns a new shutdown barrier that re-arms itself each time the
+    /// worker count reaches zero.  The new generation starts with a single
+    /// worker, so the parent should call `done()` once per round, after it
+    /// has spawned that round's work.
+    pub fn with_auto_rearm() -> Self {
+        Self::with_reasons_and_auto_rearm()
+    }
+}
+
+/// A subscription to a barrier's worker count, returned by
+/// `ShutdownBarrier::subscribe()`.  Like a `watch` channel, it only reports
+/// the latest count, so a slow subscriber skips intermediate values.
+pub struct ShutdownBarrierProgress<'a, R = (), A = ()> {
+    barrier: &'a ShutdownBarrier<R, A>,
+    /// The generation and count last returned.
+    seen: (u32, u32),
+}
+
+impl<R, A> ShutdownBarrierProgress<'_, R, A> {
+    /// Returns the worker count this subscription last saw.
+    pub fn remaining_workers(&self) -> u32 {
+        self.seen.1
+    }
+
+    /// Waits until the worker count differs from the one last seen (or the
+    /// barrier is re-armed), and returns the new count.  Once a barrier has
+    /// shut down its count stops changing, so callers should stop waiting
+    /// when this returns zero.
+    pub async fn changed(&mut self) -> u32 {
+        let barrier = self.barrier;
+        let seen = self.seen;
+        self.seen = barrier
+            .progress
+            .wait_for(|| Self::poll_changed(barrier, seen))
+            .await;
+        self.seen.1
+    }
+
+    /// Like `changed()`, but blocks the calling thread.
+    pub fn changed_blocking(&mut self) -> u32 {
+        let barrier = self.barrier;
+        let seen = self.seen;
+        self.seen = barrier
+            .progress
+            .wait_blocking_for(|| Self::poll_changed(barrier, seen), None)
+            .expect("waits without a deadline can't time out");
+        self.seen.1
+    }
+
+    fn poll_changed(barrier: &ShutdownBarrier<R, A>, seen: (u32, u32)) -> Option<(u32, u32)> {
+        let s = barrier.load();
+        let now = (s.generation(), s.count());
+        (now != seen).then_some(now)
+    }
+}
+
+/// The spawning half of a barrier, returned by `ShutdownBarrier::split()`.
+pub struct ShutdownBarrierSpawner<R = (), A = ()> {
+    barrier: Arc<ShutdownBarrier<R, A>>,
+}
+
+impl<R, A> Clone for ShutdownBarrierSpawner<R, A> {
+    fn clone(&self) -> Self {
+        Self {
+            barrier: self.barrier.clone(),
+        }
+    }
+}
+
+impl<R, A> ShutdownBarrierSpawner<R, A> {
+    /// See `ShutdownBarrier::spawn()`.
+    pub fn spawn(&self) -> Result<(), ShutdownBarrierError> {
+        self.barrier.spawn()
+    }
+
+    /// See `ShutdownBarrier::spawn_guard()`.
+    pub fn spawn_guard(&self) -> Result<WorkerGuard<'_, R, A>, ShutdownBarrierError> {
+        self.barrier.spawn_guard()
+    }
+
+    /// See `ShutdownBarrier::spawn_n()`.
+    pub fn spawn_n(&self, n: u32) -> Result<(), ShutdownBarrierError> {
+        self.barrier.spawn_n(n)
+    }
+
+    /// See `ShutdownBarrier::done()`.
+    pub fn done(&self) -> Result<ShutdownBarrierDoneResult, ShutdownBarrierError> {
+        self.barrier.done()
+    }
+
+    /// See `ShutdownBarrier::done_with_error()`.
+    pub fn done_with_error(
+        &self,
+        error: R,
+    ) -> Result<ShutdownBarrierDoneResult, ShutdownBarrierError> {
+        self.barrier.done_with_error(error)
+    }
+
+    /// See `ShutdownBarrier::done_with()`.
+    pub fn done_with(&self, value: A) -> Result<ShutdownBarrierDoneResult, ShutdownBarrierError> {
+        self.barrier.done_with(value)
+    }
+
+    /// See `ShutdownBarrier::done_many()`.
+    pub fn done_many(&self, n: u32) -> Result<ShutdownBarrierDoneResult, ShutdownBarrierError> {
+        self.barrier.done_many(n)
+    }
+
+    /// Registers a worker that runs fut, and returns a future that can be
+    /// handed to any executor.  The worker is done when the future completes
+    /// or is dropped, and cancels the barrier first if it panics, like
+    /// `spawn_guard()`.
+    ///
+    /// The worker is registered before this returns, so a waiter can't see
+    /// the barrier shut down before the task starts.
+    pub fn spawn_future<F>(
+        &self,
+        fut: F,
+    ) -> Result<impl Future<Output = F::Output> + 'static, ShutdownBarrierError>
+    where
+        F: Future + 'static,
+        R: 'static,
+        A: 'static,
+    {
+        self.spawn()?;
+        let guard = OwnedWorkerGuard {
+            barrier: self.barrier.clone(),
+        };
+        Ok(async move {
+            let _guard = guard;
+            fut.await
+        })
+    }
+
+    /// Runs fut as a tokio task registered with the barrier.  See
+    /// `spawn_future()`.
+    ///
+    /// Must be called from within a tokio runtime.
+    #[cfg(feature = "tokio-rt")]
+    pub fn spawn_tokio<F>(
+        &self,
+        fut: F,
+    ) -> Result<tokio::task::JoinHandle<F::Output>, ShutdownBarrierError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+        R: Send + Sync + 'static,
+        A: Send + Sync + 'static,
+    {
+        Ok(tokio::spawn(self.spawn_future(fut)?))
+    }
+
+    /// Runs fut as an async-std task registered with the barrier.  See
+    /// `spawn_future()`.
+    #[cfg(feature = "async-std-rt")]
+    pub fn spawn_async_std<F>(
+        &self,
+        fut: F,
+    ) -> Result<async_std::task::JoinHandle<F::Output>, ShutdownBarrierError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+        R: Send + Sync + 'static,
+        A: Send + Sync + 'static,
+    {
+        Ok(async_std::task::spawn(self.spawn_future(fut)?))
+    }
+
+    /// Runs fut as a task on smol's global executor, registered with the
+    /// barrier.  See `spawn_future()`.
+    ///
+    /// As with `smol::spawn`, dropping the returned task cancels it, which
+    /// counts as the worker being done; call `detach()` to let it run.
+    #[cfg(feature = "smol-rt")]
+    pub fn spawn_smol<F>(&self, fut: F) -> Result<smol::Task<F::Output>, ShutdownBarrierError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+        R: Send + Sync + 'static,
+        A: Send + Sync + 'static,
+    {
+        Ok(smol::spawn(self.spawn_future(fut)?))
+    }
+}
+
+/// The waiting half of a barrier, returned by `ShutdownBarrier::split()`.
+/// Besides waiting and cancelling, it can inspect the barrier's state.
+pub struct ShutdownBarrierWaiter<R = (), A = ()> {
+    barrier: Arc<ShutdownBarrier<R, A>>,
+}
+
+impl<R, A> Clone for ShutdownBarrierWaiter<R, A> {
+    fn clone(&self) -> Self {
+        Self {
+            barrier: self.barrier.clone(),
+        }
+    }
+}
+
+impl<R, A> ShutdownBarrierWaiter<R, A> {
+    /// See `ShutdownBarrier::wait()`.
+    pub fn wait(
+        &self,
+    ) -> impl Future<Output = Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError>> + '_
+    {
+        self.barrier.wait()
+    }
+
+    /// See `ShutdownBarrier::wait_blocking()`.
+    pub fn wait_blocking(&self) -> Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError> {
+        self.barrier.wait_blocking()
+    }
+
+    /// See `ShutdownBarrier::wait_timeout()`.
+    #[cfg(feature = "tokio-rt")]
+    pub async fn wait_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError> {
+        self.barrier.wait_timeout(timeout).await
+    }
+
+    /// See `ShutdownBarrier::wait_blocking_timeout()`.
+    pub fn wait_blocking_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError> {
+        self.barrier.wait_blocking_timeout(timeout)
+    }
+
+    /// See `ShutdownBarrier::wait_for_idle()`.
+    pub fn wait_for_idle(&self) -> impl Future<Output = u32> + '_ {
+        self.barrier.wait_for_idle()
+    }
+
+    /// See `ShutdownBarrier::cancel()`.
+    pub fn cancel(&self) -> Result<(), ShutdownBarrierError> {
+        self.barrier.cancel()
+    }
+
+    /// See `ShutdownBarrier::cancel_with()`.
+    pub fn cancel_with(&self, reason: R) -> Result<(), ShutdownBarrierError> {
+        self.barrier.cancel_with(reason)
+    }
+
+    /// See `ShutdownBarrier::remaining_workers()`.
+    pub fn remaining_workers(&self) -> u32 {
+        self.barrier.remaining_workers()
+    }
+
+    /// See `ShutdownBarrier::is_cancelled()`.
+    pub fn is_cancelled(&self) -> bool {
+        self.barrier.is_cancelled()
+    }
+
+    /// See `ShutdownBarrier::is_shutdown()`.
+    pub fn is_shutdown(&self) -> bool {
+        self.barrier.is_shutdown()
+    }
+
+    /// See `ShutdownBarrier::subscribe()`.
+    pub fn subscribe(&self) -> ShutdownBarrierProgress<'_, R, A> {
+        self.barrier.subscribe()
+    }
+}
+
+/// A worker registered by `ShutdownBarrier::spawn_guard()`.
+#[must_use]
+pub struct WorkerGuard<'a, R = (), A = ()> {
+    barrier: &'a ShutdownBarrier<R, A>,
+}
+
+impl<R, A> WorkerGuard<'_, R, A> {
+    /// Calls `done()` now, instead of when the guard is dropped, and returns
+    /// its result.
+    pub fn done(self) -> Result<ShutdownBarrierDoneResult, ShutdownBarrierError> {
+        let barrier = self.barrier;
+        std::mem::forget(self);
+        barrier.done()
+    }
+}
+
+impl<R, A> Drop for WorkerGuard<'_, R, A> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            // Fails if another worker cancelled first, which is fine.
+            let _ = self.barrier.cancel();
+        }
+        // The guard's registration is still outstanding, so this can't fail.
+        let _ = self.barrier.done();
+    }
+}
+
+/// The worker registered by `ShutdownBarrierSpawner::spawn_future()`, which
+/// has to outlive the spawner it came from.
+struct OwnedWorkerGuard<R, A> {
+    barrier: Arc<ShutdownBarrier<R, A>>,
+}
+
+impl<R, A> Drop for OwnedWorkerGuard<R, A> {
+    fn drop(&mut self) {
+        drop(WorkerGuard {
+            barrier: &self.barrier,
+        });
+    }
+}
+
+/// A `ShutdownBarrier` for code that isn't async: `wait()` blocks the calling
+/// thread instead of returning a future.
+///
+/// This wraps the same state machine as `ShutdownBarrier`, and `barrier()`
+/// exposes it, so async tasks can wait alongside blocked threads.
+pub struct ShutdownBarrierSync<R = (), A = ()> {
+    inner: ShutdownBarrier<R, A>,
+}
+
+impl<R, A> Default for ShutdownBarrierSync<R, A> {
+    fn default() -> Self {
+        Self {
+            inner: Default::default(),
+        }
+    }
+}
+
+impl ShutdownBarrierSync {
+    /// Returns a new shutdown barrier with a single worker.  See
+    /// `ShutdownBarrier::new()`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl<R> ShutdownBarrierSync<R> {
+    /// See `ShutdownBarrier::reduce_with()`.
+    pub fn reduce_with<A, F>(self, reducer: F) -> ShutdownBarrierSync<R, A>
+    where
+        F: Fn(A, A) -> A + Send + Sync + RefUnwindSafe + 'static,
+    {
+        ShutdownBarrierSync {
+            inner: self.inner.reduce_with(reducer),
+        }
+    }
+}
+
+impl<R, A> ShutdownBarrierSync<R, A> {
+    /// See `ShutdownBarrier::on_shutdown()`.
+    pub fn on_shutdown<F>(self, on_shutdown: F) -> Self
+    where
+        F: Fn(bool) + Send + Sync + RefUnwindSafe + 'static,
+    {
+        Self {
+            inner: self.inner.on_shutdown(on_shutdown),
+        }
+    }
+
+    /// See `ShutdownBarrier::spawn()`.
+    pub fn spawn(&self) -> Result<(), ShutdownBarrierError> {
+        self.inner.spawn()
+    }
+
+    /// See `ShutdownBarrier::spawn_guard()`.
+    pub fn spawn_guard(&self) -> Result<WorkerGuard<'_, R, A>, ShutdownBarrierError> {
+        self.inner.spawn_guard()
+    }
+
+    /// See `ShutdownBarrier::spawn_n()`.
+    pub fn spawn_n(&self, n: u32) -> Result<(), ShutdownBarrierError> {
+        self.inner.spawn_n(n)
+    }
+
+    /// See `ShutdownBarrier::cancel()`.
+    pub fn cancel(&self) -> Result<(), ShutdownBarrierError> {
+        self.inner.cancel()
+    }
+
+    /// See `ShutdownBarrier::cancel_with()`.
+    pub fn cancel_with(&self, reason: R) -> Result<(), ShutdownBarrierError> {
+        self.inner.cancel_with(reason)
+    }
+
+    /// See `ShutdownBarrier::done()`.
+    pub fn done(&self) -> Result<ShutdownBarrierDoneResult, ShutdownBarrierError> {
+        self.inner.done()
+    }
+
+    /// See `ShutdownBarrier::done_with_error()`.
+    pub fn done_with_error(
+        &self,
+        error: R,
+    ) -> Result<ShutdownBarrierDoneResult, ShutdownBarrierError> {
+        self.inner.done_with_error(error)
+    }
+
+    /// See `ShutdownBarrier::done_with()`.
+    pub fn done_with(&self, value: A) -> Result<ShutdownBarrierDoneResult, ShutdownBarrierError> {
+        self.inner.done_with(value)
+    }
+
+    /// See `ShutdownBarrier::done_many()`.
+    pub fn done_many(&self, n: u32) -> Result<ShutdownBarrierDoneResult, ShutdownBarrierError> {
+        self.inner.done_many(n)
+    }
+
+    /// Blocks until the number of workers reaches zero.  This can be called
+    /// at any time and can be called multiple times.
+    pub fn wait(&self) -> Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError> {
+        self.inner.wait_blocking()
+    }
+
+    /// Like `wait()`, but gives up and returns `Timeout` if the barrier has
+    /// not shut down within timeout.
+    pub fn wait_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError> {
+        self.inner.wait_blocking_timeout(timeout)
+    }
+
+    /// Like `wait_timeout()`, but takes a deadline.
+    pub fn wait_until(
+        &self,
+        deadline: Instant,
+    ) -> Result<ShutdownBarrierWaitResult<R, A>, ShutdownBarrierError> {
+        self.inner.wait_blocking_until(deadline)
+    }
+
+    /// See `ShutdownBarrier::reset()`.
+    pub fn reset(&self) -> Result<(), ShutdownBarrierError> {
+        self.inner.reset()
+    }
+
+    /// See `ShutdownBarrier::generation()`.
+    pub fn generation(&self) -> u32 {
+        self.inner.generation()
+    }
+
+    /// See `ShutdownBarrier::remaining_workers()`.
+    pub fn remaining_workers(&self) -> u32 {
+        self.inner.remaining_workers()
+    }
+
+    /// See `ShutdownBarrier::is_cancelled()`.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.is_cancelled()
+    }
+
+    /// See `ShutdownBarrier::is_shutdown()`.
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.is_shutdown()
+    }
+
+    /// See `ShutdownBarrier::subscribe()`.
+    pub fn subscribe(&self) -> ShutdownBarrierProgress<'_, R, A> {
+        self.inner.subscribe()
+    }
+
+    /// Returns the underlying barrier, for async waiters.
+    pub fn barrier(&self) -> &ShutdownBarrier<R, A> {
+        &self.inner
+    }
+}
+
+impl<R, A> From<ShutdownBarrier<R, A>> for ShutdownBarrierSync<R, A> {
+    fn from(inner: ShutdownBarrier<R, A>) -> Self {
+        Self { inner }
+    }
+}
+
+/// The result of arriving at a `PhasedBarrier`.
+pub struct PhasedBarrierWaitResult {
+    phase: u16,
+    leader: bool,
+}
+
+impl PhasedBarrierWaitResult {
+    /// The phase the caller arrived at.  Phases are numbered from zero, and
+    /// wrap at 2^16.
+    pub fn phase(&self) -> u16 {
+        self.phase
+    }
+
+    /// True for exactly one party per phase: the one whose arrival (or
+    /// departure) completed it.
+    pub fn is_leader(&self) -> bool {
+        self.leader
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PhasedBarrierError {
+    /// Returned when arriving at (or leaving) a barrier with no registered
+    /// parties.
+    NotRegistered,
+}
+
+impl Error for PhasedBarrierError {}
+
+impl Display for PhasedBarrierError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// A multi-phase barrier, like `std::sync::Barrier`, but parties can
+/// `register()` and leave (`arrive_and_deregister()`) between or during
+/// phases.  A phase completes when every registered party has arrived, and
+/// the next phase starts immediately, so stage-synchronized algorithms can
+/// loop on `arrive_and_wait()`.
+///
+/// Like `ShutdownBarrier`, all of the state is packed into a single word, and
+/// waiters are woken through a lock-free list of wakers, so async tasks and
+/// blocked threads can be parties of the same barrier.
+pub struct PhasedBarrier {
+    state: Atom<PhaseState, u64>,
+    waiters: WakerList,
+}
+
+/// The packed `PhasedBarrier` state: 24 bits each of registered and arrived
+/// parties, then a (wrapping) 16 bit phase number.
+#[derive(Clone, Copy, Default)]
+struct PhaseState {
+    val: u64,
+}
+
+impl PhaseState {
+    const MAX_PARTIES: u32 = (1 << 24) - 1;
+    const ARRIVED_SHIFT: u32 = 24;
+    const PHASE_SHIFT: u32 = 48;
+
+    fn parties(&self) -> u32 {
+        (self.val & u64::from(Self::MAX_PARTIES)) as u32
+    }
+    fn set_parties(&mut self, parties: u32) {
+        assert!(parties <= Self::MAX_PARTIES, "too many parties");
+        self.val = (self.val & !u64::from(Self::MAX_PARTIES)) | u64::from(parties);
+    }
+    fn arrived(&self) -> u32 {
+        ((self.val >> Self::ARRIVED_SHIFT) & u64::from(Self::MAX_PARTIES)) as u32
+    }
+    fn set_arrived(&mut self, arrived: u32) {
+        let mask = u64::from(Self::MAX_PARTIES) << Self::ARRIVED_SHIFT;
+        self.val = (self.val & !mask) | (u64::from(arrived) << Self::ARRIVED_SHIFT);
+    }
+    fn phase(&self) -> u16 {
+        (self.val >> Self::PHASE_SHIFT) as u16
+    }
+    /// Starts the next phase if every party has arrived.  Returns true if it
+    /// did.
+    fn try_advance(&mut self) -> bool {
+        let parties = self.parties();
+        if parties == 0 || self.arrived() != parties {
+            return false;
+        }
+        self.val =
+            (u64::from(self.phase().wrapping_add(1)) << Self::PHASE_SHIFT) | u64::from(parties);
+        true
+    }
+}
+
+impl PhasedBarrier {
+    /// Returns a new barrier with the given number of parties, in phase zero.
+    pub fn new(parties: u32) -> Self {
+        let this = Self {
+            state: Default::default(),
+            waiters: Default::default(),
+        };
+        unsafe {
+            atomic_try_update(&this.state, |s| {
+                s.set_parties(parties);
+                (true, ())
+            });
+        }
+        this
+    }
+
+    /// Adds a party to the barrier.  The current phase won't complete until
+    /// the new party arrives.  Returns the current phase.
+    pub fn register(&self) -> u16 {
+        unsafe {
+            atomic_try_update(&self.state, |s| {
+                s.set_parties(s.parties() + 1);
+                (true, s.phase())
+            })
+        }
+    }
+
+    /// Arrives at the current phase without waiting for it to complete.
+    pub fn arrive(&self) -> Result<PhasedBarrierWaitResult, PhasedBarrierError> {
+        let result = unsafe {
+            atomic_try_update(&self.state, |s| {
+                if s.parties() == 0 {
+                    return (false, None);
+                }
+                let phase = s.phase();
+                s.set_arrived(s.arrived() + 1);
+                let leader = s.try_advance();
+                (true, Some(PhasedBarrierWaitResult { phase, leader }))
+            })
+        };
+        self.finish(result)
+    }
+
+    /// Leaves the barrier.  If every remaining party has already arrived,
+    /// this completes the current phase.
+    pub fn arrive_and_deregister(&self) -> Result<PhasedBarrierWaitResult, PhasedBarrierError> {
+        let result = unsafe {
+            atomic_try_update(&self.state, |s| {
+                if s.parties() == 0 {
+                    return (false, None);
+                }
+                let phase = s.phase();
+                s.set_parties(s.parties() - 1);
+                let leader = s.try_advance();
+                (true, Some(PhasedBarrierWaitResult { phase, leader }))
+            })
+        };
+        self.finish(result)
+    }
+
+    /// Arrives at the current phase, and then waits for it to complete.  The
+    /// arrival happens when this is called, not when the returned future is
+    /// first polled.
+    pub fn arrive_and_wait(
+        &self,
+    ) -> impl Future<Output = Result<PhasedBarrierWaitResult, PhasedBarrierError>> + '_ {
+        let arrived = self.arrive();
+        async move {
+            let arrived = arrived?;
+            self.waiters
+                .wait_for(|| self.phase_completed(arrived.phase))
+                .await;
+            Ok(arrived)
+        }
+    }
+
+    /// Like `arrive_and_wait()`, but blocks the calling thread.
+    pub fn arrive_and_wait_blocking(&self) -> Result<PhasedBarrierWaitResult, PhasedBarrierError> {
+        let arrived = self.arrive()?;
+        self.waiters
+            .wait_blocking_for(|| self.phase_completed(arrived.phase), None)
+            .expect("waits without a deadline can't time out");
+        Ok(arrived)
+    }
+
+    /// Returns the current phase.
+    pub fn phase(&self) -> u16 {
+        self.load().phase()
+    }
+
+    /// Returns the number of registered parties.
+    pub fn parties(&self) -> u32 {
+        self.load().parties()
+    }
+
+    /// Returns the number of parties that have arrived at the current phase.
+    pub fn arrived(&self) -> u32 {
+        self.load().arrived()
+    }
+
+    fn load(&self) -> PhaseState {
+        unsafe { atomic_try_update(&self.state, |s| (false, *s)) }
+    }
+
+    fn phase_completed(&self, phase: u16) -> Option<()> {
+        (self.phase() != phase).then_some(())
+    }
+
+    fn finish(
+        &self,
+        result: Option<PhasedBarrierWaitResult>,
+    ) -> Result<PhasedBarrierWaitResult, PhasedBarrierError> {
+        let result = result.ok_or(PhasedBarrierError::NotRegistered)?;
+        if result.leader {
+            self.waiters.wake_all();
+        }
+        Ok(result)
+    }
+}
+
+/// Waits for exactly n events, such as n services reporting that they have
+/// started.  Unlike `ShutdownBarrier`, the count is fixed up front, so there
+/// is no parent worker to account for.
+pub struct CountdownLatch {
+    count: Atom<u64, u64>,
+    waiters: WakerList,
+}
+
+impl CountdownLatch {
+    /// Returns a latch that opens after n calls to `count_down()`.  A latch
+    /// created with n = 0 is already open.
+    pub fn new(n: u64) -> Self {
+        let this = Self {
+            count: Default::default(),
+            waiters: Default::default(),
+        };
+        unsafe {
+            atomic_try_update(&this.count, |count| {
+                *count = n;
+                (true, ())
+            });
+        }
+        this
+    }
+
+    /// Records one event.  Returns true if this call opened the latch.  Calls
+    /// after the latch has opened have no effect.
+    pub fn count_down(&self) -> bool {
+        let opened = unsafe {
+            atomic_try_update(&self.count, |count| match *count {
+                0 => (false, false),
+                n => {
+                    *count = n - 1;
+                    (true, n == 1)
+                }
+            })
+        };
+        if opened {
+            self.waiters.wake_all();
+        }
+        opened
+    }
+
+    /// Returns the number of events the latch is still waiting for.
+    pub fn count(&self) -> u64 {
+        unsafe { atomic_try_update(&self.count, |count| (false, *count)) }
+    }
+
+    /// Waits until the latch opens.  Returns immediately if it already has.
+    pub async fn wait(&self) {
+        self.waiters.wait_for(|| self.poll_open()).await
+    }
+
+    /// Like `wait()`, but blocks the calling thread.
+    pub fn wait_blocking(&self) {
+        self.waiters
+            .wait_blocking_for(|| self.poll_open(), None)
+            .expect("waits without a deadline can't time out")
+    }
+
+    fn poll_open(&self) -> Option<()> {
+        (self.count() == 0).then_some(())
     }
 }
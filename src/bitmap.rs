@@ -0,0 +1,176 @@
+//! A fixed-size slot allocator that keeps its entire state in one bitmap.
+//!
+//! `BitmapAllocator<N>` hands out the indexes `0..N` (for N up to 128), such
+//! as DMA channels or connection slots, where the resource itself lives in
+//! an array owned by the caller.  Each bit of an `Atom` says whether the
+//! corresponding slot is in use.  Allocating finds the lowest clear bit and
+//! sets it, and freeing clears it, each in a single CAS.  Since the lambda
+//! reads nothing but the bitmap, there is no ABA problem to worry about, and
+//! a snapshot of the bitmap is a consistent view of every slot at once.
+//!
+//! Allocators with up to 64 slots keep the bitmap in a u64, and are lock
+//! free.  Larger ones need a 128 bit word, so on stable rust, `AtomicCell`
+//! implements them with a lock, like every other `Atom<_, u128>`.
+
+use std::{error::Error, fmt::Display};
+
+use crate::{atomic_try_update, Atom};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BitmapAllocatorError {
+    /// The slot index is not less than the allocator's capacity.
+    OutOfRange,
+    /// The slot is not allocated, so it can't be freed.
+    NotAllocated,
+}
+
+impl Error for BitmapAllocatorError {}
+
+impl Display for BitmapAllocatorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// Allocates the slots `0..N`.  See the module documentation.
+pub struct BitmapAllocator<const N: usize> {
+    /// The bitmap, if N is at most 64.
+    small: Atom<u64, u64>,
+    /// The bitmap, if N is larger.
+    large: Atom<u128, u128>,
+}
+
+impl<const N: usize> Default for BitmapAllocator<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> BitmapAllocator<N> {
+    /// Bits that correspond to a slot.
+    const MASK: u128 = if N == 128 { u128::MAX } else { (1 << N) - 1 };
+
+    /// Returns an allocator with every slot free.
+    pub const fn new() -> Self {
+        assert!(N <= 128, "BitmapAllocator supports at most 128 slots");
+        Self {
+            small: Atom::new(),
+            large: Atom::new(),
+        }
+    }
+
+    /// Allocates the lowest free slot, or returns None if they are all in
+    /// use.
+    pub fn alloc(&self) -> Option<usize> {
+        self.update(|bits| {
+            let free = !*bits & Self::MASK;
+            if free == 0 {
+                return (false, None);
+            }
+            let slot = free.trailing_zeros();
+            *bits |= 1 << slot;
+            (true, Some(slot as usize))
+        })
+    }
+
+    /// Allocates slot, if it is free.  Returns false if it is already in use.
+    pub fn try_alloc_at(&self, slot: usize) -> Result<bool, BitmapAllocatorError> {
+        let bit = Self::bit(slot)?;
+        Ok(self.update(|bits| {
+            let free = *bits & bit == 0;
+            *bits |= bit;
+            (free, free)
+        }))
+    }
+
+    /// Returns slot to the pool.
+    pub fn free(&self, slot: usize) -> Result<(), BitmapAllocatorError> {
+        let bit = Self::bit(slot)?;
+        let allocated = self.update(|bits| {
+            let allocated = *bits & bit != 0;
+            *bits &= !bit;
+            (allocated, allocated)
+        });
+        if allocated {
+            Ok(())
+        } else {
+            Err(BitmapAllocatorError::NotAllocated)
+        }
+    }
+
+    pub fn is_allocated(&self, slot: usize) -> Result<bool, BitmapAllocatorError> {
+        let bit = Self::bit(slot)?;
+        Ok(self.load() & bit != 0)
+    }
+
+    /// The number of slots in use.
+    pub fn allocated(&self) -> usize {
+        self.load().count_ones() as usize
+    }
+
+    /// The total number of slots.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Returns the slots that are in use, in increasing order.  This is a
+    /// snapshot: slots allocated or freed after the call are not reflected.
+    pub fn iter_allocated(&self) -> AllocatedSlots {
+        AllocatedSlots { bits: self.load() }
+    }
+
+    fn bit(slot: usize) -> Result<u128, BitmapAllocatorError> {
+        if slot < N {
+            Ok(1 << slot)
+        } else {
+            Err(BitmapAllocatorError::OutOfRange)
+        }
+    }
+
+    /// Runs f on whichever word holds the bitmap, like `atomic_try_update`.
+    /// The u64 is widened for f, and only its low N bits are ever set.
+    fn update<R>(&self, f: impl Fn(&mut u128) -> (bool, R)) -> R {
+        unsafe {
+            if N <= 64 {
+                atomic_try_update(&self.small, |bits| {
+                    let mut wide = u128::from(*bits);
+                    let ret = f(&mut wide);
+                    *bits = wide as u64;
+                    ret
+                })
+            } else {
+                atomic_try_update(&self.large, f)
+            }
+        }
+    }
+
+    fn load(&self) -> u128 {
+        self.update(|bits| (false, *bits))
+    }
+}
+
+/// An iterator over the slots that were allocated when
+/// `BitmapAllocator::iter_allocated()` was called.
+pub struct AllocatedSlots {
+    bits: u128,
+}
+
+impl Iterator for AllocatedSlots {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.bits == 0 {
+            return None;
+        }
+        let slot = self.bits.trailing_zeros();
+        self.bits &= self.bits - 1;
+        Some(slot as usize)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.bits.count_ones() as usize;
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for AllocatedSlots {}
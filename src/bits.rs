@@ -60,6 +60,9 @@ pub struct FlagU64 {
 }
 
 impl FlagU64 {
+    /// The largest value that fits alongside the flag.
+    pub const MAX_VAL: u64 = u64::MAX >> 1;
+
     pub fn get_val(&self) -> u64 {
         self.val >> 1
     }
@@ -106,7 +109,7 @@ impl FlagU32 {
 /// TODO: Use Deref or something to make this transparently act
 /// like a T?
 #[repr(align(8))]
-pub struct Align8<T> {
+pub struct Align8<T: ?Sized> {
     pub inner: T,
 }
 
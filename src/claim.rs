@@ -3,101 +3,2149 @@
 //! and ensures that exactly one worker running if there is work
 //! to be done.
 //!
-//! TODO: The example claim queue is strange, since it combines
-//! a counter with the claim queue logic.  This is a decent example
-//! of composing semi-related algorithms with atomic_try_update,
-//! but it is unclear whether the example is general-purpose enough
-//! to be included here.
+//! `Claim` is the pattern on its own.  `ClaimQueue` is the plain version of
+//! the pattern applied to a queue, and `ClaimExecutor` uses it to run
+//! closures one at a time, on whichever thread has the claim.
+//! `ClaimQueue` can also hand the claim off to the next pusher, so that a
+//! busy queue does not monopolize the thread that happened to claim it.
+//! `ShardedClaimQueue` spreads the queue over several head words, and
+//! `PriorityClaimQueue` uses it to let urgent values jump the queue.
+//!
+//! `WriteOrderingQueue` combines the pattern with a counter, which is a
+//! decent example of composing semi-related algorithms with
+//! atomic_try_update.  It assigns each pushed value an offset (for
+//! instance, in a write-ahead log), and lets pushers that did not get the
+//! claim wait for the claim holder to process their values.  `GroupCommit`
+//! packages that up as a group-commit log writer.
+
+use std::{
+    cell::UnsafeCell,
+    collections::VecDeque,
+    error::Error,
+    fmt::{Debug, Display},
+    future::Future,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "replay")]
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+#[cfg(feature = "tokio-rt")]
+use tokio::sync::Notify;
+
+use super::{
+    atomic_try_update,
+    bits::FlagPtr,
+    stack::{thread_hint, Stack},
+    waker_list::WakerList,
+    Atom, Node, NodeIterator,
+};
+
+/// Set in the flag bits of `ClaimHead::next` while some worker has the claim.
+const CLAIMED: usize = 0b001;
+/// Set (along with `CLAIMED`) when the claim holder gave up the claim while
+/// the queue was non-empty.  The next pusher inherits the claim.
+const HANDOFF: usize = 0b010;
+/// Set once `close()` has been called.  Independent of the other bits.
+const CLOSED: usize = 0b100;
+
+/// What a claim holder is left with after `hand_off_claim` or
+/// `consume_with_budget`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClaimStatus {
+    /// The queue was empty, and the claim was released.
+    Released,
+    /// The queue was non-empty.  The claim will pass to the next thread
+    /// that pushes (or calls `try_take_handoff`).
+    HandedOff,
+}
+
+/// Limits on how long `ClaimQueue::consume_with_budget` keeps the claim.
+#[derive(Clone, Copy, Debug)]
+pub struct ClaimBudget {
+    /// Number of batches to consume before handing off the claim.
+    pub max_batches: usize,
+    /// Hand off the claim once this much time has passed (checked between
+    /// batches).
+    pub max_time: Option<Duration>,
+}
+
+impl Default for ClaimBudget {
+    /// No limits; the claim is only given up when the queue is empty.
+    fn default() -> Self {
+        Self {
+            max_batches: usize::MAX,
+            max_time: None,
+        }
+    }
+}
+
+/// Set in a `Claim` when some thread failed to get the claim while it was
+/// held, so the holder must look for more work before releasing it.
+const PENDING: usize = 0b010;
+
+#[derive(Default)]
+struct ClaimWord {
+    flags: usize,
+}
+
+/// The claim pattern on its own, for protecting a single-consumer resource
+/// that is not a queue (for instance, a flush of some shared buffer).
+///
+/// Producers make their work visible (in whatever way the resource
+/// requires), and then call `try_claim`.  Exactly one of them gets the
+/// claim, and is responsible for processing the work.  The others return
+/// immediately, but leave a note for the holder, so its `release` fails and
+/// it takes another pass instead of stranding their work.
+#[derive(Default)]
+pub struct Claim {
+    word: Atom<ClaimWord, u64>,
+}
+
+impl Claim {
+    /// Returns true iff we obtained the claim.  Otherwise, the current holder
+    /// will see that more work appeared when it calls `release`.
+    pub fn try_claim(&self) -> bool {
+        unsafe {
+            atomic_try_update(&self.word, |word| {
+                let have_claim = word.flags & CLAIMED == 0;
+                word.flags = if have_claim {
+                    CLAIMED
+                } else {
+                    CLAIMED | PENDING
+                };
+                (true, have_claim)
+            })
+        }
+    }
+
+    /// Gives up the claim, unless some other thread called `try_claim` since
+    /// we got it (or last called release).  Returns true if that happened, in
+    /// which case we still have the claim, and must process the new work
+    /// before calling release again.
+    ///
+    /// Panics if the claim is not held.
+    pub fn release(&self) -> bool {
+        let (had_claim, more_work) = unsafe {
+            atomic_try_update(&self.word, |word| {
+                let flags = word.flags;
+                word.flags = if flags & PENDING != 0 { CLAIMED } else { 0 };
+                (true, (flags & CLAIMED != 0, flags & PENDING != 0))
+            })
+        };
+        assert!(had_claim, "cannot call release unless you have the claim!");
+        more_work
+    }
+
+    pub fn is_claimed(&self) -> bool {
+        unsafe { atomic_try_update(&self.word, |word| (false, word.flags & CLAIMED != 0)) }
+    }
+
+    /// Like `try_claim`, but returns a guard that owns the claim.
+    pub fn try_guard(&self) -> Option<ClaimGuard<'_>> {
+        self.try_claim().then(|| ClaimGuard { claim: self })
+    }
+}
+
+/// Holds a `Claim`.  Use `release` to give it up; dropping the guard (for
+/// instance, while unwinding) releases the claim even if more work appeared,
+/// leaving that work for the next thread that calls `try_claim`.
+pub struct ClaimGuard<'a> {
+    claim: &'a Claim,
+}
+
+impl<'a> ClaimGuard<'a> {
+    /// Releases the claim, or returns the guard if more work appeared.
+    /// Callers typically loop:
+    ///
+    /// ```
+    /// # use atomic_try_update::claim::Claim;
+    /// fn process(claim: &Claim) {
+    ///     let Some(mut guard) = claim.try_guard() else {
+    ///         return; // The holder will process our work.
+    ///     };
+    ///     loop {
+    ///         // ... process the work ...
+    ///         match guard.release() {
+    ///             Some(still_held) => guard = still_held,
+    ///             None => break,
+    ///         }
+    ///     }
+    /// }
+    /// # process(&Claim::default());
+    /// ```
+    pub fn release(self) -> Option<ClaimGuard<'a>> {
+        let claim = self.claim;
+        std::mem::forget(self);
+        claim.release().then(|| ClaimGuard { claim })
+    }
+}
+
+impl Drop for ClaimGuard<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            atomic_try_update(&self.claim.word, |word| {
+                word.flags = 0;
+                (true, ())
+            })
+        }
+    }
+}
+
+struct ClaimHead<T> {
+    next: FlagPtr<Node<T>>,
+}
+
+/// A multi-producer queue that elects a single consumer.
+///
+/// Each push reports whether the pusher obtained the claim.  The claim is
+/// held as long as the queue is non-empty, so exactly one worker is
+/// responsible for draining it at any time, and no work is left behind when
+/// the claim is released.  The worker that has the claim must keep calling
+/// `consume_or_release_claim` until it returns false.
+///
+/// Under a steady stream of pushes, that could take forever.  A claim holder
+/// that wants to bound its work can instead call `hand_off_claim` (or use
+/// `consume_with_budget`), which passes the claim to the next pusher.  Until
+/// then, the queued values wait, so this should only be used if more pushes
+/// are guaranteed to arrive, or if some thread periodically calls
+/// `try_take_handoff`.
+pub struct ClaimQueue<T>
+where
+    T: Send,
+{
+    head: Atom<ClaimHead<T>, u64>,
+}
+
+impl<T> Default for ClaimQueue<T>
+where
+    T: Send,
+{
+    fn default() -> ClaimQueue<T> {
+        ClaimQueue::<T> {
+            head: Atom::default(),
+        }
+    }
+}
 
-use std::ptr::null_mut;
+impl<T> ClaimQueue<T>
+where
+    T: Send,
+{
+    /// Returns true iff we have the claim.  If we have the claim, we are
+    /// responsible for calling consume_or_release_claim until we manage to
+    /// release it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the queue has been closed.  Use `try_push` if producers may
+    /// race with `close()`.
+    pub fn push(&self, val: T) -> bool {
+        match self.try_push(val) {
+            Ok(have_claim) => have_claim,
+            Err(err) => panic!("ClaimQueue::push failed: {err}"),
+        }
+    }
+
+    /// Like push, but returns val in `ClaimError::Closed` if the queue has
+    /// been closed.
+    pub fn try_push(&self, val: T) -> Result<bool, ClaimError<T>> {
+        let node = Box::into_raw(Box::new(Node {
+            val,
+            next: std::ptr::null_mut(),
+        }));
+
+        let pushed = unsafe {
+            atomic_try_update(&self.head, |head: &mut ClaimHead<T>| {
+                let flag = head.next.get_flag();
+                if flag & CLOSED != 0 {
+                    return (false, None);
+                }
+                (*node).next = head.next.get_ptr();
+                head.next.set_ptr(node);
+                let have_claim = flag & CLAIMED == 0 || flag & HANDOFF != 0;
+                head.next.set_flag(CLAIMED);
+                (true, Some(have_claim))
+            })
+        };
+        pushed.ok_or_else(|| ClaimError::Closed(unsafe { Box::from_raw(node) }.val))
+    }
+
+    /// Prevents any further pushes.  Values that are already queued are
+    /// still consumed by the claim holder, so once the claim is released
+    /// after a close, the queue is empty for good.
+    ///
+    /// Returns true if this call closed the queue, and false if it was
+    /// already closed.
+    pub fn close(&self) -> bool {
+        unsafe {
+            atomic_try_update(&self.head, |head: &mut ClaimHead<T>| {
+                let flag = head.next.get_flag();
+                head.next.set_flag(flag | CLOSED);
+                (flag & CLOSED == 0, flag & CLOSED == 0)
+            })
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        unsafe {
+            atomic_try_update(&self.head, |head: &mut ClaimHead<T>| {
+                (false, head.next.get_flag() & CLOSED != 0)
+            })
+        }
+    }
+
+    /// Returns true if some thread currently has the claim.  Like
+    /// `is_empty`, this is a snapshot for health checks and debugging.
+    pub fn claimed(&self) -> bool {
+        unsafe {
+            atomic_try_update(&self.head, |head: &mut ClaimHead<T>| {
+                (false, head.next.get_flag() & CLAIMED != 0)
+            })
+        }
+    }
+
+    /// Returns true if no values are waiting to be consumed.  (Counting them
+    /// would require walking the list; see `WriteOrderingQueue::pending_len`
+    /// for a queue that tracks its size.)
+    pub fn is_empty(&self) -> bool {
+        unsafe {
+            atomic_try_update(&self.head, |head: &mut ClaimHead<T>| {
+                (false, head.next.get_ptr().is_null())
+            })
+        }
+    }
+
+    /// This removes everything from the queue.  If the queue is already
+    /// empty, it releases the claim and returns false.  See `ClaimBatch` for
+    /// the order in which the values are returned.
+    pub fn consume_or_release_claim(&self) -> (ClaimBatch<T>, bool) {
+        let (node, had_claim) = unsafe {
+            atomic_try_update(&self.head, |head: &mut ClaimHead<T>| {
+                let ret = head.next.get_ptr();
+                let flag = head.next.get_flag();
+                let had_claim = flag & (CLAIMED | HANDOFF) == CLAIMED;
+                head.next.set_ptr(null_mut());
+                if ret.is_null() {
+                    head.next.set_flag(flag & CLOSED);
+                }
+                (true, (ret, had_claim))
+            })
+        };
+        assert!(
+            had_claim,
+            "cannot call consume_or_release_claim unless you have the claim!"
+        );
+        (ClaimBatch::new(node), !node.is_null())
+    }
+
+    /// Runs the consume loop for a thread that has the claim: passes each
+    /// batch to f, and returns once the queue was found empty and the claim
+    /// was released.  Anything pushed while f was running is in a later
+    /// batch, so nothing is left behind.
+    ///
+    /// If f panics, the claim is not released, and the queue stops making
+    /// progress.  Use `ClaimExecutor` (or catch the panic in f) if that
+    /// matters.
+    pub fn consume_until_released<F>(&self, mut f: F)
+    where
+        F: FnMut(ClaimBatch<T>),
+    {
+        loop {
+            let (batch, claimed) = self.consume_or_release_claim();
+            if !claimed {
+                return;
+            }
+            f(batch);
+        }
+    }
+
+    /// Gives up the claim without consuming anything.  If the queue is
+    /// empty, this releases the claim.  Otherwise, the claim passes to the
+    /// next pusher, which becomes responsible for the values that are
+    /// already queued.
+    pub fn hand_off_claim(&self) -> ClaimStatus {
+        let (status, had_claim) = unsafe {
+            atomic_try_update(&self.head, |head: &mut ClaimHead<T>| {
+                let flag = head.next.get_flag();
+                let had_claim = flag & (CLAIMED | HANDOFF) == CLAIMED;
+                if head.next.get_ptr().is_null() {
+                    head.next.set_flag(flag & CLOSED);
+                    (true, (ClaimStatus::Released, had_claim))
+                } else {
+                    head.next.set_flag(flag | HANDOFF);
+                    (true, (ClaimStatus::HandedOff, had_claim))
+                }
+            })
+        };
+        assert!(
+            had_claim,
+            "cannot call hand_off_claim unless you have the claim!"
+        );
+        status
+    }
+
+    /// Takes the claim if it was handed off, and returns true if it did.
+    /// This lets a background thread pick up work that is stranded because
+    /// no pushes arrived after a handoff.
+    pub fn try_take_handoff(&self) -> bool {
+        unsafe {
+            atomic_try_update(&self.head, |head: &mut ClaimHead<T>| {
+                let flag = head.next.get_flag();
+                if flag & HANDOFF == 0 {
+                    return (false, false);
+                }
+                head.next.set_flag(flag & !HANDOFF);
+                (true, true)
+            })
+        }
+    }
+
+    /// Runs the consume loop for a thread that has the claim, passing each
+    /// batch to f.  Once budget is exhausted, the claim is handed off instead
+    /// of draining further batches.
+    pub fn consume_with_budget<F>(&self, budget: ClaimBudget, mut f: F) -> ClaimStatus
+    where
+        F: FnMut(ClaimBatch<T>),
+    {
+        let start = Instant::now();
+        let mut batches = 0;
+        loop {
+            let over_time = budget
+                .max_time
+                .is_some_and(|max_time| start.elapsed() >= max_time);
+            if batches >= budget.max_batches || over_time {
+                return self.hand_off_claim();
+            }
+            let (batch, claimed) = self.consume_or_release_claim();
+            if !claimed {
+                return ClaimStatus::Released;
+            }
+            f(batch);
+            batches += 1;
+        }
+    }
+}
+
+impl<T> Drop for ClaimQueue<T>
+where
+    T: Send,
+{
+    fn drop(&mut self) {
+        let node = unsafe {
+            atomic_try_update(&self.head, |head: &mut ClaimHead<T>| {
+                (false, head.next.get_ptr())
+            })
+        };
+        drop(NodeIterator::new(node));
+    }
+}
+
+/// The values removed by one call to `consume_or_release_claim`.
+///
+/// Values are yielded in FIFO order: the order in which the pushes that
+/// inserted them took effect (for `WriteOrderingQueue`, this is the order
+/// of their offsets).  Since each batch contains everything pushed since the
+/// previous one, consuming the batches in turn visits every value in push
+/// order, which makes the claim queue suitable for group commit.
+///
+/// The number of values in the batch is known up front, so this is an
+/// `ExactSizeIterator`.
+pub struct ClaimBatch<T> {
+    nodes: NodeIterator<T>,
+    len: usize,
+    /// If set, consumed nodes are returned to this pool instead of freed.
+    pool: Option<Arc<NodePool<T>>>,
+    /// The consumed nodes (only used with a pool), top and bottom, linked
+    /// through their free list links.
+    spent: (*mut Node<T>, *mut Node<T>),
+}
+
+unsafe impl<T: Send> Send for ClaimBatch<T> {}
+
+impl<T> ClaimBatch<T> {
+    /// Takes ownership of a list in LIFO (push) order, and reverses it.
+    fn new(node: *mut Node<T>) -> Self {
+        Self::from_lists(std::iter::once(node))
+    }
+
+    /// Like new, but concatenates several lists.  Each list is reversed in
+    /// place, and then linked after the previous one.
+    fn from_lists<I>(lists: I) -> Self
+    where
+        I: IntoIterator<Item = *mut Node<T>>,
+    {
+        let mut first = null_mut();
+        let mut tail: *mut Node<T> = null_mut();
+        let mut len = 0;
+        for mut node in lists {
+            let mut fifo = null_mut();
+            // The top of a LIFO list becomes the tail of the FIFO one.
+            let list_tail = node;
+            while !node.is_null() {
+                unsafe {
+                    let next = (*node).next;
+                    (*node).next = fifo;
+                    fifo = node;
+                    node = next;
+                }
+                len += 1;
+            }
+            if fifo.is_null() {
+                continue;
+            }
+            if tail.is_null() {
+                first = fifo;
+            } else {
+                unsafe { (*tail).next = fifo };
+            }
+            tail = list_tail;
+        }
+        Self {
+            nodes: NodeIterator::new(first),
+            len,
+            pool: None,
+            spent: (null_mut(), null_mut()),
+        }
+    }
+
+    /// Moves the value out of node, which must already be unlinked, and then
+    /// recycles or frees the node.
+    unsafe fn take_node(&mut self, node: *mut Node<T>) -> T {
+        let val = std::ptr::read(&(*node).val);
+        if self.pool.is_some() {
+            NodePool::free_link(node).store(self.spent.0, Ordering::Relaxed);
+            if self.spent.1.is_null() {
+                self.spent.1 = node;
+            }
+            self.spent.0 = node;
+        } else {
+            drop(Box::from_raw(node as *mut MaybeUninit<Node<T>>));
+        }
+        val
+    }
+
+    /// Removes the values for which expired returns true, and passes them
+    /// to on_cancel, in order.  The rest stay in the batch.
+    fn cancel_where<P, C>(&mut self, mut expired: P, mut on_cancel: C)
+    where
+        P: FnMut(&T) -> bool,
+        C: FnMut(T),
+    {
+        let mut link: *mut *mut Node<T> = &mut self.nodes.node;
+        unsafe {
+            while !(*link).is_null() {
+                let node = *link;
+                if expired(&(*node).val) {
+                    *link = (*node).next;
+                    self.len -= 1;
+                    on_cancel(self.take_node(node));
+                } else {
+                    link = &mut (*node).next;
+                }
+            }
+        }
+    }
+
+    /// Returns consumed nodes to pool instead of freeing them.
+    fn recycle_into(mut self, pool: &Arc<NodePool<T>>) -> Self {
+        self.pool = Some(pool.clone());
+        self
+    }
+}
+
+impl<T> ClaimBatch<T> {
+    /// Leaves the first at values in self, and returns the rest.
+    fn split_off(&mut self, at: usize) -> Self {
+        let mut rest = null_mut();
+        if at == 0 {
+            std::mem::swap(&mut rest, &mut self.nodes.node);
+        } else if at < self.len {
+            let mut node = self.nodes.node;
+            for _ in 1..at {
+                node = unsafe { (*node).next };
+            }
+            rest = unsafe { std::mem::replace(&mut (*node).next, null_mut()) };
+        }
+        let len = self.len - at.min(self.len);
+        self.len -= len;
+        Self {
+            nodes: NodeIterator::new(rest),
+            len,
+            pool: self.pool.clone(),
+            spent: (null_mut(), null_mut()),
+        }
+    }
+}
+
+impl<T> Iterator for ClaimBatch<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.pool.is_none() {
+            let val = self.nodes.next()?;
+            self.len -= 1;
+            return Some(val);
+        }
+        let node = self.nodes.node;
+        if node.is_null() {
+            return None;
+        }
+        self.len -= 1;
+        unsafe {
+            self.nodes.node = (*node).next;
+            Some(self.take_node(node))
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<T> ExactSizeIterator for ClaimBatch<T> {}
+
+impl<T> Drop for ClaimBatch<T> {
+    fn drop(&mut self) {
+        let Some(pool) = self.pool.take() else {
+            return;
+        };
+        // Put self back in recycling mode, so the remaining values are
+        // dropped and their nodes join the spent list.
+        self.pool = Some(pool);
+        for _ in self.by_ref() {}
+        let (top, bottom) = self.spent;
+        if !top.is_null() {
+            unsafe { self.pool.as_ref().unwrap().free_list(top, bottom) };
+        }
+    }
+}
+
+/// The nonce-protected free list that `WriteOrderingQueue` recycles its
+/// nodes through.  See `stack::NonceStack`, which uses the same scheme.
+///
+/// The nodes on the list have had their values moved out.  They are only
+/// freed when the pool is dropped, so the speculative read in `alloc` never
+/// touches freed memory, and every node the pool hands out must come back
+/// to it.  The pool never shrinks, so it holds as many nodes as were ever in
+/// flight at once.
+struct NodePool<T> {
+    free: Atom<FreeHead<T>, u128>,
+}
+
+/// A node allocated by a `NodePool`.  The node comes first, so pointers to
+/// the two are interchangeable.
+#[repr(C)]
+struct PooledNode<T> {
+    node: Node<T>,
+    /// The free list link.  This is separate from `node.next`, and atomic,
+    /// because `alloc` reads it speculatively, while the thread that popped
+    /// the node in race may be writing to the rest of the node.
+    free_next: AtomicPtr<Node<T>>,
+}
+
+struct FreeHead<T> {
+    head: *mut Node<T>,
+    nonce: u64,
+}
+
+impl<T> Default for NodePool<T> {
+    fn default() -> Self {
+        Self {
+            free: Default::default(),
+        }
+    }
+}
+
+impl<T> NodePool<T> {
+    /// Returns a node holding val, reusing a free node if there is one.
+    fn alloc(&self, val: T, next: *mut Node<T>) -> *mut Node<T> {
+        let node = unsafe {
+            atomic_try_update(&self.free, |head: &mut FreeHead<T>| {
+                head.nonce += 1;
+                let ret = head.head;
+                if ret.is_null() {
+                    (false, ret)
+                } else {
+                    // ret may have been reused in race; if so, the nonce
+                    // changed, and this read is discarded.
+                    head.head = Self::free_link(ret).load(Ordering::Relaxed);
+                    (true, ret)
+                }
+            })
+        };
+        if node.is_null() {
+            let node = Box::new(PooledNode {
+                node: Node { val, next },
+                free_next: AtomicPtr::new(null_mut()),
+            });
+            return Box::into_raw(node) as *mut Node<T>;
+        }
+        unsafe {
+            std::ptr::write(&mut (*node).val, val);
+            (*node).next = next;
+        }
+        node
+    }
+
+    /// Moves the value out of node, which came from alloc, and was never
+    /// published, and returns node to the pool.
+    unsafe fn reclaim(&self, node: *mut Node<T>) -> T {
+        let val = std::ptr::read(&(*node).val);
+        self.free_list(node, node);
+        val
+    }
+
+    /// Pushes the list top..=bottom, whose values have been moved out, and
+    /// which is linked through `free_link`.
+    unsafe fn free_list(&self, top: *mut Node<T>, bottom: *mut Node<T>) {
+        atomic_try_update(&self.free, |head: &mut FreeHead<T>| {
+            Self::free_link(bottom).store(head.head, Ordering::Relaxed);
+            head.nonce += 1;
+            head.head = top;
+            (true, ())
+        })
+    }
+
+    /// Returns the free list link of node, which must come from alloc.
+    unsafe fn free_link<'a>(node: *mut Node<T>) -> &'a AtomicPtr<Node<T>> {
+        &(*(node as *mut PooledNode<T>)).free_next
+    }
+}
+
+impl<T> Drop for NodePool<T> {
+    fn drop(&mut self) {
+        let mut node = unsafe { atomic_try_update(&self.free, |head| (false, head.head)) };
+        while !node.is_null() {
+            unsafe {
+                let next = Self::free_link(node).load(Ordering::Relaxed);
+                // The value was moved out, so only free the memory.
+                drop(Box::from_raw(node as *mut MaybeUninit<PooledNode<T>>));
+                node = next;
+            }
+        }
+    }
+}
+
+/// A `ClaimQueue` whose values are spread over several shards, for
+/// workloads where many producers saturate the single head word.
+///
+/// Each thread pushes to its own shard, and the shards share one logical
+/// claim (a `Claim`).  A pusher only touches the claim if its shard was
+/// empty, since otherwise whoever is responsible for the value below it
+/// will drain its value too.  The claim holder drains every shard in one
+/// pass per batch.
+///
+/// Values from one thread are consumed in push order, but there is no
+/// ordering between values on different shards, and a pusher cannot learn
+/// a global offset (that would need the shared word this avoids).  A
+/// consumer that needs offsets can number the values as it drains them.
+pub struct ShardedClaimQueue<T>
+where
+    T: Send,
+{
+    shards: Box<[Atom<ClaimHead<T>, u64>]>,
+    claim: Claim,
+}
+
+impl<T> Default for ShardedClaimQueue<T>
+where
+    T: Send,
+{
+    /// Creates one shard per available CPU.
+    fn default() -> Self {
+        Self::with_shards(
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+        )
+    }
+}
+
+impl<T> ShardedClaimQueue<T>
+where
+    T: Send,
+{
+    /// Panics if shards is zero.
+    pub fn with_shards(shards: usize) -> Self {
+        assert!(shards > 0, "ShardedClaimQueue needs at least one shard");
+        Self {
+            shards: (0..shards).map(|_| Default::default()).collect(),
+            claim: Default::default(),
+        }
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns true iff we have the claim, like `ClaimQueue::push`.
+    pub fn push(&self, val: T) -> bool {
+        self.push_to(thread_hint() % self.shards.len(), val)
+    }
+
+    fn push_to(&self, shard: usize, val: T) -> bool {
+        let node = Box::into_raw(Box::new(Node {
+            val,
+            next: null_mut(),
+        }));
+        let shard = &self.shards[shard];
+        let was_empty = unsafe {
+            atomic_try_update(shard, |head: &mut ClaimHead<T>| {
+                (*node).next = head.next.get_ptr();
+                head.next.set_ptr(node);
+                (true, (*node).next.is_null())
+            })
+        };
+        was_empty && self.claim.try_claim()
+    }
+
+    /// Detaches every shard, and returns their contents as one batch.  If
+    /// they are all empty, it releases the claim and returns false.  Values
+    /// from each shard are in push order, and the shards follow each other.
+    pub fn consume_or_release_claim(&self) -> (ClaimBatch<T>, bool) {
+        assert!(
+            self.claim.is_claimed(),
+            "cannot call consume_or_release_claim unless you have the claim!"
+        );
+        loop {
+            let batch = ClaimBatch::from_lists(self.shards.iter().map(|shard| unsafe {
+                atomic_try_update(shard, |head: &mut ClaimHead<T>| {
+                    let ret = head.next.get_ptr();
+                    head.next.set_ptr(null_mut());
+                    (!ret.is_null(), ret)
+                })
+            }));
+            if batch.len() > 0 {
+                return (batch, true);
+            }
+            // A push that raced with the pass above left a note on the claim.
+            if !self.claim.release() {
+                return (batch, false);
+            }
+        }
+    }
+
+    /// See `ClaimQueue::consume_until_released`.
+    pub fn consume_until_released<F>(&self, mut f: F)
+    where
+        F: FnMut(ClaimBatch<T>),
+    {
+        loop {
+            let (batch, claimed) = self.consume_or_release_claim();
+            if !claimed {
+                return;
+            }
+            f(batch);
+        }
+    }
+}
+
+impl<T> Drop for ShardedClaimQueue<T>
+where
+    T: Send,
+{
+    fn drop(&mut self) {
+        for shard in self.shards.iter() {
+            let node = unsafe {
+                atomic_try_update(shard, |head: &mut ClaimHead<T>| {
+                    (false, head.next.get_ptr())
+                })
+            };
+            drop(NodeIterator::new(node));
+        }
+    }
+}
+
+/// The lanes of a `PriorityClaimQueue`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Low,
+}
+
+/// A claim queue with two lanes under one claim.  Each batch contains every
+/// queued high priority value, followed by every queued low priority one,
+/// so control records (flush barriers, epoch markers, and so on) can jump
+/// ahead of bulk data.
+///
+/// This is a `ShardedClaimQueue` with one shard per priority, so values are
+/// only ordered within a lane (and, within a lane, only per thread).
+pub struct PriorityClaimQueue<T>
+where
+    T: Send,
+{
+    lanes: ShardedClaimQueue<T>,
+}
+
+impl<T> Default for PriorityClaimQueue<T>
+where
+    T: Send,
+{
+    fn default() -> Self {
+        Self {
+            lanes: ShardedClaimQueue::with_shards(2),
+        }
+    }
+}
+
+impl<T> PriorityClaimQueue<T>
+where
+    T: Send,
+{
+    /// Returns true iff we have the claim, like `ClaimQueue::push`.
+    pub fn push(&self, val: T, priority: Priority) -> bool {
+        self.lanes.push_to(priority as usize, val)
+    }
+
+    /// See `ShardedClaimQueue::consume_or_release_claim`.
+    pub fn consume_or_release_claim(&self) -> (ClaimBatch<T>, bool) {
+        self.lanes.consume_or_release_claim()
+    }
+
+    /// See `ClaimQueue::consume_until_released`.
+    pub fn consume_until_released<F>(&self, f: F)
+    where
+        F: FnMut(ClaimBatch<T>),
+    {
+        self.lanes.consume_until_released(f)
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A flat-combining executor built on `ClaimQueue`.
+///
+/// Threads submit closures with `execute`.  Whichever thread obtains the
+/// claim runs every queued closure, one at a time and in submission order,
+/// before releasing it.  Since no two closures ever run concurrently, they
+/// can share non-atomic state without a mutex, and a thread that loses the
+/// race to run its closure returns immediately instead of blocking.
+#[derive(Default)]
+pub struct ClaimExecutor {
+    queue: ClaimQueue<Job>,
+}
+
+impl ClaimExecutor {
+    /// Runs job, either on this thread (along with any jobs other threads
+    /// submitted in the meantime) or on the thread that currently has the
+    /// claim.  Returns true if this thread had the claim, in which case job
+    /// has finished running by the time this returns.
+    ///
+    /// If any job run by this thread panics, the remaining jobs still run,
+    /// and then the first panic is resumed once the claim has been released.
+    pub fn execute<F>(&self, job: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if !self.queue.push(Box::new(job)) {
+            return false;
+        }
+        let mut panic = None;
+        self.queue.consume_until_released(|batch| {
+            for job in batch {
+                if let Err(payload) = catch_unwind(AssertUnwindSafe(job)) {
+                    panic.get_or_insert(payload);
+                }
+            }
+        });
+        if let Some(payload) = panic {
+            resume_unwind(payload);
+        }
+        true
+    }
+}
+
+/// Errors returned by claim queue operations.  Operations that are rejected
+/// hand ownership of the value back to the caller.
+pub enum ClaimError<T> {
+    /// The queue has been closed.
+    Closed(T),
+    /// The value's count would have pushed the queue's offset past the
+    /// largest value it can represent.
+    Overflow(T),
+}
+
+impl<T> Debug for ClaimError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClaimError::Closed(_) => write!(f, "Closed(..)"),
+            ClaimError::Overflow(_) => write!(f, "Overflow(..)"),
+        }
+    }
+}
+
+impl<T> Error for ClaimError<T> {}
+
+impl<T> Display for ClaimError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// Why a push lambda rejected a value.  The caller turns this into a
+/// `ClaimError` once it has the value back.
+enum Rejected {
+    Closed,
+    Overflow,
+}
+
+impl Rejected {
+    fn with<T>(self, val: T) -> ClaimError<T> {
+        match self {
+            Rejected::Closed => ClaimError::Closed(val),
+            Rejected::Overflow => ClaimError::Overflow(val),
+        }
+    }
+}
 
-use super::{atomic_try_update, bits::FlagU64, Atom, Node, NodeIterator};
 /// A special purpose trait for WriteOrderingQueue
 pub trait Countable {
     fn get_count(&self) -> u64;
 }
 
-struct CountingClaimHead<T: Countable> {
-    next: *mut Node<T>,
-    /// Number of bytes inserted into this queue so far (according to Countable::get_count).
-    /// The flag is the claim bit. The invariant is that if the queue is non-empty, then
-    /// it is claimed by something (so the claim bit is set).  Strictly speaking, we could
+/// Determines the value that a `WriteOrderingQueue` packs next to its
+/// claim bit, and reports to each pusher.
+///
+/// Each pushed value is summarized as a u64, and the summaries are folded
+/// into the queue's accumulator with `combine`.  `combine` must be
+/// associative, and zero (the accumulator of an empty queue) must be its
+/// identity.  This lets `push_batch` fold a whole batch before its CAS.
+/// Returning None from `combine` rejects the push with
+/// `ClaimError::Overflow`.
+pub trait Accumulate<T> {
+    fn summarize(val: &T) -> u64;
+    fn combine(acc: u64, summary: u64) -> Option<u64>;
+}
+
+/// The default accumulator: the sum of `Countable::get_count`, so each push
+/// is assigned the offset at which its bytes start.
+pub struct ByteOffset;
+
+impl<T: Countable> Accumulate<T> for ByteOffset {
+    fn summarize(val: &T) -> u64 {
+        val.get_count()
+    }
+    fn combine(acc: u64, summary: u64) -> Option<u64> {
+        acc.checked_add(summary)
+    }
+}
+
+/// Counts records, so each push is assigned a sequence number.
+pub struct RecordCount;
+
+impl<T> Accumulate<T> for RecordCount {
+    fn summarize(_val: &T) -> u64 {
+        1
+    }
+    fn combine(acc: u64, summary: u64) -> Option<u64> {
+        acc.checked_add(summary)
+    }
+}
+
+struct CountingClaimHead<T> {
+    /// The flag is the claim bit (`CLAIMED`). The invariant is that if the queue is non-empty,
+    /// then it is claimed by something (so the claim bit is set).  Strictly speaking, we could
     /// store the claim bit implicitly for this use case, but this is a common pattern, and
     /// we leave it explicit so this data structure can be used as example code.
-    count_and_claim: FlagU64,
+    ///
+    /// The claim bit lives in the pointer's alignment bits, rather than next to the count,
+    /// so that offsets get all 64 bits.
+    next: FlagPtr<Node<T>>,
+    /// Number of bytes inserted into this queue so far (according to Countable::get_count),
+    /// or, more generally, the accumulator (see `Accumulate`).
+    count: u64,
 }
 
-pub struct WriteOrderingQueue<T>
+/// A claim queue that assigns each pushed value an offset.
+///
+/// By default, the offset is the sum of the `Countable::get_count` of the
+/// values pushed before it.  The A parameter replaces that with another
+/// `Accumulate` implementation, such as `RecordCount`, or a custom fold
+/// (for instance, the largest sequence number seen so far).  The methods
+/// below are documented in terms of byte offsets, which are the common case.
+pub struct WriteOrderingQueue<T, A = ByteOffset>
 where
-    T: Send + Countable,
+    T: Send,
+    A: Accumulate<T>,
 {
     head: Atom<CountingClaimHead<T>, u128>,
+    /// Everything before this offset has been processed by a claim holder.
+    /// See `complete_through`.
+    completed: AtomicU64,
+    /// Woken each time completed advances.
+    completion: WakerList,
+    /// The offset just past the last value returned to a claim holder.
+    /// Only the claim holder writes it.
+    consumed: AtomicU64,
+    #[cfg(feature = "stats")]
+    stats: ClaimCounters,
+    #[cfg(feature = "replay")]
+    replay: ReplayLog,
+    /// Values that `consume_up_to` detached, but did not return.  Only the
+    /// claim holder touches it.
+    pending: UnsafeCell<Pending<T>>,
+    /// Held while a claim holder uses pending, so a consume by a thread that
+    /// does not have the claim panics instead of racing with the holder.
+    consuming: Claim,
+    /// See `push_with_completion`.
+    completions: Completions,
+    /// Nodes are recycled, so a queue in steady state does not allocate.
+    pool: Arc<NodePool<T>>,
+    accumulate: PhantomData<A>,
+}
+
+/// Callbacks registered by `push_with_completion`.
+#[derive(Default)]
+struct Completions {
+    /// The end offset of each value, and its callback, in no particular
+    /// order.
+    registered: Stack<(u64, Job)>,
+    /// Held by the thread that runs callbacks.
+    running: Claim,
+    /// Callbacks that the holder of running has taken from registered, in
+    /// offset order.  Only that thread touches it.
+    waiting: UnsafeCell<VecDeque<(u64, Job)>>,
+    /// Number of `push_with_completion` calls that have pushed (or are about
+    /// to push) their value, but have not registered its callback yet.
+    registering: AtomicU64,
+    /// Set when callbacks were left waiting for registering to drop to zero.
+    deferred: AtomicBool,
+}
+
+/// A snapshot of a `WriteOrderingQueue`, returned by `state`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueState {
+    /// Some thread has the claim.
+    pub claimed: bool,
+    /// `close` has been called.
+    pub closed: bool,
+    /// The offset that the next push will be assigned.
+    pub offset: u64,
+}
+
+/// Number of events kept by the `replay` feature's log.
+#[cfg(feature = "replay")]
+pub const REPLAY_LOG_LEN: usize = 1024;
+
+/// What happened in a `ReplayEvent`.
+#[cfg(feature = "replay")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum ReplayKind {
+    /// A push (or batch push) that did not get the claim.
+    Push = 1,
+    /// A push that got the claim.
+    PushClaimed,
+    /// A claim holder consumed a batch.
+    Consume,
+    /// A claim holder found the queue empty, and released the claim.
+    Release,
+    /// The queue was closed.
+    Close,
+}
+
+/// An entry in a `WriteOrderingQueue`'s replay log.
+#[cfg(feature = "replay")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReplayEvent {
+    /// Position in the log.  Events are numbered when they are recorded,
+    /// which is shortly after they take effect, so two events that raced may
+    /// be numbered in the opposite order.  The offsets disambiguate most
+    /// such cases.
+    pub seq: u64,
+    /// A small number that identifies the thread (in the order that threads
+    /// first recorded an event).
+    pub thread: u16,
+    pub kind: ReplayKind,
+    /// For pushes, the offset of the (first) value.  For consumes, the
+    /// offset just past the batch.  Otherwise, the queue's offset.
+    pub offset: u64,
+}
+
+/// A slot in the replay ring.  kind is zero until the slot is written.
+#[cfg(feature = "replay")]
+struct ReplaySlot {
+    offset: u64,
+    /// The low bits of the event's seq, so readers can skip slots that were
+    /// overwritten (or not written yet).
+    seq: u32,
+    thread: u16,
+    kind: u8,
+    _reserved: u8,
+}
+
+/// A bounded ring of recent events.  Each slot is a single `Atom`, so
+/// recording an event never blocks, and a reader never sees a torn one.
+#[cfg(feature = "replay")]
+struct ReplayLog {
+    next: AtomicU64,
+    slots: Box<[Atom<ReplaySlot, u128>]>,
+}
+
+#[cfg(feature = "replay")]
+impl Default for ReplayLog {
+    fn default() -> Self {
+        Self {
+            next: AtomicU64::new(0),
+            slots: (0..REPLAY_LOG_LEN).map(|_| Default::default()).collect(),
+        }
+    }
+}
+
+#[cfg(feature = "replay")]
+fn replay_thread_id() -> u16 {
+    static NEXT: std::sync::atomic::AtomicU16 = std::sync::atomic::AtomicU16::new(0);
+    thread_local! {
+        static ID: u16 = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    ID.with(|id| *id)
+}
+
+#[cfg(feature = "replay")]
+impl ReplayLog {
+    fn record(&self, kind: ReplayKind, offset: u64) {
+        let seq = self.next.fetch_add(1, Ordering::Relaxed);
+        let thread = replay_thread_id();
+        let slot = &self.slots[(seq % REPLAY_LOG_LEN as u64) as usize];
+        unsafe {
+            atomic_try_update(slot, |slot: &mut ReplaySlot| {
+                *slot = ReplaySlot {
+                    offset,
+                    seq: seq as u32,
+                    thread,
+                    kind: kind.into(),
+                    _reserved: 0,
+                };
+                (true, ())
+            })
+        }
+    }
+
+    fn snapshot(&self) -> Vec<ReplayEvent> {
+        let end = self.next.load(Ordering::Relaxed);
+        let start = end.saturating_sub(REPLAY_LOG_LEN as u64);
+        (start..end)
+            .filter_map(|seq| {
+                let slot = &self.slots[(seq % REPLAY_LOG_LEN as u64) as usize];
+                let (slot_seq, thread, kind, offset) = unsafe {
+                    atomic_try_update(slot, |slot: &mut ReplaySlot| {
+                        (false, (slot.seq, slot.thread, slot.kind, slot.offset))
+                    })
+                };
+                // Skip slots that are still being written, or were reused.
+                if slot_seq != seq as u32 {
+                    return None;
+                }
+                Some(ReplayEvent {
+                    seq,
+                    thread,
+                    kind: ReplayKind::try_from(kind).ok()?,
+                    offset,
+                })
+            })
+            .collect()
+    }
 }
 
-impl<T> Default for WriteOrderingQueue<T>
+/// Owned by the claim holder of a `WriteOrderingQueue`.
+struct Pending<T> {
+    batch: Option<ClaimBatch<T>>,
+    /// The offset just past the last value returned to a claim holder.
+    offset: u64,
+    /// The offset just past the last value detached from the queue.
+    end: u64,
+}
+
+/// A snapshot of the counters that each `WriteOrderingQueue` maintains when
+/// the `stats` feature is enabled.  The counters are updated with relaxed
+/// atomics outside of the CAS, so a snapshot taken while the queue is in use
+/// is approximate.
+///
+/// `bytes` and `backlog_bytes` are derived from the queue's offsets, so they
+/// are only byte counts for the default `ByteOffset` accumulator.
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClaimStats {
+    /// Values pushed (individually or in batches).
+    pub pushes: u64,
+    /// Pushes that obtained the claim, i.e., the number of times the claim
+    /// was handed to a new consumer.
+    pub claims: u64,
+    /// Non-empty batches returned by `consume_or_release_claim`.
+    pub batches: u64,
+    /// Values returned in those batches.
+    pub items: u64,
+    /// Sum of the counts of the values returned in those batches.
+    pub bytes: u64,
+    /// Values that have been pushed but not consumed.
+    pub backlog_items: u64,
+    /// Sum of the counts of the values that have been pushed but not consumed.
+    pub backlog_bytes: u64,
+}
+
+#[cfg(feature = "stats")]
+impl ClaimStats {
+    pub fn batches_per_claim(&self) -> f64 {
+        self.batches as f64 / self.claims.max(1) as f64
+    }
+    pub fn items_per_batch(&self) -> f64 {
+        self.items as f64 / self.batches.max(1) as f64
+    }
+    pub fn bytes_per_batch(&self) -> f64 {
+        self.bytes as f64 / self.batches.max(1) as f64
+    }
+}
+
+#[cfg(feature = "stats")]
+#[derive(Default)]
+struct ClaimCounters {
+    pushes: AtomicU64,
+    claims: AtomicU64,
+    batches: AtomicU64,
+    items: AtomicU64,
+}
+
+impl<T, A> Default for WriteOrderingQueue<T, A>
 where
-    T: Send + Countable,
+    T: Send,
+    A: Accumulate<T>,
 {
-    fn default() -> WriteOrderingQueue<T> {
-        WriteOrderingQueue::<T> {
+    fn default() -> WriteOrderingQueue<T, A> {
+        WriteOrderingQueue::<T, A> {
             head: Atom::default(),
+            completed: AtomicU64::new(0),
+            completion: WakerList::new(),
+            consumed: AtomicU64::new(0),
+            #[cfg(feature = "stats")]
+            stats: Default::default(),
+            #[cfg(feature = "replay")]
+            replay: Default::default(),
+            pending: UnsafeCell::new(Pending {
+                batch: None,
+                offset: 0,
+                end: 0,
+            }),
+            consuming: Default::default(),
+            completions: Default::default(),
+            pool: Default::default(),
+            accumulate: PhantomData,
         }
     }
 }
 
+// pending is only used by the thread that holds the `consuming` claim.
+unsafe impl<T, A> Sync for WriteOrderingQueue<T, A>
+where
+    T: Send,
+    A: Accumulate<T> + Sync,
+{
+}
+
 /// This is a multi-producer "claim" queue.
-impl<T> WriteOrderingQueue<T>
+impl<T, A> WriteOrderingQueue<T, A>
 where
-    T: Send + Countable,
+    T: Send,
+    A: Accumulate<T>,
 {
     /// This returns the offset of the write, and true iff we have the claim.
     /// If we have the claim, we are responsible for calling consume_or_release_claim
     /// until we manage to release it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the queue has been closed, or if the offset would exceed
+    /// `u64::MAX`.  Use `try_push` to handle those cases.
     pub fn push(&self, val: T) -> (u64, bool) {
-        let sz = val.get_count();
-        #[allow(unused_mut)]
-        let mut node = Box::into_raw(Box::new(Node {
-            val,
-            next: std::ptr::null_mut(),
-        }));
+        match self.try_push(val) {
+            Ok(ret) => ret,
+            Err(err) => panic!("WriteOrderingQueue::push failed: {err}"),
+        }
+    }
+    /// Like push, but returns val in `ClaimError::Overflow` instead of
+    /// panicking if the offset would exceed `u64::MAX`, or in
+    /// `ClaimError::Closed` if the queue has been closed.  The queue is
+    /// left unchanged in either case.
+    pub fn try_push(&self, val: T) -> Result<(u64, bool), ClaimError<T>> {
+        let sz = A::summarize(&val);
+        self.try_push_reserving(val, |old_count| {
+            Some((old_count, A::combine(old_count, sz)?))
+        })
+        .map(|(start, _, have_claim)| (start, have_claim))
+    }
+    /// Pushes val, and sets the offset to the end of the region returned by
+    /// reserve, which is passed the old offset.  Returns the region and true
+    /// iff we have the claim.  If reserve returns None, the push is rejected
+    /// with `ClaimError::Overflow`.
+    fn try_push_reserving<F>(&self, val: T, reserve: F) -> Result<(u64, u64, bool), ClaimError<T>>
+    where
+        F: Fn(u64) -> Option<(u64, u64)>,
+    {
+        let node = self.pool.alloc(val, null_mut());
 
-        unsafe {
+        let pushed = unsafe {
             atomic_try_update(&self.head, |head: &mut CountingClaimHead<T>| {
-                (*node).next = head.next;
-                head.next = node;
-                let old_count = head.count_and_claim.get_val();
-                let have_claim = !head.count_and_claim.get_flag();
-                // TODO: need to check for overflow without panic
-                head.count_and_claim.set_val(old_count + sz);
-                head.count_and_claim.set_flag(true); // either it was already set to true, or we need to set it to true!
-                (true, (old_count, have_claim))
+                if head.next.get_flag() & CLOSED != 0 {
+                    return (false, Err(Rejected::Closed));
+                }
+                let Some((start, end)) = reserve(head.count) else {
+                    return (false, Err(Rejected::Overflow));
+                };
+                (*node).next = head.next.get_ptr();
+                head.next.set_ptr(node);
+                let have_claim = head.next.get_flag() & CLAIMED == 0;
+                head.count = end;
+                head.next.set_flag(CLAIMED); // either it was already set, or we need to set it!
+                (true, Ok((start, end, have_claim)))
             })
-            // Can safely panic on overflow here.
+        };
+        if let Ok((start, _, have_claim)) = pushed {
+            self.record_pushed(1, start, have_claim);
         }
+        pushed.map_err(|err| err.with(unsafe { self.pool.reclaim(node) }))
     }
-    /// This removes everything from the queue.  If queue is already empty, it releases the claim and returns false
-    pub fn consume_or_release_claim(&self) -> (NodeIterator<T>, bool) {
-        let (node, had_claim, claimed) = unsafe {
-            atomic_try_update(&self.head, |head| {
-                let ret = head.next;
-                let had_claim = head.count_and_claim.get_flag();
-                head.next = null_mut();
-                if ret.is_null() {
-                    head.count_and_claim.set_flag(false);
-                    (true, (ret, had_claim, false)) // no longer have claim
-                } else {
-                    (true, (ret, had_claim, true))
+    /// Pushes every value in vals with a single CAS.  The values are linked
+    /// together before the CAS, get consecutive offsets in iteration order,
+    /// and are consumed in that order.
+    ///
+    /// Returns the offset of the first value and true iff we have the claim,
+    /// like push.  An empty batch does not change the queue, and never gets
+    /// the claim.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the queue has been closed, or if the offset would exceed
+    /// `u64::MAX`.  Use `try_push_batch` to handle those cases.
+    pub fn push_batch<I>(&self, vals: I) -> (u64, bool)
+    where
+        I: IntoIterator<Item = T>,
+    {
+        match self.try_push_batch(vals) {
+            Ok(ret) => ret,
+            Err(err) => panic!("WriteOrderingQueue::push_batch failed: {err}"),
+        }
+    }
+    /// Like push_batch, but returns the values (in iteration order) in
+    /// `ClaimError::Overflow` if the offset would exceed `u64::MAX`, or in
+    /// `ClaimError::Closed` if the queue has been closed.
+    pub fn try_push_batch<I>(&self, vals: I) -> Result<(u64, bool), ClaimError<Vec<T>>>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        // Build the list in LIFO order, so it can be spliced on top of the
+        // queue as though the values had been pushed one at a time.
+        let mut top: *mut Node<T> = null_mut();
+        let mut last: *mut Node<T> = null_mut();
+        let mut sz: Option<u64> = Some(0);
+        let mut _len = 0;
+        for val in vals {
+            _len += 1;
+            sz = sz.and_then(|sz| A::combine(sz, A::summarize(&val)));
+            top = self.pool.alloc(val, top);
+            if last.is_null() {
+                last = top;
+            }
+        }
+        if top.is_null() {
+            return Ok((self.get_offset(), false));
+        }
+
+        let pushed = unsafe {
+            atomic_try_update(&self.head, |head: &mut CountingClaimHead<T>| {
+                if head.next.get_flag() & CLOSED != 0 {
+                    return (false, Err(Rejected::Closed));
                 }
+                let old_count = head.count;
+                let Some(new_count) = sz.and_then(|sz| A::combine(old_count, sz)) else {
+                    return (false, Err(Rejected::Overflow));
+                };
+                (*last).next = head.next.get_ptr();
+                head.next.set_ptr(top);
+                let have_claim = head.next.get_flag() & CLAIMED == 0;
+                head.count = new_count;
+                head.next.set_flag(CLAIMED);
+                (true, Ok((old_count, have_claim)))
             })
         };
-        assert!(
-            had_claim,
-            "cannot call consume_or_release_claim unless you have the claim!"
+        if let Ok((start, have_claim)) = pushed {
+            self.record_pushed(_len, start, have_claim);
+        }
+        pushed.map_err(|err| {
+            let batch = ClaimBatch::new(top).recycle_into(&self.pool);
+            err.with(batch.collect())
+        })
+    }
+    /// Like push, but also registers on_complete, which is called once a
+    /// claim holder reports (with `complete_through`) that val has been
+    /// processed.  Callbacks run in offset order, on the thread that called
+    /// `complete_through` (or, if that already happened, on this one).
+    ///
+    /// Callbacks are registered on a lock-free stack, and sorted by offset
+    /// when they run.  A value's offset is not known until after it has
+    /// been pushed, so no callbacks run while some other call is between
+    /// its push and its registration; the last such call runs them instead.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as push: if the queue has been closed, or if
+    /// the offset would exceed `u64::MAX`.  on_complete is dropped without
+    /// being called.
+    pub fn push_with_completion<F>(&self, val: T, on_complete: F) -> (u64, bool)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let sz = A::summarize(&val);
+        let completions = &self.completions;
+        completions.registering.fetch_add(1, Ordering::SeqCst);
+        let pushed = self.try_push(val);
+        let registered = pushed.as_ref().ok().map(|(offset, _)| {
+            let end = A::combine(*offset, sz).expect("offsets are checked by push");
+            completions.registered.push((end, Box::new(on_complete)));
+            end
+        });
+        let last = completions.registering.fetch_sub(1, Ordering::SeqCst) == 1;
+        let deferred = last && completions.deferred.swap(false, Ordering::SeqCst);
+        if deferred || registered.is_some_and(|end| self.get_completed() >= end) {
+            self.run_completions();
+        }
+        match pushed {
+            Ok(ret) => ret,
+            Err(err) => panic!("WriteOrderingQueue::push_with_completion failed: {err}"),
+        }
+    }
+    fn record_pushed(&self, _count: u64, _offset: u64, _have_claim: bool) {
+        #[cfg(feature = "replay")]
+        self.replay.record(
+            if _have_claim {
+                ReplayKind::PushClaimed
+            } else {
+                ReplayKind::Push
+            },
+            _offset,
         );
-        (NodeIterator::new(node).rev(), claimed)
+        #[cfg(feature = "stats")]
+        {
+            self.stats.pushes.fetch_add(_count, Ordering::Relaxed);
+            self.stats
+                .claims
+                .fetch_add(u64::from(_have_claim), Ordering::Relaxed);
+        }
+    }
+    /// This removes everything from the queue.  If queue is already empty, it releases the claim and returns false
+    ///
+    /// The values are returned in the order of their offsets; see `ClaimBatch`.
+    pub fn consume_or_release_claim(&self) -> (ClaimBatch<T>, bool) {
+        let (batch, claimed, _) = self.consume_through(usize::MAX, u64::MAX);
+        (batch, claimed)
+    }
+    /// Like `ClaimQueue::consume_until_released`: passes batches to f until
+    /// the queue is empty and the claim has been released.  f is responsible
+    /// for calling `complete_through` if anyone waits for completion.
+    pub fn consume_until_released<F>(&self, mut f: F)
+    where
+        F: FnMut(ClaimBatch<T>),
+    {
+        loop {
+            let (batch, claimed) = self.consume_or_release_claim();
+            if !claimed {
+                return;
+            }
+            f(batch);
+        }
+    }
+    /// Like consume_or_release_claim, but first removes the values for which
+    /// expired returns true (for instance, records of aborted transactions,
+    /// or values whose deadline has passed), and passes them to on_cancel
+    /// instead, in offset order.
+    ///
+    /// Cancelled values keep their offsets, so the region they reserved is
+    /// still covered by the next `complete_through`.  If every value in a
+    /// batch is cancelled, this returns an empty batch and true: the claim is
+    /// still held, so keep calling it until it returns false.
+    pub fn consume_or_cancel<P, C>(&self, expired: P, on_cancel: C) -> (ClaimBatch<T>, bool)
+    where
+        P: FnMut(&T) -> bool,
+        C: FnMut(T),
+    {
+        let (mut batch, claimed) = self.consume_or_release_claim();
+        batch.cancel_where(expired, on_cancel);
+        (batch, claimed)
+    }
+    /// Like consume_or_release_claim, but returns at most max_items values,
+    /// whose counts add up to at most max_bytes, so the claim holder can
+    /// bound the amount of work it does per batch.  The batch always
+    /// contains at least one value (if the queue is non-empty), even if that
+    /// value's count exceeds max_bytes.
+    ///
+    /// The oldest values are at the bottom of the queue, so this still
+    /// detaches everything with one CAS.  The values that do not fit are
+    /// kept aside for the claim holder, and are returned (ahead of any newer
+    /// values) by the next call to `consume_up_to` or
+    /// `consume_or_release_claim`.  The claim is not released until they
+    /// have all been returned.
+    ///
+    /// With a custom `Accumulate` implementation, max_bytes bounds the sum
+    /// of `Accumulate::summarize`.
+    pub fn consume_up_to(&self, max_items: usize, max_bytes: u64) -> (ClaimBatch<T>, bool) {
+        let (batch, claimed, _) = self.consume_through(max_items, max_bytes);
+        (batch, claimed)
+    }
+    /// Like consume_up_to, but also returns the offset just past the end of
+    /// the batch.
+    fn consume_through(&self, max_items: usize, max_bytes: u64) -> (ClaimBatch<T>, bool, u64) {
+        let claim_error = "cannot call consume_or_release_claim unless you have the claim!";
+        // Only the claim holder gets here, so this never fails unless the
+        // caller broke the contract.  Unlike a lock, the guard can not be
+        // poisoned, so a panic in an earlier batch does not wedge the queue.
+        let consuming = self.consuming.try_guard().expect(claim_error);
+        // Safety: we hold the guard, so nobody else is using pending.
+        let pending = unsafe { &mut *self.pending.get() };
+        let mut batch = match pending.batch.take() {
+            Some(batch) => {
+                let had_claim = unsafe {
+                    atomic_try_update(&self.head, |head| {
+                        (false, head.next.get_flag() & CLAIMED != 0)
+                    })
+                };
+                assert!(had_claim, "{claim_error}");
+                batch
+            }
+            None => {
+                let (node, had_claim, end) = unsafe {
+                    atomic_try_update(&self.head, |head| {
+                        let ret = head.next.get_ptr();
+                        let had_claim = head.next.get_flag() & CLAIMED != 0;
+                        head.next.set_ptr(null_mut());
+                        (!ret.is_null(), (ret, had_claim, head.count))
+                    })
+                };
+                assert!(had_claim, "{claim_error}");
+                if node.is_null() {
+                    // The next claim holder may start consuming as soon as
+                    // we release the claim, so give up pending first.
+                    drop(consuming);
+                    let released = unsafe {
+                        atomic_try_update(&self.head, |head| {
+                            if !head.next.get_ptr().is_null() {
+                                return (false, None);
+                            }
+                            let flag = head.next.get_flag();
+                            head.next.set_flag(flag & CLOSED); // no longer have claim
+                            (true, Some(head.count))
+                        })
+                    };
+                    let Some(end) = released else {
+                        // A push arrived, so we still have the claim.
+                        return self.consume_through(max_items, max_bytes);
+                    };
+                    #[cfg(feature = "replay")]
+                    self.replay.record(ReplayKind::Release, end);
+                    return (ClaimBatch::new(node), false, end);
+                }
+                pending.end = end;
+                ClaimBatch::new(node).recycle_into(&self.pool)
+            }
+        };
+
+        if max_items < batch.len() || max_bytes != u64::MAX {
+            // Find the longest prefix that fits, and the offset just past it.
+            let mut offset = pending.offset;
+            let mut bytes = 0u64;
+            let mut len = 0;
+            let mut node = batch.nodes.node;
+            while !node.is_null() && len < max_items {
+                let sz = A::summarize(unsafe { &(*node).val });
+                let next_bytes = bytes.saturating_add(sz);
+                if len > 0 && next_bytes > max_bytes {
+                    break;
+                }
+                // This succeeded when the value was pushed.
+                offset = A::combine(offset, sz).expect("offsets are checked by push");
+                bytes = next_bytes;
+                len += 1;
+                node = unsafe { (*node).next };
+            }
+            let rest = batch.split_off(len.max(1));
+            if rest.len() > 0 {
+                pending.batch = Some(rest);
+                pending.offset = offset;
+            } else {
+                pending.offset = pending.end;
+            }
+        } else {
+            pending.offset = pending.end;
+        }
+        let offset = pending.offset;
+        #[cfg(feature = "stats")]
+        {
+            self.stats.batches.fetch_add(1, Ordering::Relaxed);
+            self.stats
+                .items
+                .fetch_add(batch.len() as u64, Ordering::Relaxed);
+        }
+        self.consumed.store(offset, Ordering::Relaxed);
+        #[cfg(feature = "replay")]
+        self.replay.record(ReplayKind::Consume, offset);
+        (batch, true, offset)
+    }
+
+    /// Returns the most recent `REPLAY_LOG_LEN` pushes, consumes, releases
+    /// and closes, oldest first, for post-mortem debugging.
+    #[cfg(feature = "replay")]
+    pub fn replay_log(&self) -> Vec<ReplayEvent> {
+        self.replay.snapshot()
+    }
+
+    /// Returns a snapshot of this queue's statistics.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> ClaimStats {
+        let pushes = self.stats.pushes.load(Ordering::Relaxed);
+        let items = self.stats.items.load(Ordering::Relaxed);
+        let bytes = self.consumed.load(Ordering::Relaxed);
+        ClaimStats {
+            pushes,
+            claims: self.stats.claims.load(Ordering::Relaxed),
+            batches: self.stats.batches.load(Ordering::Relaxed),
+            items,
+            bytes,
+            backlog_items: pushes.saturating_sub(items),
+            backlog_bytes: self.get_offset().saturating_sub(bytes),
+        }
+    }
+
+    /// Called by the claim holder once everything before offset has been
+    /// processed (for instance, once a write-ahead log is durable up to
+    /// offset).  Wakes any `wait_for_completion` callers that were waiting
+    /// for it.  Offsets only move forward, so reporting an older offset has
+    /// no effect.
+    pub fn complete_through(&self, offset: u64) {
+        if self.completed.fetch_max(offset, Ordering::AcqRel) < offset {
+            self.completion.wake_all();
+            self.run_completions();
+        }
+    }
+
+    /// Runs the callbacks whose values have been completed, unless some
+    /// other thread is already doing so (in which case, that thread will see
+    /// the new completed offset before it stops).  If a callback panics, the
+    /// rest still run, and then the first panic is resumed.
+    fn run_completions(&self) {
+        let completions = &self.completions;
+        let Some(mut running) = completions.running.try_guard() else {
+            return;
+        };
+        // Safety: we hold the running claim, so nobody else is using waiting.
+        let waiting = unsafe { &mut *completions.waiting.get() };
+        let mut panic = None;
+        loop {
+            let old_len = waiting.len();
+            waiting.extend(completions.registered.pop_all());
+            if waiting.len() > old_len {
+                waiting.make_contiguous().sort_by_key(|(end, _)| *end);
+            }
+            // A callback with an earlier offset may not have been registered
+            // yet.  If so, the last registering thread calls us again.
+            if completions.registering.load(Ordering::SeqCst) != 0 {
+                completions.deferred.store(true, Ordering::SeqCst);
+                if completions.registering.load(Ordering::SeqCst) == 0 {
+                    // The registration finished before it could see deferred.
+                    completions.deferred.store(false, Ordering::SeqCst);
+                    continue;
+                }
+            } else {
+                let completed = self.get_completed();
+                let ready = waiting
+                    .iter()
+                    .take_while(|(end, _)| *end <= completed)
+                    .count();
+                for (_, job) in waiting.drain(..ready) {
+                    if let Err(payload) = catch_unwind(AssertUnwindSafe(job)) {
+                        panic.get_or_insert(payload);
+                    }
+                }
+            }
+            // Registering more callbacks, completing more values, and the
+            // end of a deferral all call us, which makes this fail.
+            match running.release() {
+                Some(still_held) => running = still_held,
+                None => break,
+            }
+        }
+        if let Some(payload) = panic {
+            resume_unwind(payload);
+        }
+    }
+
+    /// Returns the largest offset passed to `complete_through` so far.
+    pub fn get_completed(&self) -> u64 {
+        self.completed.load(Ordering::Acquire)
+    }
+
+    /// Waits until a claim holder reports that everything before offset has
+    /// been processed.  A pusher that did not get the claim can use this to
+    /// wait for its value, passing the offset returned by `push` plus the
+    /// value's count.
+    ///
+    /// This does not depend on any particular async runtime.
+    pub async fn wait_for_completion(&self, offset: u64) {
+        self.completion
+            .wait_for(|| (self.get_completed() >= offset).then_some(()))
+            .await
+    }
+
+    /// Prevents any further pushes.  Values that are already queued are
+    /// still consumed by the claim holder, so a log writer can shut down by
+    /// closing the queue and letting the final claim holder drain it.
+    ///
+    /// Returns true if this call closed the queue, and false if it was
+    /// already closed.
+    pub fn close(&self) -> bool {
+        let closed = unsafe {
+            atomic_try_update(&self.head, |head: &mut CountingClaimHead<T>| {
+                let flag = head.next.get_flag();
+                head.next.set_flag(flag | CLOSED);
+                (flag & CLOSED == 0, flag & CLOSED == 0)
+            })
+        };
+        #[cfg(feature = "replay")]
+        if closed {
+            self.replay.record(ReplayKind::Close, self.get_offset());
+        }
+        closed
+    }
+
+    pub fn is_closed(&self) -> bool {
+        unsafe {
+            atomic_try_update(&self.head, |head: &mut CountingClaimHead<T>| {
+                (false, head.next.get_flag() & CLOSED != 0)
+            })
+        }
     }
 
     pub fn get_offset(&self) -> u64 {
-        unsafe { atomic_try_update(&self.head, |head| (false, head.count_and_claim.get_val())) }
+        unsafe { atomic_try_update(&self.head, |head| (false, head.count)) }
+    }
+
+    /// Decodes the claim bit, the closed bit, and the offset from a single
+    /// read of the queue's head, without consuming anything.  This is meant
+    /// for health checks and debugging; the state may change as soon as it
+    /// is read.
+    pub fn state(&self) -> QueueState {
+        unsafe {
+            atomic_try_update(&self.head, |head| {
+                let flag = head.next.get_flag();
+                (
+                    false,
+                    QueueState {
+                        claimed: flag & CLAIMED != 0,
+                        closed: flag & CLOSED != 0,
+                        offset: head.count,
+                    },
+                )
+            })
+        }
+    }
+
+    /// Returns true if some thread currently has the claim.
+    pub fn claimed(&self) -> bool {
+        self.state().claimed
+    }
+
+    /// The amount (in the units of `Countable::get_count`) that has been
+    /// pushed, but not yet returned to a claim holder.  Values held aside by
+    /// `consume_up_to` count as pending.
+    ///
+    /// This reads the offset and the consumed offset separately, so it is
+    /// approximate while the queue is in use.  It is only meaningful for
+    /// accumulators that sum their values, such as `ByteOffset` and
+    /// `RecordCount`.
+    pub fn pending_len(&self) -> u64 {
+        let consumed = self.consumed.load(Ordering::Relaxed);
+        self.get_offset().saturating_sub(consumed)
+    }
+}
+
+impl<T, A> Drop for WriteOrderingQueue<T, A>
+where
+    T: Send,
+    A: Accumulate<T>,
+{
+    fn drop(&mut self) {
+        let node = unsafe {
+            atomic_try_update(&self.head, |head: &mut CountingClaimHead<T>| {
+                (false, head.next.get_ptr())
+            })
+        };
+        // The nodes came from the pool, so they have to go back to it.
+        drop(ClaimBatch::new(node).recycle_into(&self.pool));
+    }
+}
+
+/// Offset reservation with padding.  This only makes sense when offsets are
+/// byte offsets, so it is limited to the default accumulator.
+impl<T> WriteOrderingQueue<T, ByteOffset>
+where
+    T: Send + Countable,
+{
+    /// Like push, but pads the offset so that val starts on a multiple of
+    /// align, and so that the region reserved for it ends on one (for
+    /// instance, 4096 for `O_DIRECT` writes).  The padding is added in the
+    /// same CAS as the push.  Returns the reserved region as (start, end),
+    /// and true iff we have the claim.
+    ///
+    /// Consumers only see the values, so a consumer that places them at
+    /// their offsets should apply the same rounding as it drains them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if align is not a power of two, if the queue has been closed,
+    /// or if the offset would overflow (see `try_push_aligned`).
+    pub fn push_aligned(&self, val: T, align: u64) -> ((u64, u64), bool) {
+        match self.try_push_aligned(val, align) {
+            Ok(ret) => ret,
+            Err(err) => panic!("WriteOrderingQueue::push_aligned failed: {err}"),
+        }
+    }
+    /// Like push_aligned, but returns val in a `ClaimError` instead of
+    /// panicking if the queue is closed or the offset would overflow.
+    pub fn try_push_aligned(
+        &self,
+        val: T,
+        align: u64,
+    ) -> Result<((u64, u64), bool), ClaimError<T>> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        let round_up = |offset: u64| Some(offset.checked_add(align - 1)? & !(align - 1));
+        let sz = val.get_count();
+        self.try_push_reserving(val, |old_count| {
+            let start = round_up(old_count)?;
+            Some((start, round_up(start.checked_add(sz)?)?))
+        })
+        .map(|(start, end, have_claim)| ((start, end), have_claim))
+    }
+}
+
+/// A group-commit log writer built on `WriteOrderingQueue`.
+///
+/// Threads append records with `append`.  Whichever thread obtains the claim
+/// passes everything that has been queued to the writer in one call (for
+/// instance, one write and fsync of a log file), marks it complete, and
+/// repeats until the queue is empty.  The other threads return immediately,
+/// and can await the returned future to find out when their record has been
+/// written.
+///
+/// The claim guarantees the writer is never called concurrently, so it may
+/// be an `FnMut`.
+pub struct GroupCommit<T, W>
+where
+    T: Send + Countable,
+    W: FnMut(ClaimBatch<T>) + Send,
+{
+    queue: WriteOrderingQueue<T>,
+    writer: UnsafeCell<W>,
+    /// Set if the writer panicked.  Records that were not complete by then
+    /// will never be written.
+    failed: AtomicBool,
+}
+
+/// Why a record passed to `GroupCommit::append` was not written.
+#[derive(Debug, PartialEq, Eq)]
+pub enum GroupCommitError {
+    /// The writer panicked on the batch that contained the record, or on an
+    /// earlier one.
+    WriterPanicked,
+}
+
+impl Error for GroupCommitError {}
+
+impl Display for GroupCommitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+unsafe impl<T, W> Sync for GroupCommit<T, W>
+where
+    T: Send + Countable,
+    W: FnMut(ClaimBatch<T>) + Send,
+{
+}
+
+impl<T, W> GroupCommit<T, W>
+where
+    T: Send + Countable,
+    W: FnMut(ClaimBatch<T>) + Send,
+{
+    pub fn new(writer: W) -> Self {
+        GroupCommit {
+            queue: Default::default(),
+            writer: UnsafeCell::new(writer),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Appends record, returning its offset and a future that resolves once
+    /// the writer has been called on it (and on everything before it).  If
+    /// this thread gets the claim, the record has been written by the time
+    /// this returns, and the future is already ready.
+    ///
+    /// Returns the record in a `ClaimError` if the queue has been closed, or
+    /// if its offset would overflow; see `WriteOrderingQueue::try_push`.
+    ///
+    /// If the writer panics, the queue is closed, the records that were
+    /// queued behind the failed batch are dropped without being written, and
+    /// the panic is resumed (on the thread that ran the writer) once the
+    /// claim has been released.  The futures for every record that was not
+    /// written resolve to `GroupCommitError::WriterPanicked`, and later
+    /// appends return `ClaimError::Closed`.
+    pub fn append(
+        &self,
+        record: T,
+    ) -> Result<(u64, impl Future<Output = Result<(), GroupCommitError>> + '_), ClaimError<T>> {
+        let len = record.get_count();
+        let (offset, have_claim) = self.queue.try_push(record)?;
+        if have_claim {
+            self.drain();
+        }
+        // A zero length record is done once everything before it is.
+        Ok((offset, self.wait_for_write(offset + len)))
+    }
+
+    async fn wait_for_write(&self, end: u64) -> Result<(), GroupCommitError> {
+        self.queue
+            .completion
+            .wait_for(|| {
+                if self.queue.get_completed() >= end {
+                    Some(Ok(()))
+                } else if self.failed.load(Ordering::Acquire) {
+                    // Nothing completes after a failure, since the queue is
+                    // closed.
+                    Some(Err(GroupCommitError::WriterPanicked))
+                } else {
+                    None
+                }
+            })
+            .await
+    }
+
+    fn drain(&self) {
+        loop {
+            let (batch, claimed, offset) = self.queue.consume_through(usize::MAX, u64::MAX);
+            if !claimed {
+                return;
+            }
+            // Safety: we hold the claim, so nobody else is using the writer.
+            let writer = unsafe { &mut *self.writer.get() };
+            if let Err(payload) = catch_unwind(AssertUnwindSafe(|| writer(batch))) {
+                self.queue.close();
+                while self.queue.consume_or_release_claim().1 {}
+                self.failed.store(true, Ordering::Release);
+                self.queue.completion.wake_all();
+                resume_unwind(payload);
+            }
+            self.queue.complete_through(offset);
+        }
+    }
+
+    /// The underlying queue, for `close`, `get_completed`, and so on.
+    /// Callers must not consume from it directly.
+    pub fn queue(&self) -> &WriteOrderingQueue<T> {
+        &self.queue
+    }
+}
+
+/// A handle to a consumer task started by `spawn_claim_consumer`.
+#[cfg(feature = "tokio-rt")]
+pub struct ClaimConsumer<T>
+where
+    T: Send,
+{
+    queue: Arc<ClaimQueue<T>>,
+    wake: Arc<Notify>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+/// Spawns a tokio task that consumes queue, passing each batch to handler
+/// and awaiting the result.
+///
+/// Values must be pushed through the returned `ClaimConsumer`.  When a push
+/// wins the claim, the claim passes to the task, which is woken up and
+/// drains the queue until `consume_or_release_claim` releases the claim.
+/// The next push to win the claim wakes it again.  Since tokio's `Notify`
+/// remembers a wakeup that arrives before the task parks, none are lost.
+///
+/// Must be called from within a tokio runtime.
+#[cfg(feature = "tokio-rt")]
+pub fn spawn_claim_consumer<T, F, Fut>(
+    queue: Arc<ClaimQueue<T>>,
+    mut handler: F,
+) -> ClaimConsumer<T>
+where
+    T: Send + 'static,
+    F: FnMut(ClaimBatch<T>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let wake = Arc::new(Notify::new());
+    let task = tokio::spawn({
+        let queue = queue.clone();
+        let wake = wake.clone();
+        async move {
+            loop {
+                wake.notified().await;
+                // Pushes through the ClaimConsumer hand us the claim before
+                // waking us, so if it is not held, we were woken by close.
+                if !queue.claimed() {
+                    if queue.is_closed() {
+                        return;
+                    }
+                    continue;
+                }
+                loop {
+                    let (batch, claimed) = queue.consume_or_release_claim();
+                    if !claimed {
+                        break;
+                    }
+                    handler(batch).await;
+                }
+                // The queue was empty when we released the claim, so if it
+                // is closed, nothing else can arrive.
+                if queue.is_closed() {
+                    return;
+                }
+            }
+        }
+    });
+    ClaimConsumer { queue, wake, task }
+}
+
+#[cfg(feature = "tokio-rt")]
+impl<T> ClaimConsumer<T>
+where
+    T: Send,
+{
+    /// Pushes val, and wakes the consumer task if this push won the claim.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the queue has been closed.
+    pub fn push(&self, val: T) {
+        if self.queue.push(val) {
+            self.wake.notify_one();
+        }
+    }
+
+    /// Closes the queue, and waits for the consumer task to drain it and
+    /// exit.  Returns the task's result, which is an error if the handler
+    /// panicked.
+    pub async fn shutdown(self) -> Result<(), tokio::task::JoinError> {
+        self.queue.close();
+        self.wake.notify_one();
+        self.task.await
     }
 }
@@ -0,0 +1,103 @@
+//! A counter that spreads its updates over several cells, for counts that
+//! are updated far more often than they are read.
+//!
+//! Every update to a single `Atom` counter has to own its cache line, so
+//! under enough concurrent increments, the line bounces between cores and
+//! throughput stops scaling.  `ShardedCounter` (modeled on Java's
+//! `LongAdder`) gives each thread a home cell, padded out to its own cache
+//! line, and only adds the cells up when the total is read.
+//!
+//! As with `stack::ShardedStack`, this gives up linearizability: `sum()`
+//! reads the cells one at a time, so updates that race with it may or may
+//! not be included.  Once updates stop, it is exact.
+
+use crossbeam_utils::CachePadded;
+
+use crate::{atomic_try_update, stack::thread_hint, Atom};
+
+pub struct ShardedCounter {
+    cells: Box<[CachePadded<Atom<i64, u64>>]>,
+}
+
+impl Default for ShardedCounter {
+    /// Creates one cell per available CPU.
+    fn default() -> Self {
+        Self::with_shards(
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+        )
+    }
+}
+
+impl ShardedCounter {
+    /// Panics if shards is zero.
+    pub fn with_shards(shards: usize) -> Self {
+        assert!(shards > 0, "ShardedCounter needs at least one shard");
+        Self {
+            cells: (0..shards).map(|_| Default::default()).collect(),
+        }
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Adds delta to this thread's cell.  The counter wraps on overflow.
+    pub fn add(&self, delta: i64) {
+        let cell = &self.cells[thread_hint() % self.cells.len()];
+        unsafe {
+            atomic_try_update(cell, |val| {
+                *val = val.wrapping_add(delta);
+                (true, ())
+            })
+        }
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn decrement(&self) {
+        self.add(-1);
+    }
+
+    /// Returns the total of every cell.  Not atomic; see the module
+    /// documentation.
+    pub fn sum(&self) -> i64 {
+        self.cells.iter().fold(0, |sum, cell| {
+            sum.wrapping_add(unsafe { atomic_try_update(cell, |val| (false, *val)) })
+        })
+    }
+
+    /// Returns the total, and zeroes each cell as it is read.  Every update
+    /// is counted by exactly one call to `sum_and_reset()` or `flush_into()`,
+    /// even if they race.
+    pub fn sum_and_reset(&self) -> i64 {
+        self.cells.iter().fold(0, |sum, cell| {
+            sum.wrapping_add(unsafe {
+                atomic_try_update(cell, |val| {
+                    let taken = *val;
+                    *val = 0;
+                    (taken != 0, taken)
+                })
+            })
+        })
+    }
+
+    /// Moves the counts accumulated so far into target, such as a global
+    /// total that is published less often than it is updated.  Returns the
+    /// amount that was added to target.
+    pub fn flush_into(&self, target: &Atom<i64, u64>) -> i64 {
+        let flushed = self.sum_and_reset();
+        if flushed != 0 {
+            unsafe {
+                atomic_try_update(target, |val| {
+                    *val = val.wrapping_add(flushed);
+                    (true, ())
+                })
+            }
+        }
+        flushed
+    }
+}
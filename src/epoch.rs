@@ -0,0 +1,273 @@
+//! A minimal epoch-based reclamation scheme, for data structures in this
+//! crate whose readers follow pointers to nodes that other threads may
+//! unlink in race, and that would rather not pay for a hazard pointer
+//! publish-and-revalidate on every access.
+//!
+//! Readers `pin()` the collector before loading any pointers, which records
+//! the global epoch in one of the participant slots.  Threads that unlink a
+//! node `retire()` it instead of freeing it, and the node is tagged with the
+//! global epoch at that point.  The global epoch only advances when every
+//! pinned participant has observed the current one, so once it has advanced
+//! twice past a node's tag, every reader that could have seen the node has
+//! unpinned, and the node can be freed.
+//!
+//! As with `hazard::HazardSlots`, the collector is owned by each data
+//! structure, rather than being global, and there are no thread-local
+//! handles: the participant slots are a fixed array that pinned readers
+//! reserve for the duration of the pin.  Retired nodes go on a list that is
+//! only ever pushed to or detached as a whole (the `Stack::pop_all` pattern,
+//! inlined here because `Stack` itself embeds a collector when the `epoch`
+//! feature is enabled), and every `COLLECT_EVERY` retirements, the retiring
+//! thread tries to advance the epoch and frees whatever has become
+//! unreachable.  A reader that stays pinned holds back the epoch (and
+//! therefore all reclamation), so pins should be short.
+use std::{
+    hint::spin_loop,
+    ptr::null_mut,
+    sync::atomic::{fence, Ordering},
+};
+
+use crate::{atomic_try_update, Atom, Node, NodeIterator};
+
+/// Number of threads that can concurrently pin one collector.  Additional
+/// readers spin until a slot frees up.
+pub(crate) const EPOCH_SLOTS: usize = 16;
+
+/// How many nodes are retired between attempts to advance the epoch.
+const COLLECT_EVERY: u64 = 32;
+
+/// A participant slot.  Zero if the slot is free, otherwise the epoch the
+/// reader pinned, shifted left one bit, with the low bit set.
+#[derive(Default)]
+struct Participant {
+    val: u64,
+}
+
+impl Participant {
+    fn pinned(epoch: u64) -> Self {
+        Self {
+            val: (epoch << 1) | 1,
+        }
+    }
+    fn epoch(&self) -> Option<u64> {
+        (self.val & 1 != 0).then_some(self.val >> 1)
+    }
+}
+
+/// A retired node, and the type-erased function that frees it.
+struct Retired {
+    epoch: u64,
+    ptr: *mut (),
+    free: unsafe fn(*mut ()),
+}
+
+// Retired nodes are only freed once no other thread can reach them, and
+// `Collector::retire` requires that they can be freed from any thread.
+unsafe impl Send for Retired {}
+
+struct GarbageHead {
+    head: *mut Node<Retired>,
+}
+
+pub(crate) struct Collector {
+    epoch: Atom<u64, u64>,
+    participants: [Atom<Participant, u64>; EPOCH_SLOTS],
+    garbage: Atom<GarbageHead, u64>,
+    /// Total number of calls to retire, used to pace collection.
+    retired: Atom<u64, u64>,
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Self {
+            epoch: Default::default(),
+            participants: std::array::from_fn(|_| Default::default()),
+            garbage: Default::default(),
+            retired: Default::default(),
+        }
+    }
+}
+
+/// A pinned participant slot.  Dropping the guard unpins it.
+pub(crate) struct EpochGuard<'a> {
+    slot: &'a Atom<Participant, u64>,
+}
+
+impl Collector {
+    /// Pins the current epoch.  Nodes that are reachable after this returns
+    /// will not be freed until the guard is dropped.  This spins if all
+    /// `EPOCH_SLOTS` slots are in use.
+    pub(crate) fn pin(&self) -> EpochGuard<'_> {
+        let mut epoch = self.load_epoch();
+        let guard = loop {
+            if let Some(guard) = self.try_reserve(epoch) {
+                break guard;
+            }
+            spin_loop();
+            epoch = self.load_epoch();
+        };
+        loop {
+            // Orders the slot update before any loads of the data structure,
+            // and pairs with the fence in try_advance.
+            fence(Ordering::SeqCst);
+            let current = self.load_epoch();
+            if current == epoch {
+                return guard;
+            }
+            // The epoch advanced while we were reserving the slot, possibly
+            // more than once, since try_advance could not see us yet.  Catch
+            // up, so the slot never lags far enough behind for our nodes to
+            // be freed.
+            epoch = current;
+            unsafe {
+                atomic_try_update(guard.slot, |s| {
+                    *s = Participant::pinned(epoch);
+                    (true, ())
+                })
+            }
+        }
+    }
+
+    /// Returns true if any reader is pinned.  Call this after unlinking a
+    /// node: if it returns false, no reader can have reached the node, and
+    /// it can be freed right away instead of being retired.
+    #[cfg(feature = "epoch")]
+    pub(crate) fn is_pinned(&self) -> bool {
+        // Orders the unlinking CAS before the slot loads, and pairs with the
+        // fence in pin.
+        fence(Ordering::SeqCst);
+        self.participants
+            .iter()
+            .any(|slot| unsafe { atomic_try_update(slot, |s| (false, s.epoch().is_some())) })
+    }
+
+    fn try_reserve(&self, epoch: u64) -> Option<EpochGuard<'_>> {
+        self.participants.iter().find_map(|slot| {
+            let reserved = unsafe {
+                atomic_try_update(slot, |s| {
+                    if s.epoch().is_none() {
+                        *s = Participant::pinned(epoch);
+                        (true, true)
+                    } else {
+                        (false, false)
+                    }
+                })
+            };
+            reserved.then(|| EpochGuard { slot })
+        })
+    }
+
+    /// Hands ptr to the collector, which frees it with `Box::from_raw` once
+    /// no pinned reader can still reach it.
+    ///
+    /// ptr must come from `Box::into_raw`, and it must already be unlinked,
+    /// so that readers that pin after this call can't find it.  The box may
+    /// be dropped on any thread that retires a node, so its contents must be
+    /// safe to drop there (typically, they are Send, or `MaybeUninit`).
+    pub(crate) unsafe fn retire<T>(&self, ptr: *mut T) {
+        unsafe fn free<T>(ptr: *mut ()) {
+            drop(Box::from_raw(ptr as *mut T));
+        }
+        // Orders the unlinking CAS before the epoch load.
+        fence(Ordering::SeqCst);
+        self.push_garbage(Retired {
+            epoch: self.load_epoch(),
+            ptr: ptr as *mut (),
+            free: free::<T>,
+        });
+        let retired = atomic_try_update(&self.retired, |n| {
+            *n += 1;
+            (true, *n)
+        });
+        // Nothing new can be freed unless the epoch advances.
+        if retired % COLLECT_EVERY == 0 && self.try_advance() {
+            self.collect();
+        }
+    }
+
+    /// Advances the global epoch, unless some pinned reader has not observed
+    /// the current one yet.  Returns false if the epoch did not change.
+    fn try_advance(&self) -> bool {
+        let epoch = self.load_epoch();
+        fence(Ordering::SeqCst);
+        let lagging = self.participants.iter().any(|slot| {
+            let pinned = unsafe { atomic_try_update(slot, |s| (false, s.epoch())) };
+            pinned.is_some_and(|pinned| pinned != epoch)
+        });
+        if lagging {
+            return false;
+        }
+        unsafe {
+            atomic_try_update(&self.epoch, |e| {
+                // Another thread may have advanced it since we loaded it.
+                let advance = *e == epoch;
+                if advance {
+                    *e += 1;
+                }
+                (advance, advance)
+            })
+        }
+    }
+
+    /// Frees the nodes that were retired at least two epochs ago, and puts
+    /// the rest back.
+    fn collect(&self) {
+        let epoch = self.load_epoch();
+        for retired in self.take_garbage() {
+            if retired.epoch + 2 <= epoch {
+                unsafe { (retired.free)(retired.ptr) };
+            } else {
+                self.push_garbage(retired);
+            }
+        }
+    }
+
+    fn push_garbage(&self, retired: Retired) {
+        let node = Box::into_raw(Box::new(Node {
+            val: retired,
+            next: null_mut(),
+        }));
+        unsafe {
+            atomic_try_update(&self.garbage, |g| {
+                (*node).next = g.head;
+                g.head = node;
+                (true, ())
+            })
+        }
+    }
+
+    fn take_garbage(&self) -> NodeIterator<Retired> {
+        let head = unsafe {
+            atomic_try_update(&self.garbage, |g| {
+                let head = g.head;
+                g.head = null_mut();
+                (!head.is_null(), head)
+            })
+        };
+        NodeIterator::new(head)
+    }
+
+    fn load_epoch(&self) -> u64 {
+        unsafe { atomic_try_update(&self.epoch, |e| (false, *e)) }
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        // Nothing can be pinned, since that would borrow the collector.
+        for retired in self.take_garbage() {
+            unsafe { (retired.free)(retired.ptr) };
+        }
+    }
+}
+
+impl Drop for EpochGuard<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            atomic_try_update(self.slot, |s| {
+                *s = Participant::default();
+                (true, ())
+            })
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! A minimal hazard pointer scheme, used by data structures in this crate that
+//! need to dereference nodes that other threads may be in the middle of
+//! removing.
+//!
+//! A reader reserves a slot, publishes the pointer it is about to follow, and
+//! then re-validates that the pointer is still reachable from the data
+//! structure (typically inside its `atomic_try_update` lambda).  Once that
+//! validation succeeds, the target cannot be freed until the reader releases
+//! the slot: any thread that unlinks a node must check `is_protected` (after
+//! its unlinking CAS) and defer the free if the node is protected.
+//!
+//! Unlike the usual hazard pointer implementations, the slots are owned by
+//! each data structure instead of being global, and there is no background
+//! reclamation.  Callers are expected to keep a retired list and retry it
+//! from time to time.
+use std::hint::spin_loop;
+
+use crate::{atomic_try_update, Atom};
+
+/// Number of threads that can concurrently hold a hazard pointer on one data
+/// structure.  Additional readers spin until a slot frees up.
+pub(crate) const HAZARD_SLOTS: usize = 16;
+
+/// Slot states.  Any other value is a protected pointer.
+const FREE: usize = 0;
+const RESERVED: usize = 1;
+
+#[derive(Default)]
+struct HazardSlot {
+    val: usize,
+}
+
+pub(crate) struct HazardSlots {
+    slots: [Atom<HazardSlot, u64>; HAZARD_SLOTS],
+}
+
+impl Default for HazardSlots {
+    fn default() -> Self {
+        Self {
+            slots: std::array::from_fn(|_| Default::default()),
+        }
+    }
+}
+
+/// A reserved hazard slot.  Dropping the guard releases the slot.
+pub(crate) struct HazardGuard<'a> {
+    slot: &'a Atom<HazardSlot, u64>,
+}
+
+impl HazardSlots {
+    /// Reserves a slot.  This spins if all `HAZARD_SLOTS` slots are in use.
+    pub(crate) fn acquire(&self) -> HazardGuard<'_> {
+        loop {
+            if let Some(guard) = self.try_acquire() {
+                return guard;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Reserves two slots.  Callers must use this instead of calling
+    /// `acquire()` twice, which could deadlock if every slot ends up held
+    /// by a thread that is waiting for its second one.
+    pub(crate) fn acquire_pair(&self) -> (HazardGuard<'_>, HazardGuard<'_>) {
+        loop {
+            let first = self.acquire();
+            if let Some(second) = self.try_acquire() {
+                return (first, second);
+            }
+            drop(first);
+            spin_loop();
+        }
+    }
+
+    fn try_acquire(&self) -> Option<HazardGuard<'_>> {
+        self.slots.iter().find_map(|slot| {
+            let reserved = unsafe {
+                atomic_try_update(slot, |s| {
+                    if s.val == FREE {
+                        s.val = RESERVED;
+                        (true, true)
+                    } else {
+                        (false, false)
+                    }
+                })
+            };
+            reserved.then(|| HazardGuard { slot })
+        })
+    }
+
+    /// Returns true if some reader currently protects ptr.
+    pub(crate) fn is_protected<T>(&self, ptr: *mut T) -> bool {
+        self.slots
+            .iter()
+            .any(|slot| unsafe { atomic_try_update(slot, |s| (false, s.val == ptr as usize)) })
+    }
+
+    /// Collects the currently protected pointers.  The result is only useful
+    /// for pointers that were unlinked before the call; anything unlinked
+    /// afterwards may gain new readers.
+    pub(crate) fn snapshot<T>(&self) -> Vec<*mut T> {
+        self.slots
+            .iter()
+            .filter_map(|slot| {
+                let val = unsafe { atomic_try_update(slot, |s| (false, s.val)) };
+                if val == FREE || val == RESERVED {
+                    None
+                } else {
+                    Some(val as *mut T)
+                }
+            })
+            .collect()
+    }
+}
+
+impl HazardGuard<'_> {
+    /// Publishes ptr.  The caller must re-validate that ptr is still
+    /// reachable after this returns, and before dereferencing it.
+    pub(crate) fn protect<T>(&self, ptr: *mut T) {
+        let val = if ptr.is_null() {
+            RESERVED
+        } else {
+            ptr as usize
+        };
+        unsafe {
+            atomic_try_update(self.slot, |s| {
+                s.val = val;
+                (true, ())
+            })
+        }
+    }
+}
+
+impl Drop for HazardGuard<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            atomic_try_update(self.slot, |s| {
+                s.val = FREE;
+                (true, ())
+            })
+        }
+    }
+}
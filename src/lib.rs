@@ -1,16 +1,19 @@
 //! Primitives that make it easy to implement correct lock-free algorithms
 //!
-//! `atomic_try_update` is the main entry-point to this library, but (with
-//! the exception of `NonceStack`) the included example code is also designed
-//! to be used in production.  Each module implements a different family of
-//! example algorithms.  If you simply want to use general-purpose algorithms
-//! without modification, start with the public APIs of the data structures
-//! in those modules.
+//! `atomic_try_update` is the main entry-point to this library, but the
+//! included example code is also designed to be used in production.  Each
+//! module implements a different family of example algorithms.  If you
+//! simply want to use general-purpose algorithms without modification, start
+//! with the public APIs of the data structures in those modules.
 //!
 //! If you want to start implementing your own specialized lock-free logic,
 //! start with this page, then read the top-level descriptions of each
 //! of the modules this crate exports.
-use std::{marker::PhantomData, ptr::null_mut};
+use std::{
+    alloc::Layout,
+    marker::PhantomData,
+    ptr::{null_mut, NonNull},
+};
 
 // AtomicCell uses a lock-based fallback for u128 because stable rust does
 // not include AtomicU128.
@@ -20,13 +23,27 @@ use std::{marker::PhantomData, ptr::null_mut};
 // portable_atomic where possible.
 //
 // https://docs.rs/portable-atomic/latest/portable_atomic/struct.AtomicU128.html
+use allocator_api2::alloc::{Allocator, Global};
 use crossbeam_utils::atomic::AtomicCell;
 
+/// The allocator API used by `stack::Stack` and `NodeIterator`.  On stable
+/// rust, this is a copy of the unstable `std::alloc::Allocator` trait.
+pub use allocator_api2;
+
+pub mod arc;
 pub mod barrier;
+pub mod bitmap;
 pub mod bits;
 pub mod claim;
+pub mod counter;
+mod epoch;
+mod hazard;
 pub mod once;
+pub mod queue;
+pub mod semaphore;
+pub mod seqlock;
 pub mod stack;
+mod waker_list;
 
 /// A wrapper that allows an instance of type T to be treated as though it is
 /// an atomic integer type (in the style of a C/C++ union).  Use
@@ -41,7 +58,7 @@ pub struct Atom<T, U> {
 
 impl<T, U> Default for Atom<T, U>
 where
-    U: Default + Send,
+    U: AtomInt,
 {
     /// This creates a new instance of Atom, initializing the contents to
     /// all-zero bytes.
@@ -50,18 +67,56 @@ where
     /// default instance of T in the atom?  If we do that, what happens to
     /// the uninitialized padding bytes?
     fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, U> Atom<T, U>
+where
+    U: AtomInt,
+{
+    /// Like `default`, but usable in const contexts, such as `static`
+    /// initializers.
+    pub const fn new() -> Self {
         assert!(std::mem::size_of::<T>() <= std::mem::size_of::<U>());
         assert!(
             std::mem::size_of::<U>() <= 4
                 || std::mem::size_of::<T>() > std::mem::size_of::<U>() / 2
         );
         Self {
-            union: Default::default(),
-            inner: Default::default(),
+            union: PhantomData,
+            // AtomInt is only implemented for the unsigned integer types, so
+            // all-zero bytes are a valid instance of U.
+            inner: AtomicCell::new(unsafe { std::mem::zeroed() }),
         }
     }
 }
 
+/// The unsigned integer types that can back an `Atom`: `u8`, `u16`, `u32`,
+/// `u64` and `u128`.  The trait is sealed, since `Atom::new` relies on
+/// all-zero bytes being a valid `U`.
+///
+/// ```compile_fail
+/// // A String is not all-zero bytes when empty.
+/// let atom = atomic_try_update::Atom::<usize, String>::new();
+/// ```
+pub trait AtomInt: sealed::Sealed + Copy + Eq + Send {}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+macro_rules! impl_atom_int {
+    ($($int:ty),*) => {
+        $(
+            impl sealed::Sealed for $int {}
+            impl AtomInt for $int {}
+        )*
+    };
+}
+
+impl_atom_int!(u8, u16, u32, u64, u128);
+
 // TODO: Restrict these so that ptr T is OK, but most other things are not.
 // Also, it would be nice if the type of T was richer so that we could avoid
 // these.
@@ -203,6 +258,24 @@ where
     F: Fn(&mut T) -> (bool, R),
     U: Copy + Eq,
 {
+    atomic_try_update_counting_retries(state, func).0
+}
+
+/// `atomic_try_update`, but also returns the number of failed compare and
+/// swaps, for data structures that report contention statistics.
+///
+/// # Safety
+///
+/// See `atomic_try_update`.
+pub(crate) unsafe fn atomic_try_update_counting_retries<T, U, F, R>(
+    state: &Atom<T, U>,
+    func: F,
+) -> (R, u64)
+where
+    F: Fn(&mut T) -> (bool, R),
+    U: Copy + Eq,
+{
+    let mut retries = 0;
     let mut old = state.inner.load();
     let mut newval = old;
     loop {
@@ -212,12 +285,13 @@ where
             let newval_ptr: *mut T = newval_ptr as *mut T;
             res = func(&mut *newval_ptr);
             if !res.0 {
-                return res.1;
+                return (res.1, retries);
             }
         }
         match state.inner.compare_exchange(old, newval) {
-            Ok(_) => return res.1,
+            Ok(_) => return (res.1, retries),
             Err(val) => {
+                retries += 1;
                 old = val;
                 newval = old;
             }
@@ -238,29 +312,40 @@ pub struct Node<T> {
     pub next: *mut Node<T>,
 }
 
-unsafe impl<T> Send for NodeIterator<T> {}
+unsafe impl<T, A: Allocator + Send> Send for NodeIterator<T, A> {}
 
 /// A consuming iterator over a value of type Node.
 ///
+/// The nodes must have been allocated with `alloc`.  (Typically, the list
+/// was detached from a data structure that allocated its nodes with it.)
+///
 /// TODO: Document safety here, and (ideally) figure out how to
 /// allow people to write atomic_try_update lambdas from outside
 /// this package, but not write garbage to next from `safe` code.
 /// That way, this API won't need an `unsafe` annotation (which
 /// it is currently missing).
-pub struct NodeIterator<T> {
+pub struct NodeIterator<T, A: Allocator = Global> {
     node: *mut Node<T>,
+    alloc: A,
 }
 
-impl<T> Iterator for NodeIterator<T> {
+impl<T, A: Allocator> Iterator for NodeIterator<T, A> {
     type Item = T;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.node.is_null() {
             return None;
         }
-        let popped: Box<Node<T>> = unsafe { Box::from_raw(self.node) };
-        self.node = popped.next;
-        Some(popped.val)
+        let popped = self.node;
+        unsafe {
+            self.node = (*popped).next;
+            let val = std::ptr::read(&(*popped).val);
+            self.alloc.deallocate(
+                NonNull::new_unchecked(popped).cast(),
+                Layout::new::<Node<T>>(),
+            );
+            Some(val)
+        }
     }
 }
 
@@ -269,24 +354,47 @@ impl<T> NodeIterator<T> {
     ///
     /// TODO: This could take a Box, and then we wouldn't need to add an unsafe annotation to it.
     pub fn new(node: *mut Node<T>) -> Self {
-        Self { node }
+        Self::new_in(node, Global)
+    }
+
+    /// Like `next`, but returns the node that held the value instead of
+    /// freeing it, so the allocation can be reused (for instance, with
+    /// `Stack::push_node`).  The returned node's `next` pointer is null.
+    pub fn next_node(&mut self) -> Option<Box<Node<T>>> {
+        if self.node.is_null() {
+            return None;
+        }
+        // Global allocates with the same allocator as Box.
+        let mut popped: Box<Node<T>> = unsafe { Box::from_raw(self.node) };
+        self.node = popped.next;
+        popped.next = null_mut();
+        Some(popped)
+    }
+}
+
+impl<T, A: Allocator> NodeIterator<T, A> {
+    /// Takes ownership of node, which (along with the rest of the list) was
+    /// allocated with alloc.
+    pub fn new_in(node: *mut Node<T>, alloc: A) -> Self {
+        Self { node, alloc }
     }
 
     pub fn rev(mut self) -> Self {
-        let mut ret = Self { node: null_mut() };
+        let mut rev = null_mut();
         while !self.node.is_null() {
             let popped = self.node;
             unsafe {
                 self.node = (*popped).next;
-                (*popped).next = ret.node;
-                ret.node = popped;
+                (*popped).next = rev;
+                rev = popped;
             }
         }
-        ret
+        self.node = rev;
+        self
     }
 }
 
-impl<T> Drop for NodeIterator<T> {
+impl<T, A: Allocator> Drop for NodeIterator<T, A> {
     fn drop(&mut self) {
         for _ in self {}
     }
@@ -1,12 +1,36 @@
 //! A wait-free alternative to `std::sync::OnceLock`, with helper methods that make it easier to
 //! correctly register state at startup.
-use std::{error::Error, fmt::Display, ptr::null_mut};
+use std::{
+    alloc::{alloc, dealloc, handle_alloc_error, Layout},
+    borrow::Borrow,
+    cell::UnsafeCell,
+    collections::HashMap,
+    convert::Infallible,
+    error::Error,
+    fmt::Display,
+    hash::{BuildHasher, Hash, RandomState},
+    marker::PhantomData,
+    mem::{align_of, size_of, transmute_copy, MaybeUninit},
+    ops::Deref,
+    panic::{RefUnwindSafe, UnwindSafe},
+    ptr::{null_mut, NonNull},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "stats")]
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::{
     atomic_try_update,
+    barrier::ShutdownBarrier,
     bits::{Align8, FlagPtr},
+    waker_list::WakerList,
     Atom,
 };
 
@@ -17,6 +41,11 @@ enum Lifecycle {
     Setting,
     Set,
     Dead,
+    /// A set raced with a prepared cell, or an initializer panicked.
+    Poisoned,
+    /// `set_boxed` is storing an unsized value.  It moves self to Set without
+    /// running any user code, so callers that need the outcome yield to it.
+    Publishing,
 }
 
 /// Not exposed in external API.  We panic on the field `UseAfterFreeBug`, and map
@@ -27,6 +56,7 @@ enum OnceLockFreeInternalError {
     AttemptToSetConcurrently,
     UseAfterFreeBug,
     UnpreparedForSet,
+    Poisoned,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -35,6 +65,9 @@ pub enum OnceLockFreeError {
     AttemptToReadWhenUnset,
     AttemptToSetConcurrently,
     UnpreparedForSet,
+    Poisoned,
+    /// Returned by the `wait` variants that take a timeout.
+    Timeout,
 }
 
 impl Error for OnceLockFreeError {}
@@ -58,12 +91,23 @@ fn panic_on_memory_bug(err: OnceLockFreeInternalError) -> OnceLockFreeError {
             panic!("Encountered use-after-free in OnceLockFree");
         }
         OnceLockFreeInternalError::UnpreparedForSet => OnceLockFreeError::UnpreparedForSet,
+        OnceLockFreeInternalError::Poisoned => OnceLockFreeError::Poisoned,
     }
 }
 
+/// What a cell's flag_ptr points to: the `Align8<T>` that holds the value,
+/// or, if T is unsized, `boxed_marker()`.
+type Erased = Align8<()>;
+
 #[derive(Default)]
-struct OnceLockFreeState<T> {
-    flag_ptr: FlagPtr<Align8<T>>,
+struct OnceLockFreeState {
+    flag_ptr: FlagPtr<Erased>,
+}
+
+/// Stored in flag_ptr in place of the pointer to an unsized value, which is
+/// kept in `OnceLockFree::boxed` instead.
+fn boxed_marker() -> *mut Erased {
+    NonNull::dangling().as_ptr()
 }
 
 /// A wait-free alternative to `std::sync::OnceLock`
@@ -80,47 +124,162 @@ struct OnceLockFreeState<T> {
 /// the value you have a few options.
 ///
 /// If you need to memoize the result, use `get_or_prepare_to_set()` to check to see if the value
-/// has been set, and then use `set_prepared()` to install the value.  Do this in a way that
-/// guarantees that callers will not race to set the value.  After all the sets have completed, you
-/// can use `get()` or `get_or_prepare_to_set()` to read values that must be present.
+/// has been set, and then use the `SetPermit` it returns to install the value.  Do this in a way
+/// that guarantees that callers will not race to set the value.  After all the sets have completed,
+/// you can use `get()` or `get_or_prepare_to_set()` to read values that must be present.
 ///
 /// If you want to guarantee that no setters succeed after the first `get()`, and don't guarantee that
 /// all values are set by the time initialization completes, use `get_or_seal()`.
-pub struct OnceLockFree<T> {
-    inner: Atom<OnceLockFreeState<T>, u64>,
+/// Shutdown code that only needs to forbid future sets can use `seal()`.
+///
+/// T may be unsized (for instance, `str` or `[u8]`), so interned strings and
+/// byte slices can be published with `set_boxed` without the extra layer of
+/// boxing that `OnceLockFree<Box<str>>` adds.  The cell's state flags live in
+/// the low bits of a thin pointer in a u64 atom, and a fat pointer does not
+/// fit there, so an unsized value's pointer is stored next to the atom, and
+/// published by the transition to Set.  This keeps every cell lock free, but
+/// a reader that races with `set_boxed` on an unsized cell may yield until
+/// the pointer has been stored.
+pub struct OnceLockFree<T: ?Sized> {
+    inner: Atom<OnceLockFreeState, u64>,
+    /// The value's pointer, if T is unsized.  Only the thread that moves
+    /// self to Set writes it, before it does so, and it is only read in
+    /// the Set state.
+    boxed: UnsafeCell<MaybeUninit<*mut T>>,
+    /// Tasks and threads blocked in `wait` and `wait_blocking`.  Woken once self
+    /// is set, sealed or poisoned.
+    waiters: WakerList,
+    /// Called with the value when it is published.  See `with_on_set`.
+    on_set: Option<OnSet<T>>,
+    #[cfg(feature = "stats")]
+    stats: OnceLockFreeCounters,
 }
 
-impl<'a, T> OnceLockFree<T> {
-    /// Creates a new empty cell.
-    pub fn new() -> Self {
-        Default::default()
+/// A snapshot of the counters that each `OnceLockFree` maintains when the
+/// `stats` feature is enabled.  Cells that keep rejecting sets point at
+/// components that race to initialize the same global.
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OnceLockFreeStats {
+    /// Sets that returned `AlreadySet`.
+    pub already_set: u64,
+    /// Sets and prepares that returned `AttemptToSetConcurrently`.
+    pub set_concurrently: u64,
+}
+
+#[cfg(feature = "stats")]
+struct OnceLockFreeCounters {
+    already_set: AtomicU64,
+    set_concurrently: AtomicU64,
+}
+
+#[cfg(feature = "stats")]
+impl OnceLockFreeCounters {
+    const fn new() -> Self {
+        Self {
+            already_set: AtomicU64::new(0),
+            set_concurrently: AtomicU64::new(0),
+        }
     }
+}
 
-    pub fn get_or_prepare_to_set(&'a self) -> Result<Option<&'a T>, OnceLockFreeError> {
-        unsafe {
-            Ok(
+// RefUnwindSafe keeps `&OnceLockFree` usable across `catch_unwind`.
+type OnSet<T> = Box<dyn Fn(&T) + Send + Sync + RefUnwindSafe>;
+
+impl<'a, T: ?Sized> OnceLockFree<T> {
+    /// True if `*mut T` is a fat pointer, which does not fit in flag_ptr.
+    const UNSIZED: bool = size_of::<*mut T>() > size_of::<*mut Erased>();
+
+    /// Creates a new empty cell.  This is a const fn, so it can be used to
+    /// initialize a `static`.
+    pub const fn new() -> Self {
+        Self {
+            inner: Atom::new(),
+            boxed: UnsafeCell::new(MaybeUninit::uninit()),
+            waiters: WakerList::new(),
+            #[cfg(feature = "stats")]
+            stats: OnceLockFreeCounters::new(),
+            on_set: None,
+        }
+    }
+
+    /// Creates a new empty cell that calls on_set with the value when it is
+    /// published, so dependent subsystems can be started without polling or
+    /// waiting.
+    ///
+    /// on_set runs on the thread that published the value, after the value
+    /// is visible to other threads, and before the set method returns.  It
+    /// runs once per published value (`take` lets a cell publish again).
+    pub fn with_on_set<F>(on_set: F) -> Self
+    where
+        F: Fn(&T) + Send + Sync + RefUnwindSafe + 'static,
+    {
+        Self {
+            inner: Atom::new(),
+            boxed: UnsafeCell::new(MaybeUninit::uninit()),
+            waiters: WakerList::new(),
+            #[cfg(feature = "stats")]
+            stats: OnceLockFreeCounters::new(),
+            on_set: Some(Box::new(on_set)),
+        }
+    }
+
+    /// Returns the value if it has been set, or prepares self, and returns a
+    /// `SetPermit` that the caller uses to set it.  If the permit is dropped
+    /// without setting a value (for instance, because the initializer failed
+    /// or panicked), self goes back to being unset, so another caller can
+    /// initialize it.
+    ///
+    /// Callers must not race to prepare self.  If self is already prepared,
+    /// this returns `AttemptToSetConcurrently`, and poisons self, so that the
+    /// race is reported to every later caller instead of going unnoticed.
+    pub fn get_or_prepare_to_set(&'a self) -> Result<GetOrPrepare<'a, T>, OnceLockFreeError> {
+        self.prepare(true)
+    }
+
+    fn prepare(&'a self, poison_on_race: bool) -> Result<GetOrPrepare<'a, T>, OnceLockFreeError> {
+        let val = self
+            .yield_to_publisher(|| unsafe {
                 atomic_try_update(&self.inner, |s| match s.flag_ptr.get_flag().try_into() {
                     Ok(Lifecycle::NotSet) => {
                         s.flag_ptr.set_flag(Lifecycle::Setting.into());
-                        (true, Ok(None))
+                        (true, Some(Ok(None)))
+                    }
+                    Ok(Lifecycle::Setting) => {
+                        if poison_on_race {
+                            s.flag_ptr.set_flag(Lifecycle::Poisoned.into());
+                        }
+                        (
+                            poison_on_race,
+                            Some(Err(OnceLockFreeInternalError::AttemptToSetConcurrently)),
+                        )
                     }
-                    Ok(Lifecycle::Setting) => (
-                        false,
-                        Err(OnceLockFreeInternalError::AttemptToSetConcurrently),
-                    ),
                     Ok(Lifecycle::Set) => {
-                        let ptr = s.flag_ptr.get_ptr();
-                        (false, Ok(if ptr.is_null() { None } else { Some(ptr) }))
+                        // Null if self was sealed.
+                        (false, Some(Ok(Some(s.flag_ptr.get_ptr()))))
+                    }
+                    Ok(Lifecycle::Publishing) => (false, None),
+                    Ok(Lifecycle::Dead) => {
+                        (false, Some(Err(OnceLockFreeInternalError::UseAfterFreeBug)))
+                    }
+                    Ok(Lifecycle::Poisoned) => {
+                        (false, Some(Err(OnceLockFreeInternalError::Poisoned)))
                     }
-                    Ok(Lifecycle::Dead) => (false, Err(OnceLockFreeInternalError::UseAfterFreeBug)),
                     Err(_) => {
                         panic!("torn read?")
                     }
                 })
-                .map_err(panic_on_memory_bug)?
-                .map(|ptr| &(*ptr).inner),
-            )
+            })
+            .map_err(panic_on_memory_bug);
+        if poison_on_race && matches!(val, Err(OnceLockFreeError::AttemptToSetConcurrently)) {
+            self.wake_waiters();
+            self.record_rejected(&OnceLockFreeError::AttemptToSetConcurrently);
         }
+        Ok(match val? {
+            None => GetOrPrepare::Prepared(SetPermit { cell: self }),
+            Some(ptr) if ptr.is_null() => GetOrPrepare::Sealed,
+            Some(ptr) => GetOrPrepare::Set(unsafe { self.value(ptr) }),
+        })
     }
 
     /// Gets the reference to the underlying value.
@@ -147,7 +306,7 @@ impl<'a, T> OnceLockFree<T> {
                 }
                 _ => (false, None),
             })
-            .map(|ptr| &(*ptr).inner)
+            .map(|ptr| self.value(ptr))
         }
     }
 
@@ -156,44 +315,110 @@ impl<'a, T> OnceLockFree<T> {
     ///
     /// Returns error if another thread concurrently prepares self, and during shutdown.
     pub fn get_or_seal(&'a self) -> Result<Option<&'a T>, OnceLockFreeError> {
-        unsafe {
-            Ok(
+        let val = self
+            .yield_to_publisher(|| unsafe {
                 atomic_try_update(&self.inner, |s| match s.flag_ptr.get_flag().try_into() {
                     Ok(Lifecycle::NotSet) => {
                         s.flag_ptr.set_flag(Lifecycle::Set.into());
                         s.flag_ptr.set_ptr(null_mut());
-                        (true, Ok(None))
+                        (true, Some(Ok(None)))
                     }
                     Ok(Lifecycle::Setting) => (
                         false,
-                        Err(OnceLockFreeInternalError::AttemptToSetConcurrently),
+                        Some(Err(OnceLockFreeInternalError::AttemptToSetConcurrently)),
                     ),
                     Ok(Lifecycle::Set) => {
                         let ptr = s.flag_ptr.get_ptr();
-                        (false, Ok(if ptr.is_null() { None } else { Some(ptr) }))
+                        (
+                            false,
+                            Some(Ok(if ptr.is_null() { None } else { Some(ptr) })),
+                        )
+                    }
+                    Ok(Lifecycle::Publishing) => (false, None),
+                    Ok(Lifecycle::Dead) => {
+                        (false, Some(Err(OnceLockFreeInternalError::UseAfterFreeBug)))
+                    }
+                    Ok(Lifecycle::Poisoned) => {
+                        (false, Some(Err(OnceLockFreeInternalError::Poisoned)))
                     }
-                    Ok(Lifecycle::Dead) => (false, Err(OnceLockFreeInternalError::UseAfterFreeBug)),
                     Err(_) => {
                         panic!("torn read?")
                     }
                 })
-                .map_err(panic_on_memory_bug)?
-                .map(|ptr| (&(*ptr).inner)),
-            )
+            })
+            .map_err(panic_on_memory_bug)?
+            .map(|ptr| unsafe { self.value(ptr) });
+        if val.is_none() {
+            // We (or an earlier call) sealed self, so waiters can give up.
+            self.wake_waiters();
         }
+        Ok(val)
     }
-    /// set the value after a call to get_or_prepare_to_set returned None.  This is done in
-    /// two phases so that racing sets are more likely to be noticed, and to help callers
-    /// improve error messages when that happens.
-    ///
-    /// TODO: Add an Error state, and transition into it when racing get_or_prepare_to_set
-    ///       calls occur.
+    /// Seals self so that it can never be set, without reading the value.
     ///
-    /// Returns error if already set, or if we haven't been prepared
-    pub fn set_prepared(&'a self, val: T) -> Result<&'a T, OnceLockFreeError> {
-        // This ensures the ptr is 8-byte aligned (or more), so that flag_ptr can steal
-        // the three least significant bits
-        let ptr: *mut Align8<T> = Box::into_raw(Box::new(val.into()));
+    /// Returns true if we sealed self while it was empty, and false if a
+    /// value was present, or self was already sealed.  Prepared and poisoned
+    /// cells are left alone (and this returns false), so a pending
+    /// `set_prepared` still succeeds.
+    pub fn seal(&self) -> bool {
+        let sealed = unsafe {
+            atomic_try_update(&self.inner, |s| match s.flag_ptr.get_flag().try_into() {
+                Ok(Lifecycle::NotSet) => {
+                    s.flag_ptr.set_flag(Lifecycle::Set.into());
+                    s.flag_ptr.set_ptr(null_mut());
+                    (true, Ok(true))
+                }
+                Ok(Lifecycle::Setting)
+                | Ok(Lifecycle::Set)
+                | Ok(Lifecycle::Poisoned)
+                | Ok(Lifecycle::Publishing) => (false, Ok(false)),
+                Ok(Lifecycle::Dead) => (false, Err(OnceLockFreeInternalError::UseAfterFreeBug)),
+                Err(_) => {
+                    panic!("torn read?")
+                }
+            })
+        };
+        // Only UseAfterFreeBug is possible, and it panics.
+        let sealed = sealed.map_err(panic_on_memory_bug).unwrap_or(false);
+        if sealed {
+            self.wake_waiters();
+        }
+        sealed
+    }
+
+    /// Like `set_prepared`, but takes ownership of a value that is already on
+    /// the heap, which may be unsized.  If T is 8-byte aligned (or more), or
+    /// unsized, the allocation is stored as is; otherwise the value is moved
+    /// into a new, aligned one.
+    pub fn set_prepared_boxed(
+        &'a self,
+        permit: SetPermit<'a, T>,
+        val: Box<T>,
+    ) -> Result<&'a T, OnceLockFreeError> {
+        self.redeem(permit);
+        let ptr = if Self::UNSIZED {
+            // We prepared self, so no other thread writes or reads the slot
+            // until we move self to Set.
+            unsafe { (*self.boxed.get()).write(Box::into_raw(val)) };
+            boxed_marker()
+        } else {
+            into_align8(val).cast()
+        };
+        self.set_prepared_ptr(ptr)
+    }
+
+    fn redeem(&self, permit: SetPermit<'_, T>) {
+        assert!(
+            std::ptr::eq(permit.cell, self),
+            "SetPermit used with a different OnceLockFree"
+        );
+        // Whether or not the set succeeds, there is nothing left to unprepare.
+        std::mem::forget(permit);
+    }
+
+    /// Moves self from Setting to Set, and publishes ptr, which is owned by
+    /// the caller (see `value`).
+    fn set_prepared_ptr(&'a self, ptr: *mut Erased) -> Result<&'a T, OnceLockFreeError> {
         unsafe {
             atomic_try_update(&self.inner, |s| match s.flag_ptr.get_flag().try_into() {
                 Ok(Lifecycle::NotSet) => (false, Err(OnceLockFreeInternalError::UnpreparedForSet)),
@@ -202,21 +427,182 @@ impl<'a, T> OnceLockFree<T> {
                     s.flag_ptr.set_ptr(ptr);
                     (true, Ok(()))
                 }
-                Ok(Lifecycle::Set) => (false, Err(OnceLockFreeInternalError::AlreadySet)),
+                Ok(Lifecycle::Set) | Ok(Lifecycle::Publishing) => {
+                    (false, Err(OnceLockFreeInternalError::AlreadySet))
+                }
                 Ok(Lifecycle::Dead) => (false, Err(OnceLockFreeInternalError::UseAfterFreeBug)),
+                Ok(Lifecycle::Poisoned) => (false, Err(OnceLockFreeInternalError::Poisoned)),
                 Err(_) => {
                     panic!("torn read?")
                 }
             })
-            .map_err(panic_on_memory_bug)?;
-            Ok(&(*ptr).inner)
+            .map_err(|err| self.reject(ptr, err))?;
+            let val = self.value(ptr);
+            self.published(val);
+            Ok(val)
         }
     }
-    /// Set this to the provided value.  Wait free.
+    /// Waits until a value is set (by any of the set methods), and returns
+    /// it.  This is the async counterpart of polling with `get_poll`.
     ///
-    /// Returns Error if we've been prepared, or set already, and a reference to the stored val on success.
-    pub fn set(&'a self, val: T) -> Result<&'a T, OnceLockFreeError> {
-        let ptr: *mut Align8<T> = Box::into_raw(Box::new(val.into()));
+    /// Waiting tasks are kept on a lock-free list of wakers, so this does not
+    /// depend on any particular async runtime.
+    ///
+    /// Panics if self is sealed (with `get_or_seal`) or poisoned instead,
+    /// since the value can then never be set.
+    pub async fn wait(&'a self) -> &'a T {
+        self.waiters
+            .wait_for(|| {
+                let val = self.get_poll();
+                assert!(
+                    val.is_some() || !self.is_sealed(),
+                    "OnceLockFree::wait on a sealed or poisoned cell"
+                );
+                val
+            })
+            .await
+    }
+
+    /// Blocks the calling thread until a value is set, and returns it.  This
+    /// is the synchronous counterpart of `wait`.
+    ///
+    /// Panics if self is sealed (with `get_or_seal`) or poisoned instead.
+    pub fn wait_blocking(&'a self) -> &'a T {
+        match self.wait_blocking_until(None) {
+            Ok(val) => val,
+            Err(err) => unreachable!("wait_blocking without a deadline failed: {err}"),
+        }
+    }
+
+    /// Like `wait_blocking`, but gives up and returns `Timeout` if no value
+    /// is set within timeout.
+    pub fn wait_blocking_timeout(&'a self, timeout: Duration) -> Result<&'a T, OnceLockFreeError> {
+        self.wait_blocking_until(Some(Instant::now() + timeout))
+    }
+
+    fn wait_blocking_until(
+        &'a self,
+        deadline: Option<Instant>,
+    ) -> Result<&'a T, OnceLockFreeError> {
+        self.waiters
+            .wait_blocking_for(
+                || {
+                    let val = self.get_poll();
+                    assert!(
+                        val.is_some() || !self.is_sealed(),
+                        "OnceLockFree::wait_blocking on a sealed or poisoned cell"
+                    );
+                    val
+                },
+                deadline,
+            )
+            .ok_or(OnceLockFreeError::Timeout)
+    }
+
+    /// Like `wait`, but gives up and returns `Timeout` if no value is set
+    /// within timeout.  This uses tokio's timer, so it requires the
+    /// `tokio-rt` feature, and must be called from within a tokio runtime.
+    /// `wait_blocking_timeout` works without either.
+    #[cfg(feature = "tokio-rt")]
+    pub async fn wait_timeout(&'a self, timeout: Duration) -> Result<&'a T, OnceLockFreeError> {
+        tokio::time::timeout(timeout, self.wait())
+            .await
+            .map_err(|_| OnceLockFreeError::Timeout)
+    }
+
+    /// Returns true if self was sealed without a value, or poisoned.
+    fn is_sealed(&self) -> bool {
+        unsafe {
+            atomic_try_update(&self.inner, |s| {
+                let sealed = match s.flag_ptr.get_flag().try_into() {
+                    Ok(Lifecycle::Set) => s.flag_ptr.get_ptr().is_null(),
+                    Ok(Lifecycle::Poisoned) => true,
+                    _ => false,
+                };
+                (false, sealed)
+            })
+        }
+    }
+
+    /// Moves self from Setting to Poisoned.
+    fn poison(&self) {
+        let poisoned = unsafe {
+            atomic_try_update(&self.inner, |s| match s.flag_ptr.get_flag().try_into() {
+                Ok(Lifecycle::Setting) => {
+                    s.flag_ptr.set_flag(Lifecycle::Poisoned.into());
+                    (true, true)
+                }
+                _ => (false, false),
+            })
+        };
+        if poisoned {
+            self.wake_waiters();
+        }
+    }
+
+    #[cfg_attr(not(feature = "stats"), allow(unused_variables))]
+    fn record_rejected(&self, err: &OnceLockFreeError) {
+        #[cfg(feature = "stats")]
+        match err {
+            OnceLockFreeError::AlreadySet => {
+                self.stats.already_set.fetch_add(1, Ordering::Relaxed);
+            }
+            OnceLockFreeError::AttemptToSetConcurrently => {
+                self.stats.set_concurrently.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => (),
+        }
+    }
+
+    /// Returns a snapshot of this cell's statistics.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> OnceLockFreeStats {
+        OnceLockFreeStats {
+            already_set: self.stats.already_set.load(Ordering::Relaxed),
+            set_concurrently: self.stats.set_concurrently.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Called by whichever set method published val.
+    fn published(&self, val: &T) {
+        self.wake_waiters();
+        if let Some(on_set) = &self.on_set {
+            on_set(val);
+        }
+    }
+
+    fn wake_waiters(&self) {
+        self.waiters.wake_all();
+    }
+
+    /// Moves self from Setting back to NotSet.
+    fn unprepare(&self) {
+        unsafe {
+            atomic_try_update(&self.inner, |s| match s.flag_ptr.get_flag().try_into() {
+                Ok(Lifecycle::Setting) => {
+                    s.flag_ptr.set_flag(Lifecycle::NotSet.into());
+                    (true, ())
+                }
+                _ => (false, ()),
+            })
+        }
+    }
+
+    /// Like `set`, but takes ownership of a value that is already on the heap,
+    /// which may be unsized.  If T is 8-byte aligned (or more), or unsized,
+    /// the allocation is stored as is; otherwise the value is moved into a
+    /// new, aligned one.
+    pub fn set_boxed(&'a self, val: Box<T>) -> Result<&'a T, OnceLockFreeError> {
+        if Self::UNSIZED {
+            self.set_unsized(Box::into_raw(val))
+        } else {
+            self.set_ptr(into_align8(val).cast())
+        }
+    }
+
+    /// Moves self from NotSet to Set, and publishes ptr, which points to an
+    /// `Align8<T>` owned by the caller.
+    fn set_ptr(&'a self, ptr: *mut Erased) -> Result<&'a T, OnceLockFreeError> {
         unsafe {
             atomic_try_update(&self.inner, |s| match s.flag_ptr.get_flag().try_into() {
                 Ok(Lifecycle::NotSet) => {
@@ -224,31 +610,455 @@ impl<'a, T> OnceLockFree<T> {
                     s.flag_ptr.set_ptr(ptr);
                     (true, Ok(()))
                 }
-                Ok(Lifecycle::Setting) => (
-                    false,
-                    Err(OnceLockFreeInternalError::AttemptToSetConcurrently),
-                ),
-                Ok(Lifecycle::Set) => (false, Err(OnceLockFreeInternalError::AlreadySet)),
+                Ok(Lifecycle::Setting) => {
+                    // Another caller prepared self, so one of us is racing.
+                    s.flag_ptr.set_flag(Lifecycle::Poisoned.into());
+                    (
+                        true,
+                        Err(OnceLockFreeInternalError::AttemptToSetConcurrently),
+                    )
+                }
+                Ok(Lifecycle::Set) | Ok(Lifecycle::Publishing) => {
+                    (false, Err(OnceLockFreeInternalError::AlreadySet))
+                }
+                Ok(Lifecycle::Dead) => (false, Err(OnceLockFreeInternalError::UseAfterFreeBug)),
+                Ok(Lifecycle::Poisoned) => (false, Err(OnceLockFreeInternalError::Poisoned)),
+                Err(_) => {
+                    panic!("torn read?")
+                }
+            })
+            .map_err(|err| self.reject(ptr, err))?;
+            let val = self.value(ptr);
+            self.published(val);
+            Ok(val)
+        }
+    }
+
+    /// Like `set_ptr`, for unsized values, whose fat pointer does not fit in
+    /// flag_ptr.  Claims self by moving it to Publishing, stores val in the
+    /// slot, and then moves self to Set.
+    fn set_unsized(&'a self, val: *mut T) -> Result<&'a T, OnceLockFreeError> {
+        unsafe {
+            atomic_try_update(&self.inner, |s| match s.flag_ptr.get_flag().try_into() {
+                Ok(Lifecycle::NotSet) => {
+                    s.flag_ptr.set_flag(Lifecycle::Publishing.into());
+                    (true, Ok(()))
+                }
+                Ok(Lifecycle::Setting) => {
+                    // Another caller prepared self, so one of us is racing.
+                    s.flag_ptr.set_flag(Lifecycle::Poisoned.into());
+                    (
+                        true,
+                        Err(OnceLockFreeInternalError::AttemptToSetConcurrently),
+                    )
+                }
+                Ok(Lifecycle::Set) | Ok(Lifecycle::Publishing) => {
+                    (false, Err(OnceLockFreeInternalError::AlreadySet))
+                }
                 Ok(Lifecycle::Dead) => (false, Err(OnceLockFreeInternalError::UseAfterFreeBug)),
+                Ok(Lifecycle::Poisoned) => (false, Err(OnceLockFreeInternalError::Poisoned)),
                 Err(_) => {
                     panic!("torn read?")
                 }
             })
-            .map_err(panic_on_memory_bug)?;
-            Ok(&(*ptr).inner)
+            .map_err(|err| {
+                drop(Box::from_raw(val));
+                self.reject(null_mut(), err)
+            })?;
+            // Publishing keeps every other thread away from the slot.
+            (*self.boxed.get()).write(val);
+            atomic_try_update(&self.inner, |s| {
+                s.flag_ptr.set_flag(Lifecycle::Set.into());
+                s.flag_ptr.set_ptr(boxed_marker());
+                (true, ())
+            });
+            let val = &*val;
+            self.published(val);
+            Ok(val)
+        }
+    }
+
+    /// Frees ptr (unless it is null), and reports err from a failed set.
+    unsafe fn reject(&self, ptr: *mut Erased, err: OnceLockFreeInternalError) -> OnceLockFreeError {
+        if !ptr.is_null() {
+            self.drop_value(ptr);
+        }
+        if let OnceLockFreeInternalError::AttemptToSetConcurrently = err {
+            self.wake_waiters();
+        }
+        let err = panic_on_memory_bug(err);
+        self.record_rejected(&err);
+        err
+    }
+
+    /// Calls f until it returns Some, yielding while another thread is
+    /// publishing an unsized value.
+    fn yield_to_publisher<R>(&self, f: impl Fn() -> Option<R>) -> R {
+        loop {
+            match f() {
+                Some(val) => return val,
+                None => thread::yield_now(),
+            }
+        }
+    }
+
+    /// Returns the value that ptr, a non-null pointer read from flag_ptr in
+    /// the Set state, refers to.
+    unsafe fn value(&'a self, ptr: *mut Erased) -> &'a T {
+        if Self::UNSIZED {
+            &*(*self.boxed.get()).assume_init()
+        } else {
+            &(*transmute_copy::<*mut Erased, *mut Align8<T>>(&ptr)).inner
+        }
+    }
+
+    /// Frees the value that ptr refers to.  See `value`.
+    unsafe fn drop_value(&self, ptr: *mut Erased) {
+        if Self::UNSIZED {
+            drop(Box::from_raw((*self.boxed.get()).assume_init()));
+        } else {
+            drop(Box::from_raw(
+                transmute_copy::<*mut Erased, *mut Align8<T>>(&ptr),
+            ));
         }
     }
 }
 
-impl<T> Default for OnceLockFree<T> {
-    fn default() -> Self {
-        Self {
-            inner: Default::default(),
+impl<'a, T> OnceLockFree<T> {
+    /// set the value using the `SetPermit` returned by get_or_prepare_to_set.  This is done in
+    /// two phases so that racing sets are more likely to be noticed, and to help callers
+    /// improve error messages when that happens.  Since this consumes the permit, it can't be
+    /// called on a cell that we haven't prepared, or called twice.
+    ///
+    /// Racing `get_or_prepare_to_set` or `set` calls poison self, and then this returns
+    /// `Poisoned`.  Panics if permit is for a different cell.
+    pub fn set_prepared(
+        &'a self,
+        permit: SetPermit<'a, T>,
+        val: T,
+    ) -> Result<&'a T, OnceLockFreeError> {
+        self.redeem(permit);
+        // This ensures the ptr is 8-byte aligned (or more), so that flag_ptr can steal
+        // the three least significant bits
+        self.set_prepared_ptr(Box::into_raw(Box::new(Align8::from(val))).cast())
+    }
+
+    /// Gets the value, or initializes it with f if it has not been set,
+    /// like `std::sync::OnceLock::get_or_init`.
+    ///
+    /// This uses `get_or_prepare_to_set` and `set_prepared`, so exactly one
+    /// caller runs f.  Callers that race with it yield until the value has
+    /// been set, so unlike the other methods, this is not wait free.  If f
+    /// panics, self is poisoned.
+    ///
+    /// Panics if self was sealed by `get_or_seal` before a value was set, or
+    /// if self is poisoned.
+    pub fn get_or_init<F>(&'a self, f: F) -> &'a T
+    where
+        F: FnOnce() -> T,
+    {
+        match self.get_or_try_init(|| Ok::<T, Infallible>(f())) {
+            Ok(val) => val,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Like get_or_init, but f may fail.  If it does, self goes back to
+    /// being unset (instead of staying prepared forever), so another caller
+    /// can retry, and the error is returned.
+    pub fn get_or_try_init<F, E>(&'a self, f: F) -> Result<&'a T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        let permit = loop {
+            // Racing with another initializer is expected here, so don't poison.
+            match self.prepare(false) {
+                Ok(GetOrPrepare::Set(val)) => return Ok(val),
+                Ok(GetOrPrepare::Prepared(permit)) => break permit,
+                Ok(GetOrPrepare::Sealed) => {
+                    panic!("OnceLockFree::get_or_try_init called on a sealed cell")
+                }
+                Err(OnceLockFreeError::AttemptToSetConcurrently) => std::thread::yield_now(),
+                Err(err) => panic!("OnceLockFree::get_or_try_init failed: {err}"),
+            }
+        };
+        // Poisons self if f panics.  This is dropped before the permit, which
+        // would otherwise unprepare self.
+        let guard = PoisonOnUnwind(self);
+        let val = f();
+        std::mem::forget(guard);
+        // On error, dropping the permit unprepares self.
+        match permit.set(val?) {
+            Ok(val) => Ok(val),
+            Err(err) => panic!("OnceLockFree::get_or_try_init failed: {err}"),
+        }
+    }
+
+    /// Set this to the provided value.  Wait free.
+    ///
+    /// Returns Error if we've been prepared, or set already, and a reference to the stored val on success.
+    /// If we've been prepared, this is a race with the caller of `get_or_prepare_to_set`, so self
+    /// is poisoned.
+    pub fn set(&'a self, val: T) -> Result<&'a T, OnceLockFreeError> {
+        self.set_ptr(Box::into_raw(Box::new(Align8::from(val))).cast())
+    }
+
+    /// Sets self to val, or, if another thread already set self, drops val
+    /// and returns the existing value instead of an error.  The bool is true if
+    /// we set the value.  This is what most callers want when initialization
+    /// is idempotent.
+    ///
+    /// If another thread has prepared self, this yields until that thread sets
+    /// the value, so it is not wait free in that case.  Panics if self is
+    /// sealed or poisoned.
+    pub fn set_or_get(&'a self, val: T) -> (&'a T, bool) {
+        let ptr: *mut Erased = Box::into_raw(Box::new(Align8::from(val))).cast();
+        loop {
+            let existing = unsafe {
+                atomic_try_update(&self.inner, |s| match s.flag_ptr.get_flag().try_into() {
+                    Ok(Lifecycle::NotSet) => {
+                        s.flag_ptr.set_flag(Lifecycle::Set.into());
+                        s.flag_ptr.set_ptr(ptr);
+                        (true, Ok(None))
+                    }
+                    Ok(Lifecycle::Setting) | Ok(Lifecycle::Publishing) => (
+                        false,
+                        Err(OnceLockFreeInternalError::AttemptToSetConcurrently),
+                    ),
+                    Ok(Lifecycle::Set) => {
+                        let existing = s.flag_ptr.get_ptr();
+                        if existing.is_null() {
+                            (false, Err(OnceLockFreeInternalError::AlreadySet))
+                        } else {
+                            (false, Ok(Some(existing)))
+                        }
+                    }
+                    Ok(Lifecycle::Dead) => (false, Err(OnceLockFreeInternalError::UseAfterFreeBug)),
+                    Ok(Lifecycle::Poisoned) => (false, Err(OnceLockFreeInternalError::Poisoned)),
+                    Err(_) => {
+                        panic!("torn read?")
+                    }
+                })
+            };
+            match existing.map_err(panic_on_memory_bug) {
+                Ok(None) => {
+                    let val = unsafe { self.value(ptr) };
+                    self.published(val);
+                    return (val, true);
+                }
+                Ok(Some(existing)) => {
+                    unsafe { self.drop_value(ptr) };
+                    return (unsafe { self.value(existing) }, false);
+                }
+                Err(OnceLockFreeError::AttemptToSetConcurrently) => thread::yield_now(),
+                Err(OnceLockFreeError::AlreadySet) => {
+                    panic!("OnceLockFree::set_or_get called on a sealed cell")
+                }
+                Err(err) => panic!("OnceLockFree::set_or_get failed: {err}"),
+            }
+        }
+    }
+
+    /// Takes the value out of self, leaving it unset, so that it can be set
+    /// again.  Returns None if no value was set.
+    ///
+    /// This also clears a sealed, prepared or poisoned cell back to unset.  No other
+    /// thread can hold a reference to the value, since we have `&mut self`.
+    pub fn take(&mut self) -> Option<T> {
+        let ptr = unsafe {
+            atomic_try_update(&self.inner, |s| match s.flag_ptr.get_flag().try_into() {
+                Ok(Lifecycle::NotSet) => (false, Ok(null_mut())),
+                Ok(Lifecycle::Setting)
+                | Ok(Lifecycle::Set)
+                | Ok(Lifecycle::Poisoned)
+                | Ok(Lifecycle::Publishing) => {
+                    let ptr = s.flag_ptr.get_ptr();
+                    s.flag_ptr.set_flag(Lifecycle::NotSet.into());
+                    s.flag_ptr.set_ptr(null_mut());
+                    (true, Ok(ptr))
+                }
+                Ok(Lifecycle::Dead) => (false, Err(OnceLockFreeInternalError::UseAfterFreeBug)),
+                Err(_) => {
+                    panic!("torn read?")
+                }
+            })
+        };
+        match ptr.map_err(panic_on_memory_bug) {
+            Ok(ptr) if !ptr.is_null() => {
+                Some(unsafe { Box::from_raw(ptr.cast::<Align8<T>>()) }.inner)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns self to its initial state, so that pooled or recycled objects
+    /// that contain a cell can be reused without reconstructing it.  Returns
+    /// the old value, if any.
+    ///
+    /// Like `take`, but this also frees wakers left behind by cancelled
+    /// `wait` calls, so they don't pile up across reuses.
+    pub fn reset(&mut self) -> Option<T> {
+        drop(self.waiters.take());
+        self.take()
+    }
+
+    /// Consumes self, returning the stored value, or None if no value was set.
+    pub fn into_inner(mut self) -> Option<T> {
+        self.take()
+    }
+}
+
+impl<T: Clone> OnceLockFree<T> {
+    /// Like `get`, but returns a clone of the value, so that callers don't
+    /// have to hold the borrow of self (for instance, across an await point).
+    pub fn get_cloned(&self) -> Result<T, OnceLockFreeError> {
+        self.get().cloned()
+    }
+}
+
+impl<T: Copy> OnceLockFree<T> {
+    /// Like `get_cloned`, for values that are Copy.
+    pub fn get_copied(&self) -> Result<T, OnceLockFreeError> {
+        self.get().copied()
+    }
+}
+
+/// Serializes the value as `Some(val)`, or `None` if it has not been set.
+#[cfg(feature = "serde")]
+impl<T: Serialize + ?Sized> Serialize for OnceLockFree<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.get_poll().serialize(serializer)
+    }
+}
+
+/// Deserializes an `Option<T>`, and returns a cell that is already set to
+/// the value, or an unset cell for `None`.
+#[cfg(feature = "serde")]
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for OnceLockFree<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let cell = Self::new();
+        if let Some(val) = Option::<T>::deserialize(deserializer)? {
+            // A new cell can't already be set.
+            let _ = cell.set(val);
+        }
+        Ok(cell)
+    }
+}
+
+/// Converts a `Box<T>` into the raw pointer of a `Box<Align8<T>>`, reusing
+/// the allocation when T's alignment already satisfies `Align8`.  `*mut T`
+/// must be thin (T is sized); unsized values are stored as is.
+fn into_align8<T: ?Sized>(val: Box<T>) -> *mut Align8<T> {
+    let layout = Layout::for_value(&*val);
+    let ptr = Box::into_raw(val);
+    if layout.align() >= align_of::<Erased>() {
+        // Align8<T> has the same size and alignment as T here, so the two
+        // layouts match, and the allocation can be handed over directly.
+        return ptr as *mut Align8<T>;
+    }
+    // Move the value into a new, aligned allocation, without calling its
+    // destructor.
+    let aligned = layout
+        .align_to(align_of::<Erased>())
+        .unwrap()
+        .pad_to_align();
+    unsafe {
+        let new: *mut u8 = if aligned.size() == 0 {
+            // Zero-sized boxes don't allocate, and only need an aligned pointer.
+            NonNull::<Erased>::dangling().as_ptr().cast()
+        } else {
+            let new = alloc(aligned);
+            if new.is_null() {
+                handle_alloc_error(aligned);
+            }
+            std::ptr::copy_nonoverlapping(ptr.cast::<u8>(), new, layout.size());
+            dealloc(ptr.cast(), layout);
+            new
+        };
+        transmute_copy::<*mut u8, *mut Align8<T>>(&new)
+    }
+}
+
+/// Returned by `OnceLockFree::get_or_prepare_to_set`.
+pub enum GetOrPrepare<'a, T: ?Sized> {
+    /// The value has already been set.
+    Set(&'a T),
+    /// The cell was sealed without a value, so it can never be set.
+    Sealed,
+    /// The cell was unset, and is now prepared for the holder of the permit
+    /// to set it.
+    Prepared(SetPermit<'a, T>),
+}
+
+impl<'a, T: ?Sized> GetOrPrepare<'a, T> {
+    /// Returns the value, if it has been set.
+    pub fn get(&self) -> Option<&'a T> {
+        match self {
+            GetOrPrepare::Set(val) => Some(val),
+            _ => None,
         }
     }
 }
 
-impl<T> Drop for OnceLockFree<T> {
+/// Permission to set a prepared `OnceLockFree`.  If this is dropped without
+/// setting a value, the cell goes back to being unset.
+#[must_use = "dropping a SetPermit unprepares the cell"]
+pub struct SetPermit<'a, T: ?Sized> {
+    cell: &'a OnceLockFree<T>,
+}
+
+impl<'a, T> SetPermit<'a, T> {
+    /// Sets the value of the prepared cell.  Wait free.  Shorthand for
+    /// `OnceLockFree::set_prepared`.
+    ///
+    /// Returns `Poisoned` if a racing set poisoned the cell.
+    pub fn set(self, val: T) -> Result<&'a T, OnceLockFreeError> {
+        self.cell.set_prepared(self, val)
+    }
+}
+
+impl<'a, T: ?Sized> SetPermit<'a, T> {
+    /// Shorthand for `OnceLockFree::set_prepared_boxed`.
+    pub fn set_boxed(self, val: Box<T>) -> Result<&'a T, OnceLockFreeError> {
+        self.cell.set_prepared_boxed(self, val)
+    }
+}
+
+impl<T: ?Sized> Drop for SetPermit<'_, T> {
+    fn drop(&mut self) {
+        self.cell.unprepare();
+    }
+}
+
+/// Calls `poison` when dropped, which only happens if the initializer panics.
+struct PoisonOnUnwind<'a, T: ?Sized>(&'a OnceLockFree<T>);
+
+impl<T: ?Sized> Drop for PoisonOnUnwind<'_, T> {
+    fn drop(&mut self) {
+        self.0.poison();
+    }
+}
+
+impl<T: ?Sized> Default for OnceLockFree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The slot that holds an unsized value's pointer is only accessed as
+// described on `OnceLockFree::boxed`, so the cell is as thread safe as
+// `OnceLock<T>`.
+unsafe impl<T: ?Sized + Send> Send for OnceLockFree<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for OnceLockFree<T> {}
+impl<T: ?Sized + RefUnwindSafe + UnwindSafe> RefUnwindSafe for OnceLockFree<T> {}
+
+impl<T: ?Sized> Drop for OnceLockFree<T> {
     fn drop(&mut self) {
         unsafe {
             match atomic_try_update(&self.inner, |s| {
@@ -257,7 +1067,9 @@ impl<T> Drop for OnceLockFree<T> {
                         s.flag_ptr.set_flag(Lifecycle::Dead.into());
                         (true, Ok(None))
                     }
-                    Ok(Lifecycle::Setting) => {
+                    Ok(Lifecycle::Setting)
+                    | Ok(Lifecycle::Poisoned)
+                    | Ok(Lifecycle::Publishing) => {
                         s.flag_ptr.set_flag(Lifecycle::Dead.into());
                         (true, Ok(None))
                     }
@@ -287,10 +1099,516 @@ impl<T> Drop for OnceLockFree<T> {
             .unwrap()
             {
                 None => (),
-                Some(ptr) => {
-                    let _drop = Box::from_raw(ptr);
-                }
+                Some(ptr) => self.drop_value(ptr),
             };
         }
     }
 }
+
+struct OnceArcState<T> {
+    ptr: *const T,
+}
+
+/// A set-once `Arc<T>`.  Unlike `OnceLockFree<Arc<T>>`, this stores the
+/// pointer returned by `Arc::into_raw` directly in the atom, instead of
+/// boxing the Arc, so `get` clones the Arc with one indirection fewer.
+pub struct OnceArc<T> {
+    inner: Atom<OnceArcState<T>, u64>,
+    /// Atom is unconditionally Send and Sync, so this makes the cell's auto
+    /// traits match those of `Arc<T>`.
+    _arc: PhantomData<Arc<T>>,
+}
+
+impl<T> OnceArc<T> {
+    /// Creates a new empty cell.  This is a const fn, so it can be used to
+    /// initialize a `static`.
+    pub const fn new() -> Self {
+        Self {
+            inner: Atom::new(),
+            _arc: PhantomData,
+        }
+    }
+
+    fn get_ptr(&self) -> *const T {
+        unsafe { atomic_try_update(&self.inner, |s| (false, s.ptr)) }
+    }
+
+    /// Sets self to val.  Wait free.
+    ///
+    /// Returns `AlreadySet` (and drops val) if self was already set.
+    pub fn set(&self, val: Arc<T>) -> Result<&T, OnceLockFreeError> {
+        let ptr = Arc::into_raw(val);
+        let set = unsafe {
+            atomic_try_update(&self.inner, |s| {
+                if s.ptr.is_null() {
+                    s.ptr = ptr;
+                    (true, true)
+                } else {
+                    (false, false)
+                }
+            })
+        };
+        if set {
+            Ok(unsafe { &*ptr })
+        } else {
+            drop(unsafe { Arc::from_raw(ptr) });
+            Err(OnceLockFreeError::AlreadySet)
+        }
+    }
+
+    /// Returns a clone of the stored Arc, or None if self has not been set.
+    pub fn get(&self) -> Option<Arc<T>> {
+        let ptr = self.get_ptr();
+        if ptr.is_null() {
+            return None;
+        }
+        // self holds a strong reference until it is dropped, and it can't be
+        // dropped while we borrow it.
+        unsafe {
+            Arc::increment_strong_count(ptr);
+            Some(Arc::from_raw(ptr))
+        }
+    }
+
+    /// Returns a reference to the stored value, or None if self has not been
+    /// set.  This does not touch the reference count.
+    pub fn get_ref(&self) -> Option<&T> {
+        unsafe { self.get_ptr().as_ref() }
+    }
+}
+
+impl<T> Default for OnceArc<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceArc<T> {
+    fn drop(&mut self) {
+        let ptr = self.get_ptr();
+        if !ptr.is_null() {
+            drop(unsafe { Arc::from_raw(ptr) });
+        }
+    }
+}
+
+/// A value that is initialized on first access, like `std::sync::LazyLock`,
+/// but built on `OnceLockFree`.
+///
+/// The initializer is fixed at construction.  As with
+/// `OnceLockFree::get_or_init`, exactly one caller runs it, and if it panics,
+/// the value is poisoned, and later accesses panic.  That caller consumes
+/// the initializer, so, as with `LazyLock`, it may be any `FnOnce`.
+pub struct LazyLockFree<T, F = fn() -> T> {
+    cell: OnceLockFree<T>,
+    /// Taken by the caller that `get_or_init` elects.
+    init: UnsafeCell<Option<F>>,
+}
+
+// Only one caller ever touches init, and it may be on any thread.
+unsafe impl<T, F> Sync for LazyLockFree<T, F>
+where
+    T: Send + Sync,
+    F: Send,
+{
+}
+
+impl<T, F> RefUnwindSafe for LazyLockFree<T, F>
+where
+    T: RefUnwindSafe + UnwindSafe,
+    F: UnwindSafe,
+{
+}
+
+impl<T, F> LazyLockFree<T, F>
+where
+    F: FnOnce() -> T,
+{
+    /// Creates a new lazy value that will be initialized by init.  This is
+    /// a const fn, so it can be used to initialize a `static`.
+    pub const fn new(init: F) -> Self {
+        Self {
+            cell: OnceLockFree::new(),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+
+    /// Returns the value, running the initializer if this is the first access.
+    pub fn force(&self) -> &T {
+        self.cell.get_or_init(|| {
+            // Safety: get_or_init runs this at most once, even if it panics.
+            let init = unsafe { (*self.init.get()).take() };
+            init.expect("the initializer only runs once")()
+        })
+    }
+
+    /// Returns the value, or None if it has not been initialized yet.
+    pub fn get(&self) -> Option<&T> {
+        self.cell.get_poll()
+    }
+}
+
+impl<T, F> Deref for LazyLockFree<T, F>
+where
+    F: FnOnce() -> T,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.force()
+    }
+}
+
+/// A fixed-capacity array of set-once slots, for registries where
+/// components register themselves at startup, and are later looked up by
+/// index.
+///
+/// `claim_slot` reserves the next free index, and `set` publishes the value
+/// for that index.  Both are wait free, and lookups are plain loads.
+pub struct OnceSlab<T> {
+    slots: Box<[OnceLockFree<T>]>,
+    claimed: Atom<u64, u64>,
+}
+
+impl<'a, T> OnceSlab<T> {
+    /// Creates a slab with room for capacity values.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity).map(|_| OnceLockFree::default()).collect(),
+            claimed: Default::default(),
+        }
+    }
+
+    /// Reserves a slot, and returns its index, or None if the slab is full.
+    pub fn claim_slot(&self) -> Option<usize> {
+        let capacity = self.slots.len() as u64;
+        let index = unsafe {
+            atomic_try_update(&self.claimed, |claimed| {
+                if *claimed < capacity {
+                    *claimed += 1;
+                    (true, Some(*claimed - 1))
+                } else {
+                    (false, None)
+                }
+            })
+        }? as usize;
+        // We are the only claimant of this slot, so this can't race.  Keep
+        // the slot prepared until `set` is called.
+        match self.slots[index].get_or_prepare_to_set() {
+            Ok(GetOrPrepare::Prepared(permit)) => std::mem::forget(permit),
+            _ => debug_assert!(false, "claimed an unprepared slot"),
+        }
+        Some(index)
+    }
+
+    /// Publishes the value for a slot returned by `claim_slot`.
+    ///
+    /// Returns error if the slot was not claimed, or was already set.  Panics
+    /// if index is out of bounds.
+    pub fn set(&'a self, index: usize, val: T) -> Result<&'a T, OnceLockFreeError> {
+        // claim_slot prepared the slot, and dropped its permit.
+        self.slots[index].set_prepared_ptr(Box::into_raw(Box::new(Align8::from(val))).cast())
+    }
+
+    /// Returns the value in a slot, or None if it has not been published
+    /// (or index is out of bounds).
+    pub fn get(&'a self, index: usize) -> Option<&'a T> {
+        self.slots.get(index)?.get_poll()
+    }
+
+    /// Returns the number of slots that have been claimed so far.
+    pub fn claimed(&self) -> usize {
+        unsafe { atomic_try_update(&self.claimed, |claimed| (false, *claimed)) as usize }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Iterates over the published values, along with their indices.
+    pub fn iter(&'a self) -> impl Iterator<Item = (usize, &'a T)> + 'a {
+        self.slots[..self.claimed()]
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| Some((index, slot.get_poll()?)))
+    }
+}
+
+/// The default number of buckets in a `OnceMap`.
+const ONCE_MAP_BUCKETS: usize = 64;
+
+struct OnceMapEntry<K, V> {
+    key: K,
+    val: OnceLockFree<V>,
+    next: *mut OnceMapEntry<K, V>,
+}
+
+struct OnceMapBucket<K, V> {
+    head: *mut OnceMapEntry<K, V>,
+}
+
+impl<K, V> Default for OnceMapBucket<K, V> {
+    fn default() -> Self {
+        Self { head: null_mut() }
+    }
+}
+
+/// A concurrent map where the value for each key can be set exactly once,
+/// for handler registration tables that are written at startup and then read
+/// millions of times per second.
+///
+/// The index is a fixed number of buckets, each of which is a lock-free,
+/// insert-only list of `OnceLockFree` cells.  Entries are never removed (or
+/// moved) until the map is dropped, so `get` is wait free, and the
+/// references it returns live as long as the map.  The index does not
+/// resize, so pick a bucket count that is on the order of the number of keys.
+pub struct OnceMap<K, V, S = RandomState> {
+    buckets: Box<[Atom<OnceMapBucket<K, V>, u64>]>,
+    hasher: S,
+    /// Atom is unconditionally Send and Sync, so this makes the map's
+    /// auto traits depend on K and V.
+    _entries: PhantomData<*mut OnceMapEntry<K, V>>,
+}
+
+unsafe impl<K: Send, V: Send, S: Send> Send for OnceMap<K, V, S> {}
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Sync> Sync for OnceMap<K, V, S> {}
+
+impl<K, V> OnceMap<K, V> {
+    /// Creates a map with the given number of buckets.
+    pub fn with_buckets(buckets: usize) -> Self {
+        Self::with_buckets_and_hasher(buckets, Default::default())
+    }
+}
+
+impl<K, V, S> OnceMap<K, V, S> {
+    /// Creates a map with the given number of buckets, that hashes keys
+    /// with hasher.
+    pub fn with_buckets_and_hasher(buckets: usize, hasher: S) -> Self {
+        assert!(buckets > 0, "OnceMap needs at least one bucket");
+        Self {
+            buckets: (0..buckets).map(|_| Default::default()).collect(),
+            hasher,
+            _entries: PhantomData,
+        }
+    }
+}
+
+impl<K, V> Default for OnceMap<K, V> {
+    fn default() -> Self {
+        Self::with_buckets(ONCE_MAP_BUCKETS)
+    }
+}
+
+impl<'a, K, V, S> OnceMap<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    fn bucket<Q>(&self, key: &Q) -> &Atom<OnceMapBucket<K, V>, u64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        &self.buckets[self.hasher.hash_one(key) as usize % self.buckets.len()]
+    }
+
+    /// Returns the entry for key in the list starting at head, if any.
+    fn find<Q>(mut head: *mut OnceMapEntry<K, V>, key: &Q) -> Option<&'a OnceMapEntry<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        while let Some(entry) = unsafe { head.as_ref() } {
+            if entry.key.borrow() == key {
+                return Some(entry);
+            }
+            head = entry.next;
+        }
+        None
+    }
+
+    /// Sets the value for key.  Wait free, unless other threads are
+    /// concurrently inserting into the same bucket.
+    ///
+    /// Returns `AlreadySet` if key already has a value, and a reference to the
+    /// stored value on success.
+    pub fn insert(&'a self, key: K, val: V) -> Result<&'a V, OnceLockFreeError> {
+        let bucket = self.bucket(&key);
+        let entry = Box::into_raw(Box::new(OnceMapEntry {
+            key,
+            val: OnceLockFree::default(),
+            next: null_mut(),
+        }));
+        unsafe {
+            // Publish the value along with the entry, so readers never see
+            // the key without it.
+            let _ = (*entry).val.set(val);
+            let inserted = atomic_try_update(bucket, |bucket| {
+                if Self::find(bucket.head, &(*entry).key).is_some() {
+                    return (false, false);
+                }
+                // entry is not published yet, so this is not a race.
+                (*entry).next = bucket.head;
+                bucket.head = entry;
+                (true, true)
+            });
+            if inserted {
+                Ok((*entry).val.get_poll().unwrap_unchecked())
+            } else {
+                drop(Box::from_raw(entry));
+                Err(OnceLockFreeError::AlreadySet)
+            }
+        }
+    }
+
+    /// Returns the value for key, or None if it has not been inserted.
+    pub fn get<Q>(&'a self, key: &Q) -> Option<&'a V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let head = unsafe { atomic_try_update(self.bucket(key), |bucket| (false, bucket.head)) };
+        Self::find(head, key)?.val.get_poll()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+}
+
+impl<K, V, S> Drop for OnceMap<K, V, S> {
+    fn drop(&mut self) {
+        for bucket in self.buckets.iter() {
+            let mut head = unsafe { atomic_try_update(bucket, |bucket| (false, bucket.head)) };
+            while !head.is_null() {
+                let entry = unsafe { Box::from_raw(head) };
+                head = entry.next;
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigGateError {
+    /// The key was not registered when the gate was created.
+    UnknownKey,
+    AlreadySet,
+    /// The gate was cancelled or sealed before every key was set.
+    Cancelled,
+}
+
+impl Error for ConfigGateError {}
+
+impl Display for ConfigGateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// A startup readiness gate: a fixed set of keys, each of which is set
+/// exactly once, and an async `ready()` that completes once all of them are.
+///
+/// Each key is a `OnceLockFree` cell, and a `ShutdownBarrier` counts the keys
+/// that have not been set yet, so `ready()` can be called before or after
+/// the last key is set.
+pub struct ConfigGate<K, V> {
+    cells: HashMap<K, OnceLockFree<V>>,
+    /// Has one worker per unset key.
+    pending: ShutdownBarrier,
+}
+
+impl<'a, K, V> ConfigGate<K, V>
+where
+    K: Hash + Eq,
+{
+    /// Creates a gate that is ready once every one of keys has been set.
+    pub fn new(keys: impl IntoIterator<Item = K>) -> Self {
+        let cells: HashMap<K, OnceLockFree<V>> = keys
+            .into_iter()
+            .map(|key| (key, OnceLockFree::new()))
+            .collect();
+        let pending = ShutdownBarrier::new();
+        for _ in 0..cells.len() {
+            // Can't fail; the barrier's initial worker is still running.
+            let _ = pending.spawn();
+        }
+        // Retire the initial worker, so the count is the number of keys.
+        let _ = pending.done();
+        Self { cells, pending }
+    }
+
+    /// Sets the value for key.
+    ///
+    /// Returns `UnknownKey` if key was not registered, and `AlreadySet` if it
+    /// was already set, or the gate was sealed.
+    pub fn set<Q>(&'a self, key: &Q, val: V) -> Result<&'a V, ConfigGateError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let cell = self.cells.get(key).ok_or(ConfigGateError::UnknownKey)?;
+        let val = cell.set(val).map_err(|_| ConfigGateError::AlreadySet)?;
+        // Only the one successful set of each key gets here.
+        let _ = self.pending.done();
+        Ok(val)
+    }
+
+    /// Returns the value for key, or None if it is unknown or not set yet.
+    pub fn get<Q>(&'a self, key: &Q) -> Option<&'a V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.cells.get(key)?.get_poll()
+    }
+
+    /// Waits until key has been set, and returns its value, so callers can
+    /// wait for that key alone.
+    ///
+    /// Returns `UnknownKey` if key was not registered, and `Cancelled` if the
+    /// gate was sealed before key was set.
+    pub async fn wait<Q>(&'a self, key: &Q) -> Result<&'a V, ConfigGateError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let cell = self.cells.get(key).ok_or(ConfigGateError::UnknownKey)?;
+        cell.waiters
+            .wait_for(|| match cell.get_poll() {
+                Some(val) => Some(Ok(val)),
+                None if cell.is_sealed() => Some(Err(ConfigGateError::Cancelled)),
+                None => None,
+            })
+            .await
+    }
+
+    /// Waits until every key has been set.  This can be called at any time,
+    /// and can be called multiple times.
+    ///
+    /// Returns `Cancelled` if the gate is cancelled or sealed first.
+    pub async fn ready(&self) -> Result<(), ConfigGateError> {
+        match self.pending.wait().await {
+            Ok(result) if !result.is_cancelled() => Ok(()),
+            _ => Err(ConfigGateError::Cancelled),
+        }
+    }
+
+    /// Cancels the gate, so `ready()` returns `Cancelled`.  Keys can still be
+    /// set.  Does nothing if the gate is already ready.
+    pub fn cancel(&self) {
+        let _ = self.pending.cancel();
+    }
+
+    /// Seals every key that has not been set, so later sets fail, and cancels
+    /// the gate.  Does nothing if the gate is already ready.
+    pub fn seal(&self) {
+        for cell in self.cells.values() {
+            cell.seal();
+        }
+        self.cancel();
+    }
+}
@@ -0,0 +1,201 @@
+//! A multi-producer FIFO queue in the style of Dmitry Vyukov's intrusive
+//! MPSC queue.
+//!
+//! `stack::Queue` gets FIFO order by pushing onto a stack and reversing each
+//! batch the consumer detaches.  `MpscQueue` instead keeps the list in FIFO
+//! order as it is built: the head word holds pointers to both ends of the
+//! list, and a push (or a batch of pushes) swings the tail pointer to its
+//! node with `atomic_try_update`, and then links the old tail to it.
+//! Consumers detach the whole list with a single CAS, so `drain` returns the
+//! values oldest first without touching them.
+//!
+//! As in Vyukov's queue, there is a short window between a producer's CAS
+//! and the store that links its node to the old tail.  A consumer that
+//! reaches the old tail during that window has to wait for the store.  The
+//! producer is never blocked, but a producer that is descheduled in the
+//! window stalls the consumer until it runs again.
+//!
+//! Detaching never reads through the head word's pointers, so it does not
+//! suffer from the ABA problem described in the `stack` module, and any
+//! number of threads may drain the queue.  Values drained by one thread come
+//! out in the order they were pushed, but with several consumers there is no
+//! ordering between their batches.
+//!
+//! `MpscQueue` is deprecated in favor of `stack::Queue`, which does the same
+//! job.  The head word holds two pointers, so it is 128 bits wide, and on
+//! stable rust, `AtomicCell` implements it with a lock, like every other
+//! `Atom<_, u128>`.  `stack::Queue` only uses 64 bit words, and reverses each
+//! value once, which is cheaper than taking that lock on every push.  Its
+//! `pop_all` returns values oldest first, like `drain`.
+#![allow(deprecated)]
+
+use std::{
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use crate::{atomic_try_update, Atom, Node};
+
+struct QueueHead<T> {
+    head: *mut Node<T>,
+    tail: *mut Node<T>,
+}
+
+/// A multi-producer FIFO queue.  See the module documentation.
+#[deprecated(note = "use `stack::Queue`, which does not need a 128 bit atom")]
+pub struct MpscQueue<T>
+where
+    T: Send,
+{
+    head: Atom<QueueHead<T>, u128>,
+}
+
+impl<T> Default for MpscQueue<T>
+where
+    T: Send,
+{
+    fn default() -> Self {
+        Self {
+            head: Atom::default(),
+        }
+    }
+}
+
+impl<T> MpscQueue<T>
+where
+    T: Send,
+{
+    pub fn push(&self, val: T) {
+        let node = Box::into_raw(Box::new(Node {
+            val,
+            next: null_mut(),
+        }));
+        unsafe { self.splice(node, node) }
+    }
+
+    /// Pushes every value in vals with a single CAS.  The values are linked
+    /// together before they are published, so they are drained in order and
+    /// with nothing from other producers in between.
+    pub fn push_batch<I>(&self, vals: I)
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let mut first: *mut Node<T> = null_mut();
+        let mut last: *mut Node<T> = null_mut();
+        for val in vals {
+            let node = Box::into_raw(Box::new(Node {
+                val,
+                next: null_mut(),
+            }));
+            if last.is_null() {
+                first = node;
+            } else {
+                unsafe { (*last).next = node };
+            }
+            last = node;
+        }
+        if !first.is_null() {
+            unsafe { self.splice(first, last) }
+        }
+    }
+
+    /// Appends the list from first to last, which must be linked together
+    /// and not yet visible to other threads.
+    unsafe fn splice(&self, first: *mut Node<T>, last: *mut Node<T>) {
+        let prev = atomic_try_update(&self.head, |head| {
+            let prev = head.tail;
+            if prev.is_null() {
+                head.head = first;
+            }
+            head.tail = last;
+            (true, prev)
+        });
+        if !prev.is_null() {
+            // prev can't have been freed: we are not at the tail of the list
+            // it was detached with, so whoever detached it waits for this
+            // store before moving past it.
+            next_ptr(prev).store(first, Ordering::Release);
+        }
+    }
+
+    /// Atomically removes everything from the queue, oldest first.
+    pub fn drain(&self) -> QueueBatch<T> {
+        let (node, tail) = unsafe {
+            atomic_try_update(&self.head, |head| {
+                let ret = (head.head, head.tail);
+                head.head = null_mut();
+                head.tail = null_mut();
+                (!ret.0.is_null(), ret)
+            })
+        };
+        QueueBatch { node, tail }
+    }
+
+    /// Returns true if nothing has been pushed since the last drain.  This is
+    /// only a snapshot; producers may push immediately after it returns.
+    pub fn is_empty(&self) -> bool {
+        unsafe { atomic_try_update(&self.head, |head| (false, head.head.is_null())) }
+    }
+}
+
+impl<T> Drop for MpscQueue<T>
+where
+    T: Send,
+{
+    fn drop(&mut self) {
+        drop(self.drain());
+    }
+}
+
+/// Producers link nodes with atomic stores, which may race with a
+/// consumer's loads.  node must outlive the returned reference.
+unsafe fn next_ptr<'a, T>(node: *mut Node<T>) -> &'a AtomicPtr<Node<T>> {
+    AtomicPtr::from_ptr(&raw mut (*node).next)
+}
+
+/// The values detached by `MpscQueue::drain`, oldest first.  Values that are
+/// not consumed are dropped along with the batch.
+pub struct QueueBatch<T> {
+    node: *mut Node<T>,
+    /// The last node in the batch.  Every other node's next pointer is set
+    /// eventually, but may still be null if its producer is mid-push.
+    tail: *mut Node<T>,
+}
+
+unsafe impl<T: Send> Send for QueueBatch<T> {}
+
+impl<T> QueueBatch<T> {
+    pub fn is_empty(&self) -> bool {
+        self.node.is_null()
+    }
+}
+
+impl<T> Iterator for QueueBatch<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.node.is_null() {
+            return None;
+        }
+        let node = self.node;
+        self.node = if node == self.tail {
+            null_mut()
+        } else {
+            let next = unsafe { next_ptr(node) };
+            loop {
+                let next = next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    break next;
+                }
+                std::hint::spin_loop();
+            }
+        };
+        Some(unsafe { Box::from_raw(node) }.val)
+    }
+}
+
+impl<T> Drop for QueueBatch<T> {
+    fn drop(&mut self) {
+        for _ in self {}
+    }
+}
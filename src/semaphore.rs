@@ -0,0 +1,183 @@
+//! A counting semaphore, for bounding how many tasks (or threads) use a
+//! resource at once.
+//!
+//! The number of available permits and a flag that says whether anyone is
+//! waiting for them share a single `Atom`.  Acquiring is a CAS that takes the
+//! permits if there are enough, and otherwise sets the flag.  Releasing is a
+//! CAS that returns the permits and clears the flag, and only a release that
+//! found the flag set has to wake anyone, so uncontended use never touches
+//! the waker list.
+//!
+//! Waiters re-check the state after registering their waker, and the failed
+//! check sets the flag again, so a release that races with a waiter going to
+//! sleep always wakes it.  All waiters are woken on each such release and
+//! race for the permits, so the semaphore is not fair: a large request can
+//! starve behind a stream of small ones.
+use std::future::Future;
+
+use crate::{atomic_try_update, waker_list::WakerList, Atom};
+
+/// The available permits in the low 32 bits, and the waiter flag above them.
+#[derive(Clone, Copy, Default)]
+struct SemaphoreState {
+    val: u64,
+}
+
+impl SemaphoreState {
+    const WAITERS: u64 = 1 << 32;
+
+    fn permits(&self) -> u32 {
+        self.val as u32
+    }
+    fn set_permits(&mut self, permits: u32) {
+        self.val = (self.val & Self::WAITERS) | u64::from(permits);
+    }
+    fn waiters(&self) -> bool {
+        self.val & Self::WAITERS != 0
+    }
+    fn set_waiters(&mut self, waiters: bool) {
+        self.val = (self.val & !Self::WAITERS) | if waiters { Self::WAITERS } else { 0 };
+    }
+}
+
+/// A counting semaphore with async and blocking acquires.  See the module
+/// documentation.
+pub struct Semaphore {
+    state: Atom<SemaphoreState, u64>,
+    waiters: WakerList,
+}
+
+impl Semaphore {
+    /// Returns a semaphore with the given number of permits.
+    pub fn new(permits: u32) -> Self {
+        let this = Self {
+            state: Default::default(),
+            waiters: Default::default(),
+        };
+        unsafe {
+            atomic_try_update(&this.state, |s| {
+                s.set_permits(permits);
+                (true, ())
+            });
+        }
+        this
+    }
+
+    /// Takes a permit if one is available.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Takes n permits if that many are available, and otherwise takes none.
+    pub fn try_acquire_many(&self, n: u32) -> Option<SemaphorePermit<'_>> {
+        let acquired = unsafe {
+            atomic_try_update(&self.state, |s| {
+                let permits = s.permits();
+                if permits < n {
+                    (false, false)
+                } else {
+                    s.set_permits(permits - n);
+                    (true, true)
+                }
+            })
+        };
+        acquired.then(|| SemaphorePermit {
+            semaphore: self,
+            permits: n,
+        })
+    }
+
+    /// Waits until a permit is available, and takes it.
+    pub fn acquire(&self) -> impl Future<Output = SemaphorePermit<'_>> + '_ {
+        self.acquire_many(1)
+    }
+
+    /// Waits until n permits are available, and takes them all at once.
+    pub fn acquire_many(&self, n: u32) -> impl Future<Output = SemaphorePermit<'_>> + '_ {
+        self.waiters.wait_for(move || self.poll_acquire(n))
+    }
+
+    /// Like `acquire()`, but blocks the calling thread.
+    pub fn acquire_blocking(&self) -> SemaphorePermit<'_> {
+        self.acquire_many_blocking(1)
+    }
+
+    /// Like `acquire_many()`, but blocks the calling thread.
+    pub fn acquire_many_blocking(&self, n: u32) -> SemaphorePermit<'_> {
+        self.waiters
+            .wait_blocking_for(|| self.poll_acquire(n), None)
+            .expect("waits without a deadline can't time out")
+    }
+
+    /// Returns n permits to the semaphore, such as permits that were given up
+    /// with `SemaphorePermit::forget()`, or new capacity.
+    ///
+    /// Panics if the semaphore would hold more than `u32::MAX` permits.
+    pub fn add_permits(&self, n: u32) {
+        let waiters = unsafe {
+            atomic_try_update(&self.state, |s| {
+                let waiters = s.waiters();
+                s.set_permits(s.permits().checked_add(n).expect("too many permits"));
+                s.set_waiters(false);
+                (true, waiters)
+            })
+        };
+        if waiters {
+            self.waiters.wake_all();
+        }
+    }
+
+    /// Returns the number of permits that are not currently held.
+    pub fn available_permits(&self) -> u32 {
+        unsafe { atomic_try_update(&self.state, |s| (false, s.permits())) }
+    }
+
+    /// Like `try_acquire_many()`, but on failure, records that someone is
+    /// waiting, so the next release wakes them.
+    fn poll_acquire(&self, n: u32) -> Option<SemaphorePermit<'_>> {
+        let acquired = unsafe {
+            atomic_try_update(&self.state, |s| {
+                let permits = s.permits();
+                if permits >= n {
+                    s.set_permits(permits - n);
+                    (true, true)
+                } else if s.waiters() {
+                    (false, false)
+                } else {
+                    s.set_waiters(true);
+                    (true, false)
+                }
+            })
+        };
+        acquired.then(|| SemaphorePermit {
+            semaphore: self,
+            permits: n,
+        })
+    }
+}
+
+/// Permits taken from a `Semaphore`.  They are returned when this is dropped.
+#[must_use]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: u32,
+}
+
+impl SemaphorePermit<'_> {
+    /// The number of permits held.
+    pub fn permits(&self) -> u32 {
+        self.permits
+    }
+
+    /// Gives up the permits without returning them, permanently reducing the
+    /// semaphore's capacity (until `Semaphore::add_permits()`).
+    pub fn forget(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.permits);
+    }
+}
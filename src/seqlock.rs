@@ -0,0 +1,174 @@
+//! A sequence lock, for read-mostly data that is too wide for an `Atom`.
+//!
+//! `atomic_try_update` can only update values of up to 128 bits.  `SeqLock`
+//! protects a larger `Copy` value (say, a 64-byte configuration block) with
+//! a sequence word that it manages with `atomic_try_update`.  A writer bumps
+//! the sequence to an odd number, stores the new value, and bumps it
+//! back to an even number.  Readers copy the value out, and retry if the
+//! sequence was odd, or changed while they were copying.  Readers never
+//! write to shared memory, so they don't contend with each other, and they
+//! never block writers.
+//!
+//! Writers exclude each other with the same sequence word: a writer that
+//! finds it odd waits for the other writer to finish.  That is fine for an
+//! occasional writer.  If writes are frequent, funnel them through a single
+//! thread (or a `claim::Claim`), since a waiting writer spins, and readers
+//! make no progress while writes are back to back.
+//!
+//! Like other seqlocks (including the fallback in `crossbeam_utils`'s
+//! `AtomicCell`), readers copy the value with volatile reads that may race
+//! with the writer, and only use the copy if the sequence shows that no
+//! write overlapped it.
+
+use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    ptr,
+    sync::atomic::{fence, Ordering},
+    thread,
+};
+
+use crate::{atomic_try_update, Atom};
+
+/// How many times a reader or writer spins on an odd sequence before it
+/// starts yielding its time slice to the writer.
+const SPINS: u32 = 64;
+
+pub struct SeqLock<T>
+where
+    T: Copy,
+{
+    seq: Atom<u64, u64>,
+    data: UnsafeCell<T>,
+}
+
+// Readers copy the value to other threads, and writers replace it from them.
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T> Default for SeqLock<T>
+where
+    T: Copy + Default,
+{
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> SeqLock<T>
+where
+    T: Copy,
+{
+    pub const fn new(val: T) -> Self {
+        Self {
+            seq: Atom::new(),
+            data: UnsafeCell::new(val),
+        }
+    }
+
+    /// Returns a copy of the value, retrying until it gets one that no write
+    /// overlapped.
+    pub fn read(&self) -> T {
+        let mut spins = 0;
+        loop {
+            if let Some(val) = self.try_read() {
+                return val;
+            }
+            backoff(&mut spins);
+        }
+    }
+
+    /// Like `read()`, but makes a single attempt, and returns None if a write
+    /// was in progress.
+    pub fn try_read(&self) -> Option<T> {
+        let seq = self.load();
+        if seq & 1 != 0 {
+            return None;
+        }
+        let val = unsafe { ptr::read_volatile(self.data.get() as *const MaybeUninit<T>) };
+        // Keeps the copy from being reordered after the second load.
+        fence(Ordering::Acquire);
+        // If the sequence is unchanged, no write started since the first
+        // load, so the copy is a value some write left behind.
+        (self.load() == seq).then(|| unsafe { val.assume_init() })
+    }
+
+    /// Replaces the value.
+    pub fn write(&self, val: T) {
+        self.update(|data| *data = val)
+    }
+
+    /// Runs f on a copy of the value and stores the result, waiting for any
+    /// other writer to finish first.  Readers retry until f returns.  If f
+    /// panics, the value is left unchanged.
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut spins = 0;
+        while !self.try_begin_write() {
+            backoff(&mut spins);
+        }
+        let _guard = WriteGuard { seq: &self.seq };
+        // Keeps the writes below from being reordered before the odd
+        // sequence is visible.
+        fence(Ordering::Release);
+        let mut val = unsafe { ptr::read(self.data.get()) };
+        let result = f(&mut val);
+        unsafe { ptr::write_volatile(self.data.get(), val) };
+        result
+    }
+
+    /// The number of writes that have completed.  Readers can compare it
+    /// with an earlier result to tell whether anything changed.
+    pub fn version(&self) -> u64 {
+        self.load() >> 1
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    /// Makes the sequence odd, unless another writer already has.
+    fn try_begin_write(&self) -> bool {
+        unsafe {
+            atomic_try_update(&self.seq, |seq| {
+                if *seq & 1 != 0 {
+                    return (false, false);
+                }
+                *seq += 1;
+                (true, true)
+            })
+        }
+    }
+
+    fn load(&self) -> u64 {
+        unsafe { atomic_try_update(&self.seq, |seq| (false, *seq)) }
+    }
+}
+
+/// Ends a write by making the sequence even again, even if the writer
+/// panicked, so readers don't spin forever.
+struct WriteGuard<'a> {
+    seq: &'a Atom<u64, u64>,
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            atomic_try_update(self.seq, |seq| {
+                *seq += 1;
+                (true, ())
+            })
+        }
+    }
+}
+
+fn backoff(spins: &mut u32) {
+    if *spins < SPINS {
+        *spins += 1;
+        std::hint::spin_loop();
+    } else {
+        thread::yield_now();
+    }
+}
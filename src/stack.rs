@@ -21,106 +21,1852 @@
 //! from the stack atomically.  This guarantees read set equivalence because it
 //! does not read anything other than the CAS bits.
 //!
+//! `Stack` also provides a single-item `pop`.  It uses a small built-in hazard
+//! pointer scheme to make sure the node it reads the next pointer from is not
+//! freed (and therefore can not be reused) until the CAS completes.  The
+//! hazard pointers are allocated by the first `pop` (or `pop_n`), so a stack
+//! that is only drained with `pop_all` still detaches with a single CAS, and
+//! never scans them.
+//!
+//! With the `epoch` feature, `Stack::peek_top` reads the top value in place.
+//! Peekers pin an epoch collector instead of taking hazard pointers, so
+//! threads that unlink nodes defer freeing them rather than waiting for
+//! the peek to finish.
+//!
+//! `Stack::close` stops a stack from accepting values.  After that, the
+//! infallible producer methods (`push`, `push_node`, `swap_contents`,
+//! `Extend`, and the `Queue`, `NotifyStack` and `BufferedPusher` wrappers)
+//! panic.  Producers that may race with `close()` must use the `try_`
+//! variants, which return the value in `StackError::Closed` instead.
+//!
+//! `Stack` allocates its nodes with the global allocator by default.  Use
+//! `Stack::new_in` to supply an `allocator_api2::alloc::Allocator` instead
+//! (on nightly, this is the same trait as `std::alloc::Allocator`).
+//!
 //! `NonceStack` uses a nonce to ensure that no pushes have been performed
 //! in race with pop, which probabilistically guarantees that head was not popped
-//! then pushed back on to the stack in race with a pop.
+//! then pushed back on to the stack in race with a pop.  Since pop may still
+//! read from a node that was popped in race, `NonceStack` frees nodes through
+//! a small epoch-based collector, which waits until no pop can still be
+//! reading them.
+//!
+//! `EliminationStack` wraps `Stack` with an elimination-backoff layer that lets
+//! pushes that collide with a `pop_all` hand their values over directly.
+//!
+//! `ShardedStack` spreads pushes across several `Stack`s to reduce contention,
+//! at the cost of ordering between shards.
+//!
+//! `BufferedPusher` (see `Stack::buffered`) batches pushes from one thread
+//! once it notices contention, and splices each batch on with a single CAS.
+//!
+//! `Queue` pairs two `Stack`s to provide a multi-producer, single-consumer
+//! FIFO queue.  (`queue::MpscQueue` builds its list in FIFO order instead,
+//! so nothing needs to be reversed.)
+//!
+//! `NotifyStack` lets consumers `await` the arrival of new items.
+//!
+//! `TimedStack` measures how long items spend on the stack.
+//!
+//! `CountedStack` tracks its length alongside its head, so consumers can
+//! wait for a batch of a given size before detaching it.
+//!
+//! `ValueStack` stores small `Copy` values in a preallocated slab, and threads
+//! the stack through it by index, so it never allocates after construction.
+//! `SlabStack` does the same for values of any type.
 
 //!
-use super::{atomic_try_update, Atom, Node, NodeIterator};
-use std::ptr::null_mut;
+use super::{
+    atomic_try_update, atomic_try_update_counting_retries, bits::FlagPtr, epoch::Collector,
+    hazard::HazardSlots, waker_list::WakerList, Atom, Node, NodeIterator,
+};
+use allocator_api2::alloc::{Allocator, Global};
+use crossbeam_utils::atomic::AtomicCell;
+#[cfg(feature = "stats")]
+use std::sync::atomic::AtomicI64;
+use std::{
+    alloc::{handle_alloc_error, Layout},
+    cell::{Cell, UnsafeCell},
+    collections::hash_map::DefaultHasher,
+    error::Error,
+    fmt::{Debug, Display},
+    hash::{Hash, Hasher},
+    hint::spin_loop,
+    mem::MaybeUninit,
+    ptr::{null_mut, NonNull},
+    sync::atomic::{fence, AtomicPtr, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Set in the flag bits of `Head::head` once `close()` has been called.
+const CLOSED: usize = 0b001;
+/// Set in the flag bits of `Head::head` while a `NotifyStack` consumer is
+/// waiting for the stack to become non-empty.  Cleared by the next push.
+const WAITING: usize = 0b010;
+
+struct Head<T> {
+    head: FlagPtr<Node<T>>,
+}
+
+enum PushAttempt {
+    Pushed,
+    Contended,
+    Closed,
+}
+
+/// Errors returned by `Stack` operations.  Operations that are rejected
+/// hand ownership of the value back to the caller.
+pub enum StackError<T> {
+    Closed(T),
+    /// Returned by fixed-capacity stacks when every slot is in use.
+    Full(T),
+}
+
+impl<T> Debug for StackError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StackError::Closed(_) => write!(f, "Closed(..)"),
+            StackError::Full(_) => write!(f, "Full(..)"),
+        }
+    }
+}
+
+impl<T> Error for StackError<T> {}
+
+impl<T> Display for StackError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+struct RetiredHead<T> {
+    head: *mut Node<T>,
+}
+
+/// The state that `Stack::pop` and `Stack::pop_n` need, and that `pop_all`
+/// only has to consult once it exists.
+struct PopState<T> {
+    /// Protects nodes that `pop()` is about to dereference.
+    hazards: HazardSlots,
+    /// Nodes whose values have been moved out, but that were still protected
+    /// by a hazard pointer when they were unlinked.
+    retired: Atom<RetiredHead<T>, u64>,
+}
+
+impl<T> Default for PopState<T> {
+    fn default() -> Self {
+        Self {
+            hazards: Default::default(),
+            retired: Default::default(),
+        }
+    }
+}
+
+pub struct Stack<T, A = Global>
+where
+    T: Send,
+    A: Allocator + Clone,
+{
+    head: Atom<Head<T>, u64>,
+    /// Null until the first `pop()` or `pop_n()`.  See `pop_state()`.
+    pops: AtomicPtr<PopState<T>>,
+    /// Pinned by `peek_top()`.  Nodes that are unlinked while it is pinned
+    /// are freed through it.
+    #[cfg(feature = "epoch")]
+    readers: Collector,
+    /// Bumped after every update to head.  See `generation()`.
+    generation: AtomicU64,
+    #[cfg(feature = "stats")]
+    stats: StackCounters,
+    alloc: A,
+}
+
+/// A snapshot of the counters that each `Stack` maintains when the `stats`
+/// feature is enabled.  The counters are updated with relaxed atomics
+/// outside of the CAS, so a snapshot taken while the stack is in use is
+/// approximate.
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StackStats {
+    pub pushes: u64,
+    /// Successful single-item pops.
+    pub pops: u64,
+    /// Calls that detached the whole stack (`pop_all` and friends).
+    pub pop_alls: u64,
+    /// The largest number of items observed on the stack at once.
+    pub max_depth: u64,
+    /// Compare and swaps on the head that failed and had to be retried.
+    pub cas_retries: u64,
+}
+
+#[cfg(feature = "stats")]
+#[derive(Default)]
+struct StackCounters {
+    pushes: AtomicU64,
+    pops: AtomicU64,
+    pop_alls: AtomicU64,
+    /// Can transiently go negative, since removals may be recorded before
+    /// the pushes they raced with.
+    depth: AtomicI64,
+    max_depth: AtomicU64,
+    cas_retries: AtomicU64,
+}
+
+impl<T, A> Default for Stack<T, A>
+where
+    T: Send,
+    A: Allocator + Clone + Default,
+{
+    fn default() -> Self {
+        Self::new_in(Default::default())
+    }
+}
+
+impl<T, A> Stack<T, A>
+where
+    T: Send,
+    A: Allocator + Clone,
+{
+    /// Creates a stack that allocates its nodes with alloc.  Items removed
+    /// from the stack are returned in `NodeIterator`s that free their nodes
+    /// with a clone of alloc.
+    pub fn new_in(alloc: A) -> Self {
+        Self {
+            head: Default::default(),
+            pops: AtomicPtr::new(null_mut()),
+            #[cfg(feature = "epoch")]
+            readers: Default::default(),
+            generation: Default::default(),
+            #[cfg(feature = "stats")]
+            stats: Default::default(),
+            alloc,
+        }
+    }
+
+    /// Pushes val onto the stack.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack has been closed.  Stacks that are never closed
+    /// can't fail, so this is infallible for them.  Use `try_push` if
+    /// producers may race with `close()`.
+    pub fn push(&self, val: T) {
+        if self.try_push(val).is_err() {
+            panic!("push to closed Stack");
+        }
+    }
+
+    /// Pushes val onto the stack, or returns it in `StackError::Closed` if
+    /// the stack has been closed.
+    pub fn try_push(&self, val: T) -> Result<(), StackError<T>> {
+        let node = self.alloc_node(Node {
+            val,
+            next: std::ptr::null_mut(),
+        });
+
+        match unsafe { self.push_raw(node) } {
+            Some(_) => Ok(()),
+            None => Err(StackError::Closed(unsafe { self.take_node(node) })),
+        }
+    }
+    /// Links node onto the stack, and returns true if a `NotifyStack`
+    /// consumer was waiting for it.  Returns None (and leaves node owned by
+    /// the caller) if the stack is closed.
+    unsafe fn push_raw(&self, node: *mut Node<T>) -> Option<bool> {
+        self.push_list_raw(node, node, 1)
+    }
+    /// Like `push_raw`, but splices the count nodes from top to last (which
+    /// must already be linked together) onto the stack with one CAS.
+    unsafe fn push_list_raw(
+        &self,
+        top: *mut Node<T>,
+        last: *mut Node<T>,
+        count: u64,
+    ) -> Option<bool> {
+        let pushed = self.update_head(|head: &mut Head<T>| {
+            let flag = head.head.get_flag();
+            if flag & CLOSED != 0 {
+                return (false, None);
+            }
+            (*last).next = head.head.get_ptr();
+            head.head.set_ptr(top);
+            head.head.set_flag(flag & !WAITING);
+            (true, Some(flag & WAITING != 0))
+        });
+        if pushed.is_some() {
+            self.record_pushed(count);
+        }
+        pushed
+    }
+    /// Returns a handle that batches this thread's pushes.  See
+    /// `BufferedPusher`.
+    pub fn buffered(&self) -> BufferedPusher<'_, T, A> {
+        BufferedPusher {
+            stack: self,
+            top: null_mut(),
+            last: null_mut(),
+            len: 0,
+            oldest: None,
+            max_len: DEFAULT_BUFFERED_LEN,
+            max_delay: DEFAULT_BUFFERED_DELAY,
+        }
+    }
+    /// Like `push_raw`, but gives up instead of retrying if the first CAS
+    /// fails.  Unless this returns `Pushed`, node is still owned by the caller.
+    unsafe fn push_raw_once(&self, node: *mut Node<T>) -> PushAttempt {
+        // This is the only state the lambda carries between invocations; it
+        // never influences the value that gets installed.
+        let attempted = Cell::new(false);
+        let attempt = self.update_head(|head: &mut Head<T>| {
+            if head.head.get_flag() & CLOSED != 0 {
+                return (false, PushAttempt::Closed);
+            }
+            if attempted.replace(true) {
+                return (false, PushAttempt::Contended);
+            }
+            (*node).next = head.head.get_ptr();
+            head.head.set_ptr(node);
+            (true, PushAttempt::Pushed)
+        });
+        if let PushAttempt::Pushed = attempt {
+            self.record_pushed(1);
+        }
+        attempt
+    }
+    /// Every update to the head goes through this, so that we can maintain
+    /// the generation counter, and builds with the `stats` feature can count
+    /// CAS retries.
+    unsafe fn update_head<F, R>(&self, func: F) -> R
+    where
+        F: Fn(&mut Head<T>) -> (bool, R),
+    {
+        // Whether the last (and therefore successful) invocation of func
+        // asked for a write.
+        let wrote = Cell::new(false);
+        let (ret, _retries) = atomic_try_update_counting_retries(&self.head, |head| {
+            let (write, ret) = func(head);
+            wrote.set(write);
+            (write, ret)
+        });
+        if wrote.get() {
+            self.generation.fetch_add(1, Ordering::Release);
+        }
+        #[cfg(feature = "stats")]
+        self.stats
+            .cas_retries
+            .fetch_add(_retries, Ordering::Relaxed);
+        ret
+    }
+    fn record_pushed(&self, _count: u64) {
+        #[cfg(feature = "stats")]
+        {
+            let count = _count as i64;
+            self.stats.pushes.fetch_add(_count, Ordering::Relaxed);
+            let depth = self.stats.depth.fetch_add(count, Ordering::Relaxed) + count;
+            self.stats
+                .max_depth
+                .fetch_max(depth.max(0) as u64, Ordering::Relaxed);
+        }
+    }
+    /// Records the removal of a detached list.  With the `stats` feature,
+    /// this walks the list to count it.
+    fn record_detached(&self, _list: *mut Node<T>) {
+        #[cfg(feature = "stats")]
+        {
+            let mut count = 0;
+            let mut node = _list;
+            while !node.is_null() {
+                count += 1;
+                node = unsafe { (*node).next };
+            }
+            self.stats.depth.fetch_sub(count, Ordering::Relaxed);
+        }
+    }
+    /// Returns a snapshot of this stack's statistics.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> StackStats {
+        StackStats {
+            pushes: self.stats.pushes.load(Ordering::Relaxed),
+            pops: self.stats.pops.load(Ordering::Relaxed),
+            pop_alls: self.stats.pop_alls.load(Ordering::Relaxed),
+            max_depth: self.stats.max_depth.load(Ordering::Relaxed),
+            cas_retries: self.stats.cas_retries.load(Ordering::Relaxed),
+        }
+    }
+    pub fn pop_all(&self) -> NodeIterator<T, A> {
+        self.detach_all(false)
+    }
+    /// Atomically removes everything from the stack, and passes each value
+    /// to f, most recently pushed first.  Each node is freed as soon as its
+    /// value has been moved out, and if f panics, the remaining values are
+    /// dropped.
+    ///
+    /// Returns the number of values passed to f.
+    pub fn drain_with<F>(&self, mut f: F) -> usize
+    where
+        F: FnMut(T),
+    {
+        let mut count = 0;
+        for val in self.pop_all() {
+            f(val);
+            count += 1;
+        }
+        count
+    }
+    /// Clears buf, then atomically removes everything from the stack and
+    /// appends it to buf, most recently pushed first.  Each node is freed as
+    /// soon as its value has been moved into buf.
+    ///
+    /// Reusing the same buffer across calls lets tight consumer loops drain
+    /// the stack without allocating once the buffer has grown to fit.
+    pub fn pop_all_into(&self, buf: &mut Vec<T>) {
+        buf.clear();
+        buf.extend(self.pop_all());
+    }
+    /// Removes and returns the most recently pushed value, or None if the
+    /// stack is empty.
+    ///
+    /// Unlike `NonceStack::pop`, this never reads freed memory.  Before the
+    /// lambda follows `head.next`, pop publishes head in a hazard pointer
+    /// and then checks that head is still the top of the stack.  Any thread
+    /// that unlinks a node (pop, pop_all, etc.) checks the hazard pointers
+    /// after its CAS, and defers freeing protected nodes.  Since a protected
+    /// node is never freed, it can not be reallocated and pushed back on to
+    /// the stack, so `head.next` is unchanged whenever the CAS succeeds.
+    ///
+    /// The hazard pointers are allocated by the first call to pop (or
+    /// `pop_n`), and up to `HAZARD_SLOTS` (16) threads can pop concurrently.
+    /// Additional poppers spin until a slot is available.  Once they exist,
+    /// every `pop_all` checks them after its CAS.
+    pub fn pop(&self) -> Option<T> {
+        let state = self.pop_state();
+        let hazard = state.hazards.acquire();
+        let node = loop {
+            let top =
+                unsafe { self.update_head(|head: &mut Head<T>| (false, head.head.get_ptr())) };
+            if top.is_null() {
+                return None;
+            }
+            hazard.protect(top);
+            let popped = unsafe {
+                self.update_head(|head: &mut Head<T>| {
+                    if head.head.get_ptr() != top {
+                        // top may have been freed; it is not safe to follow its next pointer.
+                        return (false, false);
+                    }
+                    head.head.set_ptr((*top).next);
+                    (true, true)
+                })
+            };
+            if popped {
+                break top;
+            }
+        };
+        drop(hazard);
+        #[cfg(feature = "stats")]
+        {
+            self.stats.pops.fetch_add(1, Ordering::Relaxed);
+            self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        let val = unsafe { std::ptr::read(&(*node).val) };
+        unsafe { self.retire(state, node) };
+        Some(val)
+    }
+    /// Passes a reference to the most recently pushed value to f, without
+    /// removing it.  Returns None if the stack is empty.  By the time
+    /// peek_top returns, the value may already have been popped.
+    ///
+    /// The call pins the stack's epoch collector before it loads the head.
+    /// Threads that unlink nodes (with `pop`, `pop_all`, etc.) check for
+    /// pinned readers after their CAS, and if there are any, retire the
+    /// nodes to the collector instead of freeing them, so they never wait
+    /// for f.  A slow f only delays the freeing of nodes unlinked while it
+    /// runs.  Up to `EPOCH_SLOTS` (16) threads can peek concurrently, and
+    /// additional readers spin until a slot is available.
+    ///
+    /// Since poppers don't wait, they may move the value out of the node
+    /// while f is reading it.  That is only harmless if moving is a copy,
+    /// hence the `T: Copy` bound.
+    #[cfg(feature = "epoch")]
+    pub fn peek_top<F, R>(&self, f: F) -> Option<R>
+    where
+        T: Copy,
+        A: Send,
+        F: FnOnce(&T) -> R,
+    {
+        let _guard = self.readers.pin();
+        let top = unsafe { self.update_head(|head: &mut Head<T>| (false, head.head.get_ptr())) };
+        if top.is_null() {
+            return None;
+        }
+        Some(f(unsafe { &(*top).val }))
+    }
+    /// Atomically removes up to k of the most recently pushed values, and
+    /// returns them in LIFO order.
+    ///
+    /// This walks the top k nodes hand-over-hand, publishing a hazard pointer
+    /// for the node it is about to read, and then checking that the old head
+    /// is still at the top of the stack.  Since a protected head can not be
+    /// popped and then pushed back, an unchanged head means the nodes below
+    /// it are unchanged too, so a single CAS from the old head to the k+1'th
+    /// node detaches exactly the nodes that were walked.  Concurrent pushes
+    /// or pops restart the walk, so this costs O(k) reads of the head, and
+    /// can be starved by a steady stream of racing updates.  Use `pop_all`
+    /// if you want the whole stack.
+    pub fn pop_n(&self, k: usize) -> NodeIterator<T, A> {
+        if k == 0 {
+            return self.list(null_mut());
+        }
+        let (head_hazard, cursor_hazard) = self.pop_state().hazards.acquire_pair();
+        let load_head =
+            || unsafe { self.update_head(|head: &mut Head<T>| (false, head.head.get_ptr())) };
+        let (top, last) = 'retry: loop {
+            let top = load_head();
+            if top.is_null() {
+                return self.list(null_mut());
+            }
+            head_hazard.protect(top);
+            if load_head() != top {
+                continue;
+            }
+            let mut last = top;
+            for _ in 1..k {
+                let next = unsafe { (*last).next };
+                if next.is_null() {
+                    break;
+                }
+                cursor_hazard.protect(next);
+                if load_head() != top {
+                    continue 'retry;
+                }
+                last = next;
+            }
+            let rest = unsafe { (*last).next };
+            let detached = unsafe {
+                self.update_head(|head: &mut Head<T>| {
+                    if head.head.get_ptr() != top {
+                        return (false, false);
+                    }
+                    head.head.set_ptr(rest);
+                    (true, true)
+                })
+            };
+            if detached {
+                break (top, last);
+            }
+        };
+        drop(head_hazard);
+        drop(cursor_hazard);
+        unsafe {
+            (*last).next = null_mut();
+        }
+        self.record_detached(top);
+        self.list(unsafe { self.launder(top) })
+    }
+    /// Prevents any further pushes to this stack.  Items that are already
+    /// on the stack stay there until they are removed with `pop_all`.
+    ///
+    /// Returns true if this call closed the stack, and false if it was
+    /// already closed.
+    pub fn close(&self) -> bool {
+        unsafe {
+            self.update_head(|head: &mut Head<T>| {
+                let flag = head.head.get_flag();
+                if flag & CLOSED != 0 {
+                    (false, false)
+                } else {
+                    head.head.set_flag(flag | CLOSED);
+                    (true, true)
+                }
+            })
+        }
+    }
+    /// Atomically removes everything from the stack and closes it.  Since
+    /// both happen in the same CAS, no push can land after the final drain,
+    /// which makes this the natural way to tear down a stack that still has
+    /// live producers.
+    pub fn pop_all_and_close(&self) -> NodeIterator<T, A> {
+        self.detach_all(true)
+    }
+    /// Detaches everything if the stack is non-empty (or closed).  Otherwise,
+    /// sets the waiting flag, so that the next push reports that it needs to
+    /// wake us, and returns None.
+    fn detach_all_or_set_waiting(&self) -> Option<NodeIterator<T, A>> {
+        let node = unsafe {
+            self.update_head(|head: &mut Head<T>| {
+                let ret = head.head.get_ptr();
+                let flag = head.head.get_flag();
+                if !ret.is_null() {
+                    head.head.set_ptr(null_mut());
+                    (true, Some(ret))
+                } else if flag & CLOSED != 0 {
+                    (false, Some(ret))
+                } else {
+                    head.head.set_flag(flag | WAITING);
+                    (flag & WAITING == 0, None)
+                }
+            })
+        }?;
+        #[cfg(feature = "stats")]
+        self.stats.pop_alls.fetch_add(1, Ordering::Relaxed);
+        self.record_detached(node);
+        Some(self.list(unsafe { self.launder(node) }))
+    }
+    fn detach_all(&self, close: bool) -> NodeIterator<T, A> {
+        let node = unsafe {
+            self.update_head(|head: &mut Head<T>| {
+                let ret = head.head.get_ptr();
+                let flag = head.head.get_flag();
+                // Skip the CAS (and the generation bump) if there is nothing
+                // to do.
+                if ret.is_null() && (!close || flag & CLOSED != 0) {
+                    return (false, ret);
+                }
+                head.head.set_ptr(null_mut());
+                if close {
+                    head.head.set_flag(flag | CLOSED);
+                }
+                (true, ret)
+            })
+        };
+        #[cfg(feature = "stats")]
+        self.stats.pop_alls.fetch_add(1, Ordering::Relaxed);
+        self.record_detached(node);
+        self.list(unsafe { self.launder(node) })
+    }
+    /// Takes a list of nodes that was just unlinked from the stack and
+    /// replaces any nodes that are protected by concurrent `pop()` calls
+    /// with fresh copies, so that the caller can free the list as usual.
+    /// The protected originals are retired.  If a `peek_top()` call is in
+    /// progress, it may be reading any of the nodes, so they are all
+    /// replaced, and the originals are handed to the epoch collector.
+    ///
+    /// This only allocates if a pop or peek raced with the unlinking CAS, and
+    /// only scans the hazard pointers if pop has ever been called.
+    unsafe fn launder(&self, mut list: *mut Node<T>) -> *mut Node<T> {
+        let state = if list.is_null() {
+            None
+        } else {
+            self.pop_state_if_any()
+        };
+        let protected = state.map_or_else(Vec::new, |state| state.hazards.snapshot::<Node<T>>());
+        #[cfg(feature = "epoch")]
+        let peeked = !list.is_null() && self.readers.is_pinned();
+        #[cfg(not(feature = "epoch"))]
+        let peeked = false;
+        if protected.is_empty() && !peeked {
+            return list;
+        }
+        let mut link: *mut *mut Node<T> = &mut list;
+        while !(*link).is_null() {
+            let node = *link;
+            let protected_by = state.filter(|_| protected.contains(&node));
+            if protected_by.is_some() || peeked {
+                let copy = self.alloc_node(Node {
+                    val: std::ptr::read(&(*node).val),
+                    next: (*node).next,
+                });
+                *link = copy;
+                match protected_by {
+                    Some(state) => self.push_retired(state, node),
+                    None => self.free_node(node),
+                }
+            }
+            link = &mut (**link).next;
+        }
+        list
+    }
+    /// Returns the hazard pointers and retired list, allocating them if this
+    /// is the first `pop()` or `pop_n()`.
+    fn pop_state(&self) -> &PopState<T> {
+        let mut state = self.pops.load(Ordering::Acquire);
+        if state.is_null() {
+            let new = Box::into_raw(Box::<PopState<T>>::default());
+            state = match self.pops.compare_exchange(
+                null_mut(),
+                new,
+                Ordering::SeqCst,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => new,
+                Err(current) => {
+                    drop(unsafe { Box::from_raw(new) });
+                    current
+                }
+            };
+        }
+        // Pairs with the fence in pop_state_if_any: either the unlinking
+        // thread sees the state (and then checks our hazard pointer), or our
+        // hazard pointer validation sees its CAS.
+        fence(Ordering::SeqCst);
+        unsafe { &*state }
+    }
+    /// Returns the pop state, or None if nothing has popped yet, in which
+    /// case no node can be protected by a hazard pointer.  Call this after
+    /// the unlinking CAS.
+    fn pop_state_if_any(&self) -> Option<&PopState<T>> {
+        fence(Ordering::SeqCst);
+        unsafe { self.pops.load(Ordering::Acquire).as_ref() }
+    }
+    /// Frees a node whose value has already been moved out, or retires it
+    /// if it is protected by a hazard pointer.
+    unsafe fn retire(&self, state: &PopState<T>, node: *mut Node<T>) {
+        self.reclaim(state);
+        if state.hazards.is_protected(node) {
+            self.push_retired(state, node);
+        } else {
+            self.free_node(node);
+        }
+    }
+    unsafe fn push_retired(&self, state: &PopState<T>, node: *mut Node<T>) {
+        atomic_try_update(&state.retired, |retired| {
+            (*node).next = retired.head;
+            retired.head = node;
+            (true, ())
+        })
+    }
+    /// Frees any retired nodes that are no longer protected.
+    unsafe fn reclaim(&self, state: &PopState<T>) {
+        let mut node = atomic_try_update(&state.retired, |retired| {
+            let ret = retired.head;
+            retired.head = null_mut();
+            (!ret.is_null(), ret)
+        });
+        while !node.is_null() {
+            let next = (*node).next;
+            if state.hazards.is_protected(node) {
+                self.push_retired(state, node);
+            } else {
+                self.free_node(node);
+            }
+            node = next;
+        }
+    }
+    /// Returns a counter that changes whenever the stack is modified, so
+    /// pollers can cheaply check whether anything happened since they last
+    /// looked without draining the stack.
+    ///
+    /// The counter is only eventually consistent with the contents of the
+    /// stack.  It is a separate word that is bumped just after each
+    /// successful CAS on the head, rather than being packed into the head
+    /// word; doing the latter would require a 128 bit CAS, which stable rust
+    /// implements with a lock.  As a result, a modification becomes visible
+    /// here shortly after it is visible to `pop`, `pop_all` and `peek_top`,
+    /// so a reader can see the new head together with the old generation.
+    /// It never lags past the time the modifying call returns: if two calls
+    /// return the same value, then no push, pop or close returned in between
+    /// them.  Don't use it to pair a generation with a snapshot of the
+    /// stack's contents.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+    pub fn is_closed(&self) -> bool {
+        unsafe {
+            self.update_head(|head: &mut Head<T>| (false, head.head.get_flag() & CLOSED != 0))
+        }
+    }
+    /// Atomically removes everything from the stack, and returns it in the
+    /// order it was pushed (oldest first).
+    ///
+    /// The stack is detached with the same single CAS as `pop_all`.  The
+    /// reversal happens afterwards, outside of `atomic_try_update`, by
+    /// relinking the detached nodes in place.  This costs one pass over the
+    /// list (O(n) pointer writes, no allocation) before the first item is
+    /// returned, so callers that don't care about ordering should prefer
+    /// `pop_all`.
+    pub fn pop_all_fifo(&self) -> NodeIterator<T, A> {
+        self.pop_all().rev()
+    }
+    fn list(&self, node: *mut Node<T>) -> NodeIterator<T, A> {
+        NodeIterator::new_in(node, self.alloc.clone())
+    }
+    fn alloc_node(&self, node: Node<T>) -> *mut Node<T> {
+        let layout = Layout::new::<Node<T>>();
+        let ptr = match self.alloc.allocate(layout) {
+            Ok(ptr) => ptr.cast::<Node<T>>().as_ptr(),
+            Err(_) => handle_alloc_error(layout),
+        };
+        unsafe { ptr.write(node) };
+        ptr
+    }
+    /// Moves the value out of node, and frees it.
+    unsafe fn take_node(&self, node: *mut Node<T>) -> T {
+        let val = std::ptr::read(&(*node).val);
+        self.dealloc_node(node);
+        val
+    }
+    /// Frees the memory of a node without dropping its value.
+    unsafe fn dealloc_node(&self, node: *mut Node<T>) {
+        self.alloc.deallocate(
+            NonNull::new_unchecked(node).cast(),
+            Layout::new::<Node<T>>(),
+        );
+    }
+    /// Like `dealloc_node`, for nodes that were just unlinked from the
+    /// stack.  If a `peek_top()` call may be reading the node, it is retired
+    /// to the epoch collector instead.
+    unsafe fn free_node(&self, node: *mut Node<T>) {
+        #[cfg(feature = "epoch")]
+        if self.readers.is_pinned() {
+            // peek_top requires A: Send, and nothing else pins the collector,
+            // so the allocator may be dropped on another thread.
+            self.readers.retire(Box::into_raw(Box::new(PeekedNode {
+                node,
+                alloc: self.alloc.clone(),
+            })));
+            return;
+        }
+        self.dealloc_node(node);
+    }
+}
+
+/// A node that was unlinked while `Stack::peek_top` may have been reading
+/// it.  Its value has been moved out, so dropping this only frees the memory.
+#[cfg(feature = "epoch")]
+struct PeekedNode<T, A>
+where
+    A: Allocator,
+{
+    node: *mut Node<T>,
+    alloc: A,
+}
+
+#[cfg(feature = "epoch")]
+impl<T, A> Drop for PeekedNode<T, A>
+where
+    A: Allocator,
+{
+    fn drop(&mut self) {
+        unsafe {
+            self.alloc.deallocate(
+                NonNull::new_unchecked(self.node).cast(),
+                Layout::new::<Node<T>>(),
+            )
+        };
+    }
+}
+
+/// Methods that exchange nodes or lists with the caller.  These are only
+/// available with the global allocator, which is the only one that the
+/// nodes passed in are guaranteed to have been allocated with.
+impl<T> Stack<T>
+where
+    T: Send,
+{
+    /// Pushes an already-allocated node onto the stack.  Its `next` pointer
+    /// is overwritten.  Combined with `NodeIterator::next_node`, this lets
+    /// consumers hand nodes back to producers instead of freeing them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack has been closed.  Use `try_push_node` if producers
+    /// may race with `close()`.
+    pub fn push_node(&self, node: Box<Node<T>>) {
+        if self.try_push_node(node).is_err() {
+            panic!("push to closed Stack");
+        }
+    }
+
+    /// Like `push_node`, but returns the node if the stack has been closed.
+    pub fn try_push_node(&self, node: Box<Node<T>>) -> Result<(), StackError<Box<Node<T>>>> {
+        let node = Box::into_raw(node);
+        match unsafe { self.push_raw(node) } {
+            Some(_) => Ok(()),
+            None => Err(StackError::Closed(unsafe { Box::from_raw(node) })),
+        }
+    }
+    /// Atomically replaces the contents of the stack with list, and returns
+    /// the old contents.  The first item yielded by list becomes the top of
+    /// the stack.
+    ///
+    /// This is useful for double-buffering lists of pending work:  a consumer
+    /// can install a prebuilt list (for instance, the work it could not
+    /// finish in the previous round) and take everything that was pushed in
+    /// the meantime with a single CAS.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack has been closed, after dropping list.
+    pub fn swap_contents(&self, list: NodeIterator<T>) -> NodeIterator<T> {
+        let new = list.node;
+        std::mem::forget(list);
+        #[cfg(feature = "stats")]
+        let new_len = {
+            let mut count = 0;
+            let mut node = new;
+            while !node.is_null() {
+                count += 1;
+                node = unsafe { (*node).next };
+            }
+            count
+        };
+        let (old, closed) = unsafe {
+            self.update_head(|head: &mut Head<T>| {
+                if head.head.get_flag() & CLOSED != 0 {
+                    return (false, (null_mut(), true));
+                }
+                let old = head.head.get_ptr();
+                head.head.set_ptr(new);
+                (true, (old, false))
+            })
+        };
+        if closed {
+            drop(NodeIterator::new(new));
+            panic!("swap_contents on closed Stack");
+        }
+        #[cfg(feature = "stats")]
+        self.stats.depth.fetch_add(new_len, Ordering::Relaxed);
+        self.record_detached(old);
+        self.list(unsafe { self.launder(old) })
+    }
+}
+
+impl<T, A> Drop for Stack<T, A>
+where
+    T: Send,
+    A: Allocator + Clone,
+{
+    fn drop(&mut self) {
+        self.pop_all();
+        let state = *self.pops.get_mut();
+        if !state.is_null() {
+            let state = unsafe { Box::from_raw(state) };
+            // No hazard pointers can be held now, so this frees everything.
+            unsafe { self.reclaim(&state) };
+        }
+    }
+}
+
+/// Pushes each value in turn, so the last value yielded by the iterator ends
+/// up on top of the stack.
+///
+/// # Panics
+///
+/// Panics if the stack has been closed, like `Stack::push`.
+impl<T, A> Extend<T> for Stack<T, A>
+where
+    T: Send,
+    A: Allocator + Clone,
+{
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for val in iter {
+            self.push(val);
+        }
+    }
+}
+
+/// Builds a stack by pushing each value in turn.  Popping the result yields
+/// the values in the reverse of the iteration order.
+impl<T, A> FromIterator<T> for Stack<T, A>
+where
+    T: Send,
+    A: Allocator + Clone + Default,
+{
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut stack = Self::default();
+        stack.extend(iter);
+        stack
+    }
+}
+
+/// Drains the stack, yielding the most recently pushed value first.
+impl<T, A> IntoIterator for Stack<T, A>
+where
+    T: Send,
+    A: Allocator + Clone,
+{
+    type Item = T;
+    type IntoIter = NodeIterator<T, A>;
+
+    fn into_iter(self) -> NodeIterator<T, A> {
+        self.pop_all()
+    }
+}
+
+/// Number of values a `BufferedPusher` holds before flushing them.
+const DEFAULT_BUFFERED_LEN: usize = 32;
+/// How long a `BufferedPusher` holds values before flushing them.
+const DEFAULT_BUFFERED_DELAY: Duration = Duration::from_millis(1);
+
+/// A per-thread producer handle for a `Stack` that trades latency for lower
+/// contention on the head.
+///
+/// While the stack is uncontended, `push` behaves like `Stack::push`.  Once a
+/// push loses a CAS race, the pusher starts accumulating values in a private
+/// list instead, and splices the whole list onto the stack with a single
+/// CAS when it holds `max_len` values, or when its oldest value has waited
+/// for `max_delay`.  The time limit is only checked by `push`, so an idle
+/// pusher keeps its values until its next push, an explicit `flush`, or
+/// until it is dropped.
+///
+/// Values that were buffered together are popped together, in LIFO order.
+/// Buffered values are invisible to consumers until they are flushed.
+pub struct BufferedPusher<'a, T, A = Global>
+where
+    T: Send,
+    A: Allocator + Clone,
+{
+    stack: &'a Stack<T, A>,
+    top: *mut Node<T>,
+    last: *mut Node<T>,
+    len: usize,
+    oldest: Option<Instant>,
+    max_len: usize,
+    max_delay: Duration,
+}
+
+impl<'a, T, A> BufferedPusher<'a, T, A>
+where
+    T: Send,
+    A: Allocator + Clone,
+{
+    /// Sets the number of values to accumulate before flushing.  A max_len of
+    /// one or less disables buffering.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Sets how long values may be buffered before the next push flushes them.
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Pushes val, or buffers it if the stack is contended.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack has been closed.  This might be detected while
+    /// flushing values pushed by earlier calls, which are then dropped.  Use
+    /// `try_flush` after closing if the buffered values must be recovered.
+    pub fn push(&mut self, val: T) {
+        let node = self.stack.alloc_node(Node {
+            val,
+            next: null_mut(),
+        });
+        if self.len == 0 {
+            match unsafe { self.stack.push_raw_once(node) } {
+                PushAttempt::Pushed => return,
+                PushAttempt::Closed => {
+                    drop(unsafe { self.stack.take_node(node) });
+                    panic!("push to closed Stack");
+                }
+                PushAttempt::Contended => self.oldest = Some(Instant::now()),
+            }
+        }
+        unsafe { (*node).next = self.top };
+        if self.len == 0 {
+            self.last = node;
+        }
+        self.top = node;
+        self.len += 1;
+        if self.len >= self.max_len || self.oldest.is_some_and(|t| t.elapsed() >= self.max_delay) {
+            self.flush();
+        }
+    }
+
+    /// Number of values waiting to be flushed.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Splices any buffered values onto the stack.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack has been closed.  Use `try_flush` to get the
+    /// values back instead.
+    pub fn flush(&mut self) {
+        if self.try_flush().is_err() {
+            panic!("push to closed Stack");
+        }
+    }
+
+    /// Splices any buffered values onto the stack, or returns them (most
+    /// recently pushed first) if the stack has been closed.
+    pub fn try_flush(&mut self) -> Result<(), StackError<NodeIterator<T, A>>> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        let pushed = unsafe {
+            self.stack
+                .push_list_raw(self.top, self.last, self.len as u64)
+        };
+        let top = std::mem::replace(&mut self.top, null_mut());
+        self.last = null_mut();
+        self.len = 0;
+        self.oldest = None;
+        match pushed {
+            Some(_) => Ok(()),
+            None => Err(StackError::Closed(self.stack.list(top))),
+        }
+    }
+}
+
+/// Flushes any buffered values.  If the stack has been closed, they are
+/// dropped.
+impl<T, A> Drop for BufferedPusher<'_, T, A>
+where
+    T: Send,
+    A: Allocator + Clone,
+{
+    fn drop(&mut self) {
+        let _ = self.try_flush();
+    }
+}
+
+/// A multi-producer, single-consumer FIFO queue built from two `Stack`s.
+///
+/// Producers push onto an "incoming" stack.  When the consumer runs out of
+/// items, it detaches the incoming stack with one CAS, reverses it in place,
+/// and installs it as a "ready" stack whose top is the oldest item.  Each
+/// item is therefore reversed exactly once, and `pop` is O(1) amortized.
+///
+/// Items pushed by any one producer are popped in the order it pushed them.
+/// Concurrent consumers do not cause memory errors or lose items, but they
+/// may observe items out of order.
+pub struct Queue<T>
+where
+    T: Send,
+{
+    incoming: Stack<T>,
+    ready: Stack<T>,
+}
+
+impl<T> Default for Queue<T>
+where
+    T: Send,
+{
+    fn default() -> Self {
+        Self {
+            incoming: Default::default(),
+            ready: Default::default(),
+        }
+    }
+}
+
+impl<T> Queue<T>
+where
+    T: Send,
+{
+    /// # Panics
+    ///
+    /// Panics if the queue has been closed.  Use `try_push` if producers
+    /// may race with `close()`.
+    pub fn push(&self, val: T) {
+        self.incoming.push(val);
+    }
+
+    pub fn try_push(&self, val: T) -> Result<(), StackError<T>> {
+        self.incoming.try_push(val)
+    }
+
+    /// Removes the oldest item from the queue.
+    pub fn pop(&self) -> Option<T> {
+        if let Some(val) = self.ready.pop() {
+            return Some(val);
+        }
+        let mut batch = self.incoming.pop_all_fifo();
+        let val = batch.next()?;
+        let node = batch.node;
+        std::mem::forget(batch);
+        let raced = self.ready.swap_contents(NodeIterator::new(node));
+        // Only possible if there are multiple consumers.
+        raced.for_each(|val| self.ready.push(val));
+        Some(val)
+    }
+
+    /// Atomically removes everything that was pushed before the call, oldest
+    /// first.
+    pub fn pop_all(&self) -> NodeIterator<T> {
+        let ready = self.ready.pop_all();
+        let mut top = ready.node;
+        std::mem::forget(ready);
+        let incoming = self.incoming.pop_all_fifo();
+        let mut tail: *mut *mut Node<T> = &mut top;
+        unsafe {
+            while !(*tail).is_null() {
+                tail = &mut (**tail).next;
+            }
+            *tail = incoming.node;
+        }
+        std::mem::forget(incoming);
+        NodeIterator::new(top)
+    }
+
+    /// Prevents further pushes.  Items already in the queue can still be
+    /// popped.  Returns true if this call closed the queue.
+    pub fn close(&self) -> bool {
+        self.incoming.close()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.incoming.is_closed()
+    }
+}
+
+/// Number of elimination slots used by `EliminationStack::default()`.
+const DEFAULT_ELIMINATION_WIDTH: usize = 8;
+/// How many times a parked pusher polls its slot before withdrawing its offer.
+const ELIMINATION_SPINS: usize = 128;
+
+struct EliminationSlot<T> {
+    node: *mut Node<T>,
+}
+
+/// A `Stack` with an elimination-backoff layer in front of it.
+///
+/// Under heavy symmetric load, the single head word of `Stack` becomes a
+/// bottleneck.  When a push loses the race for the head, it backs off by
+/// parking its node in one of a small array of elimination slots for a short
+/// time.  A concurrent `pop()` or `pop_all()` collects any parked nodes along
+/// with the contents of the stack, so colliding push / pop pairs exchange
+/// values without both touching the head.  If nobody collects the node, the pusher
+/// withdraws it and tries the head again.
+///
+/// Each slot is its own `Atom`, and the slot and head updates are all simple
+/// pointer swaps, so read set equivalence holds trivially.  However,
+/// `pop_all()` is not a single atomic snapshot: it empties the slots one at
+/// a time, and then detaches the head, and parked values are placed above
+/// the values from the head.  A push that reaches the head after the slots
+/// were emptied, but before the head was detached, therefore ends up below
+/// values that were parked earlier, so the returned list is only LIFO
+/// within the parked values and within the values from the head.  Every
+/// value is still returned by exactly one pop.
+///
+/// This is opt-in because it only pays for itself under contention: an
+/// uncontended push never touches the slots, but a `pop_all()` always scans
+/// them.  `bench_elimination_stack` in the integration tests (run it with
+/// `cargo test -- --ignored`) compares it against the plain `Stack`.
+pub struct EliminationStack<T>
+where
+    T: Send,
+{
+    stack: Stack<T>,
+    slots: Box<[Atom<EliminationSlot<T>, u64>]>,
+}
+
+impl<T> Default for EliminationStack<T>
+where
+    T: Send,
+{
+    fn default() -> Self {
+        Self::with_width(DEFAULT_ELIMINATION_WIDTH)
+    }
+}
+
+impl<T> EliminationStack<T>
+where
+    T: Send,
+{
+    /// Creates an empty stack with width elimination slots.  Panics if
+    /// width is zero.
+    pub fn with_width(width: usize) -> Self {
+        assert!(width > 0, "EliminationStack needs at least one slot");
+        Self {
+            stack: Default::default(),
+            slots: (0..width).map(|_| Default::default()).collect(),
+        }
+    }
+
+    pub fn push(&self, val: T) {
+        let node = Box::into_raw(Box::new(Node {
+            val,
+            next: null_mut(),
+        }));
+        loop {
+            match unsafe { self.stack.push_raw_once(node) } {
+                PushAttempt::Pushed => return,
+                PushAttempt::Closed => unreachable!("EliminationStack is never closed"),
+                PushAttempt::Contended => {}
+            }
+            if self.try_eliminate(node) {
+                return;
+            }
+        }
+    }
+
+    /// Parks node in this thread's elimination slot and waits for a
+    /// `pop_all()` to collect it.  Returns false if the node was withdrawn,
+    /// in which case the caller still owns it.
+    fn try_eliminate(&self, node: *mut Node<T>) -> bool {
+        let slot = &self.slots[thread_hint() % self.slots.len()];
+        let offered = unsafe {
+            atomic_try_update(slot, |s| {
+                if s.node.is_null() {
+                    s.node = node;
+                    (true, true)
+                } else {
+                    (false, false)
+                }
+            })
+        };
+        if !offered {
+            return false;
+        }
+        for _ in 0..ELIMINATION_SPINS {
+            if unsafe { atomic_try_update(slot, |s| (false, s.node != node)) } {
+                return true;
+            }
+            spin_loop();
+        }
+        // Withdraw the offer.  If the slot no longer holds our node, then a
+        // pop_all() collected it in race with us.
+        unsafe {
+            atomic_try_update(slot, |s| {
+                if s.node == node {
+                    s.node = null_mut();
+                    (true, false)
+                } else {
+                    (false, true)
+                }
+            })
+        }
+    }
+
+    /// Removes a single value.  If a push is parked in an elimination slot,
+    /// this takes its value directly, without touching the head of the
+    /// stack.  Otherwise, it falls back to `Stack::pop`.
+    pub fn pop(&self) -> Option<T> {
+        for slot in self.slots.iter() {
+            let node = unsafe { take_parked(slot) };
+            if !node.is_null() {
+                return Some(unsafe { Box::from_raw(node) }.val);
+            }
+        }
+        self.stack.pop()
+    }
+
+    /// Atomically removes everything from the stack, including any values
+    /// that are parked in the elimination slots.
+    pub fn pop_all(&self) -> NodeIterator<T> {
+        let mut top: *mut Node<T> = null_mut();
+        let mut bottom: *mut Node<T> = null_mut();
+        for slot in self.slots.iter() {
+            let node = unsafe { take_parked(slot) };
+            if !node.is_null() {
+                unsafe {
+                    (*node).next = top;
+                }
+                if bottom.is_null() {
+                    bottom = node;
+                }
+                top = node;
+            }
+        }
+        let rest = self.stack.pop_all();
+        if bottom.is_null() {
+            return rest;
+        }
+        unsafe {
+            (*bottom).next = rest.node;
+        }
+        std::mem::forget(rest);
+        NodeIterator::new(top)
+    }
+}
+
+/// Removes the node parked in slot, if any.  The caller owns the result.
+unsafe fn take_parked<T>(slot: &Atom<EliminationSlot<T>, u64>) -> *mut Node<T> {
+    atomic_try_update(slot, |s| {
+        let node = s.node;
+        if node.is_null() {
+            (false, node)
+        } else {
+            s.node = null_mut();
+            (true, node)
+        }
+    })
+}
+
+/// Spreads threads across elimination slots and shards.  The hint is fixed
+/// per thread, so a thread keeps using the same slot or shard.
+pub(crate) fn thread_hint() -> usize {
+    thread_local! {
+        static HINT: usize = {
+            let mut hasher = DefaultHasher::new();
+            std::thread::current().id().hash(&mut hasher);
+            hasher.finish() as usize
+        };
+    }
+    HINT.with(|hint| *hint)
+}
+
+/// A set of independent `Stack`s for workloads where a single head word
+/// saturates under many concurrent producers.
+///
+/// Each thread pushes to (and pops from) its own shard first, so producers
+/// on different shards never contend.  `pop_all` drains every shard, and
+/// splices the results into a single list.
+///
+/// This gives up the linearizability of `Stack`:  there is no ordering
+/// between items on different shards, and `pop_all` drains the shards one at
+/// a time, so it is not atomic.  Every item that was pushed before `pop_all`
+/// was called is returned by it (or by a racing pop), but items pushed while
+/// it runs may or may not be.  Splicing the shards together costs a walk over
+/// each shard's list.
+pub struct ShardedStack<T>
+where
+    T: Send,
+{
+    shards: Box<[Stack<T>]>,
+}
+
+impl<T> Default for ShardedStack<T>
+where
+    T: Send,
+{
+    /// Creates one shard per available CPU.
+    fn default() -> Self {
+        Self::with_shards(
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+        )
+    }
+}
+
+impl<T> ShardedStack<T>
+where
+    T: Send,
+{
+    /// Panics if shards is zero.
+    pub fn with_shards(shards: usize) -> Self {
+        assert!(shards > 0, "ShardedStack needs at least one shard");
+        Self {
+            shards: (0..shards).map(|_| Default::default()).collect(),
+        }
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Pushes val onto this thread's shard.
+    pub fn push(&self, val: T) {
+        self.shards[thread_hint() % self.shards.len()].push(val);
+    }
+
+    /// Pops from this thread's shard, or from the other shards (in order) if
+    /// it is empty.
+    pub fn pop(&self) -> Option<T> {
+        let start = thread_hint() % self.shards.len();
+        (0..self.shards.len())
+            .map(|i| &self.shards[(start + i) % self.shards.len()])
+            .find_map(|shard| shard.pop())
+    }
+
+    /// Removes everything from every shard.  See the type-level docs for the
+    /// (weaker than `Stack`) guarantees this provides.
+    pub fn pop_all(&self) -> NodeIterator<T> {
+        let mut top: *mut Node<T> = null_mut();
+        let mut tail: *mut *mut Node<T> = &mut top;
+        for shard in self.shards.iter() {
+            let list = shard.pop_all();
+            let node = list.node;
+            std::mem::forget(list);
+            unsafe {
+                *tail = node;
+                while !(*tail).is_null() {
+                    tail = &mut (**tail).next;
+                }
+            }
+        }
+        NodeIterator::new(top)
+    }
+}
+
+/// A `NonceStack` node.  A thread that loses the race to pop a node may still
+/// read its `next` pointer after the winner has retired it, so nodes are
+/// freed through the stack's epoch collector, and `val` is `MaybeUninit` so
+/// that freeing a popped node doesn't drop the value that was moved out.
+struct NonceNode<T> {
+    next: *mut NonceNode<T>,
+    val: MaybeUninit<T>,
+}
+
+struct NonceHead<T> {
+    head: *mut NonceNode<T>,
+    nonce: u64,
+}
+
+impl<T> Default for NonceStack<T>
+where
+    T: Send,
+{
+    fn default() -> NonceStack<T> {
+        NonceStack::<T> {
+            head: Default::default(),
+            collector: Default::default(),
+        }
+    }
+}
+
+/// A stack with a conventional push/pop interface, implemented with a nonce
+/// (see the module-level documentation).
+///
+/// Popped nodes are retired to an epoch collector that is owned by the
+/// stack, and freed once no concurrent pop can still be reading them.  As
+/// with the hazard pointers in `Stack::pop`, up to `EPOCH_SLOTS` (16)
+/// threads can pop concurrently, and additional poppers spin until a slot
+/// is available.
+pub struct NonceStack<T>
+where
+    T: Send,
+{
+    head: Atom<NonceHead<T>, u128>,
+    collector: Collector,
+}
+
+/// Nonce-based pop.  The returned node (if any) is owned by the caller.
+///
+/// The line `head.head = (*ret).next` may read from a node that was popped
+/// in race.  This is safe because the caller pins the collector first, so
+/// ret can't be freed yet, and the value it reads is discarded: any pop or
+/// push that happened in race bumped the nonce, so the CAS fails.
+unsafe fn nonce_pop<T>(head: &Atom<NonceHead<T>, u128>) -> *mut NonceNode<T> {
+    atomic_try_update(head, |head: &mut NonceHead<T>| {
+        head.nonce += 1;
+        let ret = head.head;
+        if ret.is_null() {
+            (false, ret)
+        } else {
+            head.head = (*ret).next;
+            (true, ret)
+        }
+    })
+}
+
+impl<T> NonceStack<T>
+where
+    T: Send,
+{
+    pub fn push(&self, val: T) {
+        let node = Box::into_raw(Box::new(NonceNode {
+            next: null_mut(),
+            val: MaybeUninit::new(val),
+        }));
+        unsafe {
+            atomic_try_update(&self.head, |head| {
+                (*node).next = head.head;
+                head.nonce += 1;
+                head.head = node;
+                (true, ())
+            })
+        }
+    }
+
+    /// Pops the most recently pushed value.
+    ///
+    /// This is the textbook nonce-based pop.  The CAS loop reads
+    /// `(*ret).next` from a node that another thread may pop in race.  The
+    /// nonce makes the CAS fail in that case, so the value read is discarded,
+    /// but the read itself must not touch freed memory, which could have
+    /// been returned to the operating system.
+    ///
+    /// The collector is pinned for the duration of the CAS loop, and popped
+    /// nodes are retired to it, so a node that was reachable after we pinned
+    /// stays allocated until we unpin.
+    ///
+    /// Stacks with nonces are also sometimes used to implement slot allocators.
+    /// A slot allocator is initialized at startup with a finite number of
+    /// tokens (such as file handles, or some other finite resource).  When
+    /// a thread needs a resource, it pops from the stack.  If the stack is
+    /// empty, then the thread goes async.  Atomically checking that the stack
+    /// is empty and registering oneself for future wakeup is left as an exercise
+    /// to the reader, as it is exactly the sort of thing atomic_try_update excels
+    /// at.
+    pub fn pop(&self) -> Option<T> {
+        let guard = self.collector.pin();
+        let node = unsafe { nonce_pop(&self.head) };
+        drop(guard);
+        if node.is_null() {
+            return None;
+        }
+        // Other poppers only read next, so we can move the value out while
+        // they still hold a pointer to the node.
+        let val = unsafe { (*node).val.assume_init_read() };
+        unsafe { self.collector.retire(node) };
+        Some(val)
+    }
+}
 
-struct Head<T> {
-    head: *mut Node<T>,
+impl<T> Drop for NonceStack<T>
+where
+    T: Send,
+{
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
 }
 
-pub struct Stack<T>
+/// A `Stack` whose consumers can wait asynchronously for items to arrive,
+/// such as an actor's mailbox.
+///
+/// The "consumer waiting" flag lives in the same word as the head of the
+/// stack.  `pop_all_or_wait` atomically either detaches the contents of the
+/// stack or sets the flag, and the push that finds the flag set clears it and
+/// wakes the waiters.  Since both decisions are made by the same CAS, a
+/// consumer can not go to sleep after a producer decided not to wake it, and
+/// producers only pay for a wakeup on the push that makes the stack
+/// non-empty while somebody is waiting.
+///
+/// `pop_all_or_wait` does not depend on any particular async runtime, so it
+/// can be awaited from any executor.
+pub struct NotifyStack<T>
 where
     T: Send,
 {
-    head: Atom<Head<T>, u64>,
+    stack: Stack<T>,
+    waiters: WakerList,
 }
 
-impl<T> Default for Stack<T>
+impl<T> Default for NotifyStack<T>
 where
     T: Send,
 {
     fn default() -> Self {
         Self {
-            head: Default::default(),
+            stack: Default::default(),
+            waiters: Default::default(),
         }
     }
 }
 
-impl<T> Stack<T>
+impl<T> NotifyStack<T>
 where
     T: Send,
 {
+    /// Pushes val, and wakes any waiting consumers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack has been closed.  Use `try_push` if producers
+    /// may race with `close()`.
     pub fn push(&self, val: T) {
+        if self.try_push(val).is_err() {
+            panic!("push to closed NotifyStack");
+        }
+    }
+
+    pub fn try_push(&self, val: T) -> Result<(), StackError<T>> {
         let node = Box::into_raw(Box::new(Node {
             val,
-            next: std::ptr::null_mut(),
+            next: null_mut(),
         }));
-
-        unsafe {
-            atomic_try_update(&self.head, |head: &mut Head<T>| {
-                (*node).next = head.head;
-                head.head = node;
-                (true, ())
-            });
+        match unsafe { self.stack.push_raw(node) } {
+            Some(waiting) => {
+                if waiting {
+                    self.waiters.wake_all();
+                }
+                Ok(())
+            }
+            None => Err(StackError::Closed(unsafe { Box::from_raw(node) }.val)),
         }
     }
+
+    /// Removes everything without waiting.
     pub fn pop_all(&self) -> NodeIterator<T> {
-        NodeIterator {
-            node: unsafe {
-                atomic_try_update(&self.head, |head: &mut Head<T>| {
-                    let ret = head.head;
-                    head.head = null_mut();
-                    (true, ret)
-                })
-            },
+        self.stack.pop_all()
+    }
+
+    /// Removes everything from the stack, waiting until it is non-empty if
+    /// necessary.  Returns an empty iterator if the stack is closed and empty.
+    pub async fn pop_all_or_wait(&self) -> NodeIterator<T> {
+        // wait_for checks the stack again after registering our waker, so a
+        // push that raced with registration is not missed.
+        self.waiters
+            .wait_for(|| self.stack.detach_all_or_set_waiting())
+            .await
+    }
+
+    /// Prevents further pushes, and wakes any waiting consumers.  Returns
+    /// true if this call closed the stack.
+    pub fn close(&self) -> bool {
+        let closed = self.stack.close();
+        self.waiters.wake_all();
+        closed
+    }
+}
+
+/// Number of buckets in a `LatencyHistogram`.  Bucket i counts latencies in
+/// [2^i, 2^(i+1)) nanoseconds (bucket 0 also includes zero).
+pub const LATENCY_BUCKETS: usize = 64;
+
+/// A lock-free histogram of time-in-structure, with power-of-two buckets.
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, latency: Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - 1).saturating_sub(nanos.leading_zeros()) as usize;
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        LatencySnapshot {
+            counts: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A point-in-time copy of a `LatencyHistogram`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    pub counts: [u64; LATENCY_BUCKETS],
+}
+
+impl LatencySnapshot {
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns an upper bound on the q'th quantile (0.0 <= q <= 1.0), or None
+    /// if nothing has been recorded.  The bound is the upper edge of the
+    /// bucket that contains the quantile, so it may overestimate by up to 2x.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Duration::from_nanos(
+                    1u64.checked_shl(i as u32 + 1).unwrap_or(u64::MAX),
+                ));
+            }
         }
+        unreachable!()
     }
 }
 
-impl<T> Drop for Stack<T>
+/// A `Stack` that stamps each item with a monotonic timestamp when it is
+/// pushed, and records how long it spent on the stack when it is removed.
+///
+/// The timestamp is stored in the node alongside the value, so this costs
+/// one `Instant::now()` per push and per drain, plus a relaxed increment per
+/// item.  The timestamp is taken before the push CAS, so a push that retries
+/// is measured from its first attempt.
+pub struct TimedStack<T>
 where
     T: Send,
 {
-    fn drop(&mut self) {
-        self.pop_all();
-    }
+    stack: Stack<(Instant, T)>,
+    latency: LatencyHistogram,
 }
 
-struct NonceHead<T> {
-    head: *mut Node<T>,
-    nonce: u64,
+impl<T> Default for TimedStack<T>
+where
+    T: Send,
+{
+    fn default() -> Self {
+        Self {
+            stack: Default::default(),
+            latency: Default::default(),
+        }
+    }
 }
 
-impl<T> Default for NonceStack<T>
+impl<T> TimedStack<T>
 where
     T: Send,
 {
-    #[allow(unreachable_code)]
-    fn default() -> NonceStack<T> {
-        todo!("This example code contains a use after free.");
-        NonceStack::<T> {
-            head: Default::default(),
+    pub fn push(&self, val: T) {
+        self.stack.push((Instant::now(), val));
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let (pushed, val) = self.stack.pop()?;
+        self.latency.record(pushed.elapsed());
+        Some(val)
+    }
+
+    /// Atomically removes everything from the stack.  Each item's latency is
+    /// measured up to the time of this call, and recorded as the iterator
+    /// yields it.
+    pub fn pop_all(&self) -> TimedIterator<'_, T> {
+        TimedIterator {
+            inner: self.stack.pop_all(),
+            now: Instant::now(),
+            latency: &self.latency,
         }
     }
+
+    /// Time-in-structure of the items removed so far.
+    pub fn latency(&self) -> LatencySnapshot {
+        self.latency.snapshot()
+    }
 }
 
-pub struct NonceStack<T>
+/// The iterator returned by `TimedStack::pop_all`.
+pub struct TimedIterator<'a, T> {
+    inner: NodeIterator<(Instant, T)>,
+    now: Instant,
+    latency: &'a LatencyHistogram,
+}
+
+impl<T> Iterator for TimedIterator<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        let (pushed, val) = self.inner.next()?;
+        self.latency
+            .record(self.now.saturating_duration_since(pushed));
+        Some(val)
+    }
+}
+
+struct CountedHead<T> {
+    head: *mut Node<T>,
+    count: u64,
+}
+
+/// A push/pop_all stack that tracks its length in the same word as its head,
+/// so consumers can make decisions based on the exact number of items that
+/// a detach would return.
+///
+/// The head and count share a 128 bit word, so on stable rust, `AtomicCell`
+/// implements every push, `len` and detach with a lock, like every other
+/// `Atom<_, u128>`.  Use `Stack` if you do not need the exact count.
+pub struct CountedStack<T>
 where
     T: Send,
 {
-    head: Atom<NonceHead<T>, u128>,
+    head: Atom<CountedHead<T>, u128>,
 }
 
-impl<T> NonceStack<T>
+impl<T> Default for CountedStack<T>
+where
+    T: Send,
+{
+    fn default() -> Self {
+        Self {
+            head: Default::default(),
+        }
+    }
+}
+
+impl<T> CountedStack<T>
 where
     T: Send,
 {
-    #[allow(unused)]
     pub fn push(&self, val: T) {
         let node = Box::into_raw(Box::new(Node {
             val,
@@ -130,73 +1876,361 @@ where
         unsafe {
             atomic_try_update(&self.head, |head| {
                 (*node).next = head.head;
-                head.nonce += 1;
                 head.head = node;
+                head.count += 1;
                 (true, ())
             })
         }
     }
 
-    /// Almost-correct example of using a nonce to implement pop().
-    ///
-    /// This method contains a use-after-free on the line `head.head=(*ret).next`.
-    ///
-    /// This would be safe in a garbage collected system, or in an embedded
-    /// system without memory protection, since head.head will be discarded
-    /// if the stack has been changed, and *ret can only be freed after it is
-    /// popped from the stack.  Since *ret may have been freed, it may have
-    /// been returned to the operating system, leading to a segmentation fault
-    /// when accessed here.
-    ///
-    /// If you need a stack similar to NonceStack, consider using an epoch
-    /// collector such as crossbeam_epoch, or by maintaining a pool of
-    /// reusable objects.  For instance, you could use a second stack to
-    /// store the pool, and return objects to it after they are popped from
-    /// this stack.  If the pool stack is empty, then allocate a new object.
-    ///
-    /// Once you are sure that no thread will access either stack, you can
-    /// safely empty both stacks and free the objects they contain.
-    ///
-    /// Stacks with nonces are also sometimes used to implement slot allocators.
-    /// A slot allocator is initialized at startup with a finite number of
-    /// tokens (such as file handles, or some other finite resource).  When
-    /// a thread needs a resource, it pops from the stack.  If the stack is
-    /// empty, then the thread goes async.  Atomically checking that the stack
-    /// is empty and registering oneself for future wakeup is left as an exercise
-    /// to the reader, as it is exactly the sort of thing atomic_try_update excels
-    /// at.
-    ///
-    /// TODO: Implement a double-stack structure and/or slot such as the ones above,
-    /// so we have correct examples of the NonceStack pattern.
+    /// Number of items on the stack.  This is exact at the time it was read,
+    /// but may be stale by the time the caller looks at it.
+    pub fn len(&self) -> u64 {
+        unsafe { atomic_try_update(&self.head, |head| (false, head.count)) }
+    }
 
-    #[allow(unused)]
-    pub fn pop(&self) -> Option<T> {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn pop_all(&self) -> NodeIterator<T> {
+        self.pop_all_if_at_least(0)
+            .expect("every stack has at least zero entries")
+    }
+
+    /// Atomically detaches the contents of the stack if it holds at least n
+    /// items.  Otherwise, leaves the stack alone and returns None.
+    ///
+    /// This lets batch consumers poll without draining (and then having to
+    /// process) tiny batches.
+    pub fn pop_all_if_at_least(&self, n: u64) -> Option<NodeIterator<T>> {
         let node = unsafe {
-            atomic_try_update(&self.head, |head: &mut NonceHead<T>| unsafe {
-                head.nonce += 1;
-                let ret = head.head;
-                if ret.is_null() {
-                    (false, ret)
-                } else {
-                    head.head = (*ret).next;
-                    (true, ret)
+            atomic_try_update(&self.head, |head| {
+                if head.count < n {
+                    return (false, None);
                 }
+                let ret = head.head;
+                head.head = null_mut();
+                head.count = 0;
+                (true, Some(ret))
             })
         };
+        node.map(NodeIterator::new)
+    }
+}
 
-        if !node.is_null() {
-            Some(unsafe { *Box::from_raw(node) }.val)
-        } else {
-            None
+impl<T> Drop for CountedStack<T>
+where
+    T: Send,
+{
+    fn drop(&mut self) {
+        self.pop_all();
+    }
+}
+
+/// Marks the end of a list of slab indices.
+const NIL: u32 = u32::MAX;
+
+/// The head of a list that is threaded through a slab by index.  The tag is
+/// incremented by every push and pop, which is what makes it safe for the
+/// pop lambda to read the next index out of the slab:  if the head and tag
+/// are unchanged, then nobody has popped (and possibly reused) the slot
+/// since we read it.  This is the `NonceStack` trick, except that slab slots
+/// are never freed, so the speculative read can not segfault.
+///
+/// The tag wraps after 2^32 operations.  An ABA bug would require a pop to
+/// be descheduled for exactly that many operations, and then to find the same
+/// slot at the head of the list.
+#[derive(Default)]
+struct SlabHead {
+    index: u32,
+    tag: u32,
+}
+
+fn new_slab_list(first: u32) -> Atom<SlabHead, u64> {
+    let list: Atom<SlabHead, u64> = Default::default();
+    unsafe {
+        atomic_try_update(&list, |h| {
+            h.index = first;
+            (true, ())
+        });
+    }
+    list
+}
+
+/// Builds the `next` links for a slab where every slot starts out on the free list.
+fn new_slab_links(capacity: usize) -> Box<[AtomicCell<u32>]> {
+    assert!(capacity < NIL as usize, "slab capacity must fit in a u32");
+    (0..capacity)
+        .map(|i| {
+            AtomicCell::new(if i + 1 == capacity {
+                NIL
+            } else {
+                (i + 1) as u32
+            })
+        })
+        .collect()
+}
+
+unsafe fn slab_pop(list: &Atom<SlabHead, u64>, links: &[AtomicCell<u32>]) -> Option<u32> {
+    atomic_try_update(list, |h| {
+        if h.index == NIL {
+            return (false, None);
         }
+        let index = h.index;
+        h.index = links[index as usize].load();
+        h.tag = h.tag.wrapping_add(1);
+        (true, Some(index))
+    })
+}
+
+unsafe fn slab_push(list: &Atom<SlabHead, u64>, links: &[AtomicCell<u32>], index: u32) {
+    atomic_try_update(list, |h| {
+        links[index as usize].store(h.index);
+        h.index = index;
+        h.tag = h.tag.wrapping_add(1);
+        (true, ())
+    })
+}
+
+/// Atomically detaches an entire list, and returns the index of its first slot.
+unsafe fn slab_take_all(list: &Atom<SlabHead, u64>) -> u32 {
+    atomic_try_update(list, |h| {
+        let index = h.index;
+        h.index = NIL;
+        h.tag = h.tag.wrapping_add(1);
+        (index != NIL, index)
+    })
+}
+
+/// A fixed-capacity stack for small `Copy` values that never allocates after
+/// construction.
+///
+/// The values are stored inline in a preallocated slab, and both the stack
+/// and the list of free slots are threaded through the slab by index.  This
+/// avoids a `Box` per push, which dominates the cost of `Stack::push` for
+/// payloads such as `u32` ids.  Slots hold an `Option<T>`; when that fits in
+/// a `u64` (as it does for `u32`), the slots are lock-free `AtomicCell`s.
+/// Larger values still work, but fall back to `AtomicCell`'s lock-based
+/// implementation.
+///
+/// Unlike `Stack`, both `pop` and `pop_all` are cheap:  slots are never freed,
+/// so the tagged head is enough to guarantee read set equivalence.
+pub struct ValueStack<T>
+where
+    T: Copy + Send,
+{
+    head: Atom<SlabHead, u64>,
+    free: Atom<SlabHead, u64>,
+    links: Box<[AtomicCell<u32>]>,
+    vals: Box<[AtomicCell<Option<T>>]>,
+}
+
+impl<T> ValueStack<T>
+where
+    T: Copy + Send,
+{
+    /// Creates an empty stack that can hold up to capacity values.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            head: new_slab_list(NIL),
+            free: new_slab_list(if capacity == 0 { NIL } else { 0 }),
+            links: new_slab_links(capacity),
+            vals: (0..capacity).map(|_| AtomicCell::new(None)).collect(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.vals.len()
+    }
+
+    /// Pushes val, or returns it in `StackError::Full` if every slot is in use.
+    pub fn push(&self, val: T) -> Result<(), StackError<T>> {
+        let Some(index) = (unsafe { slab_pop(&self.free, &self.links) }) else {
+            return Err(StackError::Full(val));
+        };
+        self.vals[index as usize].store(Some(val));
+        unsafe { slab_push(&self.head, &self.links, index) };
+        Ok(())
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let index = unsafe { slab_pop(&self.head, &self.links) }?;
+        Some(self.release(index))
+    }
+
+    /// Atomically removes everything from the stack.  Slots are returned to
+    /// the free list as the iterator advances (or when it is dropped).
+    pub fn pop_all(&self) -> ValueStackIterator<'_, T> {
+        ValueStackIterator {
+            stack: self,
+            index: unsafe { slab_take_all(&self.head) },
+        }
+    }
+
+    /// Moves the value out of a slot that the caller owns, and frees the slot.
+    fn release(&self, index: u32) -> T {
+        let val = self.vals[index as usize]
+            .take()
+            .expect("slab slot on the stack has no value");
+        unsafe { slab_push(&self.free, &self.links, index) };
+        val
     }
 }
 
-impl<T> Drop for NonceStack<T>
+/// A draining iterator over the values detached by `ValueStack::pop_all`.
+pub struct ValueStackIterator<'a, T>
+where
+    T: Copy + Send,
+{
+    stack: &'a ValueStack<T>,
+    index: u32,
+}
+
+impl<T> Iterator for ValueStackIterator<'_, T>
+where
+    T: Copy + Send,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.index == NIL {
+            return None;
+        }
+        let index = self.index;
+        // We own the detached list, so nobody else can change this link.
+        self.index = self.stack.links[index as usize].load();
+        Some(self.stack.release(index))
+    }
+}
+
+impl<T> Drop for ValueStackIterator<'_, T>
+where
+    T: Copy + Send,
+{
+    fn drop(&mut self) {
+        for _ in self {}
+    }
+}
+
+/// A fixed-capacity stack of arbitrary values, whose nodes live in a slab
+/// that is allocated up front.
+///
+/// The heads store a (slab index, tag) pair instead of a pointer, so this
+/// only needs a 64-bit CAS.  Since slots are never freed, and the tag changes
+/// on every update, it has neither `Stack`'s allocation per push nor
+/// `NonceStack`'s use-after-free, and supports single-item `pop` without
+/// hazard pointers.  The cost is that the capacity is fixed.
+///
+/// Use `ValueStack` instead if `T` is small and `Copy`.
+pub struct SlabStack<T>
+where
+    T: Send,
+{
+    head: Atom<SlabHead, u64>,
+    free: Atom<SlabHead, u64>,
+    links: Box<[AtomicCell<u32>]>,
+    /// A slot's value is initialized exactly when the slot is on the stack
+    /// (or detached by `pop_all` and not yet consumed).  Whoever removes the
+    /// slot from a list has exclusive access to it.
+    vals: Box<[UnsafeCell<MaybeUninit<T>>]>,
+}
+
+unsafe impl<T> Sync for SlabStack<T> where T: Send {}
+
+impl<T> SlabStack<T>
+where
+    T: Send,
+{
+    /// Creates an empty stack that can hold up to capacity values.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            head: new_slab_list(NIL),
+            free: new_slab_list(if capacity == 0 { NIL } else { 0 }),
+            links: new_slab_links(capacity),
+            vals: (0..capacity)
+                .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+                .collect(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.vals.len()
+    }
+
+    /// Pushes val, or returns it in `StackError::Full` if every slot is in use.
+    pub fn push(&self, val: T) -> Result<(), StackError<T>> {
+        let Some(index) = (unsafe { slab_pop(&self.free, &self.links) }) else {
+            return Err(StackError::Full(val));
+        };
+        unsafe {
+            (*self.vals[index as usize].get()).write(val);
+            slab_push(&self.head, &self.links, index);
+        }
+        Ok(())
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let index = unsafe { slab_pop(&self.head, &self.links) }?;
+        Some(unsafe { self.release(index) })
+    }
+
+    /// Atomically removes everything from the stack.  Slots are returned to
+    /// the free list as the iterator advances (or when it is dropped).
+    pub fn pop_all(&self) -> SlabStackIterator<'_, T> {
+        SlabStackIterator {
+            stack: self,
+            index: unsafe { slab_take_all(&self.head) },
+        }
+    }
+
+    /// Moves the value out of a slot that the caller owns, and frees the slot.
+    unsafe fn release(&self, index: u32) -> T {
+        let val = (*self.vals[index as usize].get()).assume_init_read();
+        slab_push(&self.free, &self.links, index);
+        val
+    }
+}
+
+impl<T> Drop for SlabStack<T>
 where
     T: Send,
 {
     fn drop(&mut self) {
-        while self.pop().is_some() {}
+        self.pop_all();
+    }
+}
+
+/// A draining iterator over the values detached by `SlabStack::pop_all`.
+pub struct SlabStackIterator<'a, T>
+where
+    T: Send,
+{
+    stack: &'a SlabStack<T>,
+    index: u32,
+}
+
+impl<T> Iterator for SlabStackIterator<'_, T>
+where
+    T: Send,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.index == NIL {
+            return None;
+        }
+        let index = self.index;
+        self.index = self.stack.links[index as usize].load();
+        Some(unsafe { self.stack.release(index) })
+    }
+}
+
+impl<T> Drop for SlabStackIterator<'_, T>
+where
+    T: Send,
+{
+    fn drop(&mut self) {
+        for _ in self {}
     }
 }
@@ -0,0 +1,155 @@
+//! A lock-free list of wakers, used by the types in this crate that let
+//! tasks (and blocked threads) wait for a state change without depending on
+//! a particular async runtime.
+//!
+//! A waiter pushes its waker, and then checks the state it is waiting on
+//! again, so it can't miss a change that raced with the push: whoever changes
+//! the state calls `wake_all` after its update is visible.  The list is only
+//! ever detached as a whole, so unlike `Stack`, it doesn't need hazard
+//! pointers, and it can be constructed in a const context.
+//!
+//! A woken waiter whose check still fails has to push its waker again.  Each
+//! detach bumps an epoch counter, so waiters can tell when that is needed
+//! without pushing a duplicate on every spurious poll.
+use std::{
+    future::poll_fn,
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Poll, Wake, Waker},
+    thread::{self, Thread},
+    time::Instant,
+};
+
+use crate::{atomic_try_update, Atom, Node, NodeIterator};
+
+struct WakerListHead {
+    head: *mut Node<Waker>,
+}
+
+pub(crate) struct WakerList {
+    head: Atom<WakerListHead, u64>,
+    /// Incremented each time the list is detached, after the detach.
+    epoch: AtomicU64,
+}
+
+impl WakerList {
+    pub(crate) const fn new() -> Self {
+        Self {
+            head: Atom::new(),
+            epoch: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn push(&self, waker: Waker) {
+        let node = Box::into_raw(Box::new(Node {
+            val: waker,
+            next: null_mut(),
+        }));
+        unsafe {
+            atomic_try_update(&self.head, |s| {
+                (*node).next = s.head;
+                s.head = node;
+                (true, ())
+            })
+        }
+    }
+
+    /// Detaches every waker from the list.
+    pub(crate) fn take(&self) -> NodeIterator<Waker> {
+        let head = unsafe {
+            atomic_try_update(&self.head, |s| {
+                let head = s.head;
+                s.head = null_mut();
+                (!head.is_null(), head)
+            })
+        };
+        if !head.is_null() {
+            self.epoch.fetch_add(1, Ordering::SeqCst);
+        }
+        NodeIterator::new(head)
+    }
+
+    pub(crate) fn wake_all(&self) {
+        for waker in self.take() {
+            waker.wake();
+        }
+    }
+
+    /// Waits until check returns a value.  check is called each time the
+    /// task is woken, and once more after each new waker is registered.
+    pub(crate) async fn wait_for<R>(&self, mut check: impl FnMut() -> Option<R>) -> R {
+        let mut registered: Option<(Waker, u64)> = None;
+        poll_fn(|cx| {
+            if let Some(val) = check() {
+                return Poll::Ready(val);
+            }
+            if !registered.as_ref().is_some_and(|(waker, epoch)| {
+                *epoch == self.epoch.load(Ordering::SeqCst) && waker.will_wake(cx.waker())
+            }) {
+                let epoch = self.epoch.load(Ordering::SeqCst);
+                self.push(cx.waker().clone());
+                registered = Some((cx.waker().clone(), epoch));
+                if let Some(val) = check() {
+                    return Poll::Ready(val);
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Blocks the calling thread until check returns a value, or returns None
+    /// once deadline passes.
+    pub(crate) fn wait_blocking_for<R>(
+        &self,
+        mut check: impl FnMut() -> Option<R>,
+        deadline: Option<Instant>,
+    ) -> Option<R> {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut registered = None;
+        loop {
+            if let Some(val) = check() {
+                return Some(val);
+            }
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            if registered != Some(epoch) {
+                self.push(waker.clone());
+                registered = Some(epoch);
+                continue;
+            }
+            // Spurious wakeups are handled by the loop.
+            match deadline {
+                None => thread::park(),
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) if !timeout.is_zero() => thread::park_timeout(timeout),
+                    _ => return None,
+                },
+            }
+        }
+    }
+}
+
+impl Default for WakerList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for WakerList {
+    fn drop(&mut self) {
+        // Wakers left behind by waits that were cancelled.
+        drop(self.take());
+    }
+}
+
+/// Unparks a thread blocked in `wait_blocking_for`.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
@@ -0,0 +1,73 @@
+use std::{sync::Arc, thread};
+
+use atomic_try_update::arc::AtomicArc;
+
+#[test]
+fn test_atomic_arc() {
+    let first = Arc::new(1);
+    let cell = AtomicArc::new(first.clone());
+    assert_eq!(*cell.load(), 1);
+    assert_eq!(Arc::strong_count(&first), 2);
+
+    let second = Arc::new(2);
+    let old = cell.swap(second.clone());
+    assert!(Arc::ptr_eq(&old, &first));
+    drop(old);
+    assert_eq!(Arc::strong_count(&first), 1);
+
+    // A stale expected value leaves the cell alone and returns new.
+    let rejected = cell.compare_and_swap(&first, Arc::new(3)).unwrap_err();
+    assert_eq!(*rejected, 3);
+    assert_eq!(*cell.load(), 2);
+
+    let current = cell.load();
+    let old = cell
+        .compare_and_swap(&current, Arc::new(*current + 10))
+        .unwrap();
+    assert!(Arc::ptr_eq(&old, &second));
+    assert_eq!(*cell.load(), 12);
+
+    cell.store(first.clone());
+    assert_eq!(*cell.into_inner(), 1);
+    drop((old, current));
+    assert_eq!(Arc::strong_count(&first), 1);
+    assert_eq!(Arc::strong_count(&second), 1);
+}
+
+#[test]
+fn test_atomic_arc_concurrent() {
+    // Writers keep storing the same two allocations, so readers frequently
+    // find the pointer they borrowed from back in the cell after a swap.
+    let values = [Arc::new(0u64), Arc::new(1u64)];
+    let cell = AtomicArc::new(values[0].clone());
+    let counter = AtomicArc::new(Arc::new(0u64));
+    thread::scope(|scope| {
+        for t in 0..8 {
+            let (values, cell, counter) = (&values, &cell, &counter);
+            scope.spawn(move || {
+                for i in 0..10_000 {
+                    if t % 4 == 0 {
+                        cell.store(values[i % 2].clone());
+                    } else {
+                        let val = cell.load();
+                        assert!(*val < 2);
+                    }
+                }
+                // Read-copy-update increments.
+                for _ in 0..100 {
+                    let mut current = counter.load();
+                    while counter
+                        .compare_and_swap(&current, Arc::new(*current + 1))
+                        .is_err()
+                    {
+                        current = counter.load();
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(*counter.load(), 800);
+    drop(cell);
+    assert_eq!(Arc::strong_count(&values[0]), 1);
+    assert_eq!(Arc::strong_count(&values[1]), 1);
+}
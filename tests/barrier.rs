@@ -0,0 +1,890 @@
+use std::{
+    future::Future,
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use atomic_try_update::barrier::{
+    CountdownLatch, PhasedBarrier, PhasedBarrierError, ShutdownBarrier, ShutdownBarrierError,
+    ShutdownBarrierSync,
+};
+
+/// A minimal executor, to check that waits don't depend on tokio.
+fn block_on<F: Future>(fut: F) -> F::Output {
+    struct Unpark(thread::Thread);
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = pin!(fut);
+    loop {
+        if let Poll::Ready(val) = fut.as_mut().poll(&mut cx) {
+            return val;
+        }
+        thread::park();
+    }
+}
+
+#[test]
+fn test_wait_without_tokio() {
+    let barrier = ShutdownBarrier::new();
+    thread::scope(|scope| {
+        for _ in 0..4 {
+            barrier.spawn().unwrap();
+            scope.spawn(|| {
+                assert!(!barrier.done().unwrap().is_cancelled());
+            });
+        }
+        let waiter = scope.spawn(|| block_on(barrier.wait()).unwrap().is_cancelled());
+        barrier.done().unwrap();
+        assert!(!waiter.join().unwrap());
+    });
+    // Waiting after shutdown returns immediately.
+    assert!(!block_on(barrier.wait()).unwrap().is_cancelled());
+
+    let barrier = ShutdownBarrier::new();
+    thread::scope(|scope| {
+        let waiter = scope.spawn(|| block_on(barrier.wait()).unwrap().is_cancelled());
+        barrier.cancel().unwrap();
+        assert!(waiter.join().unwrap());
+    });
+}
+
+#[test]
+fn test_sync_barrier() {
+    let barrier = ShutdownBarrierSync::new();
+    thread::scope(|scope| {
+        let waiters: Vec<_> = (0..2)
+            .map(|_| scope.spawn(|| barrier.wait().unwrap().is_cancelled()))
+            .collect();
+        // Mixed sync and async waiters.
+        let async_waiter =
+            scope.spawn(|| block_on(barrier.barrier().wait()).unwrap().is_cancelled());
+        for _ in 0..4 {
+            barrier.spawn().unwrap();
+            scope.spawn(|| barrier.done().unwrap());
+        }
+        barrier.done().unwrap();
+        assert!(!barrier.barrier().wait_blocking().unwrap().is_cancelled());
+        for waiter in waiters {
+            assert!(!waiter.join().unwrap());
+        }
+        assert!(!async_waiter.join().unwrap());
+    });
+    assert!(barrier.spawn().is_err());
+}
+
+#[cfg(feature = "tokio-rt")]
+#[tokio::test(start_paused = true)]
+async fn test_wait_timeout() {
+    let barrier = ShutdownBarrier::new();
+    assert_eq!(
+        barrier
+            .wait_timeout(Duration::from_secs(1))
+            .await
+            .err()
+            .unwrap(),
+        ShutdownBarrierError::Timeout
+    );
+    assert_eq!(
+        barrier
+            .wait_until(Instant::now() + Duration::from_secs(1))
+            .await
+            .err()
+            .unwrap(),
+        ShutdownBarrierError::Timeout
+    );
+    barrier.cancel().unwrap();
+    assert!(barrier
+        .wait_timeout(Duration::from_secs(1))
+        .await
+        .unwrap()
+        .is_cancelled());
+}
+
+#[test]
+fn test_sync_wait_timeout() {
+    let barrier = ShutdownBarrierSync::new();
+    assert_eq!(
+        barrier
+            .wait_timeout(Duration::from_millis(10))
+            .err()
+            .unwrap(),
+        ShutdownBarrierError::Timeout
+    );
+    assert_eq!(
+        barrier.wait_until(Instant::now()).err().unwrap(),
+        ShutdownBarrierError::Timeout
+    );
+    thread::scope(|scope| {
+        let waiter = scope.spawn(|| barrier.wait_timeout(Duration::from_secs(60)));
+        barrier.done().unwrap();
+        assert!(!waiter.join().unwrap().unwrap().is_cancelled());
+    });
+}
+
+#[test]
+fn test_reset() {
+    let barrier = ShutdownBarrier::new();
+    barrier.spawn().unwrap();
+    assert_eq!(barrier.reset(), Err(ShutdownBarrierError::StillRunning));
+    barrier.cancel().unwrap();
+    barrier.done().unwrap();
+    assert_eq!(barrier.reset(), Err(ShutdownBarrierError::StillRunning));
+    barrier.done().unwrap();
+    assert!(barrier.spawn().is_err());
+    assert!(barrier.wait_blocking().unwrap().is_cancelled());
+
+    assert_eq!(barrier.generation(), 0);
+    barrier.reset().unwrap();
+    assert_eq!(barrier.generation(), 1);
+    thread::scope(|scope| {
+        let waiter = scope.spawn(|| block_on(barrier.wait()).unwrap().is_cancelled());
+        barrier.spawn().unwrap();
+        scope.spawn(|| barrier.done().unwrap());
+        barrier.done().unwrap();
+        assert!(!waiter.join().unwrap());
+    });
+    assert!(barrier.done().is_err());
+}
+
+#[test]
+fn test_auto_rearm() {
+    let barrier = ShutdownBarrierSync::from(ShutdownBarrier::with_auto_rearm());
+    for round in 0..10 {
+        assert_eq!(barrier.generation(), round);
+        thread::scope(|scope| {
+            // Created up front, so it waits for this round.
+            let wait = barrier.barrier().wait();
+            let waiter = scope.spawn(|| block_on(wait).unwrap().is_cancelled());
+            for _ in 0..4 {
+                barrier.spawn().unwrap();
+                scope.spawn(|| barrier.done().unwrap());
+            }
+            if round % 2 == 1 {
+                barrier.cancel().unwrap();
+            }
+            barrier.done().unwrap();
+            assert_eq!(waiter.join().unwrap(), round % 2 == 1);
+        });
+    }
+    // Each round leaves the barrier armed for the next one.
+    barrier.spawn().unwrap();
+    assert_eq!(
+        barrier
+            .wait_timeout(Duration::from_millis(10))
+            .err()
+            .unwrap(),
+        ShutdownBarrierError::Timeout
+    );
+}
+
+#[test]
+fn test_state_accessors() {
+    let barrier = ShutdownBarrier::new();
+    assert_eq!(barrier.remaining_workers(), 1);
+    barrier.spawn().unwrap();
+    barrier.spawn().unwrap();
+    assert_eq!(barrier.remaining_workers(), 3);
+    assert!(!barrier.is_cancelled());
+    barrier.done().unwrap();
+    barrier.cancel().unwrap();
+    assert!(barrier.is_cancelled());
+    assert!(!barrier.is_shutdown());
+    assert_eq!(barrier.remaining_workers(), 2);
+    barrier.done().unwrap();
+    barrier.done().unwrap();
+    assert!(barrier.is_shutdown());
+    assert_eq!(barrier.remaining_workers(), 0);
+    barrier.reset().unwrap();
+    assert!(!barrier.is_shutdown());
+    assert!(!barrier.is_cancelled());
+    assert_eq!(barrier.remaining_workers(), 1);
+}
+
+#[test]
+fn test_spawn_n() {
+    let barrier = ShutdownBarrier::new();
+    barrier.spawn_n(100).unwrap();
+    assert_eq!(barrier.remaining_workers(), 101);
+    // An overflowing spawn is rejected, and leaves the count alone.
+    assert_eq!(
+        barrier.spawn_n(u32::MAX - 100),
+        Err(ShutdownBarrierError::TooManyWorkers)
+    );
+    assert_eq!(barrier.remaining_workers(), 101);
+    thread::scope(|scope| {
+        for _ in 0..100 {
+            scope.spawn(|| barrier.done().unwrap());
+        }
+    });
+    assert!(barrier.done().unwrap().is_leader());
+    assert!(barrier.spawn_n(10).is_err());
+    assert_eq!(barrier.remaining_workers(), 0);
+}
+
+#[test]
+fn test_worker_guard() {
+    let barrier = ShutdownBarrier::new();
+    thread::scope(|scope| {
+        for i in 0..4 {
+            let guard = barrier.spawn_guard().unwrap();
+            scope.spawn(move || {
+                if i % 2 == 0 {
+                    // Early return; the guard still completes the worker.
+                    return;
+                }
+                assert!(!guard.done().unwrap().is_leader());
+            });
+        }
+    });
+    assert_eq!(barrier.remaining_workers(), 1);
+    assert!(barrier.done().unwrap().is_leader());
+    assert!(barrier.spawn_guard().is_err());
+
+    let barrier = ShutdownBarrier::new();
+    let guard = barrier.spawn_guard().unwrap();
+    let result = thread::scope(|scope| {
+        scope
+            .spawn(move || {
+                let _guard = guard;
+                panic!("worker failed");
+            })
+            .join()
+    });
+    assert!(result.is_err());
+    assert!(barrier.is_cancelled());
+    assert_eq!(barrier.remaining_workers(), 1);
+}
+
+#[derive(Debug, PartialEq)]
+enum StopReason {
+    Requested,
+    WorkerFailed(usize),
+}
+
+#[test]
+fn test_cancel_with_reason() {
+    let barrier = ShutdownBarrier::<StopReason>::with_reasons();
+    barrier.spawn_n(4).unwrap();
+    thread::scope(|scope| {
+        let waiters: Vec<_> = (0..4)
+            .map(|_| scope.spawn(|| barrier.wait_blocking().unwrap()))
+            .collect();
+        barrier.cancel_with(StopReason::WorkerFailed(3)).unwrap();
+        assert!(barrier.cancel_with(StopReason::Requested).is_err());
+        for waiter in waiters {
+            let result = waiter.join().unwrap();
+            assert!(result.is_cancelled());
+            assert_eq!(result.reason(), Some(&StopReason::WorkerFailed(3)));
+        }
+    });
+
+    // cancel() doesn't supply a reason.
+    let barrier = ShutdownBarrierSync::<StopReason>::from(ShutdownBarrier::with_reasons());
+    barrier.cancel().unwrap();
+    let result = barrier.wait().unwrap();
+    assert!(result.is_cancelled());
+    assert!(result.into_reason().is_none());
+
+    // Waiters of an earlier generation get its reason.
+    let barrier = ShutdownBarrier::<StopReason>::with_reasons_and_auto_rearm();
+    let wait = barrier.wait();
+    barrier.cancel_with(StopReason::Requested).unwrap();
+    barrier.done().unwrap();
+    assert_eq!(barrier.generation(), 1);
+    assert_eq!(
+        block_on(wait).unwrap().reason(),
+        Some(&StopReason::Requested)
+    );
+    let wait = barrier.wait();
+    barrier.done().unwrap();
+    assert!(!block_on(wait).unwrap().is_cancelled());
+}
+
+#[test]
+fn test_generation_log_window() {
+    let barrier = ShutdownBarrier::<StopReason>::with_reasons_and_auto_rearm();
+    let stale = barrier.wait();
+    // Every generation is cancelled, so without pruning, the reason log would
+    // grow by one entry per generation.
+    for _ in 0..200 {
+        barrier.cancel_with(StopReason::Requested).unwrap();
+        barrier.done().unwrap();
+    }
+    assert_eq!(
+        block_on(stale).err().unwrap(),
+        ShutdownBarrierError::Expired
+    );
+    // Recent generations still report their reasons.
+    let wait = barrier.wait();
+    barrier.cancel_with(StopReason::Requested).unwrap();
+    barrier.done().unwrap();
+    assert_eq!(
+        block_on(wait).unwrap().reason(),
+        Some(&StopReason::Requested)
+    );
+}
+
+#[test]
+fn test_phased_barrier() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const PARTIES: usize = 4;
+    const PHASES: usize = 20;
+    let barrier = PhasedBarrier::new(PARTIES as u32);
+    let arrivals = AtomicUsize::new(0);
+    let leaders = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for i in 0..PARTIES {
+            let (barrier, arrivals, leaders) = (&barrier, &arrivals, &leaders);
+            scope.spawn(move || {
+                for phase in 0..PHASES {
+                    // Nobody starts a phase until everyone finished the last one.
+                    assert!(arrivals.load(Ordering::SeqCst) >= phase * PARTIES);
+                    arrivals.fetch_add(1, Ordering::SeqCst);
+                    let result = if i % 2 == 0 {
+                        barrier.arrive_and_wait_blocking().unwrap()
+                    } else {
+                        block_on(barrier.arrive_and_wait()).unwrap()
+                    };
+                    assert_eq!(result.phase() as usize, phase);
+                    if result.is_leader() {
+                        leaders.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(arrivals.load(Ordering::SeqCst), PARTIES * PHASES);
+    assert_eq!(leaders.load(Ordering::SeqCst), PHASES);
+    assert_eq!(barrier.phase() as usize, PHASES);
+
+    // Membership changes mid-phase.
+    let barrier = PhasedBarrier::new(2);
+    assert!(!barrier.arrive().unwrap().is_leader());
+    assert_eq!(barrier.register(), 0);
+    assert!(!barrier.arrive().unwrap().is_leader());
+    assert_eq!(barrier.arrived(), 2);
+    let result = barrier.arrive_and_deregister().unwrap();
+    assert!(result.is_leader());
+    assert_eq!(result.phase(), 0);
+    assert_eq!(barrier.phase(), 1);
+    assert_eq!(barrier.parties(), 2);
+    barrier.arrive_and_deregister().unwrap();
+    barrier.arrive_and_deregister().unwrap();
+    assert_eq!(
+        barrier.arrive().err().unwrap(),
+        PhasedBarrierError::NotRegistered
+    );
+}
+
+#[test]
+fn test_countdown_latch() {
+    let latch = CountdownLatch::new(8);
+    thread::scope(|scope| {
+        let waiters: Vec<_> = (0..2)
+            .map(|_| scope.spawn(|| block_on(latch.wait())))
+            .chain((0..2).map(|_| scope.spawn(|| latch.wait_blocking())))
+            .collect();
+        let openers: Vec<_> = (0..8).map(|_| scope.spawn(|| latch.count_down())).collect();
+        let opened = openers
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .filter(|&opened| opened)
+            .count();
+        assert_eq!(opened, 1);
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+    });
+    assert_eq!(latch.count(), 0);
+    assert!(!latch.count_down());
+    latch.wait_blocking();
+
+    let latch = CountdownLatch::new(0);
+    block_on(latch.wait());
+}
+
+#[test]
+fn test_on_shutdown() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static SHUTDOWNS: AtomicUsize = AtomicUsize::new(0);
+    static CANCELLED: AtomicUsize = AtomicUsize::new(0);
+    let barrier = ShutdownBarrier::with_auto_rearm().on_shutdown(|cancelled| {
+        SHUTDOWNS.fetch_add(1, Ordering::SeqCst);
+        if cancelled {
+            CANCELLED.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    for round in 0..4 {
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                barrier.spawn().unwrap();
+                scope.spawn(|| barrier.done().unwrap());
+            }
+            if round == 3 {
+                barrier.cancel().unwrap();
+            }
+            barrier.done().unwrap();
+        });
+        assert_eq!(SHUTDOWNS.load(Ordering::SeqCst), round + 1);
+    }
+    assert_eq!(CANCELLED.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_progress() {
+    let barrier = ShutdownBarrier::new();
+    barrier.spawn_n(4).unwrap();
+    let mut progress = barrier.subscribe();
+    assert_eq!(progress.remaining_workers(), 5);
+    thread::scope(|scope| {
+        let reporter = scope.spawn(move || {
+            let mut reports = vec![];
+            loop {
+                let remaining = if reports.len() % 2 == 0 {
+                    progress.changed_blocking()
+                } else {
+                    block_on(progress.changed())
+                };
+                reports.push(remaining);
+                if remaining == 0 {
+                    return reports;
+                }
+            }
+        });
+        for _ in 0..5 {
+            barrier.done().unwrap();
+        }
+        let reports = reporter.join().unwrap();
+        // Intermediate counts may be skipped, but they only go down.
+        assert!(reports.windows(2).all(|w| w[0] > w[1]));
+        assert_eq!(reports.last(), Some(&0));
+    });
+}
+
+#[test]
+fn test_done_many() {
+    let barrier = ShutdownBarrier::new();
+    barrier.spawn_n(10).unwrap();
+    assert!(!barrier.done_many(4).unwrap().is_leader());
+    assert_eq!(
+        barrier.done_many(8).err().unwrap(),
+        ShutdownBarrierError::TooManyDone
+    );
+    assert_eq!(barrier.remaining_workers(), 7);
+    assert!(!barrier.done_many(0).unwrap().is_leader());
+    assert!(barrier.done_many(7).unwrap().is_leader());
+    assert!(!barrier.wait_blocking().unwrap().is_cancelled());
+    assert_eq!(
+        barrier.done_many(1).err().unwrap(),
+        ShutdownBarrierError::AlreadyShutdown
+    );
+}
+
+#[test]
+fn test_wait_stats() {
+    let barrier = ShutdownBarrier::new();
+    barrier.spawn_n(3).unwrap();
+    barrier.spawn().unwrap();
+    thread::sleep(Duration::from_millis(5));
+    barrier.done_many(4).unwrap();
+    barrier.done().unwrap();
+    let result = barrier.wait_blocking().unwrap();
+    let stats = result.stats().unwrap();
+    assert_eq!(stats.total_workers(), 5);
+    assert!(stats.elapsed().unwrap() >= Duration::from_millis(5));
+    assert!(!stats.cancelled_mid_drain());
+
+    let barrier = ShutdownBarrier::new();
+    barrier.spawn_n(2).unwrap();
+    barrier.done().unwrap();
+    barrier.cancel().unwrap();
+    let stats = *barrier.wait_blocking().unwrap().stats().unwrap();
+    assert_eq!(stats.total_workers(), 3);
+    assert!(stats.cancelled_mid_drain());
+    // Later completions don't change the summary of a cancelled generation.
+    barrier.done_many(2).unwrap();
+    assert_eq!(barrier.wait_blocking().unwrap().stats(), Some(&stats));
+
+    let barrier = ShutdownBarrier::new();
+    barrier.cancel().unwrap();
+    let result = barrier.wait_blocking().unwrap();
+    let stats = result.stats().unwrap();
+    assert_eq!(stats.total_workers(), 1);
+    assert_eq!(stats.elapsed(), None);
+    assert!(!stats.cancelled_mid_drain());
+
+    // Each generation is summarized separately.
+    let barrier = ShutdownBarrier::with_auto_rearm();
+    let wait = barrier.wait();
+    barrier.spawn_n(7).unwrap();
+    barrier.done_many(8).unwrap();
+    let wait_next = barrier.wait();
+    assert_eq!(block_on(wait).unwrap().stats().unwrap().total_workers(), 8);
+    barrier.done().unwrap();
+    assert_eq!(
+        block_on(wait_next)
+            .unwrap()
+            .stats()
+            .unwrap()
+            .total_workers(),
+        1
+    );
+}
+
+#[test]
+fn test_done_with_error() {
+    let barrier = ShutdownBarrier::<StopReason>::with_reasons();
+    barrier.spawn_n(8).unwrap();
+    thread::scope(|scope| {
+        let waiter = scope.spawn(|| barrier.wait_blocking().unwrap());
+        for i in 0..8 {
+            let barrier = &barrier;
+            scope.spawn(move || {
+                if i % 3 == 0 {
+                    barrier
+                        .done_with_error(StopReason::WorkerFailed(i))
+                        .unwrap();
+                } else {
+                    barrier.done().unwrap();
+                }
+            });
+        }
+        let result = waiter.join().unwrap();
+        assert!(result.is_cancelled());
+        assert!(matches!(
+            result.reason(),
+            Some(StopReason::WorkerFailed(0 | 3 | 6))
+        ));
+    });
+    assert_eq!(barrier.remaining_workers(), 1);
+    assert!(barrier
+        .done_with_error(StopReason::Requested)
+        .unwrap()
+        .is_cancelled());
+    assert_eq!(
+        barrier
+            .done_with_error(StopReason::Requested)
+            .err()
+            .unwrap(),
+        ShutdownBarrierError::AlreadyShutdown
+    );
+}
+
+#[test]
+fn test_first_observer() {
+    for cancel in [false, true] {
+        let barrier = ShutdownBarrier::new();
+        barrier.spawn_n(4).unwrap();
+        let firsts = thread::scope(|scope| {
+            let waiters: Vec<_> = (0..8)
+                .map(|i| {
+                    let barrier = &barrier;
+                    scope.spawn(move || {
+                        let result = if i % 2 == 0 {
+                            barrier.wait_blocking().unwrap()
+                        } else {
+                            block_on(barrier.wait()).unwrap()
+                        };
+                        result.is_first_observer()
+                    })
+                })
+                .collect();
+            if cancel {
+                barrier.cancel().unwrap();
+            }
+            barrier.done_many(5).unwrap();
+            waiters
+                .into_iter()
+                .map(|waiter| waiter.join().unwrap())
+                .filter(|&first| first)
+                .count()
+        });
+        assert_eq!(firsts, 1);
+        // Late waiters are never first.
+        assert!(!barrier.wait_blocking().unwrap().is_first_observer());
+    }
+}
+
+#[test]
+fn test_split() {
+    let (spawner, waiter) = ShutdownBarrier::<StopReason>::with_reasons().split();
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let spawner = spawner.clone();
+            spawner.spawn().unwrap();
+            thread::spawn(move || {
+                spawner.done().unwrap();
+            })
+        })
+        .collect();
+    let waiter2 = waiter.clone();
+    let wait = thread::spawn(move || waiter2.wait_blocking().unwrap().is_cancelled());
+    spawner.done().unwrap();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert!(!wait.join().unwrap());
+    assert!(waiter.is_shutdown());
+    assert!(spawner.spawn().is_err());
+
+    let (spawner, waiter) = ShutdownBarrier::<StopReason>::with_reasons().split();
+    waiter.cancel_with(StopReason::Requested).unwrap();
+    assert!(spawner.spawn_guard().is_err());
+    assert_eq!(
+        block_on(waiter.wait()).unwrap().reason(),
+        Some(&StopReason::Requested)
+    );
+}
+
+#[test]
+fn test_wait_for_idle() {
+    // Cancellation is not a quiet point.
+    let barrier = ShutdownBarrier::new();
+    barrier.spawn().unwrap();
+    let idle = barrier.wait_for_idle();
+    barrier.cancel().unwrap();
+    thread::scope(|scope| {
+        let idle = scope.spawn(|| block_on(idle));
+        thread::sleep(Duration::from_millis(10));
+        assert!(!idle.is_finished());
+        barrier.done_many(2).unwrap();
+        assert_eq!(idle.join().unwrap(), 0);
+    });
+
+    // With auto-rearm, every generation boundary is a quiet point.
+    let barrier = ShutdownBarrier::with_auto_rearm();
+    thread::scope(|scope| {
+        let checkpointer = scope.spawn(|| {
+            (0..3)
+                .map(|_| barrier.wait_for_idle_blocking())
+                .collect::<Vec<_>>()
+        });
+        while !checkpointer.is_finished() {
+            barrier.spawn().unwrap();
+            scope.spawn(|| barrier.done().unwrap()).join().unwrap();
+            barrier.done().unwrap();
+        }
+        let idle = checkpointer.join().unwrap();
+        assert!(idle.windows(2).all(|w| w[0] < w[1]));
+    });
+}
+
+#[test]
+fn test_late_waiters() {
+    let barrier = ShutdownBarrier::<StopReason>::with_reasons_and_auto_rearm();
+    let cancelled = barrier.wait();
+    barrier.cancel_with(StopReason::WorkerFailed(0)).unwrap();
+    barrier.done().unwrap();
+    let clean = barrier.wait();
+    barrier.done().unwrap();
+    for i in 2..5 {
+        barrier.cancel_with(StopReason::WorkerFailed(i)).unwrap();
+        barrier.done().unwrap();
+    }
+    assert_eq!(barrier.generation(), 5);
+
+    // Each waiter gets the outcome of its own generation, however many have
+    // ended since.
+    let result = block_on(cancelled).unwrap();
+    assert!(result.is_cancelled());
+    assert_eq!(result.reason(), Some(&StopReason::WorkerFailed(0)));
+    assert!(result.stats().is_none());
+    let result = block_on(clean).unwrap();
+    assert!(!result.is_cancelled());
+    assert!(result.reason().is_none());
+    assert!(result.stats().is_none());
+}
+
+#[test]
+fn test_done_with() {
+    let barrier = ShutdownBarrier::new().reduce_with(|a: u64, b| a + b);
+    barrier.spawn_n(64).unwrap();
+    thread::scope(|scope| {
+        let waiters: Vec<_> = (0..4)
+            .map(|_| scope.spawn(|| barrier.wait_blocking().unwrap()))
+            .collect();
+        for i in 0..64 {
+            let barrier = &barrier;
+            scope.spawn(move || barrier.done_with(i).unwrap());
+        }
+        barrier.done().unwrap();
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap().aggregate(), Some(&(0..64).sum()));
+        }
+    });
+
+    // Cancelled generations don't report an aggregate, and each generation
+    // of an auto-rearming barrier gets its own, however late the waiter.
+    let barrier = ShutdownBarrier::with_auto_rearm().reduce_with(u32::max);
+    let waits: Vec<_> = (0..4)
+        .map(|generation| {
+            let wait = barrier.wait();
+            barrier.spawn_n(2).unwrap();
+            barrier.done_with(generation * 10).unwrap();
+            if generation == 2 {
+                barrier.cancel().unwrap();
+            }
+            barrier.done_with(generation * 10 + 1).unwrap();
+            barrier.done().unwrap();
+            wait
+        })
+        .collect();
+    let aggregates: Vec<_> = waits
+        .into_iter()
+        .map(|wait| block_on(wait).unwrap().aggregate().copied())
+        .collect();
+    assert_eq!(aggregates, [Some(1), Some(11), None, Some(31)]);
+    let wait = barrier.wait();
+    barrier.done().unwrap();
+    assert!(block_on(wait).unwrap().into_aggregate().is_none());
+
+    // A panicking reducer is reported to the worker that ran it, and the
+    // generation gets no aggregate.
+    let barrier = ShutdownBarrierSync::new().reduce_with(|_: u32, _| panic!("reducer failed"));
+    barrier.spawn_n(2).unwrap();
+    barrier.done_with(1).unwrap();
+    assert!(thread::scope(|scope| scope.spawn(|| barrier.done_with(2)).join()).is_err());
+    barrier.done().unwrap();
+    assert!(barrier.wait().unwrap().aggregate().is_none());
+}
+
+#[test]
+fn test_spawn_future() {
+    let (spawner, waiter) = ShutdownBarrier::new().split();
+    let ok = spawner.spawn_future(async { 1 }).unwrap();
+    let dropped = spawner.spawn_future(async { 2 }).unwrap();
+    let panics = spawner
+        .spawn_future(async { panic!("worker failed") })
+        .unwrap();
+    // Registered before they are polled.
+    assert_eq!(waiter.remaining_workers(), 4);
+    assert_eq!(block_on(ok), 1);
+    drop(dropped);
+    assert_eq!(waiter.remaining_workers(), 2);
+    assert!(thread::spawn(move || block_on(panics)).join().is_err());
+    assert!(waiter.is_cancelled());
+    spawner.done().unwrap();
+    assert!(waiter.wait_blocking().unwrap().is_cancelled());
+    assert_eq!(
+        spawner.spawn_future(async {}).err(),
+        Some(ShutdownBarrierError::AlreadyShutdown)
+    );
+}
+
+#[cfg(feature = "tokio-rt")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_spawn_tokio() {
+    let (spawner, waiter) = ShutdownBarrier::new().split();
+    let tasks: Vec<_> = (0..8)
+        .map(|i| spawner.spawn_tokio(async move { i }).unwrap())
+        .collect();
+    spawner.done().unwrap();
+    assert!(!waiter.wait().await.unwrap().is_cancelled());
+    for (i, task) in tasks.into_iter().enumerate() {
+        assert_eq!(task.await.unwrap(), i);
+    }
+}
+
+#[cfg(feature = "async-std-rt")]
+#[test]
+fn test_spawn_async_std() {
+    let (spawner, waiter) = ShutdownBarrier::new().split();
+    let tasks: Vec<_> = (0..8)
+        .map(|i| spawner.spawn_async_std(async move { i }).unwrap())
+        .collect();
+    spawner.done().unwrap();
+    async_std::task::block_on(async {
+        assert!(!waiter.wait().await.unwrap().is_cancelled());
+        for (i, task) in tasks.into_iter().enumerate() {
+            assert_eq!(task.await, i);
+        }
+    });
+}
+
+#[cfg(feature = "smol-rt")]
+#[test]
+fn test_spawn_smol() {
+    let (spawner, waiter) = ShutdownBarrier::new().split();
+    for _ in 0..8 {
+        spawner
+            .spawn_smol(smol::future::yield_now())
+            .unwrap()
+            .detach();
+    }
+    spawner.done().unwrap();
+    smol::block_on(async {
+        assert!(!waiter.wait().await.unwrap().is_cancelled());
+    });
+    // Dropping a smol task cancels it, which completes its worker once the
+    // executor drops the future.
+    let (spawner, waiter) = ShutdownBarrier::new().split();
+    drop(spawner.spawn_smol(smol::future::pending::<()>()).unwrap());
+    spawner.done().unwrap();
+    assert!(!waiter.wait_blocking().unwrap().is_cancelled());
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing() {
+    use std::sync::Mutex;
+    use tracing::{field::Field, span, Event, Metadata, Subscriber};
+
+    #[derive(Default)]
+    struct Messages(Mutex<Vec<String>>);
+    impl Subscriber for Messages {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn event(&self, event: &Event<'_>) {
+            struct Message<'a>(&'a mut String);
+            impl tracing::field::Visit for Message<'_> {
+                fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                    if field.name() == "message" {
+                        *self.0 = format!("{value:?}");
+                    }
+                }
+            }
+            let mut message = String::new();
+            event.record(&mut Message(&mut message));
+            self.0.lock().unwrap().push(message);
+        }
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    let messages = Arc::new(Messages::default());
+    tracing::subscriber::with_default(messages.clone(), || {
+        let barrier = ShutdownBarrier::new();
+        barrier.spawn_n(2).unwrap();
+        barrier.done().unwrap();
+        barrier.cancel().unwrap();
+        barrier.done_many(2).unwrap();
+        assert!(barrier.spawn().is_err());
+    });
+    assert_eq!(
+        *messages.0.lock().unwrap(),
+        [
+            "spawn",
+            "done",
+            "cancel",
+            "done",
+            "shutdown",
+            "spawn after shutdown"
+        ]
+    );
+}
@@ -0,0 +1,79 @@
+use std::{collections::HashSet, sync::Mutex, thread};
+
+use atomic_try_update::bitmap::{BitmapAllocator, BitmapAllocatorError};
+
+#[test]
+fn test_alloc_free() {
+    let slots = BitmapAllocator::<3>::new();
+    assert_eq!(slots.capacity(), 3);
+    assert_eq!(slots.alloc(), Some(0));
+    assert_eq!(slots.alloc(), Some(1));
+    assert_eq!(slots.alloc(), Some(2));
+    assert_eq!(slots.alloc(), None);
+    assert_eq!(slots.allocated(), 3);
+
+    slots.free(1).unwrap();
+    assert_eq!(slots.free(1), Err(BitmapAllocatorError::NotAllocated));
+    assert_eq!(slots.free(3), Err(BitmapAllocatorError::OutOfRange));
+    assert_eq!(slots.is_allocated(1), Ok(false));
+    assert_eq!(slots.iter_allocated().collect::<Vec<_>>(), [0, 2]);
+    assert_eq!(slots.iter_allocated().len(), 2);
+    assert_eq!(slots.alloc(), Some(1));
+
+    slots.free(2).unwrap();
+    assert_eq!(slots.try_alloc_at(2), Ok(true));
+    assert_eq!(slots.try_alloc_at(2), Ok(false));
+    assert_eq!(slots.try_alloc_at(7), Err(BitmapAllocatorError::OutOfRange));
+}
+
+#[test]
+fn test_full_width() {
+    let slots = BitmapAllocator::<128>::default();
+    for i in 0..128 {
+        assert_eq!(slots.alloc(), Some(i));
+    }
+    assert_eq!(slots.alloc(), None);
+    assert_eq!(slots.iter_allocated().count(), 128);
+    slots.free(127).unwrap();
+    assert_eq!(slots.alloc(), Some(127));
+
+    // The largest allocator that fits in a u64.
+    let slots = BitmapAllocator::<64>::new();
+    for i in 0..64 {
+        assert_eq!(slots.alloc(), Some(i));
+    }
+    assert_eq!(slots.alloc(), None);
+    assert_eq!(
+        slots.try_alloc_at(64),
+        Err(BitmapAllocatorError::OutOfRange)
+    );
+    slots.free(63).unwrap();
+    assert_eq!(slots.iter_allocated().len(), 63);
+    assert_eq!(slots.alloc(), Some(63));
+}
+
+#[test]
+fn test_concurrent() {
+    const THREADS: usize = 8;
+    let slots = BitmapAllocator::<16>::new();
+    let held = Mutex::new(HashSet::new());
+    thread::scope(|scope| {
+        for _ in 0..THREADS {
+            scope.spawn(|| {
+                for _ in 0..1000 {
+                    // Each thread holds at most two slots, so there are
+                    // always enough.
+                    let a = slots.alloc().unwrap();
+                    let b = slots.alloc().unwrap();
+                    assert!(held.lock().unwrap().insert(a));
+                    assert!(held.lock().unwrap().insert(b));
+                    held.lock().unwrap().remove(&a);
+                    slots.free(a).unwrap();
+                    held.lock().unwrap().remove(&b);
+                    slots.free(b).unwrap();
+                }
+            });
+        }
+    });
+    assert_eq!(slots.allocated(), 0);
+}
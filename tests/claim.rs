@@ -1,14 +1,45 @@
 use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
     },
     thread,
 };
 
-use atomic_try_update::claim::{Countable, WriteOrderingQueue};
+use atomic_try_update::claim::{
+    Accumulate, Claim, ClaimBatch, ClaimBudget, ClaimExecutor, ClaimQueue, ClaimStatus, Countable,
+    GroupCommit, GroupCommitError, Priority, PriorityClaimQueue, QueueState, RecordCount,
+    ShardedClaimQueue, WriteOrderingQueue,
+};
 use rand::{rngs::ThreadRng, Rng};
 
+/// Counts the allocations made by each thread, so tests can check that
+/// steady-state operations do not allocate.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCS.try_with(|allocs| allocs.set(allocs.get() + 1));
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocs() -> usize {
+    ALLOCS.with(|allocs| allocs.get())
+}
+
 struct Chunk {
     sz: u64,
 }
@@ -87,3 +118,859 @@ fn test_write_ordering_queue() {
         total_dequeued.load(std::sync::atomic::Ordering::Relaxed)
     );
 }
+
+#[test]
+fn test_claim_queue() {
+    let queue = ClaimQueue::<u64>::default();
+    let consumed = AtomicU64::new(0);
+    let num_threads = 8;
+    let num_inserts = 10000;
+
+    thread::scope(|s| {
+        for t in 0..num_threads {
+            let queue = &queue;
+            let consumed = &consumed;
+            s.spawn(move || {
+                let mut last_seen = vec![None; num_threads as usize];
+                for i in 0..num_inserts {
+                    if !queue.push(t * num_inserts + i) {
+                        continue;
+                    }
+                    loop {
+                        let (iter, claimed) = queue.consume_or_release_claim();
+                        if !claimed {
+                            assert_eq!(iter.len(), 0);
+                            break;
+                        }
+                        let len = iter.len();
+                        let batch: Vec<_> = iter.collect();
+                        assert!(len > 0);
+                        assert_eq!(batch.len(), len);
+
+                        let start = consumed.load(Ordering::SeqCst);
+                        for (expected, val) in (start..).zip(batch) {
+                            // Each producer's values arrive in the order it pushed them.
+                            let (producer, seq) = (val / num_inserts, val % num_inserts);
+                            let last = &mut last_seen[producer as usize];
+                            assert!(last.is_none_or(|last| last < seq));
+                            *last = Some(seq);
+                            // check that no other thread is consuming.
+                            assert_eq!(consumed.fetch_add(1, Ordering::SeqCst), expected);
+                        }
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(consumed.load(Ordering::SeqCst), num_threads * num_inserts);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_wait_for_completion() {
+    let queue = Arc::new(WriteOrderingQueue::<Chunk>::default());
+    let num_tasks = 16;
+    let num_inserts = 1000;
+    let mut tasks = vec![];
+    for _ in 0..num_tasks {
+        let queue = queue.clone();
+        tasks.push(tokio::spawn(async move {
+            for _ in 0..num_inserts {
+                let sz = 10;
+                let (off, claimed) = queue.push(Chunk { sz });
+                if !claimed {
+                    queue.wait_for_completion(off + sz).await;
+                    assert!(queue.get_completed() >= off + sz);
+                    continue;
+                }
+                let mut written = off;
+                loop {
+                    let (batch, claimed) = queue.consume_or_release_claim();
+                    if !claimed {
+                        break;
+                    }
+                    written += batch.map(|chunk| chunk.get_count()).sum::<u64>();
+                    queue.complete_through(written);
+                }
+                assert!(queue.get_completed() >= off + sz);
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    assert_eq!(queue.get_completed(), num_tasks * num_inserts * 10);
+    assert_eq!(queue.get_completed(), queue.get_offset());
+}
+
+#[test]
+fn test_claim_executor() {
+    // Deliberately not thread safe; the executor must serialize access.
+    struct Unsynchronized {
+        count: std::cell::UnsafeCell<u64>,
+        running: std::sync::atomic::AtomicBool,
+    }
+    unsafe impl Sync for Unsynchronized {}
+
+    let executor = Arc::new(ClaimExecutor::default());
+    let state = Arc::new(Unsynchronized {
+        count: std::cell::UnsafeCell::new(0),
+        running: Default::default(),
+    });
+    let num_threads = 8;
+    let num_jobs = 10000;
+    thread::scope(|s| {
+        for _ in 0..num_threads {
+            let executor = &executor;
+            let state = &state;
+            s.spawn(move || {
+                for _ in 0..num_jobs {
+                    let state = state.clone();
+                    executor.execute(move || {
+                        assert!(!state.running.swap(true, Ordering::SeqCst));
+                        unsafe { *state.count.get() += 1 };
+                        state.running.store(false, Ordering::SeqCst);
+                    });
+                }
+            });
+        }
+    });
+    // Every job has run, since the claim is only released once the queue is empty.
+    assert_eq!(unsafe { *state.count.get() }, num_threads * num_jobs);
+
+    // A panicking job doesn't stop later jobs, or wedge the executor.
+    let ran = Arc::new(AtomicU64::new(0));
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        executor.execute(|| panic!("job failed"))
+    }));
+    assert!(result.is_err());
+    let ran2 = ran.clone();
+    assert!(executor.execute(move || {
+        ran2.fetch_add(1, Ordering::SeqCst);
+    }));
+    assert_eq!(ran.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_claim_handoff() {
+    let queue = ClaimQueue::<u64>::default();
+    assert!(queue.push(1));
+    assert!(!queue.push(2));
+    assert_eq!(queue.hand_off_claim(), ClaimStatus::HandedOff);
+    // The next pusher inherits the claim, and the values queued before it.
+    assert!(queue.push(3));
+    assert!(!queue.push(4));
+    let (batch, claimed) = queue.consume_or_release_claim();
+    assert!(claimed);
+    assert_eq!(batch.collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+    assert_eq!(queue.hand_off_claim(), ClaimStatus::Released);
+
+    // Nobody can take a claim that wasn't handed off.
+    assert!(!queue.try_take_handoff());
+    assert!(queue.push(5));
+    assert!(!queue.try_take_handoff());
+    assert_eq!(queue.hand_off_claim(), ClaimStatus::HandedOff);
+    assert!(queue.try_take_handoff());
+    assert!(!queue.try_take_handoff());
+    let budget = ClaimBudget {
+        max_batches: 1,
+        max_time: None,
+    };
+    let mut seen = vec![];
+    let status = queue.consume_with_budget(budget, |batch| seen.extend(batch));
+    assert_eq!(status, ClaimStatus::Released);
+    assert_eq!(seen, vec![5]);
+
+    // With a steady stream of pushes, the claim moves between threads.
+    let consumed = AtomicU64::new(0);
+    let num_threads = 4;
+    let num_inserts = 10000;
+    thread::scope(|s| {
+        for t in 0..num_threads {
+            let (queue, consumed) = (&queue, &consumed);
+            s.spawn(move || {
+                for i in 0..num_inserts {
+                    if queue.push(t * num_inserts + i) {
+                        queue.consume_with_budget(budget, |batch| {
+                            consumed.fetch_add(batch.len() as u64, Ordering::SeqCst);
+                        });
+                    }
+                }
+            });
+        }
+    });
+    // The last pushes may have been handed off with nobody left to take them.
+    if queue.try_take_handoff() {
+        let status = queue.consume_with_budget(Default::default(), |batch| {
+            consumed.fetch_add(batch.len() as u64, Ordering::SeqCst);
+        });
+        assert_eq!(status, ClaimStatus::Released);
+    }
+    assert_eq!(consumed.load(Ordering::SeqCst), num_threads * num_inserts);
+}
+
+#[test]
+fn test_write_ordering_queue_overflow() {
+    use atomic_try_update::claim::ClaimError;
+
+    let queue = WriteOrderingQueue::<Chunk>::default();
+    assert_eq!(
+        queue.try_push(Chunk { sz: u64::MAX - 10 }).unwrap(),
+        (0, true)
+    );
+    match queue.try_push(Chunk { sz: 11 }) {
+        Err(ClaimError::Overflow(chunk)) => assert_eq!(chunk.sz, 11),
+        _ => panic!("offset overflowed"),
+    }
+    // Rejected pushes don't change the offset, or take the claim.
+    assert_eq!(queue.get_offset(), u64::MAX - 10);
+    assert_eq!(
+        queue.try_push(Chunk { sz: 10 }).unwrap(),
+        (u64::MAX - 10, false)
+    );
+    assert!(matches!(
+        queue.try_push(Chunk { sz: u64::MAX }),
+        Err(ClaimError::Overflow(_))
+    ));
+    let (batch, claimed) = queue.consume_or_release_claim();
+    assert!(claimed);
+    assert_eq!(batch.len(), 2);
+    assert_eq!(queue.get_offset(), u64::MAX);
+}
+
+#[test]
+fn test_push_batch() {
+    let queue = WriteOrderingQueue::<Chunk>::default();
+    assert_eq!(queue.push_batch(std::iter::empty()), (0, false));
+    assert_eq!(queue.push(Chunk { sz: 1 }), (0, true));
+    assert_eq!(queue.push_batch((2..5).map(|sz| Chunk { sz })), (1, false));
+    assert_eq!(queue.push(Chunk { sz: 5 }), (10, false));
+    let (batch, claimed) = queue.consume_or_release_claim();
+    assert!(claimed);
+    assert_eq!(
+        batch.map(|chunk| chunk.sz).collect::<Vec<_>>(),
+        vec![1, 2, 3, 4, 5]
+    );
+    assert!(!queue.consume_or_release_claim().1);
+
+    // The batch is all or nothing.
+    match queue.try_push_batch([Chunk { sz: 1 }, Chunk { sz: u64::MAX }]) {
+        Err(atomic_try_update::claim::ClaimError::Overflow(chunks)) => {
+            assert_eq!(
+                chunks.iter().map(|c| c.sz).collect::<Vec<_>>(),
+                vec![1, u64::MAX]
+            );
+        }
+        _ => panic!("offset overflowed"),
+    }
+    assert_eq!(queue.get_offset(), 15);
+    assert_eq!(queue.push_batch([Chunk { sz: 1 }]), (15, true));
+    assert_eq!(queue.consume_or_release_claim().0.len(), 1);
+    assert!(!queue.consume_or_release_claim().1);
+}
+
+/// Tracks the largest sequence number pushed so far.
+struct MaxSeq;
+
+impl Accumulate<u64> for MaxSeq {
+    fn summarize(val: &u64) -> u64 {
+        *val
+    }
+    fn combine(acc: u64, summary: u64) -> Option<u64> {
+        Some(acc.max(summary))
+    }
+}
+
+#[test]
+fn test_accumulate() {
+    let records = WriteOrderingQueue::<Chunk, RecordCount>::default();
+    assert_eq!(records.push(Chunk { sz: 100 }), (0, true));
+    assert_eq!(
+        records.push_batch((0..3).map(|sz| Chunk { sz })),
+        (1, false)
+    );
+    assert_eq!(records.push(Chunk { sz: 7 }), (4, false));
+    assert_eq!(records.get_offset(), 5);
+    assert_eq!(records.consume_or_release_claim().0.len(), 5);
+    assert!(!records.consume_or_release_claim().1);
+
+    let seqs = WriteOrderingQueue::<u64, MaxSeq>::default();
+    assert_eq!(seqs.push(5), (0, true));
+    assert_eq!(seqs.push(3), (5, false));
+    assert_eq!(seqs.push_batch([9, 2]), (5, false));
+    assert_eq!(seqs.get_offset(), 9);
+    assert_eq!(
+        seqs.consume_or_release_claim().0.collect::<Vec<_>>(),
+        vec![5, 3, 9, 2]
+    );
+}
+
+#[test]
+fn test_consume_up_to() {
+    let sizes = |batch: ClaimBatch<Chunk>| batch.map(|chunk| chunk.sz).collect::<Vec<_>>();
+    let queue = WriteOrderingQueue::<Chunk>::default();
+    assert_eq!(queue.push(Chunk { sz: 1 }), (0, true));
+    queue.push_batch((2..=10).map(|sz| Chunk { sz }));
+
+    let (batch, claimed) = queue.consume_up_to(3, u64::MAX);
+    assert!(claimed);
+    assert_eq!(batch.len(), 3);
+    assert_eq!(sizes(batch), vec![1, 2, 3]);
+
+    // Newer values go behind the ones that were held back.
+    queue.push(Chunk { sz: 11 });
+    assert_eq!(sizes(queue.consume_up_to(usize::MAX, 10).0), vec![4, 5]);
+    // At least one value is returned, even if it is too large.
+    assert_eq!(sizes(queue.consume_up_to(10, 1).0), vec![6]);
+    assert_eq!(sizes(queue.consume_or_release_claim().0), vec![7, 8, 9, 10]);
+    assert_eq!(sizes(queue.consume_up_to(1, 1).0), vec![11]);
+    let (batch, claimed) = queue.consume_up_to(1, 1);
+    assert!(!claimed);
+    assert_eq!(batch.len(), 0);
+    // Consuming without the claim panics, but does not wedge the queue.
+    let misuse =
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| queue.consume_up_to(1, 1)));
+    assert!(misuse.is_err());
+    assert_eq!(queue.push(Chunk { sz: 1 }), (66, true));
+    assert_eq!(sizes(queue.consume_up_to(1, 1).0), vec![1]);
+}
+
+#[test]
+fn test_claim_queue_close() {
+    use atomic_try_update::claim::ClaimError;
+
+    let queue = ClaimQueue::<u64>::default();
+    assert!(queue.push(1));
+    assert!(queue.close());
+    assert!(!queue.close());
+    assert!(queue.is_closed());
+    assert!(matches!(queue.try_push(2), Err(ClaimError::Closed(2))));
+    // The claim holder still drains what was queued before the close.
+    let (batch, claimed) = queue.consume_or_release_claim();
+    assert!(claimed);
+    assert_eq!(batch.collect::<Vec<_>>(), vec![1]);
+    assert!(!queue.consume_or_release_claim().1);
+    assert!(queue.is_closed());
+
+    let queue = WriteOrderingQueue::<Chunk>::default();
+    assert_eq!(queue.push(Chunk { sz: 3 }), (0, true));
+    assert!(queue.close());
+    match queue.try_push(Chunk { sz: 4 }) {
+        Err(ClaimError::Closed(chunk)) => assert_eq!(chunk.sz, 4),
+        _ => panic!("push to closed queue succeeded"),
+    }
+    assert!(matches!(
+        queue.try_push_batch([Chunk { sz: 5 }]),
+        Err(ClaimError::Closed(chunks)) if chunks.len() == 1
+    ));
+    assert_eq!(queue.get_offset(), 3);
+    let (batch, claimed) = queue.consume_or_release_claim();
+    assert!(claimed);
+    assert_eq!(batch.len(), 1);
+    assert!(!queue.consume_or_release_claim().1);
+    assert!(queue.is_closed());
+}
+
+#[cfg(feature = "stats")]
+#[test]
+fn test_write_ordering_queue_stats() {
+    use atomic_try_update::claim::ClaimStats;
+
+    let queue = WriteOrderingQueue::<Chunk>::default();
+    assert_eq!(queue.stats(), ClaimStats::default());
+    queue.push(Chunk { sz: 10 });
+    queue.push_batch([Chunk { sz: 20 }, Chunk { sz: 30 }]);
+    let stats = queue.stats();
+    assert_eq!(stats.pushes, 3);
+    assert_eq!(stats.claims, 1);
+    assert_eq!(stats.backlog_items, 3);
+    assert_eq!(stats.backlog_bytes, 60);
+
+    assert_eq!(queue.consume_or_release_claim().0.len(), 3);
+    queue.push(Chunk { sz: 40 });
+    assert_eq!(queue.consume_or_release_claim().0.len(), 1);
+    assert!(!queue.consume_or_release_claim().1);
+    let stats = queue.stats();
+    assert_eq!(stats.claims, 1);
+    assert_eq!(stats.batches, 2);
+    assert_eq!(stats.items, 4);
+    assert_eq!(stats.bytes, 100);
+    assert_eq!(stats.backlog_items, 0);
+    assert_eq!(stats.backlog_bytes, 0);
+    assert_eq!(stats.batches_per_claim(), 2.0);
+    assert_eq!(stats.items_per_batch(), 2.0);
+    assert_eq!(stats.bytes_per_batch(), 50.0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_group_commit() {
+    let log = Arc::new(Mutex::new(Vec::<(u64, u64)>::new()));
+    let mut written = 0;
+    let writer_log = log.clone();
+    let group = Arc::new(GroupCommit::new(move |batch: ClaimBatch<Chunk>| {
+        let mut log = writer_log.lock().unwrap();
+        for chunk in batch {
+            log.push((written, chunk.sz));
+            written += chunk.sz;
+        }
+    }));
+    let num_tasks = 8;
+    let num_appends = 500;
+    let mut tasks = vec![];
+    for task in 0..num_tasks {
+        let group = group.clone();
+        let log = log.clone();
+        tasks.push(tokio::spawn(async move {
+            for _ in 0..num_appends {
+                let sz = task + 1;
+                let (off, durable) = group.append(Chunk { sz }).unwrap();
+                durable.await.unwrap();
+                let log = log.lock().unwrap();
+                let idx = log.binary_search(&(off, sz)).unwrap();
+                assert_eq!(log[idx], (off, sz));
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let log = log.lock().unwrap();
+    assert_eq!(log.len() as u64, num_tasks * num_appends);
+    let mut expected = 0;
+    for &(off, sz) in log.iter() {
+        assert_eq!(off, expected);
+        expected += sz;
+    }
+    assert_eq!(group.queue().get_completed(), expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_group_commit_writer_panic() {
+    use atomic_try_update::claim::ClaimError;
+
+    let started = Arc::new(AtomicBool::new(false));
+    let resume = Arc::new(AtomicBool::new(false));
+    let (writer_started, writer_resume) = (started.clone(), resume.clone());
+    let group = Arc::new(GroupCommit::new(move |batch: ClaimBatch<Chunk>| {
+        for chunk in batch {
+            if chunk.sz == 1 {
+                // Hold the claim until the main thread has queued behind us.
+                writer_started.store(true, Ordering::SeqCst);
+                while !writer_resume.load(Ordering::SeqCst) {
+                    thread::yield_now();
+                }
+            } else {
+                panic!("write failed");
+            }
+        }
+    }));
+    let writer = {
+        let group = group.clone();
+        thread::spawn(move || drop(group.append(Chunk { sz: 1 })))
+    };
+    while !started.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+    // The writer thread has the claim, so this record is written (or not) by
+    // the writer thread.
+    let (off, durable) = group.append(Chunk { sz: 2 }).unwrap();
+    assert_eq!(off, 1);
+    resume.store(true, Ordering::SeqCst);
+    assert_eq!(durable.await, Err(GroupCommitError::WriterPanicked));
+    // The panic is resumed on the thread that ran the writer.
+    assert!(writer.join().is_err());
+    assert_eq!(group.queue().get_completed(), 1);
+    let closed = match group.append(Chunk { sz: 3 }) {
+        Err(ClaimError::Closed(chunk)) => chunk.sz,
+        _ => panic!("append after a writer panic should fail"),
+    };
+    assert_eq!(closed, 3);
+}
+
+#[test]
+fn test_push_with_completion() {
+    let queue = Arc::new(WriteOrderingQueue::<Chunk>::default());
+    // The (thread, push) of each callback, in the order they ran.
+    let acked = Arc::new(Mutex::new(Vec::new()));
+    let num_threads = 4;
+    let num_pushes = 1000;
+    let mut threads = vec![];
+    for t in 0..num_threads {
+        let queue = queue.clone();
+        let acked = acked.clone();
+        threads.push(thread::spawn(move || {
+            let mut offsets = vec![];
+            for i in 0..num_pushes {
+                let acked = acked.clone();
+                let (off, claimed) = queue.push_with_completion(Chunk { sz: 3 }, move || {
+                    acked.lock().unwrap().push((t, i))
+                });
+                offsets.push(off);
+                if !claimed {
+                    continue;
+                }
+                let mut written = off;
+                loop {
+                    let (batch, claimed) = queue.consume_or_release_claim();
+                    if !claimed {
+                        break;
+                    }
+                    written += batch.map(|chunk| chunk.sz).sum::<u64>();
+                    queue.complete_through(written);
+                }
+            }
+            offsets
+        }));
+    }
+    let offsets: Vec<Vec<u64>> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    let acked = acked.lock().unwrap();
+    assert_eq!(acked.len(), num_threads * num_pushes);
+    let acked_offsets: Vec<u64> = acked.iter().map(|&(t, i)| offsets[t][i]).collect();
+    assert!(acked_offsets.windows(2).all(|w| w[0] < w[1]));
+}
+
+#[test]
+fn test_claim() {
+    let claim = Claim::default();
+    assert!(claim.try_claim());
+    assert!(!claim.release());
+    assert!(!claim.is_claimed());
+
+    assert!(claim.try_claim());
+    assert!(!claim.try_claim());
+    assert!(claim.release()); // more work appeared; still held
+    assert!(claim.is_claimed());
+    assert!(!claim.release());
+
+    let guard = claim.try_guard().unwrap();
+    assert!(claim.try_guard().is_none());
+    let guard = guard.release().unwrap();
+    drop(guard);
+    assert!(!claim.is_claimed());
+
+    // Every unit of work is processed by some claim holder.
+    let claim = Arc::new(Claim::default());
+    let produced = Arc::new(AtomicU64::new(0));
+    let consumed = Arc::new(AtomicU64::new(0));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let (claim, produced, consumed) = (claim.clone(), produced.clone(), consumed.clone());
+            thread::spawn(move || {
+                for _ in 0..10000 {
+                    produced.fetch_add(1, Ordering::SeqCst);
+                    let Some(mut guard) = claim.try_guard() else {
+                        continue;
+                    };
+                    loop {
+                        consumed.store(produced.load(Ordering::SeqCst), Ordering::SeqCst);
+                        match guard.release() {
+                            Some(still_held) => guard = still_held,
+                            None => break,
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(consumed.load(Ordering::SeqCst), 40000);
+}
+
+#[test]
+fn test_consume_until_released() {
+    let queue = Arc::new(ClaimQueue::<u64>::default());
+    let sum = Arc::new(AtomicU64::new(0));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let (queue, sum) = (queue.clone(), sum.clone());
+            thread::spawn(move || {
+                for i in 1..=1000 {
+                    if queue.push(i) {
+                        queue.consume_until_released(|batch| {
+                            sum.fetch_add(batch.sum::<u64>(), Ordering::Relaxed);
+                        });
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(sum.load(Ordering::Relaxed), 4 * 500500);
+    assert!(queue.push(0));
+
+    let queue = WriteOrderingQueue::<Chunk>::default();
+    assert!(queue.push(Chunk { sz: 1 }).1);
+    queue.push(Chunk { sz: 2 });
+    let mut batches = vec![];
+    queue.consume_until_released(|batch| {
+        batches.push(batch.len());
+        if batches.len() < 3 {
+            // Lands in a later batch.
+            queue.push(Chunk { sz: 3 });
+        }
+    });
+    assert_eq!(batches, vec![2, 1, 1]);
+    assert!(queue.push(Chunk { sz: 1 }).1);
+}
+
+#[test]
+fn test_sharded_claim_queue() {
+    let queue = Arc::new(ShardedClaimQueue::<(usize, u64)>::with_shards(3));
+    assert_eq!(queue.num_shards(), 3);
+    let consumed = Arc::new(Mutex::new(Vec::new()));
+    let num_threads = 6;
+    let num_pushes = 2000;
+    let threads: Vec<_> = (0..num_threads)
+        .map(|t| {
+            let (queue, consumed) = (queue.clone(), consumed.clone());
+            thread::spawn(move || {
+                for i in 0..num_pushes {
+                    if queue.push((t, i)) {
+                        queue.consume_until_released(|batch| {
+                            consumed.lock().unwrap().extend(batch);
+                        });
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    // Everything was consumed exactly once, and each thread's values were
+    // consumed in push order.
+    let consumed = consumed.lock().unwrap();
+    assert_eq!(consumed.len(), num_threads * num_pushes as usize);
+    let mut next = vec![0; num_threads];
+    for &(t, i) in consumed.iter() {
+        assert_eq!(next[t], i);
+        next[t] += 1;
+    }
+    assert!(queue.push((0, 0)));
+    let (batch, claimed) = queue.consume_or_release_claim();
+    assert!(claimed);
+    assert_eq!(batch.collect::<Vec<_>>(), vec![(0, 0)]);
+    assert!(!queue.consume_or_release_claim().1);
+}
+
+#[test]
+fn test_queue_accessors() {
+    let queue = ClaimQueue::<u64>::default();
+    assert!(!queue.claimed());
+    assert!(queue.is_empty());
+    assert!(queue.push(1));
+    assert!(queue.claimed());
+    assert!(!queue.is_empty());
+    queue.consume_until_released(|_| {});
+    assert!(!queue.claimed());
+    assert!(queue.is_empty());
+
+    let queue = WriteOrderingQueue::<Chunk>::default();
+    assert_eq!(
+        queue.state(),
+        QueueState {
+            claimed: false,
+            closed: false,
+            offset: 0
+        }
+    );
+    queue.push(Chunk { sz: 10 });
+    queue.push_batch([Chunk { sz: 5 }, Chunk { sz: 7 }]);
+    assert!(queue.claimed());
+    assert_eq!(queue.pending_len(), 22);
+    assert_eq!(queue.consume_up_to(2, u64::MAX).0.len(), 2);
+    // The value held aside by consume_up_to is still pending.
+    assert_eq!(queue.pending_len(), 7);
+    queue.consume_until_released(|_| {});
+    assert_eq!(queue.pending_len(), 0);
+    queue.close();
+    assert_eq!(
+        queue.state(),
+        QueueState {
+            claimed: false,
+            closed: true,
+            offset: 22
+        }
+    );
+}
+
+#[test]
+fn test_push_aligned() {
+    let queue = WriteOrderingQueue::<Chunk>::default();
+    assert_eq!(queue.push(Chunk { sz: 10 }), (0, true));
+    assert_eq!(
+        queue.push_aligned(Chunk { sz: 100 }, 64),
+        ((64, 192), false)
+    );
+    assert_eq!(
+        queue.push_aligned(Chunk { sz: 64 }, 64),
+        ((192, 256), false)
+    );
+    assert_eq!(queue.push_aligned(Chunk { sz: 0 }, 64), ((256, 256), false));
+    assert_eq!(queue.push(Chunk { sz: 1 }), (256, false));
+    assert_eq!(queue.get_offset(), 257);
+    assert!(matches!(
+        queue.try_push_aligned(Chunk { sz: 1 }, 1 << 63),
+        Err(atomic_try_update::claim::ClaimError::Overflow(_))
+    ));
+    assert_eq!(queue.get_offset(), 257);
+    assert_eq!(queue.consume_or_release_claim().0.len(), 5);
+}
+
+#[test]
+fn test_priority_claim_queue() {
+    let queue = PriorityClaimQueue::default();
+    assert!(queue.push(1, Priority::Low));
+    assert!(!queue.push(2, Priority::Low));
+    assert!(!queue.push(10, Priority::High));
+    assert!(!queue.push(3, Priority::Low));
+    assert!(!queue.push(11, Priority::High));
+    let (batch, claimed) = queue.consume_or_release_claim();
+    assert!(claimed);
+    assert_eq!(batch.collect::<Vec<_>>(), vec![10, 11, 1, 2, 3]);
+    assert!(!queue.push(4, Priority::Low));
+    assert!(!queue.push(12, Priority::High));
+    let mut batches = vec![];
+    queue.consume_until_released(|batch| batches.push(batch.collect::<Vec<_>>()));
+    assert_eq!(batches, vec![vec![12, 4]]);
+    assert!(queue.push(5, Priority::Low));
+}
+
+#[test]
+fn test_node_recycling() {
+    let queue = WriteOrderingQueue::<Chunk>::default();
+    let round = |queue: &WriteOrderingQueue<Chunk>| {
+        assert!(queue.push(Chunk { sz: 1 }).1);
+        for _ in 0..99 {
+            queue.push(Chunk { sz: 1 });
+        }
+        queue.push_batch((0..10).map(|sz| Chunk { sz }));
+        let (batch, _) = queue.consume_up_to(50, u64::MAX);
+        assert_eq!(batch.len(), 50);
+        drop(batch);
+        queue.consume_until_released(|batch| assert_eq!(batch.count(), 60));
+    };
+    let start = allocs();
+    round(&queue);
+    let before = allocs();
+    assert!(before >= start + 110);
+    for _ in 0..10 {
+        round(&queue);
+    }
+    assert_eq!(allocs(), before);
+}
+
+#[test]
+fn test_consume_or_cancel() {
+    let queue = WriteOrderingQueue::<Chunk>::default();
+    assert!(queue.push(Chunk { sz: 1 }).1);
+    queue.push_batch((2..=6).map(|sz| Chunk { sz }));
+    let mut cancelled = vec![];
+    let (batch, claimed) =
+        queue.consume_or_cancel(|chunk| chunk.sz % 2 == 1, |chunk| cancelled.push(chunk.sz));
+    assert!(claimed);
+    assert_eq!(batch.len(), 3);
+    assert_eq!(
+        batch.map(|chunk| chunk.sz).collect::<Vec<_>>(),
+        vec![2, 4, 6]
+    );
+    assert_eq!(cancelled, vec![1, 3, 5]);
+
+    // A batch where everything is cancelled still holds the claim.
+    queue.push(Chunk { sz: 7 });
+    let (batch, claimed) = queue.consume_or_cancel(|_| true, drop);
+    assert!(claimed);
+    assert_eq!(batch.len(), 0);
+    assert!(!queue.consume_or_cancel(|_| true, drop).1);
+    assert_eq!(queue.get_offset(), 28);
+}
+
+#[cfg(feature = "tokio-rt")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_spawn_claim_consumer() {
+    use atomic_try_update::claim::spawn_claim_consumer;
+
+    let consumed = Arc::new(Mutex::new(Vec::new()));
+    let consumer = Arc::new(spawn_claim_consumer(Arc::new(ClaimQueue::default()), {
+        let consumed = consumed.clone();
+        move |batch: ClaimBatch<(u64, u64)>| {
+            let consumed = consumed.clone();
+            async move {
+                tokio::task::yield_now().await;
+                consumed.lock().unwrap().extend(batch);
+            }
+        }
+    }));
+    let tasks: Vec<_> = (0..4)
+        .map(|t| {
+            let consumer = consumer.clone();
+            tokio::spawn(async move {
+                for i in 0..1000 {
+                    consumer.push((t, i));
+                    if i % 100 == 0 {
+                        tokio::task::yield_now().await;
+                    }
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let Ok(consumer) = Arc::try_unwrap(consumer) else {
+        panic!("consumer still shared");
+    };
+    consumer.shutdown().await.unwrap();
+    let consumed = consumed.lock().unwrap();
+    assert_eq!(consumed.len(), 4000);
+    let mut next = [0; 4];
+    for &(t, i) in consumed.iter() {
+        assert_eq!(next[t as usize], i);
+        next[t as usize] += 1;
+    }
+}
+
+#[cfg(feature = "replay")]
+#[test]
+fn test_replay_log() {
+    use atomic_try_update::claim::{ReplayKind, REPLAY_LOG_LEN};
+
+    let queue = WriteOrderingQueue::<Chunk>::default();
+    queue.push(Chunk { sz: 5 });
+    queue.push_batch([Chunk { sz: 1 }, Chunk { sz: 2 }]);
+    queue.consume_until_released(|_| {});
+    queue.close();
+    let log: Vec<_> = queue
+        .replay_log()
+        .iter()
+        .map(|event| (event.seq, event.kind, event.offset))
+        .collect();
+    assert_eq!(
+        log,
+        vec![
+            (0, ReplayKind::PushClaimed, 0),
+            (1, ReplayKind::Push, 5),
+            (2, ReplayKind::Consume, 8),
+            (3, ReplayKind::Release, 8),
+            (4, ReplayKind::Close, 8),
+        ]
+    );
+    let thread = queue.replay_log()[0].thread;
+    assert!(queue
+        .replay_log()
+        .iter()
+        .all(|event| event.thread == thread));
+
+    // The log is bounded, and keeps the most recent events.
+    let queue = WriteOrderingQueue::<Chunk>::default();
+    for _ in 0..REPLAY_LOG_LEN + 10 {
+        queue.push(Chunk { sz: 1 });
+    }
+    let log = queue.replay_log();
+    assert_eq!(log.len(), REPLAY_LOG_LEN);
+    assert_eq!(log[0].seq, 10);
+    assert_eq!(log[0].offset, 10);
+}
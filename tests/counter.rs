@@ -0,0 +1,51 @@
+use std::thread;
+
+use atomic_try_update::{atomic_try_update, counter::ShardedCounter, Atom};
+
+#[test]
+fn test_counter() {
+    let counter = ShardedCounter::with_shards(4);
+    assert_eq!(counter.num_shards(), 4);
+    thread::scope(|scope| {
+        for t in 0..8 {
+            let counter = &counter;
+            scope.spawn(move || {
+                for _ in 0..10_000 {
+                    if t % 4 == 3 {
+                        counter.decrement();
+                    } else {
+                        counter.increment();
+                    }
+                }
+                counter.add(5);
+            });
+        }
+    });
+    assert_eq!(counter.sum(), 40_000 + 40);
+    assert_eq!(counter.sum_and_reset(), 40_040);
+    assert_eq!(counter.sum(), 0);
+}
+
+#[test]
+fn test_flush_into() {
+    let counter = ShardedCounter::default();
+    let total = Atom::<i64, u64>::default();
+    thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for i in 0..10_000 {
+                    counter.increment();
+                    if i % 1000 == 0 {
+                        counter.flush_into(&total);
+                    }
+                }
+            });
+        }
+    });
+    counter.flush_into(&total);
+    assert_eq!(counter.flush_into(&total), 0);
+    assert_eq!(
+        unsafe { atomic_try_update(&total, |t| (false, *t)) },
+        40_000
+    );
+}
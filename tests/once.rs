@@ -1,6 +1,24 @@
-use std::error::Error;
+use std::{
+    error::Error,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
 
-use atomic_try_update::once::{OnceLockFree, OnceLockFreeError};
+use atomic_try_update::once::{
+    ConfigGate, ConfigGateError, GetOrPrepare, LazyLockFree, OnceArc, OnceLockFree,
+    OnceLockFreeError, OnceMap, OnceSlab, SetPermit,
+};
+
+fn prepare<T: ?Sized>(a: &OnceLockFree<T>) -> SetPermit<'_, T> {
+    match a.get_or_prepare_to_set() {
+        Ok(GetOrPrepare::Prepared(permit)) => permit,
+        _ => panic!("expected an unset cell"),
+    }
+}
 
 #[test]
 fn smoke_test() -> Result<(), Box<dyn Error>> {
@@ -11,16 +29,23 @@ fn smoke_test() -> Result<(), Box<dyn Error>> {
     assert_eq!(a.get()?, &1u64);
 
     let a = OnceLockFree::default();
-    assert_eq!(
-        a.set_prepared(1u64),
-        Err(OnceLockFreeError::UnpreparedForSet)
-    );
+    let permit = prepare(&a);
+    a.set_prepared(permit, 0u8)?;
+    assert_eq!(a.get_or_prepare_to_set()?.get(), Some(&0u8));
 
+    // Dropping the permit unprepares the cell.
     let a = OnceLockFree::default();
-    let x = a.get_or_prepare_to_set()?;
-    assert_eq!(x, None);
-    a.set_prepared(0u8)?;
-    assert_eq!(a.get_or_prepare_to_set()?, Some(&0u8));
+    drop(prepare(&a));
+    assert_eq!(a.get_poll(), None);
+    prepare(&a).set(2u64)?;
+    assert_eq!(a.get()?, &2);
+
+    let a = OnceLockFree::<u8>::default();
+    assert!(a.seal());
+    assert!(matches!(
+        a.get_or_prepare_to_set(),
+        Ok(GetOrPrepare::Sealed)
+    ));
 
     let a = OnceLockFree::default();
     let x = a.get_poll();
@@ -46,3 +71,565 @@ fn smoke_test() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[test]
+fn test_get_or_init() {
+    let a = OnceLockFree::default();
+    assert_eq!(a.get_or_init(|| 1u64), &1);
+    assert_eq!(a.get_or_init(|| 2u64), &1);
+    assert_eq!(a.get(), Ok(&1));
+
+    // Racing callers all see the value from the single initializer.
+    let a = OnceLockFree::default();
+    let inits = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for i in 0..8 {
+            let (a, inits) = (&a, &inits);
+            s.spawn(move || {
+                let val = a.get_or_init(|| {
+                    inits.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    i
+                });
+                assert_eq!(a.get(), Ok(val));
+            });
+        }
+    });
+    assert_eq!(inits.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_get_or_try_init() {
+    let a = OnceLockFree::<u32>::default();
+    assert_eq!(a.get_or_try_init(|| Err("not yet")), Err("not yet"));
+    // The failure left the cell unset, rather than prepared.
+    assert_eq!(prepare(&a).set(3), Ok(&3));
+    assert_eq!(a.get_or_try_init(|| Err(())), Ok(&3));
+
+    let a = OnceLockFree::<u32>::default();
+    assert_eq!(a.get_or_try_init(|| Err(())), Err(()));
+    assert_eq!(a.get_or_try_init(|| Ok::<_, ()>(3)), Ok(&3));
+
+    // A panic poisons the cell.
+    let a = OnceLockFree::<u32>::default();
+    let panicked = std::panic::catch_unwind(|| a.get_or_init(|| panic!("init failed")));
+    assert!(panicked.is_err());
+    assert_eq!(a.get(), Err(OnceLockFreeError::Poisoned));
+    let panicked = std::panic::catch_unwind(|| a.get_or_init(|| 4));
+    assert!(panicked.is_err());
+}
+
+#[test]
+fn test_poisoned() {
+    // Racing prepares.
+    let a = OnceLockFree::<u32>::default();
+    let permit = prepare(&a);
+    assert_eq!(
+        a.get_or_prepare_to_set().err(),
+        Some(OnceLockFreeError::AttemptToSetConcurrently)
+    );
+    assert_eq!(
+        a.get_or_prepare_to_set().err(),
+        Some(OnceLockFreeError::Poisoned)
+    );
+    assert_eq!(a.get_or_seal(), Err(OnceLockFreeError::Poisoned));
+    assert_eq!(a.get(), Err(OnceLockFreeError::Poisoned));
+    assert_eq!(a.set(1), Err(OnceLockFreeError::Poisoned));
+    assert_eq!(permit.set(1), Err(OnceLockFreeError::Poisoned));
+    assert_eq!(a.get_poll(), None);
+
+    // A set racing with a prepare.
+    let mut a = OnceLockFree::<u32>::default();
+    let permit = prepare(&a);
+    assert_eq!(a.set(1), Err(OnceLockFreeError::AttemptToSetConcurrently));
+    assert_eq!(permit.set(1), Err(OnceLockFreeError::Poisoned));
+
+    // take clears the poison.
+    assert_eq!(a.take(), None);
+    assert_eq!(a.set(2), Ok(&2));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_wait() {
+    let a = Arc::new(OnceLockFree::<u64>::default());
+    let waiters: Vec<_> = (0..8)
+        .map(|_| {
+            let a = a.clone();
+            tokio::spawn(async move { *a.wait().await })
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    a.set(7).unwrap();
+    for waiter in waiters {
+        assert_eq!(waiter.await.unwrap(), 7);
+    }
+    assert_eq!(a.wait().await, &7);
+}
+
+#[test]
+fn test_wait_blocking() {
+    let a = OnceLockFree::<u64>::default();
+    thread::scope(|scope| {
+        let waiters: Vec<_> = (0..4).map(|_| scope.spawn(|| *a.wait_blocking())).collect();
+        thread::sleep(Duration::from_millis(10));
+        a.set(3).unwrap();
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap(), 3);
+        }
+    });
+    assert_eq!(a.wait_blocking(), &3);
+}
+
+#[test]
+fn test_take() -> Result<(), Box<dyn Error>> {
+    let mut a = OnceLockFree::default();
+    assert_eq!(a.take(), None);
+    a.set(String::from("x"))?;
+    assert_eq!(a.take(), Some(String::from("x")));
+    assert_eq!(a.get_poll(), None);
+
+    // Sealed cells go back to unset.
+    assert_eq!(a.get_or_seal()?, None);
+    assert_eq!(a.take(), None);
+    a.set(String::from("y"))?;
+    assert_eq!(a.into_inner(), Some(String::from("y")));
+
+    let a = OnceLockFree::<String>::default();
+    assert_eq!(a.into_inner(), None);
+    Ok(())
+}
+
+#[test]
+fn test_set_boxed() -> Result<(), Box<dyn Error>> {
+    // 8-byte aligned, so the allocation is reused.
+    let a = OnceLockFree::default();
+    let val = Box::new(5u64);
+    let addr = &*val as *const u64;
+    assert!(std::ptr::eq(a.set_boxed(val)?, addr));
+    assert_eq!(a.set_boxed(Box::new(6)), Err(OnceLockFreeError::AlreadySet));
+    assert_eq!(a.get()?, &5);
+
+    // Not 8-byte aligned, so the value is moved.
+    let a = OnceLockFree::default();
+    let permit = prepare(&a);
+    assert_eq!(a.set_prepared_boxed(permit, Box::new(1u8))?, &1);
+    let a = OnceLockFree::default();
+    assert_eq!(prepare(&a).set_boxed(Box::new(1u8))?, &1);
+    Ok(())
+}
+
+#[test]
+fn test_lazy_lock_free() {
+    let calls = AtomicUsize::new(0);
+    let table = LazyLockFree::new(|| {
+        calls.fetch_add(1, Ordering::Relaxed);
+        vec![1, 2, 3]
+    });
+    assert_eq!(table.get(), None);
+    thread::scope(|scope| {
+        for _ in 0..8 {
+            scope.spawn(|| assert_eq!(table.len(), 3));
+        }
+    });
+    assert_eq!(table.force(), &[1, 2, 3]);
+    assert_eq!(table.get(), Some(&vec![1, 2, 3]));
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+    let answer: LazyLockFree<u64> = LazyLockFree::new(|| 42);
+    assert_eq!(*answer, 42);
+
+    // The initializer may consume what it captures.
+    let name = String::from("ab");
+    let moved = LazyLockFree::new(move || name);
+    assert_eq!(moved.as_str(), "ab");
+}
+
+static GREETING: LazyLockFree<String> = LazyLockFree::new(|| "hello".to_uppercase());
+
+#[test]
+fn test_lazy_lock_free_static() {
+    assert_eq!(GREETING.get(), None);
+    thread::scope(|scope| {
+        for _ in 0..8 {
+            scope.spawn(|| assert_eq!(GREETING.as_str(), "HELLO"));
+        }
+    });
+    assert_eq!(GREETING.get().map(String::as_str), Some("HELLO"));
+}
+
+#[test]
+fn test_once_slab() -> Result<(), Box<dyn Error>> {
+    let slab = OnceSlab::with_capacity(16);
+    thread::scope(|scope| {
+        for i in 0..16usize {
+            let slab = &slab;
+            scope.spawn(move || {
+                let index = slab.claim_slot().unwrap();
+                assert_eq!(slab.set(index, i).unwrap(), &i);
+            });
+        }
+    });
+    assert_eq!(slab.claim_slot(), None);
+    assert_eq!(slab.claimed(), slab.capacity());
+    let mut vals: Vec<_> = slab.iter().map(|(_, val)| *val).collect();
+    vals.sort();
+    assert_eq!(vals, (0..16).collect::<Vec<_>>());
+
+    let slab = OnceSlab::with_capacity(2);
+    assert_eq!(slab.set(0, "a"), Err(OnceLockFreeError::UnpreparedForSet));
+    let index = slab.claim_slot().unwrap();
+    assert_eq!(slab.get(index), None);
+    slab.set(index, "a")?;
+    assert_eq!(slab.set(index, "b"), Err(OnceLockFreeError::AlreadySet));
+    assert_eq!(slab.get(index), Some(&"a"));
+    assert_eq!(slab.get(5), None);
+    // Claimed but unpublished slots are skipped.
+    let index = slab.claim_slot().unwrap();
+    assert_eq!(slab.iter().count(), 1);
+    slab.set(index, "b")?;
+    assert_eq!(slab.iter().collect::<Vec<_>>(), vec![(0, &"a"), (1, &"b")]);
+    Ok(())
+}
+
+#[test]
+fn test_once_map() -> Result<(), Box<dyn Error>> {
+    // Few buckets, so that inserts collide.
+    let map = OnceMap::with_buckets(4);
+    let wins = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for t in 0..8 {
+            let (map, wins) = (&map, &wins);
+            scope.spawn(move || {
+                for key in 0..100 {
+                    if map.insert(format!("handler-{key}"), (key, t)).is_ok() {
+                        wins.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(wins.load(Ordering::Relaxed), 100);
+    for key in 0..100 {
+        assert_eq!(map.get(format!("handler-{key}").as_str()).unwrap().0, key);
+    }
+    assert!(!map.contains_key("handler-100"));
+
+    let map = OnceMap::default();
+    assert_eq!(map.insert(1, "a")?, &"a");
+    assert_eq!(map.insert(1, "b"), Err(OnceLockFreeError::AlreadySet));
+    assert_eq!(map.get(&1), Some(&"a"));
+    assert_eq!(map.get(&2), None);
+    Ok(())
+}
+
+#[test]
+fn test_set_or_get() {
+    let a = OnceLockFree::default();
+    let winners = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for i in 0..8u32 {
+            let (a, winners) = (&a, &winners);
+            scope.spawn(move || {
+                let (val, we_set) = a.set_or_get(i);
+                if we_set {
+                    winners.fetch_add(1, Ordering::Relaxed);
+                    assert_eq!(val, &i);
+                }
+                assert_eq!(a.get_poll(), Some(val));
+            });
+        }
+    });
+    assert_eq!(winners.load(Ordering::Relaxed), 1);
+
+    // Waits for a prepared cell to be set.
+    let a = OnceLockFree::default();
+    let permit = prepare(&a);
+    thread::scope(|scope| {
+        let waiter = scope.spawn(|| a.set_or_get(String::from("b")));
+        thread::sleep(Duration::from_millis(10));
+        permit.set(String::from("a")).unwrap();
+        assert_eq!(waiter.join().unwrap(), (&String::from("a"), false));
+    });
+}
+
+static GLOBAL: OnceLockFree<&str> = OnceLockFree::new();
+
+#[test]
+fn test_static() {
+    assert_eq!(GLOBAL.get_poll(), None);
+    assert_eq!(GLOBAL.set_or_get("config"), (&"config", true));
+    assert_eq!(GLOBAL.get_poll(), Some(&"config"));
+}
+
+#[test]
+fn test_get_cloned() -> Result<(), Box<dyn Error>> {
+    let a = OnceLockFree::default();
+    a.set(String::from("x"))?;
+    let owned: String = a.get_cloned()?;
+    drop(a);
+    assert_eq!(owned, "x");
+
+    let a = OnceLockFree::<u64>::default();
+    assert_eq!(
+        a.get_copied(),
+        Err(OnceLockFreeError::AttemptToReadWhenUnset)
+    );
+    let a = OnceLockFree::default();
+    a.set(3u64)?;
+    assert_eq!(a.get_copied(), Ok(3));
+    Ok(())
+}
+
+#[test]
+fn test_seal() {
+    let a = OnceLockFree::<u32>::default();
+    assert!(a.seal());
+    assert!(!a.seal());
+    assert_eq!(a.set(1), Err(OnceLockFreeError::AlreadySet));
+    assert_eq!(a.get_or_seal(), Ok(None));
+
+    let a = OnceLockFree::<u32>::default();
+    a.set(1).unwrap();
+    assert!(!a.seal());
+    assert_eq!(a.get(), Ok(&1));
+
+    // A prepared cell can still be set.
+    let a = OnceLockFree::<u32>::default();
+    let permit = prepare(&a);
+    assert!(!a.seal());
+    assert_eq!(permit.set(2), Ok(&2));
+}
+
+#[test]
+fn test_on_set() {
+    let published = Arc::new(AtomicUsize::new(0));
+    let a = OnceLockFree::with_on_set({
+        let published = published.clone();
+        move |val: &usize| {
+            published.fetch_add(*val, Ordering::Relaxed);
+        }
+    });
+    thread::scope(|scope| {
+        for i in 1..=8 {
+            let a = &a;
+            scope.spawn(move || a.set_or_get(i));
+        }
+    });
+    let val = *a.get().unwrap();
+    assert_eq!(published.load(Ordering::Relaxed), val);
+    assert_eq!(a.set(9), Err(OnceLockFreeError::AlreadySet));
+    assert_eq!(published.load(Ordering::Relaxed), val);
+}
+
+#[test]
+fn test_once_arc() {
+    let a = OnceArc::default();
+    assert!(a.get().is_none());
+    let val = Arc::new(String::from("handler"));
+    assert_eq!(a.set(val.clone()), Ok(&String::from("handler")));
+    assert_eq!(
+        a.set(Arc::new(String::from("other"))),
+        Err(OnceLockFreeError::AlreadySet)
+    );
+    let got = a.get().unwrap();
+    assert!(Arc::ptr_eq(&got, &val));
+    assert_eq!(Arc::strong_count(&val), 3);
+    drop(got);
+    assert_eq!(a.get_ref(), Some(&String::from("handler")));
+    drop(a);
+    assert_eq!(Arc::strong_count(&val), 1);
+}
+
+#[tokio::test]
+async fn test_reset() {
+    let mut a = OnceLockFree::<u32>::default();
+    for i in 0..3 {
+        // Leave a waker behind, and reset it without setting a value.
+        let cancelled = tokio::time::timeout(Duration::from_millis(1), a.wait()).await;
+        assert!(cancelled.is_err());
+        assert_eq!(a.reset(), None);
+        a.set(i).unwrap();
+        assert_eq!(a.reset(), Some(i));
+        assert_eq!(a.get_poll(), None);
+    }
+}
+
+#[cfg(feature = "tokio-rt")]
+#[tokio::test]
+async fn test_wait_timeout() {
+    let a = OnceLockFree::<u32>::default();
+    assert_eq!(
+        a.wait_timeout(Duration::from_millis(10)).await,
+        Err(OnceLockFreeError::Timeout)
+    );
+    a.set(1).unwrap();
+    assert_eq!(a.wait_timeout(Duration::from_secs(60)).await, Ok(&1));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_wait_blocking_timeout() {
+    let a = Arc::new(OnceLockFree::<u32>::default());
+    assert_eq!(
+        a.wait_blocking_timeout(Duration::from_millis(10)),
+        Err(OnceLockFreeError::Timeout)
+    );
+
+    let waiter = {
+        let a = a.clone();
+        tokio::task::spawn_blocking(move || {
+            a.wait_blocking_timeout(Duration::from_secs(60)).copied()
+        })
+    };
+    a.set(1).unwrap();
+    assert_eq!(waiter.await.unwrap(), Ok(1));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_config_gate() {
+    let gate = Arc::new(ConfigGate::new(["db", "cache", "auth"]));
+    let ready = tokio::spawn({
+        let gate = gate.clone();
+        async move { gate.ready().await }
+    });
+    for key in ["db", "cache", "auth"] {
+        let gate = gate.clone();
+        tokio::spawn(async move {
+            gate.set(key, key.len()).unwrap();
+        });
+    }
+    assert_eq!(ready.await.unwrap(), Ok(()));
+    // Also ready for callers that show up late.
+    assert_eq!(gate.ready().await, Ok(()));
+    assert_eq!(gate.get("db"), Some(&2));
+    assert_eq!(gate.set("db", 0), Err(ConfigGateError::AlreadySet));
+    assert_eq!(gate.set("queue", 0), Err(ConfigGateError::UnknownKey));
+
+    let gate = Arc::new(ConfigGate::new(["db", "cache"]));
+    let cache = tokio::spawn({
+        let gate = gate.clone();
+        async move { gate.wait("cache").await.copied() }
+    });
+    gate.set("db", 1).unwrap();
+    assert_eq!(gate.wait("db").await, Ok(&1));
+    assert_eq!(gate.wait("queue").await, Err(ConfigGateError::UnknownKey));
+    gate.seal();
+    assert_eq!(cache.await.unwrap(), Err(ConfigGateError::Cancelled));
+    assert_eq!(gate.ready().await, Err(ConfigGateError::Cancelled));
+    assert_eq!(gate.set("cache", 2), Err(ConfigGateError::AlreadySet));
+    assert_eq!(gate.get("db"), Some(&1));
+
+    let gate = ConfigGate::<&str, u32>::new([]);
+    assert_eq!(gate.ready().await, Ok(()));
+}
+
+#[test]
+#[should_panic(expected = "SetPermit used with a different OnceLockFree")]
+fn test_set_prepared_wrong_cell() {
+    let a = OnceLockFree::<u32>::default();
+    let b = OnceLockFree::<u32>::default();
+    let _ = b.set_prepared(prepare(&a), 1);
+}
+
+#[test]
+fn test_unsized() {
+    let name: OnceLockFree<str> = OnceLockFree::new();
+    assert_eq!(name.get_poll(), None);
+    assert_eq!(name.set_boxed("interned".into()), Ok("interned"));
+    assert_eq!(
+        name.set_boxed("other".into()),
+        Err(OnceLockFreeError::AlreadySet)
+    );
+    assert_eq!(name.get(), Ok("interned"));
+
+    let bytes: OnceLockFree<[u8]> = OnceLockFree::default();
+    thread::scope(|scope| {
+        for i in 0..4u8 {
+            let bytes = &bytes;
+            scope.spawn(move || bytes.set_boxed(vec![i; 3].into_boxed_slice()));
+        }
+        let got = bytes.wait_blocking();
+        assert_eq!(got.len(), 3);
+        assert!(got.iter().all(|b| *b == got[0]));
+    });
+
+    // Empty slices have a dangling pointer, which still counts as set.
+    let empty: OnceLockFree<[u8]> = OnceLockFree::new();
+    assert_eq!(empty.set_boxed(Box::new([])), Ok(&[][..]));
+    assert_eq!(empty.get_poll(), Some(&[][..]));
+    assert!(!empty.seal());
+
+    let prepared: OnceLockFree<str> = OnceLockFree::new();
+    let permit = prepare(&prepared);
+    assert_eq!(
+        prepared.set_boxed("racing".into()),
+        Err(OnceLockFreeError::AttemptToSetConcurrently)
+    );
+    assert_eq!(
+        permit.set_boxed("prepared".into()),
+        Err(OnceLockFreeError::Poisoned)
+    );
+
+    let prepared: OnceLockFree<str> = OnceLockFree::new();
+    assert_eq!(
+        prepare(&prepared).set_boxed("prepared".into()),
+        Ok("prepared")
+    );
+    assert_eq!(prepared.wait_blocking(), "prepared");
+
+    let sealed: OnceLockFree<str> = OnceLockFree::new();
+    assert_eq!(sealed.get_or_seal(), Ok(None));
+    assert_eq!(
+        sealed.set_boxed("late".into()),
+        Err(OnceLockFreeError::AlreadySet)
+    );
+
+    // Sized values that are less than 8-byte aligned are moved into an
+    // aligned allocation.
+    let sized = OnceLockFree::new();
+    assert_eq!(sized.set_boxed(Box::new(7u8)), Ok(&7));
+    assert_eq!(sized.into_inner(), Some(7));
+    let unit = OnceLockFree::new();
+    assert_eq!(unit.set_boxed(Box::new(())), Ok(&()));
+}
+
+#[cfg(feature = "stats")]
+#[test]
+fn test_stats() {
+    use atomic_try_update::once::OnceLockFreeStats;
+
+    let a = OnceLockFree::<u32>::default();
+    a.set(1).unwrap();
+    assert!(a.set(2).is_err());
+    assert!(a.set(3).is_err());
+    assert_eq!(
+        a.stats(),
+        OnceLockFreeStats {
+            already_set: 2,
+            set_concurrently: 0,
+        }
+    );
+
+    let a = OnceLockFree::<u32>::default();
+    let _permit = prepare(&a);
+    assert!(a.get_or_prepare_to_set().is_err());
+    assert_eq!(a.stats().set_concurrently, 1);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde() {
+    use std::collections::BTreeMap;
+
+    let mut config: BTreeMap<&str, OnceLockFree<String>> = BTreeMap::new();
+    config.insert("db", OnceLockFree::new());
+    config.insert("cache", OnceLockFree::new());
+    config["db"].set(String::from("postgres://")).unwrap();
+    let json = serde_json::to_string(&config).unwrap();
+    assert_eq!(json, r#"{"cache":null,"db":"postgres://"}"#);
+
+    let restored: BTreeMap<String, OnceLockFree<String>> = serde_json::from_str(&json).unwrap();
+    assert_eq!(restored["db"].get(), Ok(&String::from("postgres://")));
+    assert_eq!(restored["cache"].get_poll(), None);
+    // The unset cell can still be set.
+    assert!(restored["cache"].set(String::from("redis://")).is_ok());
+}
@@ -0,0 +1,95 @@
+// MpscQueue is deprecated, but still supported.
+#![allow(deprecated)]
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use atomic_try_update::queue::MpscQueue;
+
+#[test]
+fn test_fifo() {
+    let queue = MpscQueue::default();
+    assert!(queue.is_empty());
+    assert!(queue.drain().is_empty());
+    for i in 0..10 {
+        queue.push(i);
+    }
+    queue.push_batch(10..20);
+    queue.push_batch(std::iter::empty());
+    assert!(!queue.is_empty());
+    assert_eq!(
+        queue.drain().collect::<Vec<_>>(),
+        (0..20).collect::<Vec<_>>()
+    );
+    assert!(queue.is_empty());
+
+    queue.push(20);
+    let mut batch = queue.drain();
+    queue.push(21);
+    assert_eq!(batch.next(), Some(20));
+    assert_eq!(batch.next(), None);
+    assert_eq!(queue.drain().collect::<Vec<_>>(), [21]);
+}
+
+#[test]
+fn test_concurrent_producers() {
+    const PRODUCERS: u64 = 4;
+    const PUSHES: u64 = 10_000;
+    let queue = MpscQueue::default();
+    let mut drained = Vec::new();
+    thread::scope(|scope| {
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|p| {
+                let queue = &queue;
+                scope.spawn(move || {
+                    for i in (0..PUSHES).step_by(10) {
+                        if i % 20 == 0 {
+                            queue.push_batch((i..i + 10).map(|i| (p, i)));
+                        } else {
+                            (i..i + 10).for_each(|i| queue.push((p, i)));
+                        }
+                    }
+                })
+            })
+            .collect();
+        while !producers.iter().all(|p| p.is_finished()) {
+            drained.extend(queue.drain());
+        }
+    });
+    drained.extend(queue.drain());
+    assert_eq!(drained.len() as u64, PRODUCERS * PUSHES);
+    // Each producer's values come out in the order it pushed them.
+    for p in 0..PRODUCERS {
+        let mine: Vec<_> = drained
+            .iter()
+            .filter(|(producer, _)| *producer == p)
+            .map(|(_, i)| *i)
+            .collect();
+        assert_eq!(mine, (0..PUSHES).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn test_drop() {
+    struct Counted(Arc<AtomicUsize>);
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let queue = MpscQueue::default();
+    queue.push_batch((0..10).map(|_| Counted(dropped.clone())));
+    let mut batch = queue.drain();
+    drop(batch.next());
+    drop(batch);
+    assert_eq!(dropped.load(Ordering::SeqCst), 10);
+    queue.push_batch((0..10).map(|_| Counted(dropped.clone())));
+    drop(queue);
+    assert_eq!(dropped.load(Ordering::SeqCst), 20);
+}
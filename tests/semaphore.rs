@@ -0,0 +1,86 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use atomic_try_update::semaphore::Semaphore;
+
+#[test]
+fn test_try_acquire() {
+    let semaphore = Semaphore::new(3);
+    let one = semaphore.try_acquire().unwrap();
+    assert_eq!(one.permits(), 1);
+    let two = semaphore.try_acquire_many(2).unwrap();
+    assert_eq!(semaphore.available_permits(), 0);
+    assert!(semaphore.try_acquire().is_none());
+    drop(two);
+    assert_eq!(semaphore.available_permits(), 2);
+    assert!(semaphore.try_acquire_many(3).is_none());
+    assert_eq!(semaphore.available_permits(), 2);
+
+    one.forget();
+    assert_eq!(semaphore.available_permits(), 2);
+    semaphore.add_permits(1);
+    assert_eq!(semaphore.available_permits(), 3);
+}
+
+#[test]
+fn test_acquire_blocking() {
+    let semaphore = Semaphore::new(2);
+    let active = AtomicU32::new(0);
+    thread::scope(|scope| {
+        for _ in 0..8 {
+            scope.spawn(|| {
+                for _ in 0..100 {
+                    let permit = semaphore.acquire_blocking();
+                    assert!(active.fetch_add(1, Ordering::SeqCst) < 2);
+                    thread::yield_now();
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    drop(permit);
+                }
+            });
+        }
+    });
+    assert_eq!(semaphore.available_permits(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_acquire() {
+    let semaphore = Arc::new(Semaphore::new(3));
+    let active = Arc::new(AtomicU32::new(0));
+    let tasks: Vec<_> = (0..16)
+        .map(|i| {
+            let semaphore = semaphore.clone();
+            let active = active.clone();
+            tokio::spawn(async move {
+                for _ in 0..100 {
+                    let n = i % 3 + 1;
+                    let permit = semaphore.acquire_many(n).await;
+                    assert!(active.fetch_add(n, Ordering::SeqCst) + n <= 3);
+                    tokio::task::yield_now().await;
+                    active.fetch_sub(n, Ordering::SeqCst);
+                    drop(permit);
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    assert_eq!(semaphore.available_permits(), 3);
+
+    // A waiter is woken when permits are added.
+    let permit = semaphore.acquire_many(3).await;
+    permit.forget();
+    let waiter = tokio::spawn({
+        let semaphore = semaphore.clone();
+        async move { semaphore.acquire().await.permits() }
+    });
+    tokio::task::yield_now().await;
+    assert!(!waiter.is_finished());
+    semaphore.add_permits(1);
+    assert_eq!(waiter.await.unwrap(), 1);
+}
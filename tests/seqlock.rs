@@ -0,0 +1,79 @@
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
+use atomic_try_update::seqlock::SeqLock;
+
+/// Wider than anything an `Atom` can hold.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Config {
+    words: [u64; 8],
+}
+
+#[test]
+fn test_read_write() {
+    let lock = SeqLock::<Config>::default();
+    assert_eq!(lock.version(), 0);
+    assert_eq!(lock.read(), Config::default());
+    lock.write(Config { words: [1; 8] });
+    assert_eq!(lock.version(), 1);
+    assert_eq!(lock.try_read(), Some(Config { words: [1; 8] }));
+    let old = lock.update(|config| std::mem::replace(&mut config.words[0], 2));
+    assert_eq!(old, 1);
+    assert_eq!(lock.read().words[..2], [2, 1]);
+    assert_eq!(lock.version(), 2);
+
+    // A panicking update leaves the value alone, and doesn't wedge readers.
+    assert!(catch_unwind(AssertUnwindSafe(|| {
+        lock.update(|config| {
+            config.words[1] = 3;
+            panic!("update failed");
+        })
+    }))
+    .is_err());
+    assert_eq!(lock.read().words[..2], [2, 1]);
+    assert_eq!(lock.into_inner().words[..2], [2, 1]);
+}
+
+#[test]
+fn test_concurrent() {
+    let lock = SeqLock::new(Config::default());
+    let stop = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut reads = 0;
+                    while !stop.load(Ordering::SeqCst) {
+                        let config = lock.read();
+                        // Every write sets all the words to the same value,
+                        // so a torn read would show up as a mismatch.
+                        assert!(config.words.iter().all(|&w| w == config.words[0]));
+                        reads += 1;
+                    }
+                    reads
+                })
+            })
+            .collect();
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                scope.spawn(|| {
+                    for _ in 0..10_000 {
+                        lock.update(|config| config.words = [config.words[0] + 1; 8]);
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        stop.store(true, Ordering::SeqCst);
+        for reader in readers {
+            assert!(reader.join().unwrap() > 0);
+        }
+    });
+    assert_eq!(lock.read().words, [20_000; 8]);
+    assert_eq!(lock.version(), 20_000);
+}
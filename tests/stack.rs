@@ -7,7 +7,7 @@ use std::{
     time::Instant,
 };
 
-use atomic_try_update::stack::*;
+use atomic_try_update::{stack::*, NodeIterator};
 
 fn worker(num_inserts: u64, n: u64, stack: &Stack<u64>, total: &std::sync::atomic::AtomicU64) {
     let mut count = 0;
@@ -28,6 +28,23 @@ fn worker(num_inserts: u64, n: u64, stack: &Stack<u64>, total: &std::sync::atomi
     total.fetch_add(count, std::sync::atomic::Ordering::SeqCst);
 }
 
+fn elimination_worker(
+    num_inserts: u64,
+    n: u64,
+    stack: &EliminationStack<u64>,
+    total: &std::sync::atomic::AtomicU64,
+) {
+    let mut count = 0;
+    for i in 0..num_inserts {
+        stack.push(n * num_inserts + i);
+        if i % 17 == 0 {
+            count += stack.pop_all().count() as u64;
+        }
+    }
+    count += stack.pop_all().count() as u64;
+    total.fetch_add(count, std::sync::atomic::Ordering::SeqCst);
+}
+
 const NUM_THREADS: u64 = 100;
 const NUM_INSERTS: u64 = 10000;
 
@@ -60,7 +77,6 @@ fn test_stack() {
 }
 
 #[test]
-#[ignore]
 fn test_nonce_stack() {
     use std::thread;
     let stack: NonceStack<u64> = Default::default();
@@ -97,6 +113,30 @@ fn test_nonce_stack() {
     assert_eq!(popped.load(Ordering::Relaxed), total);
 }
 
+#[test]
+fn test_nonce_stack_drops_values_once() {
+    use std::thread;
+    let val = Arc::new(());
+    let stack: NonceStack<Arc<()>> = Default::default();
+    thread::scope(|s| {
+        for _ in 0..NUM_THREADS {
+            s.spawn(|| {
+                for i in 0..1_000 {
+                    stack.push(val.clone());
+                    if i % 3 != 0 {
+                        drop(stack.pop());
+                    }
+                }
+            });
+        }
+    });
+    // Popped nodes are freed without dropping their (moved out) values, and
+    // the values that are still on the stack are dropped along with it.
+    assert!(Arc::strong_count(&val) > 1);
+    drop(stack);
+    assert_eq!(Arc::strong_count(&val), 1);
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn test_tokio_stack() -> Result<(), Box<dyn Error>> {
     let stack: Stack<u64> = Default::default();
@@ -146,3 +186,746 @@ fn test_node_iterator_reverse() {
     }
     assert_eq!(iter.next(), None);
 }
+
+#[test]
+fn test_pop_all_fifo() {
+    let stack: Stack<u64> = Default::default();
+    assert_eq!(stack.pop_all_fifo().next(), None);
+
+    for i in 1..100 {
+        stack.push(i);
+    }
+
+    let mut iter = stack.pop_all_fifo();
+    for i in 1..100 {
+        assert_eq!(iter.next().unwrap(), i);
+    }
+    assert_eq!(iter.next(), None);
+    assert_eq!(stack.pop_all().next(), None);
+}
+
+#[test]
+fn test_close() {
+    let stack: Stack<u64> = Default::default();
+    stack.push(1);
+    assert!(!stack.is_closed());
+    assert!(stack.close());
+    assert!(!stack.close());
+    assert!(stack.is_closed());
+    assert!(matches!(stack.try_push(2), Err(StackError::Closed(2))));
+    assert_eq!(stack.pop_all().collect::<Vec<_>>(), vec![1]);
+
+    let stack: Stack<u64> = Default::default();
+    stack.push(1);
+    stack.push(2);
+    assert_eq!(stack.pop_all_and_close().collect::<Vec<_>>(), vec![2, 1]);
+    assert!(stack.is_closed());
+    assert!(stack.try_push(3).is_err());
+    assert_eq!(stack.pop_all().next(), None);
+}
+
+#[test]
+fn test_elimination_stack() {
+    use std::thread;
+    let stack: EliminationStack<u64> = Default::default();
+    assert_eq!(stack.pop_all().next(), None);
+    assert_eq!(stack.pop(), None);
+    stack.push(1);
+    stack.push(2);
+    assert_eq!(stack.pop_all().collect::<Vec<_>>(), vec![2, 1]);
+    stack.push(3);
+    assert_eq!(stack.pop(), Some(3));
+
+    // Mix pushes with both kinds of pop, so that parked pushes are collected
+    // by each, and check that every value comes out exactly once.
+    const THREADS: u64 = 16;
+    const INSERTS: u64 = 2_000;
+    let popped: Vec<Vec<u64>> = thread::scope(|s| {
+        let workers: Vec<_> = (0..THREADS)
+            .map(|n| {
+                let stack = &stack;
+                s.spawn(move || {
+                    let mut popped = vec![];
+                    for i in 0..INSERTS {
+                        stack.push(n * INSERTS + i);
+                        if i % 17 == 0 {
+                            popped.extend(stack.pop_all());
+                        } else if i % 3 == 0 {
+                            popped.extend(stack.pop());
+                        }
+                    }
+                    popped
+                })
+            })
+            .collect();
+        workers.into_iter().map(|w| w.join().unwrap()).collect()
+    });
+    let mut popped: Vec<u64> = popped.into_iter().flatten().collect();
+    popped.extend(stack.pop_all());
+    popped.sort_unstable();
+    assert_eq!(popped, (0..THREADS * INSERTS).collect::<Vec<_>>());
+}
+
+/// Compares `EliminationStack` with the plain `Stack` under the same
+/// workload.  This only prints timings, so it is not run by default.
+#[test]
+#[ignore]
+fn bench_elimination_stack() {
+    use std::thread;
+    let stack: EliminationStack<u64> = Default::default();
+    let total = AtomicU64::new(0);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for n in 0..NUM_THREADS {
+            let stack = &stack;
+            let total = &total;
+            s.spawn(move || {
+                elimination_worker(NUM_INSERTS, n, stack, total);
+            });
+        }
+    });
+    assert_eq!(total.load(Ordering::SeqCst), NUM_THREADS * NUM_INSERTS);
+    let duration = start.elapsed().as_micros();
+    println!("elimination stack time elapsed (usec) {duration}");
+
+    let stack: Stack<u64> = Default::default();
+    let total = AtomicU64::new(0);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for n in 0..NUM_THREADS {
+            let stack = &stack;
+            let total = &total;
+            s.spawn(move || {
+                worker(NUM_INSERTS, n, stack, total);
+            });
+        }
+    });
+    assert_eq!(total.load(Ordering::SeqCst), NUM_THREADS * NUM_INSERTS);
+    let duration = start.elapsed().as_micros();
+    println!("plain stack time elapsed (usec) {duration}");
+}
+
+#[test]
+fn test_pop() {
+    use std::thread;
+    let stack: Stack<u64> = Default::default();
+    assert_eq!(stack.pop(), None);
+    stack.push(1);
+    stack.push(2);
+    assert_eq!(stack.pop(), Some(2));
+    assert_eq!(stack.pop(), Some(1));
+    assert_eq!(stack.pop(), None);
+
+    let total = 250_000u64;
+    let pushed = AtomicU64::new(0);
+    let popped = AtomicU64::new(0);
+    thread::scope(|s| {
+        for n in 0..NUM_THREADS {
+            let stack = &stack;
+            let pushed = &pushed;
+            let popped = &popped;
+            s.spawn(move || loop {
+                let mut done = true;
+                let val = pushed.fetch_add(1, Ordering::Relaxed);
+                if val < total {
+                    stack.push(val);
+                    done = false;
+                }
+                // Mix in some drains so pop races with pop_all.
+                if n % 10 == 0 {
+                    let count = stack.pop_all().count() as u64;
+                    popped.fetch_add(count, Ordering::Relaxed);
+                } else if stack.pop().is_some() {
+                    popped.fetch_add(1, Ordering::Relaxed);
+                    done = false;
+                }
+                if done {
+                    break;
+                }
+            });
+        }
+    });
+    popped.fetch_add(stack.pop_all().count() as u64, Ordering::Relaxed);
+    assert_eq!(popped.load(Ordering::Relaxed), total);
+}
+
+#[test]
+fn test_pop_n() {
+    use std::thread;
+    let stack: Stack<u64> = Default::default();
+    assert_eq!(stack.pop_n(3).next(), None);
+    for i in 0..10 {
+        stack.push(i);
+    }
+    assert_eq!(stack.pop_n(0).next(), None);
+    assert_eq!(stack.pop_n(3).collect::<Vec<_>>(), vec![9, 8, 7]);
+    assert_eq!(stack.pop_n(1).collect::<Vec<_>>(), vec![6]);
+    assert_eq!(stack.pop_n(100).count(), 6);
+    assert_eq!(stack.pop(), None);
+
+    let total = 250_000u64;
+    let pushed = AtomicU64::new(0);
+    let popped = AtomicU64::new(0);
+    thread::scope(|s| {
+        for n in 0..NUM_THREADS {
+            let stack = &stack;
+            let pushed = &pushed;
+            let popped = &popped;
+            s.spawn(move || loop {
+                let val = pushed.fetch_add(1, Ordering::Relaxed);
+                if val >= total {
+                    break;
+                }
+                stack.push(val);
+                let count = match n % 3 {
+                    0 => stack.pop().map_or(0, |_| 1),
+                    1 => stack.pop_n(4).count() as u64,
+                    _ => stack.pop_all().count() as u64,
+                };
+                popped.fetch_add(count, Ordering::Relaxed);
+            });
+        }
+    });
+    popped.fetch_add(stack.pop_all().count() as u64, Ordering::Relaxed);
+    assert_eq!(popped.load(Ordering::Relaxed), total);
+}
+
+#[test]
+fn test_drain_with() {
+    let stack: Stack<u64> = Default::default();
+    assert_eq!(stack.drain_with(|_| panic!("empty stack")), 0);
+    for i in 0..10 {
+        stack.push(i);
+    }
+    let mut drained = vec![];
+    assert_eq!(stack.drain_with(|val| drained.push(val)), 10);
+    assert_eq!(drained, (0..10).rev().collect::<Vec<_>>());
+    assert_eq!(stack.pop(), None);
+}
+
+#[test]
+fn test_collection_traits() {
+    let mut stack: Stack<u64> = (0..5).collect();
+    stack.extend(5..10);
+    assert_eq!(stack.pop(), Some(9));
+    assert_eq!(
+        stack.into_iter().collect::<Vec<_>>(),
+        (0..9).rev().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_value_stack() {
+    use std::thread;
+    let stack = ValueStack::<u32>::with_capacity(4);
+    assert_eq!(stack.pop(), None);
+    for i in 0..4 {
+        stack.push(i).unwrap();
+    }
+    assert!(matches!(stack.push(4), Err(StackError::Full(4))));
+    assert_eq!(stack.pop(), Some(3));
+    stack.push(5).unwrap();
+    assert_eq!(stack.pop_all().collect::<Vec<_>>(), vec![5, 2, 1, 0]);
+    assert_eq!(stack.pop(), None);
+
+    let stack = ValueStack::<u32>::with_capacity(64);
+    let popped = AtomicU64::new(0);
+    thread::scope(|s| {
+        for n in 0..NUM_THREADS {
+            let stack = &stack;
+            let popped = &popped;
+            s.spawn(move || {
+                for i in 0..1000 {
+                    while stack.push(i).is_err() {
+                        // Full; make room.
+                        if stack.pop().is_some() {
+                            popped.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    let count = if n % 2 == 0 {
+                        stack.pop().map_or(0, |_| 1)
+                    } else {
+                        stack.pop_all().count() as u64
+                    };
+                    popped.fetch_add(count, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+    popped.fetch_add(stack.pop_all().count() as u64, Ordering::Relaxed);
+    assert_eq!(popped.load(Ordering::Relaxed), NUM_THREADS * 1000);
+}
+
+#[test]
+fn test_swap_contents() {
+    let stack: Stack<u64> = Default::default();
+    stack.push(1);
+    stack.push(2);
+    let pending: Stack<u64> = (10..13).collect();
+    let old = stack.swap_contents(pending.pop_all());
+    assert_eq!(old.collect::<Vec<_>>(), vec![2, 1]);
+    stack.push(13);
+    let old = stack.swap_contents(NodeIterator::new(std::ptr::null_mut()));
+    assert_eq!(old.collect::<Vec<_>>(), vec![13, 12, 11, 10]);
+    assert_eq!(stack.pop(), None);
+}
+
+#[cfg(feature = "stats")]
+#[test]
+fn test_stack_stats() {
+    let stack: Stack<u64> = Default::default();
+    for i in 0..10 {
+        stack.push(i);
+    }
+    assert_eq!(stack.pop(), Some(9));
+    assert_eq!(stack.pop_all().count(), 9);
+    stack.push(10);
+    let stats = stack.stats();
+    assert_eq!(stats.pushes, 11);
+    assert_eq!(stats.pops, 1);
+    assert_eq!(stats.pop_alls, 1);
+    assert_eq!(stats.max_depth, 10);
+    assert_eq!(stats.cas_retries, 0);
+}
+
+#[test]
+fn test_slab_stack() {
+    use std::thread;
+    let stack = SlabStack::<String>::with_capacity(3);
+    assert_eq!(stack.pop(), None);
+    stack.push("a".to_string()).unwrap();
+    stack.push("b".to_string()).unwrap();
+    stack.push("c".to_string()).unwrap();
+    assert!(matches!(
+        stack.push("d".to_string()),
+        Err(StackError::Full(_))
+    ));
+    assert_eq!(stack.pop().as_deref(), Some("c"));
+    assert_eq!(stack.pop_all().collect::<Vec<_>>(), vec!["b", "a"]);
+    // Leave something behind for Drop to clean up.
+    stack.push("e".to_string()).unwrap();
+
+    let stack = SlabStack::<Box<u64>>::with_capacity(32);
+    let popped = AtomicU64::new(0);
+    thread::scope(|s| {
+        for _ in 0..NUM_THREADS {
+            let stack = &stack;
+            let popped = &popped;
+            s.spawn(move || {
+                for i in 0..1000u64 {
+                    let mut val = Box::new(i);
+                    while let Err(StackError::Full(v)) = stack.push(val) {
+                        val = v;
+                        if let Some(v) = stack.pop() {
+                            popped.fetch_add(1, Ordering::Relaxed);
+                            assert!(*v < 1000);
+                        }
+                    }
+                    if stack.pop().is_some() {
+                        popped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    popped.fetch_add(stack.pop_all().count() as u64, Ordering::Relaxed);
+    assert_eq!(popped.load(Ordering::Relaxed), NUM_THREADS * 1000);
+}
+
+#[test]
+fn test_sharded_stack() {
+    use std::thread;
+    let stack = ShardedStack::<u64>::with_shards(4);
+    assert_eq!(stack.pop_all().next(), None);
+    assert_eq!(stack.pop(), None);
+
+    let total = AtomicU64::new(0);
+    thread::scope(|s| {
+        for n in 0..NUM_THREADS {
+            let stack = &stack;
+            let total = &total;
+            s.spawn(move || {
+                let mut count = 0;
+                for i in 0..NUM_INSERTS {
+                    stack.push(n * NUM_INSERTS + i);
+                    if i % 17 == 0 {
+                        count += stack.pop_all().count() as u64;
+                    } else if i % 5 == 0 && stack.pop().is_some() {
+                        count += 1;
+                    }
+                }
+                total.fetch_add(count, Ordering::SeqCst);
+            });
+        }
+    });
+    total.fetch_add(stack.pop_all().count() as u64, Ordering::SeqCst);
+    assert_eq!(total.load(Ordering::SeqCst), NUM_THREADS * NUM_INSERTS);
+}
+
+#[cfg(feature = "epoch")]
+#[test]
+fn test_peek_top() {
+    use std::thread;
+    let stack: Stack<u64> = Default::default();
+    assert_eq!(stack.peek_top(|v| *v), None);
+    stack.push(1);
+    stack.push(22);
+    let gen = stack.generation();
+    assert_eq!(stack.peek_top(|v| *v), Some(22));
+    assert_eq!(
+        stack.generation(),
+        gen,
+        "reads must not bump the generation"
+    );
+    assert_eq!(stack.pop(), Some(22));
+    assert_eq!(stack.peek_top(|v| *v * 2), Some(2));
+    stack.pop_all();
+
+    // Peekers hold their pin across a slow closure, so poppers and drains
+    // keep unlinking (and retiring) the nodes they are reading.
+    let done = std::sync::atomic::AtomicBool::new(false);
+    thread::scope(|s| {
+        for n in 0..8 {
+            let stack = &stack;
+            let done = &done;
+            s.spawn(move || {
+                if n % 2 == 0 {
+                    for i in 0..1_000u64 {
+                        stack.push(i);
+                        if i % 3 == 0 {
+                            stack.pop_all();
+                        } else {
+                            stack.pop();
+                        }
+                    }
+                    done.store(true, Ordering::Relaxed);
+                } else {
+                    while !done.load(Ordering::Relaxed) {
+                        if let Some(val) = stack.peek_top(|v| {
+                            thread::yield_now();
+                            *v
+                        }) {
+                            assert!(val < 1_000);
+                        }
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_notify_stack() -> Result<(), Box<dyn Error>> {
+    let stack = Arc::new(NotifyStack::<u64>::default());
+    stack.push(1);
+    assert_eq!(stack.pop_all_or_wait().await.collect::<Vec<_>>(), vec![1]);
+
+    let consumer = {
+        let stack = stack.clone();
+        tokio::spawn(async move {
+            let mut received = 0;
+            while received < NUM_THREADS * 100 {
+                let batch = stack.pop_all_or_wait().await.count() as u64;
+                assert!(batch > 0);
+                received += batch;
+            }
+            // Closing wakes us up with an empty batch.
+            assert_eq!(stack.pop_all_or_wait().await.next(), None);
+            received
+        })
+    };
+    let mut producers = vec![];
+    for n in 0..NUM_THREADS {
+        let stack = stack.clone();
+        producers.push(tokio::spawn(async move {
+            for i in 0..100 {
+                stack.push(n * 100 + i);
+                tokio::task::yield_now().await;
+            }
+        }));
+    }
+    for p in producers {
+        p.await?;
+    }
+    assert!(stack.close());
+    assert_eq!(consumer.await?, NUM_THREADS * 100);
+    Ok(())
+}
+
+#[test]
+fn test_timed_stack() {
+    let stack = TimedStack::<u64>::default();
+    assert_eq!(stack.latency().quantile(0.5), None);
+    for i in 0..10 {
+        stack.push(i);
+    }
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    assert_eq!(stack.pop(), Some(9));
+    assert_eq!(stack.pop_all().count(), 9);
+    let latency = stack.latency();
+    assert_eq!(latency.total(), 10);
+    assert!(latency.quantile(0.0).unwrap() >= std::time::Duration::from_millis(2));
+    assert!(latency.quantile(1.0).unwrap() < std::time::Duration::from_secs(60));
+}
+
+#[test]
+fn test_counted_stack() {
+    let stack = Arc::new(CountedStack::<u64>::default());
+    assert!(stack.is_empty());
+    assert!(stack.pop_all_if_at_least(1).is_none());
+    assert_eq!(stack.pop_all().count(), 0);
+
+    let threads = 4;
+    let per_thread = 1000;
+    let producers: Vec<_> = (0..threads)
+        .map(|t| {
+            let stack = stack.clone();
+            std::thread::spawn(move || {
+                for i in 0..per_thread {
+                    stack.push(t * per_thread + i);
+                }
+            })
+        })
+        .collect();
+
+    let mut seen = vec![];
+    while seen.len() < (threads * per_thread) as usize {
+        if let Some(batch) = stack.pop_all_if_at_least(100) {
+            let batch: Vec<_> = batch.collect();
+            assert!(batch.len() >= 100);
+            seen.extend(batch);
+        } else if producers.iter().all(|p| p.is_finished()) {
+            seen.extend(stack.pop_all());
+        }
+    }
+    for p in producers {
+        p.join().unwrap();
+    }
+    seen.sort();
+    assert_eq!(seen, (0..threads * per_thread).collect::<Vec<_>>());
+    assert_eq!(stack.len(), 0);
+}
+
+#[test]
+fn test_queue() {
+    let queue = Arc::new(Queue::<(u64, u64)>::default());
+    assert!(queue.pop().is_none());
+    assert_eq!(queue.pop_all().count(), 0);
+
+    let threads = 4;
+    let per_thread = 10000;
+    let producers: Vec<_> = (0..threads)
+        .map(|t| {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                for i in 0..per_thread {
+                    queue.push((t, i));
+                }
+            })
+        })
+        .collect();
+
+    // Each producer's items must come out in the order it pushed them, no
+    // matter how pop and pop_all are interleaved.
+    let mut next = vec![0; threads as usize];
+    let mut received = 0;
+    while received < threads * per_thread {
+        let batch: Vec<_> = if received % 3 == 0 {
+            queue.pop_all().collect()
+        } else {
+            queue.pop().into_iter().collect()
+        };
+        for (t, i) in batch {
+            assert_eq!(next[t as usize], i);
+            next[t as usize] += 1;
+            received += 1;
+        }
+    }
+    for p in producers {
+        p.join().unwrap();
+    }
+
+    queue.push((0, 0));
+    queue.push((0, 1));
+    assert!(queue.close());
+    assert!(queue.try_push((0, 2)).is_err());
+    assert_eq!(queue.pop(), Some((0, 0)));
+    assert_eq!(queue.pop_all().collect::<Vec<_>>(), vec![(0, 1)]);
+}
+
+#[test]
+fn test_push_node() {
+    let stack = Stack::<u64>::default();
+    let mut spare = vec![];
+    for round in 0..3 {
+        for i in 0..10 {
+            let mut node = spare.pop().unwrap_or_else(|| {
+                Box::new(atomic_try_update::Node {
+                    val: 0,
+                    next: std::ptr::null_mut(),
+                })
+            });
+            node.val = round * 10 + i;
+            stack.push_node(node);
+        }
+        let mut drained = stack.pop_all();
+        let mut expected = round * 10 + 10;
+        while let Some(node) = drained.next_node() {
+            expected -= 1;
+            assert_eq!(node.val, expected);
+            assert!(node.next.is_null());
+            spare.push(node);
+        }
+        assert_eq!(spare.len(), 10);
+    }
+
+    stack.close();
+    let node = spare.pop().unwrap();
+    match stack.try_push_node(node) {
+        Err(StackError::Closed(node)) => assert_eq!(node.val, 20),
+        _ => panic!("push_node to closed stack succeeded"),
+    }
+}
+
+#[test]
+fn test_generation() {
+    let stack = Stack::<u64>::default();
+    let gen = stack.generation();
+    stack.pop_all();
+    assert!(stack.pop().is_none());
+    assert_eq!(
+        stack.generation(),
+        gen,
+        "reads must not bump the generation"
+    );
+
+    stack.push(1);
+    let pushed = stack.generation();
+    assert_ne!(pushed, gen);
+    assert_eq!(stack.pop(), Some(1));
+    assert_ne!(stack.generation(), pushed);
+
+    let before_close = stack.generation();
+    stack.close();
+    assert_ne!(stack.generation(), before_close);
+}
+
+#[test]
+fn test_stack_allocator() {
+    use atomic_try_update::allocator_api2::alloc::{AllocError, Allocator, Global, Layout};
+    use std::ptr::NonNull;
+
+    #[derive(Default)]
+    struct CountingAlloc {
+        live: AtomicU64,
+        total: AtomicU64,
+    }
+    unsafe impl Allocator for &CountingAlloc {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.live.fetch_add(1, Ordering::Relaxed);
+            self.total.fetch_add(1, Ordering::Relaxed);
+            Global.allocate(layout)
+        }
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.live.fetch_sub(1, Ordering::Relaxed);
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    let alloc = CountingAlloc::default();
+    {
+        let stack = Stack::new_in(&alloc);
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let stack = &stack;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        stack.push(t * 1000 + i);
+                        if i % 2 == 0 {
+                            stack.pop();
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(alloc.total.load(Ordering::Relaxed), 4000);
+        assert_eq!(stack.pop_n(10).count(), 10);
+        assert_eq!(stack.pop_all_fifo().count(), 1990);
+        stack.push(1);
+        stack.close();
+        assert!(matches!(stack.try_push(2), Err(StackError::Closed(2))));
+    }
+    assert_eq!(alloc.live.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_pop_all_into() {
+    let stack = Stack::<u64>::default();
+    let mut buf = vec![42];
+    stack.pop_all_into(&mut buf);
+    assert!(buf.is_empty());
+
+    for round in 0..3 {
+        for i in 0..100 {
+            stack.push(round * 100 + i);
+        }
+        stack.pop_all_into(&mut buf);
+        assert_eq!(
+            buf,
+            (round * 100..round * 100 + 100).rev().collect::<Vec<_>>()
+        );
+    }
+    let capacity = buf.capacity();
+    stack.push(1);
+    stack.pop_all_into(&mut buf);
+    assert_eq!(buf, vec![1]);
+    assert_eq!(buf.capacity(), capacity);
+}
+
+#[test]
+fn test_buffered_pusher() {
+    let stack = Stack::<u64>::default();
+    let threads = 4;
+    let per_thread = 10000;
+    std::thread::scope(|s| {
+        for t in 0..threads {
+            let stack = &stack;
+            s.spawn(move || {
+                let mut pusher = stack
+                    .buffered()
+                    .with_max_len(16)
+                    .with_max_delay(std::time::Duration::from_micros(100));
+                for i in 0..per_thread {
+                    pusher.push(t * per_thread + i);
+                    assert!(pusher.len() < 16);
+                }
+                pusher.flush();
+                assert!(pusher.is_empty());
+            });
+        }
+        // Drain concurrently, so the pushers see some contention.
+        s.spawn(|| {
+            for _ in 0..1000 {
+                stack.pop();
+            }
+        });
+    });
+    let remaining = stack.pop_all().count() as u64;
+    assert!(remaining >= threads * per_thread - 1000);
+
+    // Dropping the pusher flushes it.
+    {
+        let mut pusher = stack.buffered();
+        pusher.push(1);
+        pusher.push(2);
+    }
+    let mut drained: Vec<_> = stack.pop_all().collect();
+    drained.sort();
+    assert_eq!(drained, vec![1, 2]);
+
+    stack.close();
+    let mut pusher = stack.buffered();
+    assert!(pusher.try_flush().is_ok());
+    let pushed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| pusher.push(3)));
+    assert!(pushed.is_err());
+}